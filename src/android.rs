@@ -3,10 +3,11 @@
 use crate::tun2proxy::TunToProxy;
 use crate::{error::Error, tun_to_proxy, NetworkInterface, Options, Proxy};
 use jni::{
-    objects::{JClass, JString},
+    objects::{GlobalRef, JClass, JObject, JString, JValue},
     sys::{jboolean, jint},
-    JNIEnv,
+    JNIEnv, JavaVM,
 };
+use std::os::unix::io::RawFd;
 
 static mut TUN_TO_PROXY: Option<TunToProxy> = None;
 
@@ -15,12 +16,64 @@ static mut TUN_TO_PROXY: Option<TunToProxy> = None;
 /// Running tun2proxy
 #[no_mangle]
 pub unsafe extern "C" fn Java_com_github_shadowsocks_bg_Tun2proxy_run(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _clazz: JClass,
     proxy_url: JString,
     tun_fd: jint,
     tun_mtu: jint,
     verbose: jboolean,
+) -> jint {
+    run(env, proxy_url, tun_fd, tun_mtu, verbose, None)
+}
+
+/// # Safety
+///
+/// Running tun2proxy, with each socket to the proxy passed to `VpnService.protect()` of
+/// `vpn_service` before it connects, so that it does not loop back into the tunnel
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_github_shadowsocks_bg_Tun2proxy_runWithVpnService(
+    env: JNIEnv,
+    _clazz: JClass,
+    vpn_service: JObject,
+    proxy_url: JString,
+    tun_fd: jint,
+    tun_mtu: jint,
+    verbose: jboolean,
+) -> jint {
+    let vpn_service = env
+        .get_java_vm()
+        .and_then(|vm| Ok((vm, env.new_global_ref(vpn_service)?)));
+    match vpn_service {
+        Ok(vpn_service) => run(env, proxy_url, tun_fd, tun_mtu, verbose, Some(vpn_service)),
+        Err(error) => {
+            log::error!(
+                "failed to reference the VPN service with error: {:?}",
+                error
+            );
+            1
+        }
+    }
+}
+
+// Sockets are protected from any thread, which is attached to the VM for the call.
+fn protect(vm: &JavaVM, service: &GlobalRef, fd: RawFd) -> bool {
+    let result = vm.attach_current_thread().and_then(|mut env| {
+        env.call_method(service, "protect", "(I)Z", &[JValue::Int(fd)])?
+            .z()
+    });
+    result.unwrap_or_else(|error| {
+        log::error!("failed to protect socket with error: {:?}", error);
+        false
+    })
+}
+
+unsafe fn run(
+    mut env: JNIEnv,
+    proxy_url: JString,
+    tun_fd: jint,
+    tun_mtu: jint,
+    verbose: jboolean,
+    mut vpn_service: Option<(JavaVM, GlobalRef)>,
 ) -> jint {
     let log_level = if verbose != 0 { "trace" } else { "info" };
     let filter_str = &format!("off,tun2proxy={log_level}");
@@ -42,7 +95,10 @@ pub unsafe extern "C" fn Java_com_github_shadowsocks_bg_Tun2proxy_run(
         let proxy_type = proxy.proxy_type;
         log::info!("Proxy {proxy_type} server: {addr}");
 
-        let options = Options::new().with_virtual_dns().with_mtu(tun_mtu as usize);
+        let mut options = Options::new().with_virtual_dns().with_mtu(tun_mtu as usize);
+        if let Some((vm, service)) = vpn_service.take() {
+            options = options.with_socket_protector(move |fd| protect(&vm, &service, fd));
+        }
 
         let interface = NetworkInterface::Fd(tun_fd);
         let tun2proxy = tun_to_proxy(&interface, &proxy, options)?;
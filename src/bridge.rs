@@ -0,0 +1,65 @@
+use mio::net::UnixStream;
+use mio::{Registry, Token, Waker};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+
+/// Hands connections to a bridge thread as one end of a socket pair, which unlike a loopback
+/// listener cannot be connected to by other processes. The thread is told to stop once the
+/// handle is dropped.
+pub(crate) struct BridgeHandle {
+    sender: Option<Sender<UnixStream>>,
+    waker: Arc<Waker>,
+}
+
+impl BridgeHandle {
+    /// A new connection to the bridge thread.
+    pub(crate) fn connect(&self) -> std::io::Result<UnixStream> {
+        let (stream, bridge_end) = UnixStream::pair()?;
+        let stopped = || std::io::Error::new(std::io::ErrorKind::NotConnected, "bridge stopped");
+        let sender = self.sender.as_ref().ok_or_else(stopped)?;
+        sender.send(bridge_end).map_err(|_| stopped())?;
+        self.waker.wake()?;
+        Ok(stream)
+    }
+}
+
+impl Drop for BridgeHandle {
+    fn drop(&mut self) {
+        self.sender.take();
+        let _ = self.waker.wake();
+    }
+}
+
+/// The connections handed to a bridge thread, which it takes once the waker registered with
+/// its poll under the given token has been triggered.
+pub(crate) struct BridgeConnections {
+    receiver: Receiver<UnixStream>,
+}
+
+impl BridgeConnections {
+    /// The connections made since the last call, or `None` once the handle has been dropped and
+    /// the thread is to stop.
+    pub(crate) fn take(&self) -> Option<Vec<UnixStream>> {
+        let mut streams = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(stream) => streams.push(stream),
+                Err(TryRecvError::Empty) => return Some(streams),
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+pub(crate) fn channel(
+    registry: &Registry,
+    token: Token,
+) -> std::io::Result<(BridgeHandle, BridgeConnections)> {
+    let waker = Arc::new(Waker::new(registry, token)?);
+    let (sender, receiver) = mpsc::channel();
+    let handle = BridgeHandle {
+        sender: Some(sender),
+        waker,
+    };
+    Ok((handle, BridgeConnections { receiver }))
+}
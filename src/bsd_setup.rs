@@ -0,0 +1,483 @@
+#![cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+
+use crate::error::Error;
+use smoltcp::wire::IpCidr;
+
+use std::ffi::OsStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::process::{Command, Output};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use fork::Fork;
+
+// Addresses of the point-to-point link the tun device forms, which the routes to the tunnel point
+// to, as the BSDs route to gateways rather than to interfaces.
+const TUN_ADDR_V4: &str = "169.254.19.1";
+const TUN_PEER_V4: &str = "169.254.19.2";
+const TUN_ADDR_V6: &str = "fdc6:7475:6e32::1";
+const TUN_PEER_V6: &str = "fdc6:7475:6e32::2";
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.tun2proxy";
+
+// Whether the privileged process has been asked to restore the network configuration.
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_termination(_: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+}
+
+#[derive(Clone)]
+pub struct Setup {
+    routes: Vec<IpCidr>,
+    tunnel_bypass_addrs: Vec<IpAddr>,
+    allow_private: bool,
+    tun: String,
+    set_up: bool,
+    proxy_routes: Vec<IpAddr>,
+    child: libc::pid_t,
+    route_pipe: Option<RawFd>,
+    tun_addrs: Vec<IpAddr>,
+    tun_mtu: Option<u32>,
+}
+
+pub fn get_default_cidrs() -> [IpCidr; 4] {
+    [
+        IpCidr::new(Ipv4Addr::from_str("0.0.0.0").unwrap().into(), 1),
+        IpCidr::new(Ipv4Addr::from_str("128.0.0.0").unwrap().into(), 1),
+        IpCidr::new(Ipv6Addr::from_str("::").unwrap().into(), 1),
+        IpCidr::new(Ipv6Addr::from_str("8000::").unwrap().into(), 1),
+    ]
+}
+
+fn run_command<I, S>(args: I, error: &str, require_success: bool) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut args = args.into_iter();
+    let mut command = Command::new(args.next().ok_or(error)?);
+    command.args(args);
+    let output = command.output().map_err(|_| Error::from(error))?;
+    if !require_success || output.status.success() {
+        return Ok(output);
+    }
+    let program = command.get_program().to_string_lossy();
+    let args: Vec<_> = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy())
+        .collect();
+    Err(format!(
+        "[{}] Command `{} {}` failed: {}",
+        nix::unistd::getpid(),
+        program,
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    )
+    .into())
+}
+
+fn family(addr: &IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "-inet",
+        IpAddr::V6(_) => "-inet6",
+    }
+}
+
+impl Setup {
+    pub fn new(
+        tun: impl Into<String>,
+        tunnel_bypass_addr: &IpAddr,
+        routes: impl IntoIterator<Item = IpCidr>,
+        allow_private: bool,
+    ) -> Self {
+        Self {
+            tun: tun.into(),
+            tunnel_bypass_addrs: vec![*tunnel_bypass_addr],
+            allow_private,
+            routes: routes.into_iter().collect(),
+            set_up: false,
+            proxy_routes: Vec::new(),
+            child: 0,
+            route_pipe: None,
+            tun_addrs: Vec::new(),
+            tun_mtu: None,
+        }
+    }
+
+    /// Give the tun interface an MTU of `mtu` in place of the default of 1500 bytes.
+    pub fn with_tun_mtu(mut self, mtu: u32) -> Self {
+        self.tun_mtu = Some(mtu);
+        self
+    }
+
+    /// Give the tun interface the address `addr` in place of the default one of its family, with
+    /// the next address as its peer. The prefix length is ignored, as the link is point-to-point.
+    pub fn with_tun_addr(mut self, addr: &IpAddr, _prefix_len: u8) -> Self {
+        self.tun_addrs
+            .retain(|other| other.is_ipv4() != addr.is_ipv4());
+        self.tun_addrs.push(*addr);
+        self
+    }
+
+    // The address of the tun interface and of its peer for the given family.
+    fn point_to_point(&self, ipv6: bool) -> (String, String) {
+        match self.tun_addrs.iter().find(|addr| addr.is_ipv6() == ipv6) {
+            Some(IpAddr::V4(addr)) => {
+                let peer = Ipv4Addr::from(u32::from(*addr).wrapping_add(1));
+                (addr.to_string(), peer.to_string())
+            }
+            Some(IpAddr::V6(addr)) => {
+                let peer = Ipv6Addr::from(u128::from(*addr).wrapping_add(1));
+                (addr.to_string(), peer.to_string())
+            }
+            None if ipv6 => (TUN_ADDR_V6.to_string(), TUN_PEER_V6.to_string()),
+            None => (TUN_ADDR_V4.to_string(), TUN_PEER_V4.to_string()),
+        }
+    }
+
+    /// Let traffic to another address bypass the tunnel, e.g. that of a fallback proxy.
+    pub fn with_bypass_addr(mut self, tunnel_bypass_addr: &IpAddr) -> Self {
+        if !self.tunnel_bypass_addrs.contains(tunnel_bypass_addr) {
+            self.tunnel_bypass_addrs.push(*tunnel_bypass_addr);
+        }
+        self
+    }
+
+    /// Leave a range out of the routes to the tunnel, so that its traffic follows the routes
+    /// otherwise in place, e.g. to the local network.
+    pub fn with_bypass_cidr(mut self, cidr: &IpCidr) -> Self {
+        self.routes = crate::cidr::exclude(&self.routes, cidr);
+        self
+    }
+
+    // The gateway of the default route of the address family of `addr` which does not lead into
+    // the tunnel, as listed by netstat(1).
+    fn default_gateway(&self, addr: &IpAddr) -> Result<Option<String>, Error> {
+        let family = match addr {
+            IpAddr::V4(_) => "inet",
+            IpAddr::V6(_) => "inet6",
+        };
+        let routes = run_command(
+            ["netstat", "-rn", "-f", family],
+            "failed to get routing table",
+            true,
+        )?;
+        let routes = String::from_utf8_lossy(&routes.stdout);
+        let gateway = routes.lines().find_map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            match columns.as_slice() {
+                ["default", gateway, .., interface] if *interface != self.tun => {
+                    Some(gateway.to_string())
+                }
+                _ => None,
+            }
+        });
+        Ok(gateway)
+    }
+
+    fn route_proxy_address(&self, tunnel_bypass_addr: IpAddr) -> Result<bool, Error> {
+        let addr = tunnel_bypass_addr.to_string();
+        let family = family(&tunnel_bypass_addr);
+        let route = run_command(
+            ["route", "-n", "get", family, addr.as_str()],
+            "failed to get route",
+            false,
+        )?;
+        let route = String::from_utf8_lossy(&route.stdout);
+        let field = |name: &str| {
+            route.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+        // The address is routed through a more specific route than the default route, unless
+        // that is one of the routes to the tunnel. In this case, there is nothing to do.
+        if field("destination").as_deref() != Some("default")
+            && field("interface").is_some_and(|interface| interface != self.tun)
+        {
+            return Ok(false);
+        }
+        let gateway = match self.default_gateway(&tunnel_bypass_addr)? {
+            Some(gateway) => gateway,
+            None => return Ok(false),
+        };
+        run_command(
+            [
+                "route",
+                "-n",
+                "add",
+                family,
+                "-host",
+                addr.as_str(),
+                gateway.as_str(),
+            ],
+            "failed to add route for proxy",
+            false,
+        )?;
+        Ok(true)
+    }
+
+    fn add_proxy_route(&mut self, tunnel_bypass_addr: IpAddr) -> Result<(), Error> {
+        if self.proxy_routes.contains(&tunnel_bypass_addr) {
+            return Ok(());
+        }
+        if self.route_proxy_address(tunnel_bypass_addr)? {
+            log::info!(
+                "[{}] Routing {} around the tunnel",
+                nix::unistd::getpid(),
+                tunnel_bypass_addr
+            );
+            self.proxy_routes.push(tunnel_bypass_addr);
+        }
+        Ok(())
+    }
+
+    // Add the routes for the addresses written to the pipe, one per line. Returns false once the
+    // pipe is closed.
+    fn read_route_requests(
+        &mut self,
+        read_routes: RawFd,
+        pending: &mut Vec<u8>,
+    ) -> Result<bool, Error> {
+        let mut buf = [0; 256];
+        let size = nix::unistd::read(read_routes, &mut buf)?;
+        if size == 0 {
+            return Ok(false);
+        }
+        pending.extend(&buf[..size]);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let addr = std::str::from_utf8(&line)?.trim().parse::<IpAddr>()?;
+            if let Err(e) = self.add_proxy_route(addr) {
+                log::error!("{e}");
+            }
+        }
+        Ok(true)
+    }
+
+    // There is no bind mount of single files, so the file is replaced and restored later on.
+    fn setup_resolv_conf() -> Result<(), Error> {
+        std::fs::copy(RESOLV_CONF, RESOLV_CONF_BACKUP)?;
+        std::fs::write(RESOLV_CONF, "nameserver 198.18.0.1\n")?;
+        Ok(())
+    }
+
+    fn add_tunnel_routes(&self) -> Result<(), Error> {
+        for route in &self.routes {
+            let (family, (_, gateway)) = match route {
+                IpCidr::Ipv4(_) => ("-inet", self.point_to_point(false)),
+                IpCidr::Ipv6(_) => ("-inet6", self.point_to_point(true)),
+            };
+            run_command(
+                [
+                    "route",
+                    "-n",
+                    "add",
+                    family,
+                    "-net",
+                    route.to_string().as_str(),
+                    gateway.as_str(),
+                ],
+                "failed to add route",
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.set_up = false;
+        log::info!(
+            "[{}] Restoring network configuration",
+            nix::unistd::getpid()
+        );
+        let _ = Command::new("ifconfig")
+            .args([self.tun.as_str(), "destroy"])
+            .output();
+        for proxy_route in std::mem::take(&mut self.proxy_routes) {
+            let addr = proxy_route.to_string();
+            let _ = Command::new("route")
+                .args(["-n", "delete", family(&proxy_route), "-host", addr.as_str()])
+                .output();
+        }
+        if std::path::Path::new(RESOLV_CONF_BACKUP).exists() {
+            std::fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF)?;
+        }
+        Ok(())
+    }
+
+    fn setup_and_handle_signals(
+        &mut self,
+        read_from_child: RawFd,
+        write_to_parent: RawFd,
+        read_routes: RawFd,
+    ) {
+        if let Err(e) = (|| -> Result<(), Error> {
+            use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+            use nix::sys::signal::{SIGINT, SIGQUIT, SIGTERM};
+
+            nix::unistd::close(read_from_child)?;
+            if let Some(write_routes) = self.route_pipe.take() {
+                nix::unistd::close(write_routes)?;
+            }
+
+            // Without SA_RESTART, the signals interrupt the wait for route requests below. The
+            // handler only sets an atomic flag, which is async-signal-safe.
+            let action = SigAction::new(
+                SigHandler::Handler(request_termination),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            for signal in [SIGINT, SIGTERM, SIGQUIT] {
+                unsafe { sigaction(signal, &action) }?;
+            }
+
+            run_command(
+                ["ifconfig", self.tun.as_str(), "create"],
+                "failed to create tunnel device",
+                true,
+            )?;
+            self.set_up = true;
+
+            let (addr, peer) = self.point_to_point(false);
+            let mtu = self.tun_mtu.map(|mtu| mtu.to_string());
+            let mut command = vec![
+                "ifconfig",
+                self.tun.as_str(),
+                "inet",
+                addr.as_str(),
+                peer.as_str(),
+                "up",
+            ];
+            if let Some(mtu) = &mtu {
+                command.extend(["mtu", mtu]);
+            }
+            run_command(command, "failed to bring up tunnel device", true)?;
+            let tun = self.tun.as_str();
+            let (addr, peer) = self.point_to_point(true);
+            run_command(
+                [
+                    "ifconfig",
+                    tun,
+                    "inet6",
+                    addr.as_str(),
+                    peer.as_str(),
+                    "prefixlen",
+                    "128",
+                ],
+                "failed to configure IPv6 on tunnel device",
+                true,
+            )?;
+
+            for tunnel_bypass_addr in self.tunnel_bypass_addrs.clone() {
+                if self.route_proxy_address(tunnel_bypass_addr)? {
+                    self.proxy_routes.push(tunnel_bypass_addr);
+                }
+            }
+            Self::setup_resolv_conf()?;
+            self.add_tunnel_routes()?;
+
+            // Signal to child that we are done setting up everything.
+            if nix::unistd::write(write_to_parent, &[1])? != 1 {
+                return Err("Failed to write to pipe".into());
+            }
+            nix::unistd::close(write_to_parent)?;
+
+            // Routes for new proxy addresses are requested through the pipe until a termination
+            // signal arrives or the parent goes away, which closes the pipe, as there is no death
+            // signal on the BSDs.
+            let mut pending = Vec::new();
+            while !TERMINATING.load(Ordering::SeqCst) {
+                match self.read_route_requests(read_routes, &mut pending) {
+                    Ok(true) | Err(Error::OSError(nix::errno::Errno::EINTR)) => {}
+                    Ok(false) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            self.shutdown()?;
+            Ok(())
+        })() {
+            log::error!("{e}");
+            self.shutdown().unwrap();
+        };
+    }
+
+    pub fn drop_privileges(&self) -> Result<(), Error> {
+        // 32767 is the nobody user of OpenBSD and NetBSD. Even in cases it is not, it is safer to
+        // use this ID than running with UID and GID 0.
+        nix::unistd::setgid(nix::unistd::Gid::from_raw(32767))?;
+        nix::unistd::setuid(nix::unistd::Uid::from_raw(32767))?;
+
+        Ok(())
+    }
+
+    pub fn configure(&mut self) -> Result<(), Error> {
+        log::info!(
+            "[{}] Setting up network configuration",
+            nix::unistd::getpid()
+        );
+        if nix::unistd::getuid() != 0.into() {
+            return Err("Automatic setup requires root privileges".into());
+        }
+
+        for tunnel_bypass_addr in &self.tunnel_bypass_addrs {
+            if tunnel_bypass_addr.is_loopback() && !self.allow_private {
+                log::warn!(
+                    "The proxy address {} is a loopback address. You may need to manually \
+                    provide --setup-ip to specify the server IP bypassing the tunnel",
+                    tunnel_bypass_addr
+                )
+            }
+        }
+
+        let (read_from_child, write_to_parent) = nix::unistd::pipe()?;
+        let (read_routes, write_routes) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+        self.route_pipe = Some(write_routes);
+        match fork::fork() {
+            Ok(Fork::Child) => {
+                self.setup_and_handle_signals(read_from_child, write_to_parent, read_routes);
+                std::process::exit(0);
+            }
+            Ok(Fork::Parent(child)) => {
+                self.child = child;
+                nix::unistd::close(write_to_parent)?;
+                nix::unistd::close(read_routes)?;
+                let mut buf = [0];
+                if nix::unistd::read(read_from_child, &mut buf)? != 1 {
+                    return Err("Failed to read from pipe".into());
+                }
+                nix::unistd::close(read_from_child)?;
+
+                Ok(())
+            }
+            _ => Err("Failed to fork".into()),
+        }
+    }
+
+    /// Let traffic to another address bypass the tunnel once it is set up, e.g. the new address
+    /// of a proxy which has moved. The route is added by the privileged process.
+    pub fn add_bypass_route(&self, tunnel_bypass_addr: &IpAddr) -> Result<(), Error> {
+        let pipe = self
+            .route_pipe
+            .ok_or("The network configuration is not set up")?;
+        let data = format!("{tunnel_bypass_addr}\n");
+        if nix::unistd::write(pipe, data.as_bytes())? != data.len() {
+            return Err("Failed to write to pipe".into());
+        }
+        Ok(())
+    }
+
+    /// Restore the network configuration and wait for the privileged process to finish. Unlike
+    /// a signal, this also works once privileges have been dropped.
+    pub fn restore(&mut self) -> Result<(), Error> {
+        if let Some(pipe) = self.route_pipe.take() {
+            nix::unistd::close(pipe)?;
+        }
+        nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(self.child), None)?;
+        Ok(())
+    }
+}
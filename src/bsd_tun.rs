@@ -0,0 +1,165 @@
+#![cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::rc::Rc;
+
+// Each packet on the device is preceded by its address family in network byte order.
+const HEADER_SIZE: usize = 4;
+
+// Write `packet` preceded by its address family.
+fn write_packet(file: &File, packet: &[u8]) -> io::Result<()> {
+    let family = match packet.first().map(|b| b >> 4) {
+        Some(6) => libc::AF_INET6,
+        _ => libc::AF_INET,
+    };
+    let header = (family as u32).to_be_bytes();
+    let mut writer = file;
+    writer.write_vectored(&[IoSlice::new(&header), IoSlice::new(packet)])?;
+    Ok(())
+}
+
+// _IOW('t', 66, int), which makes NetBSD prepend the address family like OpenBSD always does.
+#[cfg(target_os = "netbsd")]
+const TUNSIFHEAD: libc::c_ulong = 0x8004_7442;
+
+/// A tun device of OpenBSD or NetBSD, e.g. `/dev/tun0`, in place of the TUN interface of
+/// smoltcp, which is only available on Linux.
+pub struct BsdTun {
+    file: Rc<File>,
+    mtu: usize,
+}
+
+impl AsRawFd for BsdTun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl BsdTun {
+    /// Open the device of the interface called `name`, which is created unless it exists.
+    pub fn new(name: &str, mtu: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(format!("/dev/{name}"))?;
+        Self::with_file(file, mtu)
+    }
+
+    /// Take over the device opened as `fd`, e.g. by a privileged parent process.
+    pub fn from_fd(fd: RawFd, mtu: usize) -> io::Result<Self> {
+        let file = unsafe { File::from_raw_fd(fd) };
+        let flags = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFL)?;
+        let flags = nix::fcntl::OFlag::from_bits_truncate(flags) | nix::fcntl::OFlag::O_NONBLOCK;
+        nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(flags))?;
+        Self::with_file(file, mtu)
+    }
+
+    fn with_file(file: File, mtu: usize) -> io::Result<Self> {
+        #[cfg(target_os = "netbsd")]
+        {
+            let mut enabled: libc::c_int = 1;
+            if unsafe { libc::ioctl(file.as_raw_fd(), TUNSIFHEAD as _, &mut enabled) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Self {
+            file: Rc::new(file),
+            mtu,
+        })
+    }
+
+    /// Read a packet into `buffer`, without the address family preceding it, unless there is none
+    /// left.
+    pub fn read_packet(&self, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut header = [0; HEADER_SIZE];
+        loop {
+            let mut slices = [IoSliceMut::new(&mut header), IoSliceMut::new(buffer)];
+            match (&*self.file).read_vectored(&mut slices) {
+                Ok(size) if size > HEADER_SIZE => return Ok(Some(size - HEADER_SIZE)),
+                // Skip whatever is too short to hold a packet.
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Write a packet, prepending its address family.
+    pub fn write_packet(&self, packet: &[u8]) -> io::Result<()> {
+        write_packet(&self.file, packet)
+    }
+}
+
+impl Device for BsdTun {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.max_transmission_unit = self.mtu;
+        capabilities.medium = Medium::Ip;
+        capabilities
+    }
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let mut buffer = vec![0; self.mtu];
+        match self.read_packet(&mut buffer) {
+            Ok(Some(size)) => {
+                buffer.truncate(size);
+                let tx = TxToken {
+                    file: self.file.clone(),
+                };
+                Some((RxToken { buffer }, tx))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                log::warn!("Read from tun device: {error}");
+                None
+            }
+        }
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken {
+            file: self.file.clone(),
+        })
+    }
+}
+
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.buffer)
+    }
+}
+
+pub struct TxToken {
+    file: Rc<File>,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer);
+        // Like a physical link, the device may drop packets under pressure.
+        if let Err(error) = write_packet(&self.file, &buffer) {
+            log::debug!("Write to tun device: {error}");
+        }
+        result
+    }
+}
@@ -0,0 +1,31 @@
+use std::cell::RefCell;
+
+// Buffers kept for reuse, beyond which those given back are freed.
+const MAX_POOLED: usize = 256;
+
+/// Byte buffers which are given back once done with, to be handed out again in place of new
+/// allocations, e.g. for the packets passing through the engine.
+#[derive(Default)]
+pub(crate) struct BufferPool(RefCell<Vec<Vec<u8>>>);
+
+impl BufferPool {
+    /// An empty buffer, which keeps the capacity it had when given back.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.0.borrow_mut().pop().unwrap_or_default()
+    }
+
+    /// A buffer of `len` zeroed bytes.
+    pub(crate) fn take_sized(&self, len: usize) -> Vec<u8> {
+        let mut buffer = self.take();
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    pub(crate) fn give_back(&self, mut buffer: Vec<u8>) {
+        let mut buffers = self.0.borrow_mut();
+        if buffers.len() < MAX_POOLED {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use smoltcp::wire::IpCidr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The two ranges one bit more specific than `cidr`, which together cover it.
+fn halves(cidr: &IpCidr) -> [IpCidr; 2] {
+    let prefix_len = cidr.prefix_len() + 1;
+    let (lower, upper): (IpAddr, IpAddr) = match IpAddr::from(cidr.address()) {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(cidr.prefix_len()));
+            let lower = u32::from(addr) & mask.unwrap_or(0);
+            let upper = lower | 1 << (32 - u32::from(prefix_len));
+            (Ipv4Addr::from(lower).into(), Ipv4Addr::from(upper).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(cidr.prefix_len()));
+            let lower = u128::from(addr) & mask.unwrap_or(0);
+            let upper = lower | 1 << (128 - u32::from(prefix_len));
+            (Ipv6Addr::from(lower).into(), Ipv6Addr::from(upper).into())
+        }
+    };
+    [
+        IpCidr::new(lower.into(), prefix_len),
+        IpCidr::new(upper.into(), prefix_len),
+    ]
+}
+
+// Whether `outer` covers all of `inner`.
+fn covers(outer: &IpCidr, inner: &IpCidr) -> bool {
+    outer.prefix_len() <= inner.prefix_len() && outer.contains_addr(&inner.address())
+}
+
+/// The ranges covering `routes` except for `excluded`, which splits the routes overlapping with
+/// it into the more specific ranges around it.
+pub(crate) fn exclude(routes: &[IpCidr], excluded: &IpCidr) -> Vec<IpCidr> {
+    let mut remaining = Vec::new();
+    for route in routes {
+        if covers(excluded, route) {
+            continue;
+        }
+        if !covers(route, excluded) {
+            remaining.push(*route);
+            continue;
+        }
+        let mut current = *route;
+        while current.prefix_len() < excluded.prefix_len() {
+            let [lower, upper] = halves(&current);
+            if covers(&lower, excluded) {
+                remaining.push(upper);
+                current = lower;
+            } else {
+                remaining.push(lower);
+                current = upper;
+            }
+        }
+    }
+    remaining
+}
@@ -0,0 +1,89 @@
+use crate::error::Error;
+use crate::{Credentials, Proxy, ProxyType};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const KEYRING_SERVICE: &str = "tun2proxy";
+
+/// Where the password of a proxy is taken from if its URL does not contain one, so that it does
+/// not show up in the command line of the process.
+#[derive(Clone, Debug)]
+pub enum CredentialSource {
+    /// A file containing `username:password` on its first line, or only the password.
+    File(PathBuf),
+    /// The password itself, e.g. taken from an environment variable.
+    Password(String),
+    /// The keyring of the OS, where the password is stored for the service `tun2proxy` and the
+    /// username as the account.
+    Keyring,
+}
+
+fn read_file(path: &Path, username: &str) -> Result<Credentials, Error> {
+    let e = format!("`{}` could not be read", path.display());
+    let content = std::fs::read_to_string(path).map_err(|_| Error::from(&e))?;
+    let line = content.lines().next().unwrap_or_default();
+    Ok(match line.split_once(':') {
+        Some((username, password)) => Credentials::new(username, password),
+        None => Credentials::new(username, line),
+    })
+}
+
+// The keyring is queried through `secret-tool` of libsecret on Linux and `security` on macOS.
+fn keyring_password(username: &str) -> Result<String, Error> {
+    if username.is_empty() {
+        return Err("The keyring requires the proxy username in the URL".into());
+    }
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args([
+            "find-generic-password",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            username,
+            "-w",
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", KEYRING_SERVICE, "username", username]);
+        command
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let e = format!("`{program}` could not be run to query the keyring");
+    let output = command.output().map_err(|_| Error::from(e))?;
+    let e = format!("The keyring has no password for `{username}`");
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(e.into());
+    }
+    let password = String::from_utf8(output.stdout).map_err(|_| Error::from(&e))?;
+    Ok(password.trim_end_matches(['\r', '\n']).into())
+}
+
+impl Proxy {
+    /// Complete the credentials of the proxy from `source`, unless its URL contains a password
+    /// or the proxy type does not use one.
+    pub fn load_credentials(&mut self, source: &CredentialSource) -> Result<(), Error> {
+        let uses_password = matches!(
+            self.proxy_type,
+            ProxyType::Socks5
+                | ProxyType::Http
+                | ProxyType::Http2
+                | ProxyType::Masque
+                | ProxyType::Ssh
+        );
+        let username = match &self.credentials {
+            _ if !uses_password => return Ok(()),
+            Some(credentials) if !credentials.password.is_empty() => return Ok(()),
+            Some(credentials) => String::from_utf8(credentials.username.clone())
+                .map_err(|_| Error::from("The proxy username is not valid UTF-8"))?,
+            None => String::new(),
+        };
+        self.credentials = Some(match source {
+            CredentialSource::File(path) => read_file(path, &username)?,
+            CredentialSource::Password(password) => Credentials::new(&username, password),
+            CredentialSource::Keyring => Credentials::new(&username, &keyring_password(&username)?),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use crate::error::Error;
+use crate::Credentials;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Hash algorithm of a Digest challenge as given by its `algorithm` parameter.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    /// The algorithm of the parameter, which defaults to MD5. Session variants are not
+    /// supported.
+    pub fn parse(algorithm: Option<&str>) -> Option<Self> {
+        match algorithm {
+            None => Some(Algorithm::Md5),
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("MD5") => Some(Algorithm::Md5),
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("SHA-256") => Some(Algorithm::Sha256),
+            Some(_) => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn hash(self, parts: &[&[u8]]) -> String {
+        let digest = match self {
+            Algorithm::Md5 => parts
+                .iter()
+                .fold(Md5::new(), |hasher, part| hasher.chain_update(part))
+                .finalize()
+                .to_vec(),
+            Algorithm::Sha256 => parts
+                .iter()
+                .fold(Sha256::new(), |hasher, part| hasher.chain_update(part))
+                .finalize()
+                .to_vec(),
+        };
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The parameters of a Digest challenge needed to answer it.
+#[derive(Clone, Debug)]
+pub(crate) struct Challenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Algorithm,
+    /// Whether the response is protected through a client nonce, i.e. `qop=auth` is offered.
+    pub qop: bool,
+    /// Whether the challenge renews an expired nonce, so that the credentials are not at fault.
+    pub stale: bool,
+}
+
+/// The credentials of the `Proxy-Authorization` header field answering `challenge` for a
+/// request with `method` and `uri`, as defined by RFC 7616. `nonce_count` counts the requests
+/// made with the nonce of the challenge so far, including this one.
+pub(crate) fn authorization(
+    credentials: &Credentials,
+    challenge: &Challenge,
+    method: &str,
+    uri: &str,
+    nonce_count: u32,
+) -> Result<String, Error> {
+    let mut cnonce = [0; 16];
+    getrandom::getrandom(&mut cnonce).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    let cnonce = cnonce
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    Ok(answer(
+        credentials,
+        challenge,
+        method,
+        uri,
+        nonce_count,
+        &cnonce,
+    ))
+}
+
+// The credentials answering `challenge` with the client nonce `cnonce`, which only protects the
+// response with `qop=auth`.
+fn answer(
+    credentials: &Credentials,
+    challenge: &Challenge,
+    method: &str,
+    uri: &str,
+    nonce_count: u32,
+    cnonce: &str,
+) -> String {
+    let algorithm = challenge.algorithm;
+    let ha1 = algorithm.hash(&[
+        &credentials.username,
+        b":",
+        challenge.realm.as_bytes(),
+        b":",
+        &credentials.password,
+    ]);
+    let ha2 = algorithm.hash(&[method.as_bytes(), b":", uri.as_bytes()]);
+    let username = String::from_utf8_lossy(&credentials.username);
+
+    let mut fields = vec![
+        format!("username={}", quote(&username)),
+        format!("realm={}", quote(&challenge.realm)),
+        format!("nonce={}", quote(&challenge.nonce)),
+        format!("uri={}", quote(uri)),
+        format!("algorithm={}", algorithm.name()),
+    ];
+    let response = if challenge.qop {
+        let nc = format!("{nonce_count:08x}");
+        let response = algorithm.hash(&[
+            ha1.as_bytes(),
+            b":",
+            challenge.nonce.as_bytes(),
+            b":",
+            nc.as_bytes(),
+            b":",
+            cnonce.as_bytes(),
+            b":auth:",
+            ha2.as_bytes(),
+        ]);
+        fields.push(String::from("qop=auth"));
+        fields.push(format!("nc={nc}"));
+        fields.push(format!("cnonce={}", quote(cnonce)));
+        response
+    } else {
+        algorithm.hash(&[
+            ha1.as_bytes(),
+            b":",
+            challenge.nonce.as_bytes(),
+            b":",
+            ha2.as_bytes(),
+        ])
+    };
+    fields.push(format!("response={}", quote(&response)));
+    if let Some(opaque) = &challenge.opaque {
+        fields.push(format!("opaque={}", quote(opaque)));
+    }
+    format!("Digest {}", fields.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example of RFC 7616, section 3.9.1.
+    fn rfc_challenge(algorithm: Algorithm) -> Challenge {
+        Challenge {
+            realm: "http-auth@example.org".into(),
+            nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".into(),
+            opaque: Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".into()),
+            algorithm,
+            qop: true,
+            stale: false,
+        }
+    }
+
+    fn rfc_answer(algorithm: Algorithm) -> String {
+        let credentials = Credentials::new("Mufasa", "Circle of Life");
+        let challenge = rfc_challenge(algorithm);
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        answer(
+            &credentials,
+            &challenge,
+            "GET",
+            "/dir/index.html",
+            1,
+            cnonce,
+        )
+    }
+
+    #[test]
+    fn rfc7616_md5() {
+        assert_eq!(
+            rfc_answer(Algorithm::Md5),
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", uri=\"/dir/index.html\", \
+             algorithm=MD5, qop=auth, nc=00000001, \
+             cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", \
+             response=\"8ca523f5e9506fed4657c9700eebdbec\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""
+        );
+    }
+
+    #[test]
+    fn rfc7616_sha256() {
+        let authorization = rfc_answer(Algorithm::Sha256);
+        assert!(authorization.contains(", algorithm=SHA-256, "));
+        assert!(authorization.contains(
+            ", response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\", "
+        ));
+    }
+
+    #[test]
+    fn without_qop() {
+        let credentials = Credentials::new("Mufasa", "Circle of Life");
+        let challenge = Challenge {
+            qop: false,
+            opaque: None,
+            ..rfc_challenge(Algorithm::Md5)
+        };
+        let authorization = answer(&credentials, &challenge, "GET", "/dir/index.html", 1, "");
+        // MD5(HA1:nonce:HA2) as defined by RFC 2069.
+        assert!(authorization.ends_with(", response=\"7b2cc3b30e75b4777ea31027084363fd\""));
+        assert!(!authorization.contains("qop="));
+        assert!(!authorization.contains("cnonce="));
+    }
+
+    #[test]
+    fn quotes_values() {
+        assert_eq!(quote(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn parses_algorithms() {
+        assert_eq!(Algorithm::parse(None), Some(Algorithm::Md5));
+        assert_eq!(Algorithm::parse(Some("md5")), Some(Algorithm::Md5));
+        assert_eq!(Algorithm::parse(Some("SHA-256")), Some(Algorithm::Sha256));
+        assert_eq!(Algorithm::parse(Some("MD5-sess")), None);
+        assert_eq!(Algorithm::parse(Some("SHA-512-256")), None);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::Error;
+use crate::tun2proxy::{Connection, DnsForwarder};
+use mio::Waker;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+/// A resolver of the embedding application, e.g. its own DNS-over-HTTPS stack, which answers the
+/// DNS queries captured from the tunnel in place of the built-in upstream resolvers.
+pub trait DnsBackend {
+    /// Resolve `query`, a DNS message, and pass the response to `reply`, either right away or
+    /// later on from any thread. Dropping `reply` leaves the query unanswered.
+    fn resolve(&self, query: &[u8], reply: DnsReply);
+}
+
+/// Hands the response to a query back to the client which sent it.
+pub struct DnsReply {
+    connection: Connection,
+    responses: Sender<(Connection, Vec<u8>)>,
+    waker: Arc<Waker>,
+}
+
+impl DnsReply {
+    /// The address of the client which sent the query.
+    pub fn client(&self) -> SocketAddr {
+        self.connection.src
+    }
+
+    /// Send `response`, a DNS message answering the query, to the client.
+    pub fn send(self, response: Vec<u8>) {
+        if self.responses.send((self.connection, response)).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+pub(crate) struct BackendForwarder {
+    backend: Rc<dyn DnsBackend>,
+    sender: Sender<(Connection, Vec<u8>)>,
+    responses: Receiver<(Connection, Vec<u8>)>,
+    waker: Arc<Waker>,
+}
+
+impl BackendForwarder {
+    pub fn new(backend: Rc<dyn DnsBackend>, waker: Arc<Waker>) -> Self {
+        let (sender, responses) = mpsc::channel();
+        Self {
+            backend,
+            sender,
+            responses,
+            waker,
+        }
+    }
+}
+
+impl DnsForwarder for BackendForwarder {
+    fn send_query(&self, connection: &Connection, query: &[u8]) -> Result<(), Error> {
+        let reply = DnsReply {
+            connection: connection.clone(),
+            responses: self.sender.clone(),
+            waker: self.waker.clone(),
+        };
+        self.backend.resolve(query, reply);
+        Ok(())
+    }
+
+    fn receive_response(&self) -> Option<(Connection, Vec<u8>)> {
+        self.responses.try_recv().ok()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::doh::DohServer;
+use crate::dot::DotServer;
+use crate::error::Error;
+use crate::virtdns;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Where the queries matched by a [`DnsRule`] are resolved.
+#[derive(Clone, Debug)]
+pub enum DnsTarget {
+    /// The virtual DNS, so that connections to the names reach the proxy by name.
+    Virtual,
+    /// A resolver over plain TCP, which is reached through the tunnel like any other server.
+    Tcp(SocketAddr),
+    /// A DNS-over-HTTPS resolver.
+    Https(DohServer),
+    /// A DNS-over-TLS server.
+    Tls(DotServer),
+}
+
+/// A rule of split DNS given as `domain=target`, e.g. `*.corp=10.0.0.53`. The domain is matched
+/// exactly or, if given as e.g. `*.corp`, by its subdomains. The target is `virtual`, a resolver
+/// reached over TCP as `IP[:port]`, a DNS-over-HTTPS URL or a DNS-over-TLS server as
+/// `tls://host[:port]`.
+#[derive(Clone, Debug)]
+pub struct DnsRule {
+    domain: String,
+    target: DnsTarget,
+}
+
+impl FromStr for DnsRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || Error::from(format!("`{s}` is not a DNS rule of the form domain=target"));
+        let (domain, target) = s.split_once('=').ok_or_else(e)?;
+        if domain.is_empty() {
+            return Err(e());
+        }
+        let target = match (target, target.strip_prefix("tls://")) {
+            ("virtual", _) => DnsTarget::Virtual,
+            (_, Some(server)) => DnsTarget::Tls(DotServer::from_str(server)?),
+            (url, _) if url.starts_with("https://") => DnsTarget::Https(DohServer::from_url(url)?),
+            (addr, _) => match (SocketAddr::from_str(addr), IpAddr::from_str(addr)) {
+                (Ok(addr), _) => DnsTarget::Tcp(addr),
+                (_, Ok(ip)) => DnsTarget::Tcp(SocketAddr::new(ip, 53)),
+                _ => return Err(format!("`{addr}` is not a DNS target").into()),
+            },
+        };
+        Ok(Self {
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            target,
+        })
+    }
+}
+
+impl DnsRule {
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        virtdns::matches_domain(&self.domain, &name.to_ascii_lowercase())
+    }
+
+    pub fn target(&self) -> &DnsTarget {
+        &self.target
+    }
+}
@@ -0,0 +1,303 @@
+use crate::error::Error;
+use crate::protect;
+use crate::resolve;
+use crate::tcp_dns;
+use crate::tls::{TlsConfig, TlsOptions};
+use crate::tun2proxy::{Connection, DnsForwarder};
+use mio::Waker;
+use rustls::{ClientConnection, StreamOwned};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_SIZE: usize = 65535;
+
+// Queries are sent on this many connections at the same time, each made by a worker of its own.
+// Queries beyond those waiting for a worker are dropped, like those of a congested resolver.
+const WORKERS: usize = 4;
+const MAX_QUEUED: usize = 256;
+
+/// A DNS-over-HTTPS resolver given by its URL, e.g. `https://1.1.1.1/dns-query`. The query of
+/// the URL may contain the TLS options of proxy URLs, e.g. `sni` or `insecure`.
+#[derive(Clone, Debug)]
+pub struct DohServer {
+    host: String,
+    port: u16,
+    path: String,
+    authority: String,
+    tls: TlsOptions,
+}
+
+impl DohServer {
+    /// A hostname in the URL is only resolved once the resolver is connected to.
+    pub fn from_url(s: &str) -> Result<Self, Error> {
+        let e = format!("`{s}` is not a valid DNS-over-HTTPS URL");
+        let url = url::Url::parse(s).map_err(|_| Error::from(&e))?;
+        if url.scheme() != "https" {
+            return Err(e.into());
+        }
+        let host = match url.host().ok_or(Error::from(&e))? {
+            url::Host::Domain(domain) => domain.to_string(),
+            url::Host::Ipv4(addr) => addr.to_string(),
+            url::Host::Ipv6(addr) => addr.to_string(),
+        };
+        let port = url.port().unwrap_or(443);
+
+        let mut tls = TlsOptions::new(&host);
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                key if TlsOptions::is_option(key) => tls.set_option(key, &value),
+                key => {
+                    query.append_pair(key, &value);
+                }
+            }
+        }
+        let mut path = url.path().to_string();
+        let query = query.finish();
+        if !query.is_empty() {
+            path.push('?');
+            path.push_str(&query);
+        }
+        let authority = url.host_str().unwrap_or_default();
+        let authority = match port {
+            443 => authority.to_string(),
+            _ => format!("{authority}:{port}"),
+        };
+        Ok(Self {
+            host,
+            port,
+            path,
+            authority,
+            tls,
+        })
+    }
+}
+
+type TlsStream = BufReader<StreamOwned<ClientConnection, TcpStream>>;
+
+/// Answers DNS queries through a DoH resolver. The HTTPS requests are made by worker threads,
+/// each of which keeps a connection to the resolver alive across queries and wakes the event
+/// loop once a response has arrived. The connections are protected from the tunnel like those to
+/// the proxies, and a hostname of the resolver is looked up through the name servers in use
+/// before the setup, as the system resolver may by now be the virtual DNS forwarding here.
+pub(crate) struct DohClient {
+    queries: SyncSender<(Connection, Vec<u8>)>,
+    responses: Receiver<(Connection, Vec<u8>)>,
+}
+
+impl DohClient {
+    pub fn new(
+        server: &DohServer,
+        nameservers: &[IpAddr],
+        waker: Arc<Waker>,
+    ) -> Result<Self, Error> {
+        let (queries, worker_queries) = mpsc::sync_channel::<(Connection, Vec<u8>)>(MAX_QUEUED);
+        let worker_queries = Arc::new(Mutex::new(worker_queries));
+        let (worker_responses, responses) = mpsc::channel();
+        let tls = TlsConfig::new(&server.tls)?.with_alpn(&[b"http/1.1"]);
+        for _ in 0..WORKERS {
+            let mut worker = Worker {
+                server: server.clone(),
+                nameservers: nameservers.to_vec(),
+                tls: tls.clone(),
+                stream: None,
+            };
+            let queries = worker_queries.clone();
+            let responses = worker_responses.clone();
+            let waker = waker.clone();
+            std::thread::spawn(move || loop {
+                // The queue is only locked while waiting for the next query.
+                let next = queries.lock().unwrap().recv();
+                let (connection, query) = match next {
+                    Ok(next) => next,
+                    Err(_) => break,
+                };
+                match worker.resolve(&query) {
+                    Ok(response) => {
+                        if responses.send((connection, response)).is_err() {
+                            break;
+                        }
+                        let _ = waker.wake();
+                    }
+                    Err(error) => log::warn!("DNS over HTTPS: {error}"),
+                }
+            });
+        }
+        Ok(Self { queries, responses })
+    }
+}
+
+impl DnsForwarder for DohClient {
+    fn send_query(&self, connection: &Connection, query: &[u8]) -> Result<(), Error> {
+        match self.queries.try_send((connection.clone(), query.to_vec())) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                log::warn!("DNS over HTTPS: Too many queries are pending");
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err("The DNS-over-HTTPS client has stopped".into())
+            }
+        }
+    }
+
+    fn receive_response(&self) -> Option<(Connection, Vec<u8>)> {
+        self.responses.try_recv().ok()
+    }
+}
+
+struct Worker {
+    server: DohServer,
+    nameservers: Vec<IpAddr>,
+    tls: TlsConfig,
+    stream: Option<TlsStream>,
+}
+
+impl Worker {
+    // The addresses of the resolver, IPv4 addresses first.
+    fn addrs(&self) -> Result<Vec<IpAddr>, Error> {
+        if let Ok(addr) = self.server.host.parse() {
+            return Ok(vec![addr]);
+        }
+        let host = &self.server.host;
+        let addrs: Vec<IpAddr> = resolve::resolve(host, &self.nameservers)?
+            .into_iter()
+            .filter(|addr| !tcp_dns::is_virtual(*addr))
+            .collect();
+        match addrs.is_empty() {
+            true => Err(format!("`{host}` only resolves to virtual addresses").into()),
+            false => Ok(addrs),
+        }
+    }
+
+    fn connect(&self) -> Result<TlsStream, Error> {
+        let mut result = Err(std::io::ErrorKind::NotFound.into());
+        for addr in self.addrs()? {
+            let server = SocketAddr::new(addr, self.server.port);
+            result = protect::connect_tcp_blocking(server, TIMEOUT);
+            if result.is_ok() {
+                break;
+            }
+        }
+        let socket = result?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        socket.set_nodelay(true)?;
+        let session = self.tls.client_connection()?;
+        Ok(BufReader::new(StreamOwned::new(session, socket)))
+    }
+
+    fn resolve(&mut self, query: &[u8]) -> Result<Vec<u8>, Error> {
+        // The resolver may have closed the connection kept alive in the meantime.
+        let reused = self.stream.is_some();
+        match self.exchange(query) {
+            Err(_) if reused => self.exchange(query),
+            result => result,
+        }
+    }
+
+    fn exchange(&mut self, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\n\
+             Content-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            self.server.path,
+            self.server.authority,
+            query.len()
+        );
+        let mut message = request.into_bytes();
+        message.extend(query);
+        stream.get_mut().write_all(&message)?;
+        stream.get_mut().flush()?;
+
+        let (response, keep_alive) = read_response(&mut stream)?;
+        if keep_alive {
+            self.stream = Some(stream);
+        }
+        Ok(response)
+    }
+}
+
+fn read_line(stream: &mut impl BufRead) -> Result<String, Error> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err("The resolver closes the connection".into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).into())
+}
+
+// The body of the response and whether the connection can be used for the next query.
+fn read_response(stream: &mut impl BufRead) -> Result<(Vec<u8>, bool), Error> {
+    let status = read_line(stream)?;
+    let mut parts = status.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    if parts.next() != Some("200") {
+        return Err(format!("The resolver responds with `{status}`").into());
+    }
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(stream)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some(field) => field,
+            None => continue,
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let e = format!("Invalid content length `{value}`");
+            length = Some(value.parse::<usize>().map_err(|_| Error::from(e))?);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("Connection") {
+            keep_alive = !value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let line = read_line(stream)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let e = format!("Invalid chunk size `{size}`");
+            let size = usize::from_str_radix(size, 16).map_err(|_| Error::from(e))?;
+            if size == 0 {
+                // Trailer fields are skipped.
+                while !read_line(stream)?.is_empty() {}
+                break;
+            }
+            if body.len() + size > MAX_RESPONSE_SIZE {
+                return Err("The DNS response is too large".into());
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            stream.read_exact(&mut body[start..])?;
+            read_line(stream)?;
+        }
+    } else if let Some(length) = length {
+        if length > MAX_RESPONSE_SIZE {
+            return Err("The DNS response is too large".into());
+        }
+        body.resize(length, 0);
+        stream.read_exact(&mut body)?;
+    } else {
+        stream
+            .take(MAX_RESPONSE_SIZE as u64 + 1)
+            .read_to_end(&mut body)?;
+        if body.len() > MAX_RESPONSE_SIZE {
+            return Err("The DNS response is too large".into());
+        }
+        keep_alive = false;
+    }
+    Ok((body, keep_alive))
+}
@@ -0,0 +1,58 @@
+use crate::error::Error;
+use crate::tcp_dns::Upstream;
+use crate::tls::{TlsConfig, TlsOptions};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+const DEFAULT_PORT: u16 = 853;
+
+/// A DNS-over-TLS server given as `host[:port]`, e.g. `1.1.1.1` or `dns.example:853`,
+/// optionally followed by the TLS options of proxy URLs, e.g. `1.1.1.1?sni=one.one.one.one`. The
+/// hostname is resolved right away, as it could not be resolved through the tunnel once the
+/// system points to it for DNS.
+#[derive(Clone, Debug)]
+pub struct DotServer {
+    addr: SocketAddr,
+    tls: TlsOptions,
+}
+
+impl FromStr for DotServer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = format!("`{s}` is not a valid DNS-over-TLS server");
+        let url = url::Url::parse(&format!("tls://{s}")).map_err(|_| Error::from(&e))?;
+        if !matches!(url.path(), "" | "/") {
+            return Err(e.into());
+        }
+        let host = match url.host().ok_or(Error::from(&e))? {
+            url::Host::Domain(domain) => domain.to_string(),
+            url::Host::Ipv4(addr) => addr.to_string(),
+            url::Host::Ipv6(addr) => addr.to_string(),
+        };
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let e = format!("`{host}` does not resolve to a usable IP address");
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::from(e))?;
+
+        let mut tls = TlsOptions::new(&host);
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                key if TlsOptions::is_option(key) => tls.set_option(key, &value),
+                key => return Err(format!("`{key}` is an invalid DNS server option").into()),
+            }
+        }
+        Ok(Self { addr, tls })
+    }
+}
+
+impl DotServer {
+    pub(crate) fn upstream(&self) -> Result<Upstream, Error> {
+        Ok(Upstream::Tls(
+            self.addr,
+            Box::new(TlsConfig::new(&self.tls)?),
+        ))
+    }
+}
@@ -21,12 +21,18 @@ pub enum Error {
     #[error("smoltcp::socket::tcp::ListenError {0:?}")]
     Listen(#[from] smoltcp::socket::tcp::ListenError),
 
+    #[error("smoltcp::socket::tcp::ConnectError {0:?}")]
+    Connect(#[from] smoltcp::socket::tcp::ConnectError),
+
     #[error("smoltcp::socket::udp::BindError {0:?}")]
     Bind(#[from] smoltcp::socket::udp::BindError),
 
     #[error("smoltcp::socket::tcp::SendError {0:?}")]
     Send(#[from] smoltcp::socket::tcp::SendError),
 
+    #[error("rustls::Error {0:?}")]
+    Tls(#[from] rustls::Error),
+
     #[error("std::str::Utf8Error {0:?}")]
     Utf8(#[from] std::str::Utf8Error),
 
@@ -0,0 +1,49 @@
+use crate::error::Error;
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+// The first file descriptor passed by systemd, SD_LISTEN_FDS_START.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Pass the file descriptor of the tun interface `fd` over `socket`, e.g. from a privileged
+/// helper which has opened the interface to tun2proxy running as an unprivileged user.
+pub fn send_tun_fd(socket: &UnixStream, fd: RawFd) -> Result<(), Error> {
+    let fds = [fd];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+    // At least one byte has to be sent along with the file descriptor.
+    let iov = [IoSlice::new(&[0])];
+    socket::sendmsg::<()>(socket.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Receive the file descriptor of the tun interface passed over `socket` through
+/// [`send_tun_fd`].
+pub fn receive_tun_fd(socket: &UnixStream) -> Result<RawFd, Error> {
+    let mut buf = [0];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+    let flags = MsgFlags::MSG_CMSG_CLOEXEC;
+    let msg = socket::recvmsg::<()>(socket.as_raw_fd(), &mut iov, Some(&mut cmsg_buffer), flags)?;
+    let fd = msg.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+        _ => None,
+    });
+    fd.ok_or_else(|| "No file descriptor of a tun interface was received".into())
+}
+
+/// The file descriptor of the tun interface passed by systemd, e.g. from the file descriptor
+/// store of the unit, as with `sd_listen_fds`. The variables telling about it are removed from
+/// the environment, so that they are not inherited by child processes.
+pub fn systemd_tun_fd() -> Option<RawFd> {
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.parse() != Ok(std::process::id()) || fds.parse::<u32>().map_or(true, |fds| fds < 1) {
+        return None;
+    }
+    Some(LISTEN_FDS_START)
+}
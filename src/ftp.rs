@@ -0,0 +1,156 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Port of FTP control connections, whose active mode data connections have to be accepted by
+/// the proxy on behalf of the client.
+pub(crate) const CONTROL_PORT: u16 = 21;
+
+/// Port from which FTP servers in active mode connect to the client.
+pub(crate) const DATA_PORT: u16 = 20;
+
+/// Lines longer than this are not expected on a control connection, which is then passed
+/// through unchanged.
+const MAX_LINE_LENGTH: usize = 4096;
+
+// Parse a `PORT` or `EPRT` command, returning the announced address and whether the command is
+// the extended one.
+fn parse_command(line: &[u8]) -> Option<(SocketAddr, bool)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (command, argument) = line.split_once(' ')?;
+    let argument = argument.trim();
+    if command.eq_ignore_ascii_case("PORT") {
+        let numbers = argument
+            .split(',')
+            .map(|number| number.trim().parse().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if numbers.len() != 6 {
+            return None;
+        }
+        let ip = Ipv4Addr::new(numbers[0], numbers[1], numbers[2], numbers[3]);
+        let port = u16::from_be_bytes([numbers[4], numbers[5]]);
+        Some((SocketAddr::new(ip.into(), port), false))
+    } else if command.eq_ignore_ascii_case("EPRT") {
+        // The argument is of the form `|1|192.0.2.1|1234|`, where `|` may be any delimiter.
+        let delimiter = argument.chars().next()?;
+        let fields: Vec<&str> = argument.split(delimiter).collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        let ip: IpAddr = fields[2].parse().ok()?;
+        match (fields[1], ip) {
+            ("1", IpAddr::V4(_)) | ("2", IpAddr::V6(_)) => {}
+            _ => return None,
+        }
+        Some((SocketAddr::new(ip, fields[3].parse().ok()?), true))
+    } else {
+        None
+    }
+}
+
+fn format_command(addr: SocketAddr, extended: bool) -> Vec<u8> {
+    match addr {
+        SocketAddr::V4(v4) if !extended => {
+            let [a, b, c, d] = v4.ip().octets();
+            let [p1, p2] = v4.port().to_be_bytes();
+            format!("PORT {a},{b},{c},{d},{p1},{p2}\r\n")
+        }
+        _ => {
+            let protocol = if addr.is_ipv4() { 1 } else { 2 };
+            format!("EPRT |{protocol}|{}|{}|\r\n", addr.ip(), addr.port())
+        }
+    }
+    .into_bytes()
+}
+
+fn is_auth_command(line: &[u8]) -> bool {
+    line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"AUTH ")
+}
+
+/// Inspects the commands a client sends through an FTP control connection, so that the data
+/// connections it requests in active mode through `PORT` or `EPRT` can be accepted by the
+/// proxy. Such a command is held back until the proxy has bound an address for the data
+/// connection and is then rewritten to announce that address to the server.
+#[derive(Default)]
+pub(crate) struct ActiveMode {
+    // Data of the client which has not been forwarded to the server yet.
+    held: Vec<u8>,
+    // Set while the held data starts with a command whose address is being bound, telling
+    // whether the command is the extended one.
+    pending: Option<bool>,
+    // Set once the connection does not carry plain FTP commands, e.g. after `AUTH TLS`.
+    passthrough: bool,
+}
+
+impl ActiveMode {
+    /// Process data of the client. Returns the data to forward to the server and the address
+    /// on which the client listens for a data connection, if it has requested one.
+    pub fn client_data(&mut self, data: &[u8]) -> (Vec<u8>, Option<SocketAddr>) {
+        if self.passthrough && self.held.is_empty() {
+            return (data.to_vec(), None);
+        }
+        self.held.extend_from_slice(data);
+        self.process()
+    }
+
+    /// Announce the address the proxy has bound for the requested data connection in place of
+    /// the address of the client.
+    pub fn bound(&mut self, addr: SocketAddr) -> (Vec<u8>, Option<SocketAddr>) {
+        match self.pending.take() {
+            Some(extended) => self.replace_command(format_command(addr, extended)),
+            None => (Vec::new(), None),
+        }
+    }
+
+    /// Forward the command requesting the data connection unchanged, e.g. because the proxy
+    /// cannot accept connections.
+    pub fn release(&mut self) -> (Vec<u8>, Option<SocketAddr>) {
+        match self.pending.take() {
+            Some(_) => {
+                let end = self.line_end().unwrap_or(self.held.len());
+                let line = self.held[..end].to_vec();
+                self.replace_command(line)
+            }
+            None => (Vec::new(), None),
+        }
+    }
+
+    fn line_end(&self) -> Option<usize> {
+        self.held.iter().position(|&b| b == b'\n').map(|i| i + 1)
+    }
+
+    fn replace_command(&mut self, mut command: Vec<u8>) -> (Vec<u8>, Option<SocketAddr>) {
+        let end = self.line_end().unwrap_or(self.held.len());
+        self.held.drain(..end);
+        let (data, request) = self.process();
+        command.extend(data);
+        (command, request)
+    }
+
+    fn process(&mut self) -> (Vec<u8>, Option<SocketAddr>) {
+        let mut forward = Vec::new();
+        if self.pending.is_some() {
+            return (forward, None);
+        }
+        while !self.passthrough {
+            let end = match self.line_end() {
+                Some(end) => end,
+                None if self.held.len() > MAX_LINE_LENGTH => {
+                    self.passthrough = true;
+                    break;
+                }
+                None => break,
+            };
+            let line = &self.held[..end];
+            if let Some((addr, extended)) = parse_command(line) {
+                self.pending = Some(extended);
+                return (forward, Some(addr));
+            }
+            // The data following the command is encrypted.
+            self.passthrough = is_auth_command(line);
+            forward.extend(self.held.drain(..end));
+        }
+        if self.passthrough {
+            forward.append(&mut self.held);
+        }
+        (forward, None)
+    }
+}
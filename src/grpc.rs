@@ -0,0 +1,227 @@
+use crate::error::Error;
+use crate::h2::{H2Session, StreamEvent};
+use crate::tls::TlsOptions;
+use crate::tun2proxy::{
+    Direction, IncomingDataEvent, IncomingDirection, OutgoingDataEvent, OutgoingDirection, TcpProxy,
+};
+use std::net::SocketAddr;
+
+const MAX_PAYLOAD: usize = 0x4000;
+const MAX_MESSAGE: usize = 0x100_0000;
+
+/// Tag of the `data` field of the message carried by the tunnel stream, as in
+/// `message Hunk { bytes data = 1; }`.
+const HUNK_DATA_TAG: u8 = 0x0a;
+
+#[derive(Clone, Debug)]
+pub struct GrpcOptions {
+    /// Name of the gRPC service, which is called through `/<service>/Tun`
+    pub service: String,
+    /// Value of the `:authority` pseudo-header
+    pub authority: String,
+    /// TLS options if the HTTP/2 connection is secured, i.e. `grpcs`
+    pub tls: Option<TlsOptions>,
+}
+
+fn encode_varint(mut value: usize, buffer: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn decode_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Extract the data of the tunnel message, skipping fields unknown to this implementation.
+fn decode_hunk(mut message: &[u8], data: &mut Vec<u8>) -> Result<(), Error> {
+    let e = "malformed gRPC tunnel message";
+    while !message.is_empty() {
+        let (key, size) = decode_varint(message).ok_or(e)?;
+        message = &message[size..];
+        let len = match key & 0x7 {
+            0 => decode_varint(message).ok_or(e)?.1,
+            1 => 8,
+            2 => {
+                let (len, size) = decode_varint(message).ok_or(e)?;
+                message = &message[size..];
+                len
+            }
+            5 => 4,
+            _ => return Err(e.into()),
+        };
+        if len > message.len() {
+            return Err(e.into());
+        }
+        if key == HUNK_DATA_TAG as usize {
+            data.extend(&message[..len]);
+        }
+        message = &message[len..];
+    }
+    Ok(())
+}
+
+/// Carries the connection to the proxy server in a bidirectional gRPC stream over its own
+/// HTTP/2 connection, compatible with the gRPC transport of V2Ray.
+pub(crate) struct GrpcConnection {
+    inner: Box<dyn TcpProxy>,
+    session: H2Session,
+    stream_id: u32,
+    inbuf: Vec<u8>,
+}
+
+impl GrpcConnection {
+    pub fn new(inner: Box<dyn TcpProxy>, options: &GrpcOptions) -> Result<Self, Error> {
+        let mut session = H2Session::new();
+        let scheme: &[u8] = match options.tls {
+            Some(_) => b"https",
+            None => b"http",
+        };
+        let path = format!("/{}/Tun", options.service);
+        let stream_id = session.open_stream(&[
+            (b":method", b"POST"),
+            (b":scheme", scheme),
+            (b":path", path.as_bytes()),
+            (b":authority", options.authority.as_bytes()),
+            (b"content-type", b"application/grpc"),
+            (b"te", b"trailers"),
+        ]);
+        let mut result = Self {
+            inner,
+            session,
+            stream_id,
+            inbuf: Vec::new(),
+        };
+        result.pump();
+        Ok(result)
+    }
+
+    // Hand the data the wrapped handler wants to send to the stream as gRPC messages.
+    fn pump(&mut self) {
+        loop {
+            let event = self.inner.peek_data(OutgoingDirection::ToServer);
+            if event.buffer.is_empty() {
+                break;
+            }
+            let len = event.buffer.len().min(MAX_PAYLOAD);
+            let mut hunk = vec![HUNK_DATA_TAG];
+            encode_varint(len, &mut hunk);
+            hunk.extend(&event.buffer[..len]);
+
+            let mut message = vec![0];
+            message.extend((hunk.len() as u32).to_be_bytes());
+            message.extend(hunk);
+            self.session.send_data(self.stream_id, &message);
+            self.inner.consume_data(OutgoingDirection::ToServer, len);
+        }
+    }
+
+    fn receive(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        let mut events = Vec::new();
+        self.session.receive(buffer, &mut events)?;
+        for (stream_id, event) in events {
+            if stream_id != self.stream_id {
+                continue;
+            }
+            match event {
+                StreamEvent::Response(200) => {}
+                StreamEvent::Response(status) => {
+                    return Err(format!("gRPC call failed with status {status}").into());
+                }
+                StreamEvent::Data(data) => self.inbuf.extend(data),
+                // Anything still in flight is delivered before the server closes the connection.
+                StreamEvent::End => {}
+                StreamEvent::Reset => return Err("gRPC stream was reset".into()),
+            }
+        }
+
+        let mut offset = 0;
+        let mut data = Vec::new();
+        while self.inbuf.len() - offset >= 5 {
+            let header = &self.inbuf[offset..offset + 5];
+            if header[0] != 0 {
+                return Err("compressed gRPC messages are not supported".into());
+            }
+            let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            if len > MAX_MESSAGE {
+                return Err("gRPC message is too long".into());
+            }
+            if self.inbuf.len() - offset < 5 + len {
+                break;
+            }
+            decode_hunk(&self.inbuf[offset + 5..offset + 5 + len], &mut data)?;
+            offset += 5 + len;
+        }
+        self.inbuf.drain(0..offset);
+
+        if !data.is_empty() {
+            self.inner.push_data(IncomingDataEvent {
+                direction: IncomingDirection::FromServer,
+                buffer: &data,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl TcpProxy for GrpcConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => self.receive(event.buffer)?,
+            IncomingDirection::FromClient => self.inner.push_data(event)?,
+        }
+        self.pump();
+        Ok(())
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        if dir == OutgoingDirection::ToServer {
+            self.session.outbuf.drain(0..size);
+        } else {
+            self.inner.consume_data(dir, size);
+        }
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        if dir == OutgoingDirection::ToServer {
+            OutgoingDataEvent {
+                direction: dir,
+                buffer: &self.session.outbuf,
+            }
+        } else {
+            self.inner.peek_data(dir)
+        }
+    }
+
+    fn connection_established(&self) -> bool {
+        self.inner.connection_established()
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Outgoing(OutgoingDirection::ToServer) => !self.session.outbuf.is_empty(),
+            _ => self.inner.have_data(dir),
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.inner.get_udp_associate()
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.inner.get_bind_address()
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.inner.hostname_refused()
+    }
+}
@@ -0,0 +1,300 @@
+use crate::error::Error;
+use std::ffi::{c_void, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::sync::OnceLock;
+
+/// Names under which the GSS-API library of MIT Kerberos and Heimdal is installed.
+const LIBRARIES: &[&[u8]] = &[b"libgssapi_krb5.so.2\0", b"libgssapi.so.3\0"];
+
+/// OID of `GSS_C_NT_HOSTBASED_SERVICE`, i.e. 1.2.840.113554.1.2.1.4.
+const NT_HOSTBASED_SERVICE: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+
+const MUTUAL_FLAG: u32 = 2;
+const REPLAY_FLAG: u32 = 4;
+const SEQUENCE_FLAG: u32 = 8;
+const CONF_FLAG: u32 = 16;
+const INTEG_FLAG: u32 = 32;
+
+const S_CONTINUE_NEEDED: u32 = 1;
+const GSS_CODE: c_int = 1;
+const MECH_CODE: c_int = 2;
+
+#[repr(C)]
+struct Buffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl Buffer {
+    fn empty() -> Self {
+        Self {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+
+    fn borrowed(data: &[u8]) -> Self {
+        Self {
+            length: data.len(),
+            value: data.as_ptr() as *mut c_void,
+        }
+    }
+}
+
+#[repr(C)]
+struct Oid {
+    length: u32,
+    elements: *mut c_void,
+}
+
+type ImportName =
+    unsafe extern "C" fn(*mut u32, *const Buffer, *const Oid, *mut *mut c_void) -> u32;
+type InitSecContext = unsafe extern "C" fn(
+    *mut u32,
+    *mut c_void,
+    *mut *mut c_void,
+    *mut c_void,
+    *const Oid,
+    u32,
+    u32,
+    *mut c_void,
+    *const Buffer,
+    *mut *mut Oid,
+    *mut Buffer,
+    *mut u32,
+    *mut u32,
+) -> u32;
+type Wrap = unsafe extern "C" fn(
+    *mut u32,
+    *mut c_void,
+    c_int,
+    u32,
+    *const Buffer,
+    *mut c_int,
+    *mut Buffer,
+) -> u32;
+type Unwrap = unsafe extern "C" fn(
+    *mut u32,
+    *mut c_void,
+    *const Buffer,
+    *mut Buffer,
+    *mut c_int,
+    *mut u32,
+) -> u32;
+type DisplayStatus =
+    unsafe extern "C" fn(*mut u32, u32, c_int, *const Oid, *mut u32, *mut Buffer) -> u32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut u32, *mut Buffer) -> u32;
+type ReleaseName = unsafe extern "C" fn(*mut u32, *mut *mut c_void) -> u32;
+type DeleteSecContext = unsafe extern "C" fn(*mut u32, *mut *mut c_void, *mut Buffer) -> u32;
+
+// Look up a function of the library, which has to be of type `T`.
+unsafe fn symbol<T: Copy>(handle: *mut c_void, name: &str) -> Result<T, String> {
+    let c_name = CString::new(name).expect("symbol names contain no NUL");
+    let symbol = libc::dlsym(handle, c_name.as_ptr());
+    if symbol.is_null() {
+        return Err(format!("GSS-API library lacks `{name}`"));
+    }
+    Ok(std::mem::transmute_copy::<*mut c_void, T>(&symbol))
+}
+
+/// The functions of the system GSS-API library, which is loaded on first use so that Kerberos
+/// only needs to be installed where it is used.
+struct Library {
+    import_name: ImportName,
+    init_sec_context: InitSecContext,
+    wrap: Wrap,
+    unwrap: Unwrap,
+    display_status: DisplayStatus,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    delete_sec_context: DeleteSecContext,
+}
+
+impl Library {
+    fn get() -> Result<&'static Library, Error> {
+        static LIBRARY: OnceLock<Result<Library, String>> = OnceLock::new();
+        LIBRARY
+            .get_or_init(Self::load)
+            .as_ref()
+            .map_err(Error::from)
+    }
+
+    fn load() -> Result<Library, String> {
+        let handle = LIBRARIES
+            .iter()
+            .map(|name| unsafe { libc::dlopen(name.as_ptr() as *const c_char, libc::RTLD_NOW) })
+            .find(|handle| !handle.is_null())
+            .ok_or("no GSS-API library is installed")?;
+        unsafe {
+            Ok(Library {
+                import_name: symbol(handle, "gss_import_name")?,
+                init_sec_context: symbol(handle, "gss_init_sec_context")?,
+                wrap: symbol(handle, "gss_wrap")?,
+                unwrap: symbol(handle, "gss_unwrap")?,
+                display_status: symbol(handle, "gss_display_status")?,
+                release_buffer: symbol(handle, "gss_release_buffer")?,
+                release_name: symbol(handle, "gss_release_name")?,
+                delete_sec_context: symbol(handle, "gss_delete_sec_context")?,
+            })
+        }
+    }
+
+    // Copy a buffer allocated by the library and release it.
+    fn take(&self, buffer: &mut Buffer) -> Vec<u8> {
+        let data = match buffer.value.is_null() {
+            true => Vec::new(),
+            false => unsafe {
+                std::slice::from_raw_parts(buffer.value as *const u8, buffer.length).to_vec()
+            },
+        };
+        let mut minor = 0;
+        unsafe { (self.release_buffer)(&mut minor, buffer) };
+        data
+    }
+
+    fn check(&self, operation: &str, major: u32, minor: u32) -> Result<u32, Error> {
+        // Only the calling and routine error fields denote a failure.
+        if major & 0xffff_0000 == 0 {
+            return Ok(major);
+        }
+        let mut messages = Vec::new();
+        for (status, status_type) in [(major, GSS_CODE), (minor, MECH_CODE)] {
+            let mut context = 0;
+            loop {
+                let (mut ignored, mut string) = (0, Buffer::empty());
+                let result = unsafe {
+                    (self.display_status)(
+                        &mut ignored,
+                        status,
+                        status_type,
+                        ptr::null(),
+                        &mut context,
+                        &mut string,
+                    )
+                };
+                if result != 0 {
+                    break;
+                }
+                messages.push(String::from_utf8_lossy(&self.take(&mut string)).into_owned());
+                if context == 0 {
+                    break;
+                }
+            }
+        }
+        Err(format!("{operation} failed: {}", messages.join(": ")).into())
+    }
+}
+
+/// A GSS-API security context initiated towards a host-based service, e.g. `rcmd@proxy.example`,
+/// using the default credentials of the user, i.e. a Kerberos ticket obtained through `kinit`.
+pub(crate) struct SecurityContext {
+    library: &'static Library,
+    target: *mut c_void,
+    context: *mut c_void,
+    established: bool,
+}
+
+impl SecurityContext {
+    pub fn new(service: &str) -> Result<Self, Error> {
+        let library = Library::get()?;
+        let oid = Oid {
+            length: NT_HOSTBASED_SERVICE.len() as u32,
+            elements: NT_HOSTBASED_SERVICE.as_ptr() as *mut c_void,
+        };
+        let (mut minor, mut target) = (0, ptr::null_mut());
+        let major = unsafe {
+            (library.import_name)(
+                &mut minor,
+                &Buffer::borrowed(service.as_bytes()),
+                &oid,
+                &mut target,
+            )
+        };
+        library.check("gss_import_name", major, minor)?;
+        Ok(Self {
+            library,
+            target,
+            context: ptr::null_mut(),
+            established: false,
+        })
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Process the token received from the acceptor, if any, and return the token to send.
+    pub fn step(&mut self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let (mut minor, mut output) = (0, Buffer::empty());
+        let flags = MUTUAL_FLAG | REPLAY_FLAG | SEQUENCE_FLAG | CONF_FLAG | INTEG_FLAG;
+        let major = unsafe {
+            (self.library.init_sec_context)(
+                &mut minor,
+                ptr::null_mut(),
+                &mut self.context,
+                self.target,
+                ptr::null(),
+                flags,
+                0,
+                ptr::null_mut(),
+                &Buffer::borrowed(input),
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let output = self.library.take(&mut output);
+        let major = self.library.check("gss_init_sec_context", major, minor)?;
+        self.established = major & S_CONTINUE_NEEDED == 0;
+        Ok(output)
+    }
+
+    pub fn wrap(&self, data: &[u8], confidential: bool) -> Result<Vec<u8>, Error> {
+        let (mut minor, mut output) = (0, Buffer::empty());
+        let major = unsafe {
+            (self.library.wrap)(
+                &mut minor,
+                self.context,
+                confidential as c_int,
+                0,
+                &Buffer::borrowed(data),
+                ptr::null_mut(),
+                &mut output,
+            )
+        };
+        let output = self.library.take(&mut output);
+        self.library.check("gss_wrap", major, minor)?;
+        Ok(output)
+    }
+
+    pub fn unwrap(&self, token: &[u8]) -> Result<Vec<u8>, Error> {
+        let (mut minor, mut output) = (0, Buffer::empty());
+        let major = unsafe {
+            (self.library.unwrap)(
+                &mut minor,
+                self.context,
+                &Buffer::borrowed(token),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let output = self.library.take(&mut output);
+        self.library.check("gss_unwrap", major, minor)?;
+        Ok(output)
+    }
+}
+
+impl Drop for SecurityContext {
+    fn drop(&mut self) {
+        let mut minor = 0;
+        unsafe {
+            if !self.context.is_null() {
+                (self.library.delete_sec_context)(&mut minor, &mut self.context, ptr::null_mut());
+            }
+            (self.library.release_name)(&mut minor, &mut self.target);
+        }
+    }
+}
@@ -0,0 +1,829 @@
+use crate::bridge::{self, BridgeConnections, BridgeHandle};
+use crate::error::Error;
+use crate::http::HttpManager;
+use crate::protect;
+use crate::tls::TlsConfig;
+use crate::tun2proxy::{Connection, ConnectionManager, TcpProxy};
+use crate::Credentials;
+use base64::Engine;
+use mio::net::{TcpStream, UnixStream};
+use mio::{Events, Interest, Poll, Token};
+use rustls::ClientConnection;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const ERROR_CANCEL: u32 = 0x8;
+
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const LOCAL_WINDOW_SIZE: u32 = 1 << 20;
+const LOCAL_CONNECTION_WINDOW_INCREMENT: u32 = (1 << 24) - 65535;
+
+/// Events of an HTTP/2 session which concern individual streams.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum StreamEvent {
+    Response(u16),
+    Data(Vec<u8>),
+    End,
+    Reset,
+}
+
+#[derive(Default)]
+struct Stream {
+    send_window: i64,
+    pending: VecDeque<u8>,
+    local_closed: bool,
+    end_sent: bool,
+    remote_closed: bool,
+    response_received: bool,
+}
+
+/// A sans-IO HTTP/2 client session carrying requests such as CONNECT tunnels as streams.
+pub(crate) struct H2Session {
+    pub(crate) outbuf: Vec<u8>,
+    inbuf: Vec<u8>,
+    encoder: hpack::Encoder<'static>,
+    decoder: hpack::Decoder<'static>,
+    streams: HashMap<u32, Stream>,
+    next_stream_id: u32,
+    send_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    header_block: Option<(u32, u8, Vec<u8>)>,
+    going_away: bool,
+}
+
+impl H2Session {
+    pub(crate) fn new() -> Self {
+        let mut session = Self {
+            outbuf: PREFACE.to_vec(),
+            inbuf: Vec::new(),
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            streams: HashMap::new(),
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW_SIZE,
+            initial_window: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            header_block: None,
+            going_away: false,
+        };
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_INITIAL_WINDOW_SIZE, LOCAL_WINDOW_SIZE),
+        ] {
+            settings.extend(id.to_be_bytes());
+            settings.extend(value.to_be_bytes());
+        }
+        session.write_frame(FRAME_SETTINGS, 0, 0, &settings);
+        session.write_frame(
+            FRAME_WINDOW_UPDATE,
+            0,
+            0,
+            &LOCAL_CONNECTION_WINDOW_INCREMENT.to_be_bytes(),
+        );
+        session
+    }
+
+    fn write_frame(&mut self, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        self.outbuf
+            .extend(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.outbuf.extend([frame_type, flags]);
+        self.outbuf.extend(stream_id.to_be_bytes());
+        self.outbuf.extend(payload);
+    }
+
+    pub(crate) fn open_stream(&mut self, headers: &[(&[u8], &[u8])]) -> u32 {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 2;
+
+        let headers: Vec<(Vec<u8>, Vec<u8>)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_vec(), value.to_vec()))
+            .collect();
+        let block = self.encoder.encode(&headers);
+        self.write_frame(FRAME_HEADERS, FLAG_END_HEADERS, stream_id, &block);
+        self.streams.insert(
+            stream_id,
+            Stream {
+                send_window: self.initial_window,
+                ..Default::default()
+            },
+        );
+        stream_id
+    }
+
+    pub(crate) fn send_data(&mut self, stream_id: u32, data: &[u8]) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.pending.extend(data);
+            self.flush_stream(stream_id);
+        }
+    }
+
+    /// Half-close the stream once its pending data has been sent.
+    pub(crate) fn end_stream(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.local_closed = true;
+            self.flush_stream(stream_id);
+        }
+    }
+
+    pub(crate) fn reset_stream(&mut self, stream_id: u32) {
+        if self.streams.remove(&stream_id).is_some() {
+            self.write_frame(FRAME_RST_STREAM, 0, stream_id, &ERROR_CANCEL.to_be_bytes());
+        }
+    }
+
+    fn flush_stream(&mut self, stream_id: u32) {
+        let max_frame_size = self.max_frame_size;
+        let mut frames = Vec::new();
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            while !stream.pending.is_empty() && stream.send_window > 0 && self.send_window > 0 {
+                let size = stream
+                    .pending
+                    .len()
+                    .min(stream.send_window as usize)
+                    .min(self.send_window as usize)
+                    .min(max_frame_size);
+                frames.push((0, stream.pending.drain(0..size).collect::<Vec<u8>>()));
+                stream.send_window -= size as i64;
+                self.send_window -= size as i64;
+            }
+            if stream.pending.is_empty() && stream.local_closed && !stream.end_sent {
+                stream.end_sent = true;
+                frames.push((FLAG_END_STREAM, Vec::new()));
+            }
+            if stream.end_sent && stream.remote_closed {
+                self.streams.remove(&stream_id);
+            }
+        }
+        for (flags, data) in frames {
+            self.write_frame(FRAME_DATA, flags, stream_id, &data);
+        }
+    }
+
+    fn flush_all_streams(&mut self) {
+        let stream_ids: Vec<u32> = self.streams.keys().copied().collect();
+        for stream_id in stream_ids {
+            self.flush_stream(stream_id);
+        }
+    }
+
+    pub(crate) fn receive(
+        &mut self,
+        data: &[u8],
+        events: &mut Vec<(u32, StreamEvent)>,
+    ) -> Result<(), Error> {
+        self.inbuf.extend(data);
+        let mut offset = 0;
+        while self.inbuf.len() - offset >= 9 {
+            let header = &self.inbuf[offset..offset + 9];
+            let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let frame_type = header[3];
+            let flags = header[4];
+            let stream_id =
+                u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fffffff;
+            if self.inbuf.len() - offset < 9 + length {
+                break;
+            }
+            let payload = self.inbuf[offset + 9..offset + 9 + length].to_vec();
+            offset += 9 + length;
+            self.receive_frame(frame_type, flags, stream_id, payload, events)?;
+        }
+        self.inbuf.drain(0..offset);
+        Ok(())
+    }
+
+    fn receive_frame(
+        &mut self,
+        frame_type: u8,
+        flags: u8,
+        stream_id: u32,
+        mut payload: Vec<u8>,
+        events: &mut Vec<(u32, StreamEvent)>,
+    ) -> Result<(), Error> {
+        if self.header_block.is_some() && frame_type != FRAME_CONTINUATION {
+            return Err("HTTP/2 header block was interrupted".into());
+        }
+        if frame_type == FRAME_DATA || frame_type == FRAME_HEADERS {
+            payload = strip_padding(flags, payload)?;
+        }
+        match frame_type {
+            FRAME_DATA => {
+                // The data is buffered right away, so the window can be replenished immediately.
+                if !payload.is_empty() {
+                    let increment = (payload.len() as u32).to_be_bytes();
+                    self.write_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                    if flags & FLAG_END_STREAM == 0 {
+                        self.write_frame(FRAME_WINDOW_UPDATE, 0, stream_id, &increment);
+                    }
+                    events.push((stream_id, StreamEvent::Data(payload)));
+                }
+                if flags & FLAG_END_STREAM != 0 {
+                    self.close_remote(stream_id, events);
+                }
+            }
+            FRAME_HEADERS => {
+                if flags & FLAG_PRIORITY != 0 {
+                    if payload.len() < 5 {
+                        return Err("malformed HTTP/2 HEADERS frame".into());
+                    }
+                    payload.drain(0..5);
+                }
+                self.header_block = Some((stream_id, flags, payload));
+                if flags & FLAG_END_HEADERS != 0 {
+                    self.receive_header_block(events)?;
+                }
+            }
+            FRAME_CONTINUATION => {
+                let end_headers = flags & FLAG_END_HEADERS != 0;
+                match &mut self.header_block {
+                    Some((id, _, block)) if *id == stream_id => block.extend(payload),
+                    _ => return Err("unexpected HTTP/2 CONTINUATION frame".into()),
+                }
+                if end_headers {
+                    self.receive_header_block(events)?;
+                }
+            }
+            FRAME_RST_STREAM => {
+                self.streams.remove(&stream_id);
+                events.push((stream_id, StreamEvent::Reset));
+            }
+            FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+                for setting in payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            let delta = value as i64 - self.initial_window;
+                            self.initial_window = value as i64;
+                            for stream in self.streams.values_mut() {
+                                stream.send_window += delta;
+                            }
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                        _ => {}
+                    }
+                }
+                self.write_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]);
+                self.flush_all_streams();
+            }
+            FRAME_PING if flags & FLAG_ACK == 0 => {
+                self.write_frame(FRAME_PING, FLAG_ACK, 0, &payload);
+            }
+            FRAME_GOAWAY => {
+                self.going_away = true;
+                let last_stream_id = payload
+                    .get(0..4)
+                    .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]) & 0x7fffffff)
+                    .unwrap_or_default();
+                let refused: Vec<u32> = self
+                    .streams
+                    .keys()
+                    .copied()
+                    .filter(|id| *id > last_stream_id)
+                    .collect();
+                for stream_id in refused {
+                    self.streams.remove(&stream_id);
+                    events.push((stream_id, StreamEvent::Reset));
+                }
+            }
+            FRAME_WINDOW_UPDATE => {
+                let increment = payload
+                    .get(0..4)
+                    .map(|inc| u32::from_be_bytes([inc[0], inc[1], inc[2], inc[3]]) & 0x7fffffff)
+                    .ok_or("malformed HTTP/2 WINDOW_UPDATE frame")?;
+                if stream_id == 0 {
+                    self.send_window += increment as i64;
+                    self.flush_all_streams();
+                } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.send_window += increment as i64;
+                    self.flush_stream(stream_id);
+                }
+            }
+            // PRIORITY, PUSH_PROMISE (disabled), acknowledgements and unknown frames.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn receive_header_block(&mut self, events: &mut Vec<(u32, StreamEvent)>) -> Result<(), Error> {
+        let (stream_id, flags, block) = self.header_block.take().unwrap_or_default();
+        let headers = self
+            .decoder
+            .decode(&block)
+            .map_err(|e| Error::from(format!("HTTP/2 header decoding failed: {e:?}")))?;
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if !stream.response_received {
+                let status = headers
+                    .iter()
+                    .find(|(name, _)| name == b":status")
+                    .and_then(|(_, value)| std::str::from_utf8(value).ok())
+                    .and_then(|value| value.parse().ok())
+                    .ok_or("HTTP/2 response does not contain a status")?;
+                // Informational responses precede the actual one.
+                if !(100..200).contains(&status) {
+                    stream.response_received = true;
+                    events.push((stream_id, StreamEvent::Response(status)));
+                }
+            }
+        }
+        if flags & FLAG_END_STREAM != 0 {
+            self.close_remote(stream_id, events);
+        }
+        Ok(())
+    }
+
+    fn close_remote(&mut self, stream_id: u32, events: &mut Vec<(u32, StreamEvent)>) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.remote_closed = true;
+            if stream.end_sent {
+                self.streams.remove(&stream_id);
+            }
+            events.push((stream_id, StreamEvent::End));
+        }
+    }
+}
+
+fn strip_padding(flags: u8, mut payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if flags & FLAG_PADDED != 0 {
+        let padding = *payload.first().ok_or("malformed HTTP/2 frame")? as usize;
+        if padding + 1 > payload.len() {
+            return Err("malformed HTTP/2 frame".into());
+        }
+        payload.truncate(payload.len() - padding);
+        payload.remove(0);
+    }
+    Ok(payload)
+}
+
+/// The TLS connection to the proxy carrying one HTTP/2 session.
+struct Upstream {
+    stream: TcpStream,
+    tls: ClientConnection,
+    session: H2Session,
+    locals: Vec<Token>,
+}
+
+impl Upstream {
+    fn flush(&mut self) -> Result<(), Error> {
+        loop {
+            // The TLS session only buffers a limited amount of plaintext.
+            let written = self.tls.writer().write(&self.session.outbuf)?;
+            self.session.outbuf.drain(0..written);
+            let mut blocked = false;
+            while self.tls.wants_write() {
+                match self.tls.write_tls(&mut self.stream) {
+                    Ok(_) => {}
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                        blocked = true;
+                        break;
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            if blocked || self.session.outbuf.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn receive(&mut self, events: &mut Vec<(u32, StreamEvent)>) -> Result<bool, Error> {
+        let mut open = true;
+        let mut plaintext = Vec::new();
+        while open {
+            match self.tls.read_tls(&mut self.stream) {
+                Ok(0) => open = false,
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            }
+            self.tls.process_new_packets()?;
+            match self.tls.reader().read_to_end(&mut plaintext) {
+                Ok(_) => open = false,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        if !self.tls.is_handshaking() && self.tls.alpn_protocol() != Some(b"h2") {
+            return Err("proxy server does not support HTTP/2".into());
+        }
+        self.session.receive(&plaintext, events)?;
+        Ok(open)
+    }
+}
+
+#[derive(Eq, PartialEq)]
+enum LocalState {
+    ExpectRequest,
+    ExpectResponse,
+    Established,
+}
+
+/// A connection of the tunnel interface, handed to the bridge as a plain CONNECT request.
+struct Local {
+    stream: UnixStream,
+    state: LocalState,
+    request: Vec<u8>,
+    upstream: Option<(Token, u32)>,
+    outbuf: VecDeque<u8>,
+    eof: bool,
+    close_after_write: bool,
+}
+
+impl Local {
+    fn flush(&mut self) -> Result<(), Error> {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(self.outbuf.make_contiguous()) {
+                Ok(0) => return Err("local stream closed".into()),
+                Ok(written) => {
+                    self.outbuf.drain(0..written);
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error.into()),
+            }
+        }
+        if self.close_after_write {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+const WAKER_TOKEN: Token = Token(0);
+
+/// Multiplexes the CONNECT requests of the connections handed over to it as streams over a
+/// single HTTP/2 session to the proxy server.
+struct Bridge {
+    poll: Poll,
+    connections: BridgeConnections,
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+    tls: TlsConfig,
+    upstreams: HashMap<Token, Upstream>,
+    current_upstream: Option<Token>,
+    locals: HashMap<Token, Local>,
+    next_token: usize,
+}
+
+impl Bridge {
+    fn new_token(&mut self) -> Token {
+        self.next_token += 1;
+        Token(self.next_token)
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(error) = self.poll.poll(&mut events, None) {
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER_TOKEN => match self.connections.take() {
+                        None => return Ok(()),
+                        Some(streams) => self.accept(streams)?,
+                    },
+                    token if self.upstreams.contains_key(&token) => {
+                        if let Err(error) = self.upstream_event(token) {
+                            log::error!("HTTP/2 session: {error}");
+                            self.remove_upstream(token);
+                        }
+                    }
+                    token => {
+                        if let Err(error) = self.local_event(token) {
+                            log::debug!("HTTP/2 stream: {error}");
+                            self.remove_local(token);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self, streams: Vec<UnixStream>) -> Result<(), Error> {
+        for mut stream in streams {
+            let token = self.new_token();
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let local = Local {
+                stream,
+                state: LocalState::ExpectRequest,
+                request: Vec::new(),
+                upstream: None,
+                outbuf: VecDeque::new(),
+                eof: false,
+                close_after_write: false,
+            };
+            self.locals.insert(token, local);
+        }
+        Ok(())
+    }
+
+    fn connect_upstream(&mut self) -> Result<Token, Error> {
+        if let Some(token) = self.current_upstream {
+            match self.upstreams.get(&token) {
+                Some(upstream) if !upstream.session.going_away => return Ok(token),
+                _ => {}
+            }
+        }
+        let mut stream = protect::connect_tcp(self.server, false)?;
+        let token = self.new_token();
+        self.poll.registry().register(
+            &mut stream,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        let tls = self.tls.client_connection()?;
+        let upstream = Upstream {
+            stream,
+            tls,
+            session: H2Session::new(),
+            locals: Vec::new(),
+        };
+        self.upstreams.insert(token, upstream);
+        self.current_upstream = Some(token);
+        Ok(token)
+    }
+
+    fn local_event(&mut self, token: Token) -> Result<(), Error> {
+        let local = match self.locals.get_mut(&token) {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        let mut data = Vec::new();
+        let eof = match local.stream.read_to_end(&mut data) {
+            Ok(_) => true,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => false,
+            Err(error) => return Err(error.into()),
+        };
+        local.eof |= eof;
+        local.flush()?;
+
+        if local.state == LocalState::ExpectRequest {
+            local.request.extend(&data);
+            data.clear();
+            let end = match local.request.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => end,
+                None if eof => return Err("incomplete CONNECT request".into()),
+                None => return Ok(()),
+            };
+            data = local.request.split_off(end + 4);
+            let request = String::from_utf8_lossy(&local.request).to_string();
+            let authority = request
+                .strip_prefix("CONNECT ")
+                .and_then(|request| request.split(' ').next())
+                .ok_or("invalid CONNECT request")?
+                .to_string();
+            local.state = LocalState::ExpectResponse;
+
+            let authorization = self.credentials.as_ref().map(|credentials| {
+                let mut auth_plain = credentials.username.clone();
+                auth_plain.extend(b":".iter());
+                auth_plain.extend(&credentials.password);
+                let auth_b64 = base64::engine::general_purpose::STANDARD.encode(auth_plain);
+                format!("Basic {auth_b64}")
+            });
+            let mut headers: Vec<(&[u8], &[u8])> = vec![
+                (b":method", b"CONNECT"),
+                (b":authority", authority.as_bytes()),
+            ];
+            if let Some(authorization) = &authorization {
+                headers.push((b"proxy-authorization", authorization.as_bytes()));
+            }
+
+            let upstream_token = self.connect_upstream()?;
+            let upstream = self
+                .upstreams
+                .get_mut(&upstream_token)
+                .ok_or("no session")?;
+            let stream_id = upstream.session.open_stream(&headers);
+            upstream.locals.push(token);
+            upstream.flush()?;
+            if let Some(local) = self.locals.get_mut(&token) {
+                local.upstream = Some((upstream_token, stream_id));
+            }
+        }
+
+        let local = self.locals.get(&token).ok_or("no local stream")?;
+        if let Some((upstream_token, stream_id)) = local.upstream {
+            if let Some(upstream) = self.upstreams.get_mut(&upstream_token) {
+                if !data.is_empty() {
+                    upstream.session.send_data(stream_id, &data);
+                }
+                if eof {
+                    upstream.session.end_stream(stream_id);
+                }
+                upstream.flush()?;
+            }
+        }
+        self.finish_local(token);
+        Ok(())
+    }
+
+    fn upstream_event(&mut self, token: Token) -> Result<(), Error> {
+        let upstream = match self.upstreams.get_mut(&token) {
+            Some(upstream) => upstream,
+            None => return Ok(()),
+        };
+        let mut events = Vec::new();
+        let open = upstream.receive(&mut events)?;
+        upstream.flush()?;
+
+        let local_tokens = upstream.locals.clone();
+        let locals: HashMap<u32, Token> = local_tokens
+            .iter()
+            .filter_map(|local_token| {
+                let local = self.locals.get(local_token)?;
+                Some((local.upstream?.1, *local_token))
+            })
+            .collect();
+        for (stream_id, event) in events {
+            let local_token = match locals.get(&stream_id) {
+                Some(local_token) => *local_token,
+                None => continue,
+            };
+            let local = match self.locals.get_mut(&local_token) {
+                Some(local) => local,
+                None => continue,
+            };
+            match event {
+                StreamEvent::Response(status) => {
+                    let response = format!("HTTP/1.1 {status} HTTP/2\r\n\r\n");
+                    local.outbuf.extend(response.as_bytes());
+                    if (200..300).contains(&status) {
+                        local.state = LocalState::Established;
+                    } else {
+                        local.close_after_write = true;
+                    }
+                }
+                StreamEvent::Data(data) => local.outbuf.extend(data),
+                StreamEvent::End => local.close_after_write = true,
+                StreamEvent::Reset => {
+                    self.remove_local(local_token);
+                    continue;
+                }
+            }
+            if let Err(error) = local.flush() {
+                log::debug!("HTTP/2 stream: {error}");
+                self.remove_local(local_token);
+            }
+            self.finish_local(local_token);
+        }
+        if !open {
+            return Err("proxy server closed the connection".into());
+        }
+        Ok(())
+    }
+
+    /// Remove the connection once both directions of its stream are closed.
+    fn finish_local(&mut self, token: Token) {
+        let finished = match self.locals.get(&token) {
+            Some(local) => {
+                local.eof
+                    && local.close_after_write
+                    && local.outbuf.is_empty()
+                    && !matches!(local.upstream, Some((upstream_token, stream_id))
+                    if self.upstreams.get(&upstream_token).is_some_and(|upstream| {
+                        upstream.session.streams.contains_key(&stream_id)
+                    }))
+            }
+            None => false,
+        };
+        if finished {
+            self.remove_local(token);
+        }
+    }
+
+    fn remove_local(&mut self, token: Token) {
+        if let Some(mut local) = self.locals.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut local.stream);
+            let _ = local.stream.shutdown(Shutdown::Both);
+            if let Some((upstream_token, stream_id)) = local.upstream {
+                if let Some(upstream) = self.upstreams.get_mut(&upstream_token) {
+                    upstream.locals.retain(|local_token| *local_token != token);
+                    upstream.session.reset_stream(stream_id);
+                    let _ = upstream.flush();
+                    if upstream.session.going_away && upstream.locals.is_empty() {
+                        self.remove_upstream(upstream_token);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_upstream(&mut self, token: Token) {
+        if let Some(mut upstream) = self.upstreams.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut upstream.stream);
+            for local_token in upstream.locals {
+                self.remove_local(local_token);
+            }
+        }
+        if self.current_upstream == Some(token) {
+            self.current_upstream = None;
+        }
+    }
+}
+
+/// Proxies connections through CONNECT requests multiplexed over one HTTP/2 session. The
+/// connections are handed over to a bridge thread, which owns the session, through socket pairs
+/// as HTTP/1.1 CONNECT requests.
+pub(crate) struct H2Manager {
+    http: Rc<HttpManager>,
+    bridge: BridgeHandle,
+}
+
+impl ConnectionManager for H2Manager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        self.http.handles_connection(connection)
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        self.http.new_connection(connection, manager)
+    }
+
+    fn close_connection(&self, connection: &Connection) {
+        self.http.close_connection(connection)
+    }
+
+    fn get_server(&self) -> SocketAddr {
+        self.http.get_server()
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        // The bridge authenticates the HTTP/2 requests.
+        &None
+    }
+
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        Some(&self.bridge)
+    }
+}
+
+impl H2Manager {
+    pub fn new(
+        server: SocketAddr,
+        credentials: Option<Credentials>,
+        tls: TlsConfig,
+    ) -> Result<Rc<Self>, Error> {
+        let poll = Poll::new()?;
+        let (handle, connections) = bridge::channel(poll.registry(), WAKER_TOKEN)?;
+
+        let mut bridge = Bridge {
+            poll,
+            connections,
+            server,
+            credentials,
+            tls: tls.with_alpn(&[b"h2"]),
+            upstreams: HashMap::new(),
+            current_upstream: None,
+            locals: HashMap::new(),
+            next_token: usize::from(WAKER_TOKEN),
+        };
+        std::thread::spawn(move || {
+            if let Err(error) = bridge.run() {
+                log::error!("HTTP/2 bridge: {error}");
+            }
+        });
+
+        Ok(Rc::new(Self {
+            http: HttpManager::new(server, None, None),
+            bridge: handle,
+        }))
+    }
+}
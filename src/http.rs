@@ -1,108 +1,449 @@
+use crate::digest;
 use crate::error::Error;
+use crate::ntlm;
+use crate::tls::{TlsConfig, TlsConnection};
 use crate::tun2proxy::{
     Connection, ConnectionManager, Direction, IncomingDataEvent, IncomingDirection,
-    OutgoingDataEvent, OutgoingDirection, TcpProxy,
+    OutgoingDataEvent, OutgoingDirection, OutgoingSlices, TcpProxy,
 };
 use crate::Credentials;
 use base64::Engine;
 use smoltcp::wire::IpProtocol;
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::rc::Rc;
 
+const MAX_RESPONSE_HEADER: usize = 0x4000;
+
 #[derive(Eq, PartialEq, Debug)]
-#[allow(dead_code)]
 enum HttpState {
-    SendRequest,
-    ExpectStatusCode,
     ExpectResponse,
+    SkipBody(usize),
     Established,
+    Idle,
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum NtlmState {
+    Idle,
+    NegotiateSent,
+    AuthenticateSent,
+}
+
+/// A challenge of the proxy through `Proxy-Authenticate` which can be answered.
+#[derive(Clone, Debug)]
+enum AuthChallenge {
+    Basic,
+    Digest(digest::Challenge),
+    /// NTLM along with the token of the CHALLENGE_MESSAGE, if any.
+    Ntlm(String),
+}
+
+impl AuthChallenge {
+    fn new(scheme: &str, token: Option<&str>, params: &[(String, String)]) -> Option<Self> {
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => Some(AuthChallenge::Basic),
+            "ntlm" => Some(AuthChallenge::Ntlm(token.unwrap_or_default().into())),
+            "digest" => {
+                // Only `auth` protection is supported, not `auth-int`.
+                let qop = match param("qop") {
+                    None => false,
+                    Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => true,
+                    Some(_) => return None,
+                };
+                Some(AuthChallenge::Digest(digest::Challenge {
+                    realm: param("realm")?.into(),
+                    nonce: param("nonce")?.into(),
+                    opaque: param("opaque").map(String::from),
+                    algorithm: digest::Algorithm::parse(param("algorithm"))?,
+                    qop,
+                    stale: param("stale").is_some_and(|stale| stale.eq_ignore_ascii_case("true")),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    // Schemes are preferred in the same order as curl does.
+    fn strength(&self) -> u8 {
+        match self {
+            AuthChallenge::Basic => 0,
+            AuthChallenge::Ntlm(_) => 1,
+            AuthChallenge::Digest(challenge) => match challenge.algorithm {
+                digest::Algorithm::Md5 => 2,
+                digest::Algorithm::Sha256 => 3,
+            },
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        matches!(self, AuthChallenge::Digest(challenge) if challenge.stale)
+    }
+
+    /// The strongest of `challenges` to answer. Once a challenge has been `answered`, the proxy
+    /// has rejected the credentials unless it merely renews the nonce.
+    fn select(challenges: Vec<AuthChallenge>, answered: bool) -> Option<AuthChallenge> {
+        challenges
+            .into_iter()
+            .filter(|challenge| !answered || challenge.is_stale())
+            .max_by_key(AuthChallenge::strength)
+    }
+}
+
+// Split a header field value at the commas outside of quoted strings.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(value[start..].trim());
+    items
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+fn is_token68(s: &str) -> bool {
+    let s = s.trim_end_matches('=');
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c))
+}
+
+// Parse an authentication parameter, e.g. `realm="proxy"`.
+fn auth_param(item: &str) -> Option<(String, String)> {
+    let (name, value) = item.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    if !is_token(name) {
+        return None;
+    }
+    let value = match value.strip_prefix('"') {
+        Some(quoted) => {
+            let mut result = String::new();
+            let mut chars = quoted.strip_suffix('"')?.chars();
+            while let Some(c) = chars.next() {
+                result.push(if c == '\\' { chars.next()? } else { c });
+            }
+            result
+        }
+        None if is_token(value) => value.into(),
+        None => return None,
+    };
+    Some((name.to_ascii_lowercase(), value))
+}
+
+/// Parse the challenges of a `Proxy-Authenticate` header field, which may hold several of them
+/// separated by commas, e.g. `Basic realm="proxy", Digest realm="proxy", nonce="abc"`. Those of
+/// unsupported schemes are skipped.
+fn parse_challenges(value: &str) -> Vec<AuthChallenge> {
+    // The scheme, token and parameters of each challenge.
+    let mut challenges: Vec<(&str, Option<&str>, Vec<_>)> = Vec::new();
+    for item in split_list(value) {
+        if item.is_empty() {
+            continue;
+        }
+        if let (Some(param), Some((_, _, params))) = (auth_param(item), challenges.last_mut()) {
+            params.push(param);
+            continue;
+        }
+        // Otherwise the item starts the next challenge.
+        let (scheme, rest) = item.split_once(' ').unwrap_or((item, ""));
+        let rest = rest.trim();
+        let token = is_token68(rest).then_some(rest);
+        let params = auth_param(rest).into_iter().collect::<Vec<_>>();
+        challenges.push((scheme, token, params));
+    }
+    challenges
+        .iter()
+        .filter_map(|(scheme, token, params)| AuthChallenge::new(scheme, *token, params))
+        .collect()
+}
+
+/// The challenge answered last for a proxy, with which the following connections are
+/// authenticated right away rather than once challenged.
+#[derive(Default)]
+struct AuthCache {
+    challenge: Option<AuthChallenge>,
+    nonce_count: u32,
+}
+
+/// Status code and header fields of a response of the proxy.
+struct Response {
+    status: u16,
+    keep_alive: bool,
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn parse(header: &[u8]) -> Result<Self, Error> {
+        let header = String::from_utf8_lossy(header);
+        let mut lines = header.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let (version, status) = match status_line.split(' ').collect::<Vec<_>>().as_slice() {
+            [version, status, ..] if version.starts_with("HTTP/1.") => {
+                status.parse().ok().map(|status| (*version, status))
+            }
+            _ => None,
+        }
+        .ok_or_else(|| format!("Invalid status line `{status_line}`."))?;
+        let mut result = Self {
+            status,
+            keep_alive: false,
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().into()))
+                .collect(),
+        };
+        // HTTP/1.1 keeps the connection open unless told otherwise, HTTP/1.0 only on request.
+        let connection = |value: &str| {
+            result
+                .header("connection")
+                .chain(result.header("proxy-connection"))
+                .any(|field| field.eq_ignore_ascii_case(value))
+        };
+        result.keep_alive = match version {
+            "HTTP/1.0" => connection("keep-alive"),
+            _ => !connection("close"),
+        };
+        Ok(result)
+    }
+
+    fn header(&self, name: &str) -> impl Iterator<Item = &str> {
+        let name = name.to_string();
+        self.headers
+            .iter()
+            .filter(move |(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn challenges(&self) -> Vec<AuthChallenge> {
+        self.header("proxy-authenticate")
+            .flat_map(parse_challenges)
+            .collect()
+    }
 }
 
 pub struct HttpConnection {
     state: HttpState,
+    ntlm: NtlmState,
+    destination: String,
+    credentials: Option<Credentials>,
+    auth: Rc<RefCell<AuthCache>>,
+    answered: bool,
     client_inbuf: VecDeque<u8>,
     server_inbuf: VecDeque<u8>,
     client_outbuf: VecDeque<u8>,
     server_outbuf: VecDeque<u8>,
     data_buf: VecDeque<u8>,
-    crlf_state: u8,
+    refused: Option<u16>,
 }
 
 impl HttpConnection {
-    fn new(connection: &Connection, manager: Rc<dyn ConnectionManager>) -> Self {
-        let mut server_outbuf: VecDeque<u8> = VecDeque::new();
-        {
-            let credentials = manager.get_credentials();
-            server_outbuf.extend(b"CONNECT ".iter());
-            server_outbuf.extend(connection.dst.to_string().as_bytes());
-            server_outbuf.extend(b" HTTP/1.1\r\nHost: ".iter());
-            server_outbuf.extend(connection.dst.to_string().as_bytes());
-            server_outbuf.extend(b"\r\n".iter());
-            if let Some(credentials) = credentials {
-                server_outbuf.extend(b"Proxy-Authorization: Basic ");
+    fn new(
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+        auth: Rc<RefCell<AuthCache>>,
+    ) -> Result<Self, Error> {
+        let mut result = Self {
+            state: HttpState::ExpectResponse,
+            ntlm: NtlmState::Idle,
+            destination: connection.dst.to_string(),
+            credentials: manager.get_credentials().clone(),
+            auth,
+            answered: false,
+            client_inbuf: Default::default(),
+            server_inbuf: Default::default(),
+            client_outbuf: Default::default(),
+            server_outbuf: Default::default(),
+            data_buf: Default::default(),
+            refused: None,
+        };
+        // Credentials are only sent once the proxy has asked for them through a challenge.
+        let challenge = result.auth.borrow().challenge.clone();
+        let authorization = match challenge {
+            Some(challenge) if result.credentials.is_some() => Some(result.answer(&challenge)?),
+            _ => None,
+        };
+        result.send_request(authorization.as_deref());
+        Ok(result)
+    }
+
+    fn send_request(&mut self, authorization: Option<&str>) {
+        self.server_outbuf.extend(b"CONNECT ".iter());
+        self.server_outbuf.extend(self.destination.as_bytes());
+        self.server_outbuf.extend(b" HTTP/1.1\r\nHost: ".iter());
+        self.server_outbuf.extend(self.destination.as_bytes());
+        self.server_outbuf.extend(b"\r\n".iter());
+        if let Some(authorization) = authorization {
+            self.server_outbuf.extend(b"Proxy-Authorization: ");
+            self.server_outbuf.extend(authorization.as_bytes());
+            self.server_outbuf.extend(b"\r\n".iter());
+        }
+        self.server_outbuf.extend(b"\r\n".iter());
+    }
+
+    /// The authorization answering `challenge`, which is remembered for the following
+    /// connections.
+    fn answer(&mut self, challenge: &AuthChallenge) -> Result<String, Error> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or("No credentials are supplied")?;
+        let mut auth = self.auth.borrow_mut();
+        let authorization = match challenge {
+            AuthChallenge::Basic => {
                 let mut auth_plain = credentials.username.clone();
                 auth_plain.extend(b":".iter());
                 auth_plain.extend(&credentials.password);
                 let auth_b64 = base64::engine::general_purpose::STANDARD.encode(auth_plain);
-                server_outbuf.extend(auth_b64.as_bytes().iter());
-                server_outbuf.extend(b"\r\n".iter());
+                format!("Basic {auth_b64}")
             }
-            server_outbuf.extend(b"\r\n".iter());
-        }
+            AuthChallenge::Digest(digest) => {
+                // Requests made with the same nonce are counted.
+                auth.nonce_count = match &auth.challenge {
+                    Some(AuthChallenge::Digest(cached)) if cached.nonce == digest.nonce => {
+                        auth.nonce_count + 1
+                    }
+                    _ => 1,
+                };
+                let uri = &self.destination;
+                digest::authorization(credentials, digest, "CONNECT", uri, auth.nonce_count)?
+            }
+            AuthChallenge::Ntlm(_) => {
+                self.ntlm = NtlmState::NegotiateSent;
+                let message = ntlm::negotiate_message();
+                let message = base64::engine::general_purpose::STANDARD.encode(message);
+                format!("NTLM {message}")
+            }
+        };
+        auth.challenge = Some(challenge.clone());
+        Ok(authorization)
+    }
 
-        Self {
-            state: HttpState::ExpectStatusCode,
-            client_inbuf: Default::default(),
-            server_inbuf: Default::default(),
-            client_outbuf: Default::default(),
-            server_outbuf,
-            data_buf: Default::default(),
-            crlf_state: Default::default(),
+    /// Answer the challenge of the proxy if it asks for authentication, choosing the strongest
+    /// of the schemes it offers. The NTLM authentication is bound to the connection, so its
+    /// messages are exchanged over the same connection.
+    fn authenticate(&mut self, response: &Response) -> Result<bool, Error> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(false),
+        };
+        let challenges = response.challenges();
+        let authorization = match self.ntlm {
+            NtlmState::NegotiateSent => {
+                let challenge = challenges.iter().find_map(|challenge| match challenge {
+                    AuthChallenge::Ntlm(token) if !token.is_empty() => Some(token),
+                    _ => None,
+                });
+                let challenge = match challenge {
+                    Some(challenge) => challenge,
+                    None => return Ok(false),
+                };
+                let challenge = base64::engine::general_purpose::STANDARD
+                    .decode(challenge)
+                    .map_err(|_| "NTLM challenge is not valid base64")?;
+                let challenge = ntlm::Challenge::parse(&challenge)?;
+                self.ntlm = NtlmState::AuthenticateSent;
+                let message = ntlm::authenticate_message(credentials, &challenge)?;
+                let message = base64::engine::general_purpose::STANDARD.encode(message);
+                format!("NTLM {message}")
+            }
+            NtlmState::AuthenticateSent => return Ok(false),
+            NtlmState::Idle => match AuthChallenge::select(challenges, self.answered) {
+                Some(challenge) => {
+                    self.answered = true;
+                    self.answer(&challenge)?
+                }
+                None => return Ok(false),
+            },
+        };
+        if !response.keep_alive {
+            // The following connections are authenticated right away.
+            return Err("The proxy closes the connection during authentication.".into());
         }
+        self.send_request(Some(&authorization));
+        Ok(true)
     }
 
     fn state_change(&mut self) -> Result<(), Error> {
-        let http_len = "HTTP/1.1 200".len();
         match self.state {
-            HttpState::ExpectStatusCode if self.server_inbuf.len() > http_len => {
-                let status_line: Vec<u8> =
-                    self.server_inbuf.range(0..http_len + 1).copied().collect();
-                let slice = &status_line.as_slice()[0.."HTTP/1.1 2".len()];
-                if slice != b"HTTP/1.1 2" && slice != b"HTTP/1.0 2"
-                    || self.server_inbuf[http_len] != b' '
-                {
-                    let status_str = String::from_utf8_lossy(&status_line.as_slice()[0..http_len]);
-                    let e =
-                        format!("Expected success status code. Server replied with {status_str}.");
-                    return Err(e.into());
-                }
-                self.state = HttpState::ExpectResponse;
-                return self.state_change();
-            }
             HttpState::ExpectResponse => {
-                let mut counter = 0usize;
-                for b_ref in self.server_inbuf.iter() {
-                    let b = *b_ref;
-                    if b == b'\n' {
-                        self.crlf_state += 1;
-                    } else if b != b'\r' {
-                        self.crlf_state = 0;
+                let data = self.server_inbuf.make_contiguous();
+                let end = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+                    Some(position) => position + 4,
+                    None if data.len() > MAX_RESPONSE_HEADER => {
+                        return Err("The response of the proxy is too long.".into());
                     }
-                    counter += 1;
+                    None => return Ok(()),
+                };
+                let response = Response::parse(&data[..end])?;
+                self.server_inbuf.drain(0..end);
 
-                    if self.crlf_state == 2 {
-                        self.server_inbuf.drain(0..counter);
-
-                        self.server_outbuf.append(&mut self.data_buf);
-                        self.data_buf.clear();
-
-                        self.state = HttpState::Established;
-                        return self.state_change();
+                if (200..300).contains(&response.status) {
+                    self.server_outbuf.append(&mut self.data_buf);
+                    self.state = HttpState::Established;
+                    return self.state_change();
+                }
+                let chunked = response.header("transfer-encoding").next().is_some();
+                let length = response
+                    .header("content-length")
+                    .next()
+                    .map(|length| length.parse())
+                    .transpose()?;
+                if response.status != 407 || !self.authenticate(&response)? {
+                    // The connection can carry another request once the body has been skipped.
+                    let status = response.status;
+                    let length = match status {
+                        204 | 304 => Some(0),
+                        _ => length.filter(|_| !chunked),
+                    };
+                    match length {
+                        Some(length) if response.keep_alive => {
+                            self.refused = Some(status);
+                            self.state = HttpState::SkipBody(length);
+                            return self.state_change();
+                        }
+                        _ => return Err(refused(status)),
                     }
                 }
-
-                self.server_inbuf.drain(0..counter);
+                if chunked {
+                    return Err("Chunked responses of the proxy are not supported.".into());
+                }
+                self.state = HttpState::SkipBody(length.unwrap_or_default());
+                return self.state_change();
+            }
+            HttpState::SkipBody(length) => {
+                let skipped = length.min(self.server_inbuf.len());
+                self.server_inbuf.drain(0..skipped);
+                if skipped < length {
+                    self.state = HttpState::SkipBody(length - skipped);
+                } else if let Some(status) = self.refused {
+                    self.state = HttpState::Idle;
+                    return Err(refused(status));
+                } else {
+                    self.state = HttpState::ExpectResponse;
+                    return self.state_change();
+                }
             }
             HttpState::Established => {
                 self.client_outbuf.extend(self.server_inbuf.iter());
@@ -110,12 +451,16 @@ impl HttpConnection {
                 self.server_inbuf.clear();
                 self.client_inbuf.clear();
             }
-            _ => {}
+            HttpState::Idle => return Err(refused(self.refused.unwrap_or_default())),
         }
         Ok(())
     }
 }
 
+fn refused(status: u16) -> Error {
+    format!("Expected success status code. Server replied with {status}.").into()
+}
+
 impl TcpProxy for HttpConnection {
     fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
         let direction = event.direction;
@@ -157,10 +502,27 @@ impl TcpProxy for HttpConnection {
         }
     }
 
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &self.server_outbuf
+        } else {
+            &self.client_outbuf
+        };
+        buffer.into()
+    }
+
     fn connection_established(&self) -> bool {
         self.state == HttpState::Established
     }
 
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn is_reusable(&self) -> bool {
+        self.state == HttpState::Idle && self.server_inbuf.is_empty()
+    }
+
     fn have_data(&mut self, dir: Direction) -> bool {
         match dir {
             Direction::Incoming(incoming) => match incoming {
@@ -180,6 +542,8 @@ impl TcpProxy for HttpConnection {
 pub(crate) struct HttpManager {
     server: SocketAddr,
     credentials: Option<Credentials>,
+    tls: Option<TlsConfig>,
+    auth: Rc<RefCell<AuthCache>>,
 }
 
 impl ConnectionManager for HttpManager {
@@ -195,7 +559,11 @@ impl ConnectionManager for HttpManager {
         if connection.proto != IpProtocol::Tcp {
             return Ok(None);
         }
-        Ok(Some(Box::new(HttpConnection::new(connection, manager))))
+        let handler = Box::new(HttpConnection::new(connection, manager, self.auth.clone())?);
+        match &self.tls {
+            None => Ok(Some(handler)),
+            Some(tls) => Ok(Some(Box::new(TlsConnection::new(handler, tls)?))),
+        }
     }
 
     fn close_connection(&self, _: &Connection) {}
@@ -210,10 +578,102 @@ impl ConnectionManager for HttpManager {
 }
 
 impl HttpManager {
-    pub fn new(server: SocketAddr, credentials: Option<Credentials>) -> Rc<Self> {
+    pub fn new(
+        server: SocketAddr,
+        credentials: Option<Credentials>,
+        tls: Option<TlsConfig>,
+    ) -> Rc<Self> {
         Rc::new(Self {
             server,
             credentials,
+            tls,
+            auth: Default::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_digest(challenge: &AuthChallenge) -> &digest::Challenge {
+        match challenge {
+            AuthChallenge::Digest(challenge) => challenge,
+            _ => panic!("not a Digest challenge: {:?}", challenge),
+        }
+    }
+
+    #[test]
+    fn parses_several_challenges() {
+        let challenges = parse_challenges(
+            "Negotiate, Basic realm=\"proxy\", NTLM, \
+             Digest realm=\"proxy, inc.\", nonce=\"abc\", qop=\"auth,auth-int\", opaque=\"xyz\"",
+        );
+        assert_eq!(challenges.len(), 3);
+        assert!(matches!(challenges[0], AuthChallenge::Basic));
+        assert!(matches!(&challenges[1], AuthChallenge::Ntlm(token) if token.is_empty()));
+        let challenge = as_digest(&challenges[2]);
+        assert_eq!(challenge.realm, "proxy, inc.");
+        assert_eq!(challenge.nonce, "abc");
+        assert_eq!(challenge.opaque.as_deref(), Some("xyz"));
+        assert_eq!(challenge.algorithm, digest::Algorithm::Md5);
+        assert!(challenge.qop);
+        assert!(!challenge.stale);
+    }
+
+    #[test]
+    fn skips_unsupported_digest_challenges() {
+        let challenges = parse_challenges(
+            "Digest realm=\"a\", nonce=\"1\", qop=\"auth-int\", \
+             Digest realm=\"b\", nonce=\"2\", algorithm=SHA-512-256, \
+             Digest realm=\"c\", nonce=\"3\", algorithm=MD5-sess, \
+             Digest realm=\"d\"",
+        );
+        assert!(challenges.is_empty());
+    }
+
+    #[test]
+    fn selects_the_strongest_challenge() {
+        let header = "Basic realm=\"proxy\", \
+            Digest realm=\"proxy\", nonce=\"md5\", algorithm=MD5, \
+            Digest realm=\"proxy\", nonce=\"sha\", algorithm=SHA-256, \
+            NTLM";
+        let challenge = AuthChallenge::select(parse_challenges(header), false).unwrap();
+        assert_eq!(as_digest(&challenge).nonce, "sha");
+        assert_eq!(as_digest(&challenge).algorithm, digest::Algorithm::Sha256);
+
+        let challenge = AuthChallenge::select(parse_challenges("Basic, NTLM"), false).unwrap();
+        assert!(matches!(challenge, AuthChallenge::Ntlm(_)));
+        let challenge = AuthChallenge::select(parse_challenges("Basic realm=\"a\""), false);
+        assert!(matches!(challenge, Some(AuthChallenge::Basic)));
+        assert!(AuthChallenge::select(parse_challenges("Bearer"), false).is_none());
+    }
+
+    #[test]
+    fn answers_again_only_stale_challenges() {
+        let header = "Basic realm=\"proxy\", \
+            Digest realm=\"proxy\", nonce=\"old\", algorithm=SHA-256, stale=false";
+        assert!(AuthChallenge::select(parse_challenges(header), true).is_none());
+
+        let header = "Basic realm=\"proxy\", \
+            Digest realm=\"proxy\", nonce=\"new\", algorithm=MD5, stale=TRUE";
+        let challenges = parse_challenges(header);
+        assert!(as_digest(&challenges[1]).stale);
+        let challenge = AuthChallenge::select(challenges, true).unwrap();
+        assert_eq!(as_digest(&challenge).nonce, "new");
+    }
+
+    #[test]
+    fn unquotes_parameters() {
+        assert_eq!(
+            auth_param(r#"realm="a \"b\" \\c""#),
+            Some(("realm".into(), r#"a "b" \c"#.into()))
+        );
+        assert_eq!(
+            auth_param("Nonce=abc"),
+            Some(("nonce".into(), "abc".into()))
+        );
+        assert_eq!(auth_param("realm=\"open"), None);
+        assert_eq!(auth_param("realm=a b"), None);
+    }
+}
@@ -0,0 +1,631 @@
+use crate::bridge::{self, BridgeConnections, BridgeHandle};
+use crate::error::Error;
+use crate::protect;
+use mio::net::{UdpSocket, UnixStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+
+const OVERHEAD: usize = 24;
+const MTU: usize = 1400;
+const MSS: usize = MTU - OVERHEAD;
+const SEND_WINDOW: u32 = 256;
+const RECEIVE_WINDOW: u32 = 512;
+const INTERVAL: u32 = 10;
+const MIN_RTO: u32 = 30;
+const MAX_RTO: u32 = 60000;
+const FAST_RESEND: u32 = 2;
+const DEAD_LINK: u32 = 20;
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120000;
+
+// Sequence numbers wrap around, so they are compared through their difference.
+fn diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+struct Segment {
+    sn: u32,
+    ts: u32,
+    resend_ts: u32,
+    rto: u32,
+    fast_ack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+/// A sans-IO KCP control block in stream mode, compatible with the segments of ikcp. It is
+/// configured like the `nodelay` mode of ikcp without congestion control, which trades
+/// bandwidth for latency on lossy links.
+pub(crate) struct Kcp {
+    conv: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    rmt_wnd: u32,
+    srtt: u32,
+    rttval: u32,
+    rto: u32,
+    snd_queue: VecDeque<u8>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: HashMap<u32, Vec<u8>>,
+    rcv_data: Vec<u8>,
+    acks: Vec<(u32, u32)>,
+    probe_send: bool,
+    probe_tell: bool,
+    probe_wait: u32,
+    ts_probe: u32,
+    ts_flush: u32,
+    output: Vec<Vec<u8>>,
+    dead: bool,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Self {
+        Self {
+            conv,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            rmt_wnd: RECEIVE_WINDOW,
+            srtt: 0,
+            rttval: 0,
+            rto: 200,
+            snd_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: HashMap::new(),
+            rcv_data: Vec::new(),
+            acks: Vec::new(),
+            probe_send: false,
+            probe_tell: false,
+            probe_wait: 0,
+            ts_probe: 0,
+            ts_flush: 0,
+            output: Vec::new(),
+            dead: false,
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) {
+        self.snd_queue.extend(data);
+    }
+
+    /// Take the data received in order so far.
+    pub fn recv(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.rcv_data)
+    }
+
+    /// Number of bytes which have not been acknowledged by the peer yet.
+    pub fn pending(&self) -> usize {
+        self.snd_queue.len() + self.snd_buf.len() * MSS
+    }
+
+    /// Whether nothing is left to be sent or acknowledged, so the control block needs no updates.
+    pub fn is_idle(&self) -> bool {
+        self.snd_queue.is_empty()
+            && self.snd_buf.is_empty()
+            && self.acks.is_empty()
+            && !self.probe_tell
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Take the datagrams to be sent to the peer.
+    pub fn output(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn unused_window(&self) -> u16 {
+        let used = self.rcv_buf.len() as u32 + (self.rcv_data.len() / MSS) as u32;
+        RECEIVE_WINDOW.saturating_sub(used) as u16
+    }
+
+    fn update_rtt(&mut self, rtt: u32) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.srtt);
+            self.rttval = (3 * self.rttval + delta) / 4;
+            self.srtt = ((7 * self.srtt + rtt) / 8).max(1);
+        }
+        let rto = self.srtt + INTERVAL.max(4 * self.rttval);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn shrink_buffer(&mut self) {
+        self.snd_una = self
+            .snd_buf
+            .front()
+            .map_or(self.snd_nxt, |segment| segment.sn);
+    }
+
+    pub fn input(&mut self, current: u32, mut data: &[u8]) -> Result<(), Error> {
+        let mut max_ack = None;
+        while data.len() >= OVERHEAD {
+            if read_u32(data) != self.conv {
+                return Err("KCP segment belongs to another conversation".into());
+            }
+            let cmd = data[4];
+            let wnd = u16::from_le_bytes([data[6], data[7]]);
+            let ts = read_u32(&data[8..]);
+            let sn = read_u32(&data[12..]);
+            let una = read_u32(&data[16..]);
+            let len = read_u32(&data[20..]) as usize;
+            data = &data[OVERHEAD..];
+            if data.len() < len || !(CMD_PUSH..=CMD_WINS).contains(&cmd) {
+                return Err("malformed KCP segment".into());
+            }
+            let payload = &data[..len];
+            data = &data[len..];
+
+            self.rmt_wnd = wnd as u32;
+            while self
+                .snd_buf
+                .front()
+                .is_some_and(|segment| diff(una, segment.sn) > 0)
+            {
+                self.snd_buf.pop_front();
+            }
+            self.shrink_buffer();
+
+            match cmd {
+                CMD_ACK => {
+                    if diff(current, ts) >= 0 {
+                        self.update_rtt(diff(current, ts) as u32);
+                    }
+                    if let Some(index) = self.snd_buf.iter().position(|segment| segment.sn == sn) {
+                        self.snd_buf.remove(index);
+                    }
+                    self.shrink_buffer();
+                    max_ack = match max_ack {
+                        Some(max_ack) if diff(max_ack, sn) > 0 => Some(max_ack),
+                        _ => Some(sn),
+                    };
+                }
+                // Segments beyond the receive window are dropped without acknowledgement.
+                CMD_PUSH if diff(sn, self.rcv_nxt.wrapping_add(RECEIVE_WINDOW)) < 0 => {
+                    self.acks.push((sn, ts));
+                    if diff(sn, self.rcv_nxt) >= 0 {
+                        self.rcv_buf.entry(sn).or_insert_with(|| payload.to_vec());
+                    }
+                    while let Some(payload) = self.rcv_buf.remove(&self.rcv_nxt) {
+                        self.rcv_data.extend(payload);
+                        self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                    }
+                }
+                CMD_WASK => self.probe_tell = true,
+                _ => {}
+            }
+        }
+
+        // Segments sent before the highest acknowledged one were probably lost.
+        if let Some(max_ack) = max_ack {
+            for segment in self.snd_buf.iter_mut() {
+                if diff(max_ack, segment.sn) > 0 {
+                    segment.fast_ack += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the control block if its interval has elapsed.
+    pub fn update(&mut self, current: u32) {
+        if diff(current, self.ts_flush) >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(INTERVAL);
+            if diff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(INTERVAL);
+            }
+            self.flush(current);
+        }
+    }
+
+    fn flush(&mut self, current: u32) {
+        let mut buffer = Vec::with_capacity(MTU);
+        let wnd = self.unused_window();
+        let una = self.rcv_nxt;
+        let conv = self.conv;
+        let output = &mut self.output;
+        let mut write = |cmd: u8, ts: u32, sn: u32, data: &[u8]| {
+            if buffer.len() + OVERHEAD + data.len() > MTU {
+                output.push(std::mem::take(&mut buffer));
+            }
+            buffer.extend(conv.to_le_bytes());
+            buffer.extend([cmd, 0]);
+            buffer.extend(wnd.to_le_bytes());
+            buffer.extend(ts.to_le_bytes());
+            buffer.extend(sn.to_le_bytes());
+            buffer.extend(una.to_le_bytes());
+            buffer.extend((data.len() as u32).to_le_bytes());
+            buffer.extend(data);
+        };
+
+        for (sn, ts) in self.acks.drain(..) {
+            write(CMD_ACK, ts, sn, &[]);
+        }
+
+        // Probe the window of the peer while it is closed.
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if diff(current, self.ts_probe) >= 0 {
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe_send = true;
+            }
+        } else {
+            self.probe_wait = 0;
+            self.ts_probe = 0;
+        }
+        if std::mem::take(&mut self.probe_send) {
+            write(CMD_WASK, 0, 0, &[]);
+        }
+        if std::mem::take(&mut self.probe_tell) {
+            write(CMD_WINS, 0, 0, &[]);
+        }
+
+        let window = SEND_WINDOW.min(self.rmt_wnd);
+        while !self.snd_queue.is_empty() && diff(self.snd_nxt, self.snd_una) < window as i32 {
+            let size = self.snd_queue.len().min(MSS);
+            self.snd_buf.push_back(Segment {
+                sn: self.snd_nxt,
+                ts: 0,
+                resend_ts: current,
+                rto: self.rto,
+                fast_ack: 0,
+                xmit: 0,
+                data: self.snd_queue.drain(0..size).collect(),
+            });
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        }
+
+        for segment in self.snd_buf.iter_mut() {
+            let send = if segment.xmit == 0 {
+                segment.rto = self.rto;
+                true
+            } else if diff(current, segment.resend_ts) >= 0 {
+                segment.rto += self.rto / 2;
+                true
+            } else {
+                segment.fast_ack >= FAST_RESEND
+            };
+            if send {
+                segment.xmit += 1;
+                segment.fast_ack = 0;
+                segment.ts = current;
+                segment.resend_ts = current.wrapping_add(segment.rto);
+                write(CMD_PUSH, segment.ts, segment.sn, &segment.data);
+                if segment.xmit >= DEAD_LINK {
+                    self.dead = true;
+                }
+            }
+        }
+
+        if !buffer.is_empty() {
+            output.push(buffer);
+        }
+    }
+}
+
+const SMUX_VERSION: u8 = 1;
+const SMUX_SYN: u8 = 0;
+const SMUX_FIN: u8 = 1;
+const SMUX_PSH: u8 = 2;
+const SMUX_NOP: u8 = 3;
+const SMUX_HEADER: usize = 8;
+const SMUX_MAX_FRAME: usize = 0x4000;
+const SMUX_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+const MAX_PENDING: usize = 1 << 20;
+
+/// A connection handed over to the bridge and the smux stream carrying it.
+struct Local {
+    stream: UnixStream,
+    sid: u32,
+    readable: bool,
+    outbuf: VecDeque<u8>,
+    eof: bool,
+    fin_sent: bool,
+    remote_finished: bool,
+}
+
+/// The KCP session to the server which multiplexes the connections through smux.
+struct Session {
+    socket: UdpSocket,
+    kcp: Kcp,
+    inbuf: Vec<u8>,
+    next_sid: u32,
+    last_keep_alive: Instant,
+}
+
+impl Session {
+    fn send_frame(&mut self, cmd: u8, sid: u32, data: &[u8]) {
+        let mut frame = vec![SMUX_VERSION, cmd];
+        frame.extend((data.len() as u16).to_le_bytes());
+        frame.extend(sid.to_le_bytes());
+        frame.extend(data);
+        self.kcp.send(&frame);
+    }
+}
+
+const WAKER_TOKEN: Token = Token(0);
+const SESSION_TOKEN: Token = Token(1);
+
+/// Carries each connection handed over to it through a stream of a single KCP session to the
+/// server, multiplexed through smux like kcptun does.
+struct KcpBridge {
+    poll: Poll,
+    connections: BridgeConnections,
+    server: SocketAddr,
+    start: Instant,
+    session: Option<Session>,
+    locals: HashMap<Token, Local>,
+    sids: HashMap<u32, Token>,
+    next_token: usize,
+}
+
+impl KcpBridge {
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = self.session.as_ref().map(|session| {
+                if session.kcp.is_idle() && !self.locals.values().any(|local| local.readable) {
+                    SMUX_KEEP_ALIVE.saturating_sub(session.last_keep_alive.elapsed())
+                } else {
+                    Duration::from_millis(INTERVAL as u64)
+                }
+            });
+            if let Err(error) = self.poll.poll(&mut events, timeout) {
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            for event in events.iter() {
+                let result = match event.token() {
+                    WAKER_TOKEN => match self.connections.take() {
+                        None => return Ok(()),
+                        Some(streams) => self.accept(streams),
+                    },
+                    SESSION_TOKEN => self.receive(),
+                    token => {
+                        if let Some(local) = self.locals.get_mut(&token) {
+                            local.readable = true;
+                        }
+                        Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    log::error!("KCP transport: {error}");
+                    self.close_session();
+                }
+            }
+            if let Err(error) = self.process() {
+                log::error!("KCP transport: {error}");
+                self.close_session();
+            }
+        }
+    }
+
+    fn accept(&mut self, streams: Vec<UnixStream>) -> Result<(), Error> {
+        for mut stream in streams {
+            if self.session.is_none() {
+                self.session = Some(self.connect()?);
+            }
+            let session = self.session.as_mut().ok_or("no session")?;
+            let sid = session.next_sid;
+            session.next_sid += 2;
+            session.send_frame(SMUX_SYN, sid, &[]);
+
+            self.next_token += 1;
+            let token = Token(self.next_token);
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let local = Local {
+                stream,
+                sid,
+                readable: true,
+                outbuf: VecDeque::new(),
+                eof: false,
+                fin_sent: false,
+                remote_finished: false,
+            };
+            self.locals.insert(token, local);
+            self.sids.insert(sid, token);
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<Session, Error> {
+        let mut socket = protect::bind_udp(self.server)?;
+        socket.connect(self.server)?;
+        self.poll
+            .registry()
+            .register(&mut socket, SESSION_TOKEN, Interest::READABLE)?;
+        let mut conv = [0u8; 4];
+        getrandom::getrandom(&mut conv)
+            .map_err(|_| Error::from("failed to obtain random bytes"))?;
+        Ok(Session {
+            socket,
+            kcp: Kcp::new(u32::from_le_bytes(conv)),
+            inbuf: Vec::new(),
+            next_sid: 1,
+            last_keep_alive: Instant::now(),
+        })
+    }
+
+    fn receive(&mut self) -> Result<(), Error> {
+        let current = self.now();
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let mut buffer = [0u8; 65535];
+        loop {
+            match session.socket.recv(&mut buffer) {
+                Ok(size) => {
+                    if let Err(error) = session.kcp.input(current, &buffer[..size]) {
+                        log::debug!("KCP transport: {error}");
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn process(&mut self) -> Result<(), Error> {
+        let current = self.now();
+        let Self {
+            poll,
+            session,
+            locals,
+            sids,
+            ..
+        } = self;
+        let session = match session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        session.inbuf.extend(session.kcp.recv());
+        let mut offset = 0;
+        while session.inbuf.len() - offset >= SMUX_HEADER {
+            let header = &session.inbuf[offset..offset + SMUX_HEADER];
+            let cmd = header[1];
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let sid = read_u32(&header[4..]);
+            if session.inbuf.len() - offset < SMUX_HEADER + len {
+                break;
+            }
+            let data = &session.inbuf[offset + SMUX_HEADER..offset + SMUX_HEADER + len];
+            if let Some(local) = sids.get(&sid).and_then(|token| locals.get_mut(token)) {
+                match cmd {
+                    SMUX_PSH => local.outbuf.extend(data),
+                    SMUX_FIN => local.remote_finished = true,
+                    _ => {}
+                }
+            }
+            offset += SMUX_HEADER + len;
+        }
+        session.inbuf.drain(0..offset);
+
+        let mut finished = Vec::new();
+        let mut buffer = vec![0u8; SMUX_MAX_FRAME];
+        for (token, local) in locals.iter_mut() {
+            while local.readable && !local.eof && session.kcp.pending() < MAX_PENDING {
+                match local.stream.read(&mut buffer) {
+                    Ok(0) => local.eof = true,
+                    Ok(size) => session.send_frame(SMUX_PSH, local.sid, &buffer[..size]),
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                        local.readable = false;
+                    }
+                    Err(_) => local.eof = true,
+                }
+            }
+            if local.eof && !local.fin_sent {
+                local.fin_sent = true;
+                session.send_frame(SMUX_FIN, local.sid, &[]);
+            }
+            while !local.outbuf.is_empty() {
+                match local.stream.write(local.outbuf.make_contiguous()) {
+                    Ok(written) => {
+                        local.outbuf.drain(0..written);
+                    }
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        local.outbuf.clear();
+                        local.eof = true;
+                    }
+                }
+            }
+            if local.remote_finished && local.outbuf.is_empty() {
+                let _ = local.stream.shutdown(Shutdown::Write);
+                if local.fin_sent {
+                    finished.push(*token);
+                }
+            }
+        }
+        for token in finished {
+            if let Some(mut local) = locals.remove(&token) {
+                sids.remove(&local.sid);
+                let _ = poll.registry().deregister(&mut local.stream);
+            }
+        }
+
+        if session.last_keep_alive.elapsed() >= SMUX_KEEP_ALIVE {
+            session.last_keep_alive = Instant::now();
+            session.send_frame(SMUX_NOP, 0, &[]);
+        }
+        session.kcp.update(current);
+        for datagram in session.kcp.output() {
+            // Lost datagrams are retransmitted by KCP.
+            let _ = session.socket.send(&datagram);
+        }
+        if session.kcp.is_dead() {
+            return Err("KCP session timed out".into());
+        }
+        if locals.is_empty() && session.kcp.is_idle() {
+            self.close_session();
+        }
+        Ok(())
+    }
+
+    fn close_session(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            let _ = self.poll.registry().deregister(&mut session.socket);
+        }
+        for (_, mut local) in self.locals.drain() {
+            let _ = self.poll.registry().deregister(&mut local.stream);
+            let _ = local.stream.shutdown(Shutdown::Both);
+        }
+        self.sids.clear();
+    }
+}
+
+/// Start a bridge thread carrying the connections made through the returned handle through a
+/// KCP session. The thread stops once the handle is dropped.
+pub(crate) fn spawn_bridge(server: SocketAddr) -> Result<BridgeHandle, Error> {
+    let poll = Poll::new()?;
+    let (handle, connections) = bridge::channel(poll.registry(), WAKER_TOKEN)?;
+
+    let mut bridge = KcpBridge {
+        poll,
+        connections,
+        server,
+        start: Instant::now(),
+        session: None,
+        locals: HashMap::new(),
+        sids: HashMap::new(),
+        next_token: usize::from(SESSION_TOKEN),
+    };
+    std::thread::spawn(move || {
+        if let Err(error) = bridge.run() {
+            log::error!("KCP transport: {error}");
+        }
+    });
+    Ok(handle)
+}
@@ -1,70 +1,367 @@
 use crate::error::Error;
+use crate::h2::H2Manager;
+use crate::masque::MasqueManager;
+use crate::pool::PoolManager;
+use crate::redirect::{DirectManager, RedirectManager};
+use crate::resolve::ResolvingManager;
 use crate::socks::SocksVersion;
+use crate::ssh::SshManager;
+use crate::tls::TlsConfig;
+use crate::tun2proxy::ConnectionManager;
+use crate::vless::VlessManager;
+use crate::vmess::VmessManager;
+use crate::wireguard::WireGuardTunnel;
 use crate::{http::HttpManager, socks::SocksManager, tun2proxy::TunToProxy};
-use std::net::{SocketAddr, ToSocketAddrs};
+use smoltcp::wire::IpCidr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
 
 mod android;
+mod bridge;
+mod bsd_tun;
+mod buffers;
+mod cidr;
+mod credentials;
+mod digest;
+mod dns_backend;
+mod dns_rule;
+mod doh;
+mod dot;
 pub mod error;
+pub mod fd_passing;
+mod ftp;
+mod grpc;
+mod gssapi;
+mod h2;
 mod http;
+mod kcp;
+mod masque;
+mod netlink;
+pub mod nfqueue;
+mod ntlm;
+mod obfs4;
+mod obfuscation;
+mod offload;
+mod packet_source;
+mod pool;
+pub mod privileges;
+mod protect;
+mod proxy_protocol;
+mod quic;
+mod redirect;
+mod resolve;
+pub mod sandbox;
+mod sd_notify;
+#[cfg_attr(
+    any(target_os = "openbsd", target_os = "netbsd"),
+    path = "bsd_setup.rs"
+)]
 pub mod setup;
 mod socks;
+mod ssh;
+mod tcp_dns;
+mod tls;
+pub mod transparent;
+mod transport;
 mod tun2proxy;
+pub mod tun_config;
+mod uring;
 mod virtdevice;
 mod virtdns;
+mod vless;
+mod vmess;
+mod websocket;
+mod wireguard;
+
+pub use crate::credentials::CredentialSource;
+pub use crate::dns_backend::{DnsBackend, DnsReply};
+pub use crate::dns_rule::{DnsRule, DnsTarget};
+pub use crate::doh::DohServer;
+pub use crate::dot::DotServer;
+pub use crate::grpc::GrpcOptions;
+pub use crate::obfs4::Obfs4Options;
+pub use crate::packet_source::{PacketHandle, PacketSource};
+pub use crate::pool::Balance;
+pub use crate::protect::SocketProtector;
+pub use crate::proxy_protocol::ProxyProtocol;
+pub use crate::resolve::system_nameservers;
+pub use crate::socks::Isolation;
+pub use crate::ssh::SshOptions;
+pub use crate::tls::{CertificatePin, TlsOptions};
+pub use crate::transport::{QuicOptions, Transport};
+pub use crate::tun2proxy::ShutdownHandle;
+pub use crate::virtdns::{
+    hashed_ipv4_address, DnsEviction, DnsFilter, DnsQuery, DnsRecord, DnsStats, DnssecMode,
+    Ipv6Prefix, LocalDnsPolicy, VirtualDnsState,
+};
+pub use crate::vmess::VmessSecurity;
+pub use crate::websocket::WebSocketOptions;
+pub use crate::wireguard::WireGuardOptions;
 
 #[derive(Clone, Debug)]
 pub struct Proxy {
     pub proxy_type: ProxyType,
+    /// Address of the proxy, which is `127.0.0.1:0` for a proxy reached through a Unix domain
+    /// socket
     pub addr: SocketAddr,
     pub credentials: Option<Credentials>,
+    pub tls: Option<TlsOptions>,
+    pub ssh: Option<SshOptions>,
+    pub wireguard: Option<WireGuardOptions>,
+    pub transport: Transport,
+    /// Service name used for GSSAPI authentication with a SOCKS5 proxy, e.g. `rcmd@proxy.example`
+    pub gssapi: Option<String>,
+    /// Policy by which a SOCKS proxy is given distinct credentials per connection
+    pub isolation: Option<Isolation>,
+    /// Network interface through which direct connections are made, e.g. `eth0`
+    pub interface: Option<String>,
+    /// Hostname of the proxy, which is resolved again when the proxy may have moved
+    pub hostname: Option<String>,
+    /// Every address the hostname of the proxy resolved to, the first of which is that of `addr`
+    pub resolved_addrs: Vec<IpAddr>,
 }
 
 pub enum NetworkInterface {
     Named(String),
-    Fd(std::os::fd::RawFd),
+    Fd(RawFd),
+    Packets(PacketSource),
 }
 
 impl Proxy {
     pub fn from_url(s: &str) -> Result<Proxy, Error> {
         let e = format!("`{s}` is not a valid proxy URL");
         let url = url::Url::parse(s).map_err(|_| Error::from(&e))?;
+
+        // Direct connections are given as e.g. `direct://` or `direct://?interface=eth0`.
+        if url.scheme().eq_ignore_ascii_case("direct") {
+            let mut interface = None;
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "interface" => interface = Some(value.into_owned()),
+                    _ => return Err(format!("`{key}` is an invalid direct option").into()),
+                }
+            }
+            return Ok(Proxy {
+                proxy_type: ProxyType::Direct,
+                addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                credentials: None,
+                tls: None,
+                ssh: None,
+                wireguard: None,
+                transport: Transport::Tcp,
+                gssapi: None,
+                isolation: None,
+                interface,
+                hostname: None,
+                resolved_addrs: Vec::new(),
+            });
+        }
+
         let e = format!("`{s}` does not contain a host");
         let host = url.host_str().ok_or(Error::from(e))?;
 
-        let mut url_host = String::from(host);
-        let e = format!("`{s}` does not contain a port");
-        let port = url.port().ok_or(Error::from(&e))?;
-        url_host.push(':');
-        url_host.push_str(port.to_string().as_str());
+        // A proxy listening on a Unix domain socket is given as e.g. `socks5://unix:/path`.
+        let unix = (host == "unix" && url.port().is_none()).then(|| {
+            let path = percent_encoding::percent_decode_str(url.path()).decode_utf8_lossy();
+            PathBuf::from(path.as_ref())
+        });
+
+        let hostname = match url.host() {
+            Some(url::Host::Domain(domain)) if unix.is_none() => Some(domain.to_string()),
+            _ => None,
+        };
+
+        let mut resolved_addrs = Vec::new();
+        let addr = match unix {
+            Some(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            None => {
+                let mut url_host = String::from(host);
+                let e = format!("`{s}` does not contain a port");
+                let port = url.port().ok_or(Error::from(&e))?;
+                url_host.push(':');
+                url_host.push_str(port.to_string().as_str());
 
-        let e = format!("`{host}` could not be resolved");
-        let mut addr_iter = url_host.to_socket_addrs().map_err(|_| Error::from(&e))?;
+                let e = format!("`{host}` could not be resolved");
+                let addr_iter = url_host.to_socket_addrs().map_err(|_| Error::from(&e))?;
+                for addr in addr_iter {
+                    if !resolved_addrs.contains(&addr.ip()) {
+                        resolved_addrs.push(addr.ip());
+                    }
+                }
 
-        let e = format!("`{host}` does not resolve to a usable IP address");
-        let addr = addr_iter.next().ok_or(Error::from(&e))?;
+                let e = format!("`{host}` does not resolve to a usable IP address");
+                let ip = *resolved_addrs.first().ok_or(Error::from(&e))?;
+                SocketAddr::new(ip, port)
+            }
+        };
 
         let credentials = if url.username() == "" && url.password().is_none() {
             None
         } else {
-            let username = String::from(url.username());
-            let password = String::from(url.password().unwrap_or(""));
+            let decode = |s| percent_encoding::percent_decode_str(s).decode_utf8_lossy();
+            let username = decode(url.username());
+            let password = decode(url.password().unwrap_or(""));
             Some(Credentials::new(&username, &password))
         };
 
-        let scheme = url.scheme();
+        let scheme = url.scheme().to_ascii_lowercase();
+        let (transport, scheme) = match (scheme.split_once('+'), unix) {
+            (Some((transport, _)), Some(_)) => {
+                return Err(
+                    format!("`{transport}` cannot be used with a Unix domain socket").into(),
+                )
+            }
+            (Some((transport, scheme)), None) => {
+                (Transport::from_url(transport, host, &url)?, scheme)
+            }
+            (None, Some(path)) => (Transport::Unix(path), scheme.as_str()),
+            (None, None) => (Transport::Tcp, scheme.as_str()),
+        };
 
-        let proxy_type = match url.scheme().to_ascii_lowercase().as_str() {
-            "socks4" => Some(ProxyType::Socks4),
-            "socks5" => Some(ProxyType::Socks5),
-            "http" => Some(ProxyType::Http),
+        let (mut proxy_type, mut use_tls) = match scheme {
+            "socks4" => Some((ProxyType::Socks4, false)),
+            "socks5" => Some((ProxyType::Socks5, false)),
+            "socks5s" => Some((ProxyType::Socks5, true)),
+            "http" => Some((ProxyType::Http, false)),
+            "https" => Some((ProxyType::Http, true)),
+            "h2" => Some((ProxyType::Http2, true)),
+            "masque" => Some((ProxyType::Masque, true)),
+            "vmess" => Some((ProxyType::Vmess(VmessSecurity::Aes128Gcm), false)),
+            "vless" => Some((ProxyType::Vless, false)),
+            "ssh" => Some((ProxyType::Ssh, false)),
+            "wireguard" => Some((ProxyType::WireGuard, false)),
+            "tcp" => Some((ProxyType::Redirect, false)),
             _ => None,
         }
         .ok_or(Error::from(&format!("`{scheme}` is an invalid proxy type")))?;
 
+        if let ProxyType::Vmess(security) = &mut proxy_type {
+            let credentials = credentials.as_ref().ok_or("VMess requires a user ID")?;
+            vmess::parse_uuid(std::str::from_utf8(&credentials.username)?)?;
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "security" => *security = value.parse()?,
+                    key if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid VMess option").into()),
+                }
+            }
+        }
+
+        if proxy_type == ProxyType::Vless {
+            let credentials = credentials.as_ref().ok_or("VLESS requires a user ID")?;
+            vmess::parse_uuid(std::str::from_utf8(&credentials.username)?)?;
+            for (key, value) in url.query_pairs() {
+                match (key.as_ref(), value.as_ref()) {
+                    ("security", "tls") => use_tls = true,
+                    ("security", "none") => use_tls = false,
+                    ("security", _) => {
+                        return Err(format!("`{value}` is an invalid VLESS security type").into())
+                    }
+                    (key, _) if TlsOptions::is_option(key) => {}
+                    (key, _) if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid VLESS option").into()),
+                }
+            }
+        }
+
+        let (mut gssapi, mut isolation) = (None, None);
+        if matches!(proxy_type, ProxyType::Socks4 | ProxyType::Socks5) {
+            let socks5 = proxy_type == ProxyType::Socks5;
+            let name = if socks5 { "SOCKS5" } else { "SOCKS4" };
+            let (mut enabled, mut service) = (false, String::from("rcmd"));
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "gssapi" if socks5 => enabled = matches!(value.as_ref(), "1" | "true"),
+                    "gssapi_service" if socks5 => service = value.into_owned(),
+                    "isolate" => isolation = Some(value.parse()?),
+                    key if use_tls && TlsOptions::is_option(key) => {}
+                    key if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid {name} option").into()),
+                }
+            }
+            gssapi = enabled.then(|| format!("{service}@{host}"));
+        }
+
+        let mut ssh = None;
+        if proxy_type == ProxyType::Ssh {
+            if credentials.is_none() {
+                return Err(format!("`{s}` does not contain a user name").into());
+            }
+            let mut options = SshOptions::default();
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "key" => options.key = Some(value.into_owned().into()),
+                    "fingerprint" => options.fingerprint = Some(value.into_owned()),
+                    "known_hosts" => options.known_hosts = Some(value.into_owned().into()),
+                    key if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid SSH option").into()),
+                }
+            }
+            ssh = Some(options);
+        }
+
+        let mut wireguard = None;
+        if proxy_type == ProxyType::WireGuard {
+            let (mut private_key, mut public_key, mut preshared_key, mut keepalive) =
+                (None, None, None, None);
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "private_key" => private_key = Some(wireguard::parse_key(&value)?),
+                    "public_key" => public_key = Some(wireguard::parse_key(&value)?),
+                    "preshared_key" => preshared_key = Some(wireguard::parse_key(&value)?),
+                    "keepalive" => keepalive = Some(value.parse()?),
+                    key if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid WireGuard option").into()),
+                }
+            }
+            wireguard = Some(WireGuardOptions {
+                private_key: private_key.ok_or("WireGuard requires a private key")?,
+                public_key: public_key.ok_or("WireGuard requires the public key of the peer")?,
+                preshared_key,
+                keepalive,
+            });
+        }
+
+        let mut tls = None;
+        if use_tls {
+            let mut options = TlsOptions::new(host);
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "security" if proxy_type == ProxyType::Vless => {}
+                    "gssapi" | "gssapi_service" | "isolate" if proxy_type == ProxyType::Socks5 => {}
+                    key if TlsOptions::is_option(key) => options.set_option(key, &value),
+                    key if transport.is_option(key) => {}
+                    _ => return Err(format!("`{key}` is an invalid TLS option").into()),
+                }
+            }
+            tls = Some(options);
+        }
+
+        if !matches!(transport, Transport::Tcp)
+            && matches!(
+                proxy_type,
+                ProxyType::Http2 | ProxyType::Masque | ProxyType::Ssh | ProxyType::WireGuard
+            )
+        {
+            return Err(format!("`{proxy_type}` does not support a transport").into());
+        }
+
         Ok(Proxy {
             proxy_type,
             addr,
             credentials,
+            tls,
+            ssh,
+            wireguard,
+            transport,
+            gssapi,
+            isolation,
+            interface: None,
+            hostname,
+            resolved_addrs,
         })
     }
 }
@@ -74,6 +371,16 @@ pub enum ProxyType {
     Socks4,
     Socks5,
     Http,
+    Http2,
+    Masque,
+    Vmess(VmessSecurity),
+    Vless,
+    Ssh,
+    WireGuard,
+    /// Forwards the byte stream to the proxy address without any handshake.
+    Redirect,
+    /// Connects straight to the destination without a proxy.
+    Direct,
 }
 
 impl std::fmt::Display for ProxyType {
@@ -82,14 +389,64 @@ impl std::fmt::Display for ProxyType {
             ProxyType::Socks4 => write!(f, "socks4"),
             ProxyType::Socks5 => write!(f, "socks5"),
             ProxyType::Http => write!(f, "http"),
+            ProxyType::Http2 => write!(f, "h2"),
+            ProxyType::Masque => write!(f, "masque"),
+            ProxyType::Vmess(_) => write!(f, "vmess"),
+            ProxyType::Vless => write!(f, "vless"),
+            ProxyType::Ssh => write!(f, "ssh"),
+            ProxyType::WireGuard => write!(f, "wireguard"),
+            ProxyType::Redirect => write!(f, "tcp"),
+            ProxyType::Direct => write!(f, "direct"),
         }
     }
 }
 
+/// The resolver to which the DNS queries captured from the tunnel are forwarded.
+pub(crate) enum DnsUpstream {
+    Https(DohServer),
+    Tls(DotServer),
+    /// The resolver each query is addressed to, over TCP.
+    Tcp,
+    Backend(Rc<dyn DnsBackend>),
+}
+
+type DnsQueryHandler = Rc<dyn Fn(&DnsQuery)>;
+
 #[derive(Default)]
 pub struct Options {
     virtdns: Option<virtdns::VirtualDns>,
+    dns_upstream: Option<DnsUpstream>,
+    dns_exclusion_server: Option<IpAddr>,
+    dns_hijack: bool,
+    dns_ports: Option<Vec<u16>>,
+    local_dns: Option<LocalDnsPolicy>,
+    dns_rules: Vec<DnsRule>,
     mtu: Option<usize>,
+    mss: Option<u16>,
+    gateways: Vec<IpAddr>,
+    bind_addrs: Vec<IpAddr>,
+    bypass: Vec<IpCidr>,
+    udp_timeout: Option<u64>,
+    tcp_timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    balance: Balance,
+    proxy_protocol: Option<ProxyProtocol>,
+    warm_pool: usize,
+    tcp_buffers: Option<(usize, usize)>,
+    memory_budget: Option<usize>,
+    nagle: bool,
+    fast_open: bool,
+    keep_alive: Option<(u32, u32, u32)>,
+    proxy_ca: Option<PathBuf>,
+    proxy_pins: Vec<CertificatePin>,
+    connect_retries: u32,
+    retry_delay: Option<u64>,
+    nameservers: Vec<IpAddr>,
+    on_proxy_moved: Option<Rc<dyn Fn(IpAddr)>>,
+    on_dns_query: Option<DnsQueryHandler>,
+    dns_stats: Option<Arc<DnsStats>>,
+    protector: Option<SocketProtector>,
+    on_tun_opened: Option<Box<dyn FnOnce() -> Result<(), Error>>>,
 }
 
 impl Options {
@@ -102,10 +459,285 @@ impl Options {
         self
     }
 
+    pub fn with_virtual_dns_ipv6(mut self, prefix: Ipv6Prefix) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_ipv6_prefix(prefix);
+        self
+    }
+
+    pub fn with_virtual_dns_record(mut self, record: DnsRecord) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .add_record(record);
+        self
+    }
+
+    pub fn with_virtual_dns_max_mappings(mut self, max: usize, eviction: DnsEviction) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_max_mappings(max, eviction);
+        self
+    }
+
+    pub fn with_dns_eviction_handler(mut self, handler: impl Fn(IpAddr, &str) + 'static) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_eviction_handler(Rc::new(handler));
+        self
+    }
+
+    pub fn with_virtual_dns_filter(mut self, filter: DnsFilter) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_filter(filter);
+        self
+    }
+
+    pub fn with_virtual_dns_dnssec(mut self, mode: DnssecMode) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_dnssec(mode);
+        self
+    }
+
+    pub fn with_virtual_dns_hashing(mut self) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_hashed();
+        self
+    }
+
+    pub fn with_virtual_dns_ttl(mut self, ttl: u32) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_ttl(ttl);
+        self
+    }
+
+    pub fn with_virtual_dns_lifetime(mut self, timeout: u64) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_mapping_timeout(timeout);
+        self
+    }
+
+    pub fn with_virtual_dns_state(mut self, state: VirtualDnsState) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .set_state(state);
+        self
+    }
+
+    pub fn with_virtual_dns_exclusion(mut self, domain: &str) -> Self {
+        self.virtdns
+            .get_or_insert_with(virtdns::VirtualDns::new)
+            .exclude(domain);
+        self
+    }
+
+    pub fn with_dns_exclusion_server(mut self, nameserver: IpAddr) -> Self {
+        self.dns_exclusion_server = Some(nameserver);
+        self
+    }
+
+    pub fn with_dns_hijack(mut self) -> Self {
+        self.dns_hijack = true;
+        self
+    }
+
+    pub fn with_dns_ports(mut self, ports: &[u16]) -> Self {
+        self.dns_ports = Some(ports.to_vec());
+        self
+    }
+
+    pub fn with_local_dns(mut self, policy: LocalDnsPolicy) -> Self {
+        self.local_dns = Some(policy);
+        self
+    }
+
+    pub fn with_dns_rule(mut self, rule: DnsRule) -> Self {
+        if let DnsTarget::Virtual = rule.target() {
+            self.virtdns.get_or_insert_with(virtdns::VirtualDns::new);
+        }
+        self.dns_rules.push(rule);
+        self
+    }
+
+    pub fn with_dns_over_https(mut self, server: DohServer) -> Self {
+        self.dns_upstream = Some(DnsUpstream::Https(server));
+        self
+    }
+
+    pub fn with_dns_over_tls(mut self, server: DotServer) -> Self {
+        self.dns_upstream = Some(DnsUpstream::Tls(server));
+        self
+    }
+
+    pub fn with_dns_over_tcp(mut self) -> Self {
+        self.dns_upstream = Some(DnsUpstream::Tcp);
+        self
+    }
+
+    pub fn with_dns_backend(mut self, backend: impl DnsBackend + 'static) -> Self {
+        self.dns_upstream = Some(DnsUpstream::Backend(Rc::new(backend)));
+        self
+    }
+
     pub fn with_mtu(mut self, mtu: usize) -> Self {
         self.mtu = Some(mtu);
         self
     }
+
+    pub fn with_mss(mut self, mss: u16) -> Self {
+        self.mss = Some(mss);
+        self
+    }
+
+    pub fn with_gateway(mut self, gateway: IpAddr) -> Self {
+        self.gateways
+            .retain(|addr| addr.is_ipv4() != gateway.is_ipv4());
+        self.gateways.push(gateway);
+        self
+    }
+
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind_addrs
+            .retain(|bound| bound.is_ipv4() != addr.is_ipv4());
+        self.bind_addrs.push(addr);
+        self
+    }
+
+    pub fn with_bypass(mut self, cidr: IpCidr) -> Self {
+        self.bypass.push(cidr);
+        self
+    }
+
+    pub fn with_udp_timeout(mut self, timeout: u64) -> Self {
+        self.udp_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_tcp_timeout(mut self, timeout: u64) -> Self {
+        self.tcp_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: u64) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_balance(mut self, balance: Balance) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    pub fn with_proxy_protocol(mut self, version: ProxyProtocol) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    pub fn with_warm_pool(mut self, count: usize) -> Self {
+        self.warm_pool = count;
+        self
+    }
+
+    pub fn with_tcp_buffers(mut self, rx_size: usize, tx_size: usize) -> Self {
+        self.tcp_buffers = Some((rx_size, tx_size));
+        self
+    }
+
+    pub fn with_memory_budget(mut self, size: usize) -> Self {
+        self.memory_budget = Some(size);
+        self
+    }
+
+    pub fn with_nagle(mut self, nagle: bool) -> Self {
+        self.nagle = nagle;
+        self
+    }
+
+    pub fn with_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, idle: u32, interval: u32, count: u32) -> Self {
+        self.keep_alive = Some((idle, interval, count));
+        self
+    }
+
+    pub fn with_proxy_ca(mut self, path: PathBuf) -> Self {
+        self.proxy_ca = Some(path);
+        self
+    }
+
+    pub fn with_proxy_pin(mut self, pin: CertificatePin) -> Self {
+        self.proxy_pins.push(pin);
+        self
+    }
+
+    pub fn with_connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, delay: u64) -> Self {
+        self.retry_delay = Some(delay);
+        self
+    }
+
+    pub fn with_nameservers(mut self, nameservers: Vec<IpAddr>) -> Self {
+        self.nameservers = nameservers;
+        self
+    }
+
+    /// Call `handler` once the tun interface is open, before any thread of the tunnel is spawned,
+    /// e.g. to drop privileges and to enter a sandbox which is then inherited by all of them.
+    pub fn with_tun_opened_handler(
+        mut self,
+        handler: impl FnOnce() -> Result<(), Error> + 'static,
+    ) -> Self {
+        self.on_tun_opened = Some(Box::new(handler));
+        self
+    }
+
+    pub fn with_proxy_moved_handler(mut self, handler: impl Fn(IpAddr) + 'static) -> Self {
+        self.on_proxy_moved = Some(Rc::new(handler));
+        self
+    }
+
+    pub fn with_dns_query_handler(mut self, handler: impl Fn(&DnsQuery) + 'static) -> Self {
+        self.on_dns_query = Some(Rc::new(handler));
+        self
+    }
+
+    pub fn with_dns_stats(mut self, stats: Arc<DnsStats>) -> Self {
+        self.dns_stats = Some(stats);
+        self
+    }
+
+    pub fn with_socket_protector(
+        mut self,
+        protector: impl Fn(RawFd) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.protector = Some(protect::chain(self.protector.take(), Arc::new(protector)));
+        self
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn with_socket_mark(mut self, mark: u32) -> Self {
+        let protector = protect::mark_protector(mark);
+        self.protector = Some(protect::chain(self.protector.take(), protector));
+        self
+    }
+
+    pub fn with_out_interface(mut self, interface: &str) -> Self {
+        let protector = protect::device_protector(interface.to_string());
+        self.protector = Some(protect::chain(self.protector.take(), protector));
+        self
+    }
 }
 
 #[derive(Default, Clone, Debug)]
@@ -123,29 +755,173 @@ impl Credentials {
     }
 }
 
-pub fn tun_to_proxy<'a>(
-    interface: &NetworkInterface,
+fn connection_manager(
     proxy: &Proxy,
-    options: Options,
-) -> Result<TunToProxy<'a>, Error> {
-    let mut ttp = TunToProxy::new(interface, options)?;
-    match proxy.proxy_type {
-        ProxyType::Socks4 => {
-            ttp.add_connection_manager(SocksManager::new(
-                proxy.addr,
-                SocksVersion::V4,
-                proxy.credentials.clone(),
-            ));
-        }
+    nameservers: &[IpAddr],
+    on_proxy_moved: &Option<Rc<dyn Fn(IpAddr)>>,
+) -> Result<Rc<dyn ConnectionManager>, Error> {
+    let manager: Rc<dyn ConnectionManager> = match proxy.proxy_type {
+        ProxyType::Socks4 => SocksManager::new(
+            proxy.addr,
+            SocksVersion::V4,
+            proxy.credentials.clone(),
+            None,
+            None,
+            proxy.isolation,
+        ),
         ProxyType::Socks5 => {
-            ttp.add_connection_manager(SocksManager::new(
+            let tls = match &proxy.tls {
+                None => None,
+                Some(options) => Some(TlsConfig::new(options)?),
+            };
+            SocksManager::new(
                 proxy.addr,
                 SocksVersion::V5,
                 proxy.credentials.clone(),
-            ));
+                tls,
+                proxy.gssapi.clone(),
+                proxy.isolation,
+            )
         }
         ProxyType::Http => {
-            ttp.add_connection_manager(HttpManager::new(proxy.addr, proxy.credentials.clone()));
+            let tls = match &proxy.tls {
+                None => None,
+                Some(options) => Some(TlsConfig::new(options)?),
+            };
+            HttpManager::new(proxy.addr, proxy.credentials.clone(), tls)
+        }
+        ProxyType::Http2 => {
+            let options = proxy.tls.as_ref().ok_or("HTTP/2 requires TLS options")?;
+            H2Manager::new(
+                proxy.addr,
+                proxy.credentials.clone(),
+                TlsConfig::new(options)?,
+            )?
+        }
+        ProxyType::Masque => {
+            let options = proxy.tls.as_ref().ok_or("MASQUE requires TLS options")?;
+            MasqueManager::new(
+                proxy.addr,
+                proxy.credentials.clone(),
+                TlsConfig::new(options)?,
+            )?
+        }
+        ProxyType::Vmess(security) => {
+            VmessManager::new(proxy.addr, proxy.credentials.clone(), security)?
+        }
+        ProxyType::Vless => {
+            let tls = match &proxy.tls {
+                None => None,
+                Some(options) => Some(TlsConfig::new(options)?),
+            };
+            VlessManager::new(proxy.addr, proxy.credentials.clone(), tls)?
+        }
+        ProxyType::Ssh => SshManager::new(
+            proxy.addr,
+            &proxy
+                .hostname
+                .clone()
+                .unwrap_or_else(|| proxy.addr.ip().to_string()),
+            proxy.credentials.clone(),
+            proxy.ssh.clone().unwrap_or_default(),
+        )?,
+        ProxyType::WireGuard => {
+            return Err("WireGuard cannot be combined with other proxies".into());
+        }
+        ProxyType::Redirect => RedirectManager::new(proxy.addr),
+        ProxyType::Direct => DirectManager::new(proxy.interface.clone()),
+    };
+    let manager = transport::wrap_manager(manager, proxy.addr, &proxy.transport)?;
+    // Proxies reached through a bridge are connected to by the bridge alone.
+    let bridged = matches!(
+        proxy.proxy_type,
+        ProxyType::Http2 | ProxyType::Masque | ProxyType::Ssh
+    ) || matches!(
+        proxy.transport,
+        Transport::Quic(_) | Transport::Kcp | Transport::Unix(_)
+    );
+    match &proxy.hostname {
+        Some(host) if !bridged => Ok(ResolvingManager::new(
+            manager,
+            host,
+            proxy.addr,
+            proxy.resolved_addrs.clone(),
+            nameservers.to_vec(),
+            on_proxy_moved.clone(),
+        )),
+        _ => Ok(manager),
+    }
+}
+
+/// Set up the tunnel to forward connections through the proxy.
+pub fn tun_to_proxy<'a>(
+    interface: &NetworkInterface,
+    proxy: &Proxy,
+    options: Options,
+) -> Result<TunToProxy<'a>, Error> {
+    tun_to_proxy_multi(interface, std::slice::from_ref(proxy), options)
+}
+
+/// Set up the tunnel to forward connections through the proxies, which are chosen according to
+/// the balancing strategy of the options.
+pub fn tun_to_proxy_multi<'a>(
+    interface: &NetworkInterface,
+    proxies: &[Proxy],
+    options: Options,
+) -> Result<TunToProxy<'a>, Error> {
+    let balance = options.balance;
+    if options.proxy_protocol.is_some() {
+        // The header would have to be sent by the bridge carrying the connections, or would
+        // reach the destination itself.
+        let unsupported = [
+            ProxyType::Http2,
+            ProxyType::Masque,
+            ProxyType::Ssh,
+            ProxyType::WireGuard,
+            ProxyType::Direct,
+        ];
+        if let Some(proxy) = proxies
+            .iter()
+            .find(|proxy| unsupported.contains(&proxy.proxy_type))
+        {
+            let proxy_type = proxy.proxy_type;
+            return Err(format!("`{proxy_type}` does not support the PROXY protocol").into());
+        }
+    }
+    // The trust settings of the options apply to every TLS connection to a proxy.
+    let proxies = proxies
+        .iter()
+        .cloned()
+        .map(|mut proxy| {
+            for tls in proxy.tls.iter_mut().chain(proxy.transport.tls_mut()) {
+                if options.proxy_ca.is_some() {
+                    tls.ca = options.proxy_ca.clone();
+                }
+                tls.pins.extend(&options.proxy_pins);
+            }
+            proxy
+        })
+        .collect::<Vec<_>>();
+    let nameservers = options.nameservers.clone();
+    let on_proxy_moved = options.on_proxy_moved.clone();
+    let manager_for = |proxy| connection_manager(proxy, &nameservers, &on_proxy_moved);
+    let mut ttp = TunToProxy::new(interface, options)?;
+    match proxies.as_slice() {
+        [] => return Err("No proxy is supplied".into()),
+        [proxy] if proxy.proxy_type == ProxyType::WireGuard => {
+            let options = proxy
+                .wireguard
+                .as_ref()
+                .ok_or("WireGuard options are missing")?;
+            ttp.set_wireguard(WireGuardTunnel::new(options), proxy.addr)?;
+        }
+        [proxy] => ttp.add_connection_manager(manager_for(proxy)?),
+        proxies => {
+            let managers = proxies
+                .iter()
+                .map(manager_for)
+                .collect::<Result<Vec<_>, _>>()?;
+            ttp.add_connection_manager(PoolManager::new(managers, balance));
         }
     }
     Ok(ttp)
@@ -159,3 +935,12 @@ pub fn main_entry(
     let ttp = tun_to_proxy(interface, proxy, options);
     ttp?.run()
 }
+
+pub fn main_entry_multi(
+    interface: &NetworkInterface,
+    proxies: &[Proxy],
+    options: Options,
+) -> Result<(), Error> {
+    let ttp = tun_to_proxy_multi(interface, proxies, options);
+    ttp?.run()
+}
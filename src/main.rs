@@ -1,35 +1,128 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use env_logger::Env;
+use smoltcp::wire::IpCidr;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tun2proxy::error::Error;
-use tun2proxy::{main_entry, Proxy};
-use tun2proxy::{NetworkInterface, Options};
+use tun2proxy::fd_passing::{receive_tun_fd, systemd_tun_fd};
+use tun2proxy::tun_config::TunAddress;
+use tun2proxy::{system_nameservers, CredentialSource, DohServer, DotServer, VirtualDnsState};
+use tun2proxy::{
+    tun_to_proxy_multi, Balance, CertificatePin, Proxy, ProxyProtocol, ProxyType, Transport,
+};
+use tun2proxy::{DnsEviction, DnsFilter, DnsRecord, DnsRule, DnsStats, DnsTarget, DnssecMode};
+use tun2proxy::{Ipv6Prefix, LocalDnsPolicy, NetworkInterface, Options};
 
 #[cfg(target_os = "linux")]
+use tun2proxy::nfqueue::NfQueue;
+#[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+use tun2proxy::privileges::drop_privileges;
+#[cfg(target_os = "linux")]
+use tun2proxy::sandbox::enter_sandbox;
+#[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
 use tun2proxy::setup::{get_default_cidrs, Setup};
+#[cfg(target_os = "linux")]
+use tun2proxy::transparent::{TransparentListener, TransparentMode};
+#[cfg(target_os = "linux")]
+use tun2proxy::tun_config::TunConfig;
 
 /// Tunnel interface to proxy
 #[derive(Parser)]
 #[command(author, version, about = "Tunnel interface to proxy.", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<ArgCommand>,
+
     /// Name of the tun interface
     #[arg(short, long, value_name = "name", default_value = "tun0")]
     tun: String,
 
-    /// File descriptor of the tun interface
-    #[arg(long, value_name = "fd")]
+    /// File descriptor of the tun interface, e.g. inherited from a wrapper which opened it
+    #[arg(long, value_name = "fd", conflicts_with = "tun")]
     tun_fd: Option<i32>,
 
-    /// MTU of the tun interface (only with tunnel file descriptor)
-    #[arg(long, value_name = "mtu", default_value = "1500")]
-    tun_mtu: usize,
+    /// Unix socket over which the file descriptor of the tun interface is received
+    #[arg(long, value_name = "path", conflicts_with = "tun_fd")]
+    tun_socket: Option<PathBuf>,
+
+    /// MTU of the tun interface, which is set on it unless passed as file descriptor
+    #[arg(
+        long,
+        alias = "mtu",
+        value_name = "mtu",
+        value_parser = clap::value_parser!(u16).range(576..)
+    )]
+    tun_mtu: Option<u16>,
+
+    /// Create the tun interface, which is deleted on exit unless persistent
+    #[arg(long, conflicts_with_all = ["tun_fd", "tun_socket", "setup"])]
+    tun_create: bool,
+
+    /// Owner of the tun interface created or set up
+    #[arg(long, value_name = "user")]
+    tun_owner: Option<String>,
+
+    /// Group of the tun interface created or set up
+    #[arg(long, value_name = "group")]
+    tun_group: Option<String>,
+
+    /// Keep the created tun interface after exit
+    #[arg(long, requires = "tun_create")]
+    tun_persist: bool,
+
+    /// Exchange TCP segments of up to 64 KiB with the created tun interface (GSO and GRO)
+    #[arg(long, requires = "tun_create")]
+    tun_offload: bool,
+
+    /// Address of the tun interface when created or set up, in CIDR notation (repeatable)
+    #[arg(long, value_name = "CIDR")]
+    tun_address: Vec<TunAddress>,
+
+    /// Address of tun2proxy within the tunnel instead of 0.0.0.1 or ::1 (repeatable)
+    #[arg(long, value_name = "IP")]
+    tun_gateway: Vec<IpAddr>,
+
+    /// Proxy the TCP connections the firewall diverts
+    #[arg(
+        long,
+        value_name = "target",
+        value_enum,
+        conflicts_with_all = ["tun_fd", "tun_socket", "tun_create", "setup"]
+    )]
+    transparent: Option<ArgTransparent>,
 
-    /// Proxy URL in the form proto://[username[:password]@]host:port
-    #[arg(short, long, value_parser = Proxy::from_url, value_name = "URL")]
-    proxy: Proxy,
+    /// Address on which the diverted connections are accepted
+    #[arg(long, value_name = "addr", default_value = "[::]:12345")]
+    transparent_listen: SocketAddr,
+
+    /// Netfilter queue whose packets are proxied instead of those of a tun interface
+    #[arg(
+        long,
+        value_name = "number",
+        conflicts_with_all = ["tun_fd", "tun_socket", "tun_create", "setup", "transparent"]
+    )]
+    nfqueue: Option<u16>,
+
+    /// Proxy URL in the form proto://[username[:password]@]host:port (repeatable)
+    #[arg(short, long, value_parser = Proxy::from_url, value_name = "URL", required = true)]
+    proxy: Vec<Proxy>,
+
+    /// File containing the proxy credentials as username:password, or only the password
+    #[arg(long, value_name = "file")]
+    proxy_cred_file: Option<PathBuf>,
+
+    /// Take the proxy password from the keyring of the OS
+    #[arg(long)]
+    proxy_cred_keyring: bool,
 
     /// DNS handling
     #[arg(
@@ -41,6 +134,191 @@ struct Args {
     )]
     dns: ArgDns,
 
+    /// Unique local IPv6 prefix of virtual addresses answering AAAA queries
+    #[arg(long, value_name = "CIDR")]
+    dns_ipv6_prefix: Option<Ipv6Prefix>,
+
+    /// Address family of virtual DNS answers: ipv4-only or ipv6-only
+    #[arg(
+        long,
+        value_name = "family",
+        value_enum,
+        hide_possible_values = true,
+        requires_if("ipv6-only", "dns_ipv6_prefix")
+    )]
+    dns_filter: Option<ArgDnsFilter>,
+
+    /// TTL of virtual DNS answers in seconds
+    #[arg(long, value_name = "seconds", default_value = "30")]
+    dns_ttl: u32,
+
+    /// Lifetime of unused virtual DNS mappings in seconds, at least the TTL
+    #[arg(long, value_name = "seconds", default_value = "60")]
+    dns_lifetime: u64,
+
+    /// Static record of the virtual DNS as name=IP (repeatable)
+    #[arg(long, value_name = "record")]
+    dns_record: Vec<DnsRecord>,
+
+    /// Hosts file with static records of the virtual DNS
+    #[arg(long, value_name = "file")]
+    dns_hosts: Option<PathBuf>,
+
+    /// Answer DNS queries to any resolver, not only to the virtual DNS (virtual, doh or dot)
+    #[arg(long)]
+    dns_hijack: bool,
+
+    /// Port on which DNS queries are intercepted instead of 53 (repeatable)
+    #[arg(long, value_name = "port")]
+    dns_port: Vec<u16>,
+
+    /// Log each DNS query with its client and virtual address
+    #[arg(long)]
+    dns_log: bool,
+
+    /// Log the DNS counters every so many seconds
+    #[arg(long, value_name = "seconds")]
+    dns_stats: Option<u64>,
+
+    /// Virtual DNS mappings held at most
+    #[arg(long, value_name = "count")]
+    dns_max_mappings: Option<usize>,
+
+    /// Policy once `--dns-max-mappings` is reached: lru or none
+    #[arg(
+        long,
+        value_name = "policy",
+        value_enum,
+        default_value = "lru",
+        hide_possible_values = true
+    )]
+    dns_eviction: ArgDnsEviction,
+
+    /// Map each name to an address derived from its hash, the same across runs
+    #[arg(long)]
+    dns_hashed: bool,
+
+    /// File keeping the virtual DNS mappings across restarts
+    #[arg(long, value_name = "file")]
+    dns_state: Option<PathBuf>,
+
+    /// Domain such as *.corp.example resolved by a real name server (repeatable)
+    #[arg(long, value_name = "domain")]
+    dns_exclude: Vec<String>,
+
+    /// Name server of `--dns-exclude` instead of the first one of /etc/resolv.conf
+    #[arg(long, value_name = "IP")]
+    dns_exclude_server: Option<IpAddr>,
+
+    /// Split DNS rule as domain=target, e.g. *.corp=10.0.0.53 (repeatable)
+    #[arg(long, value_name = "rule")]
+    dns_rule: Vec<DnsRule>,
+
+    /// Virtual DNS queries with the DO bit: strip it or forward them like `--dns-exclude`
+    #[arg(long, value_name = "mode", value_enum, hide_possible_values = true)]
+    dns_dnssec: Option<ArgDnssec>,
+
+    /// mDNS and LLMNR queries: drop, pass out of `--local-dns-dev` or answer
+    #[arg(
+        long,
+        value_name = "policy",
+        value_enum,
+        hide_possible_values = true,
+        requires_if("pass", "local_dns_dev")
+    )]
+    local_dns: Option<ArgLocalDns>,
+
+    /// Physical interface out of which `--local-dns pass` sends the queries
+    #[arg(long, value_name = "name")]
+    local_dns_dev: Option<String>,
+
+    /// DNS-over-HTTPS resolver of `--dns doh`
+    #[arg(
+        long,
+        value_parser = DohServer::from_url,
+        value_name = "URL",
+        default_value = "https://1.1.1.1/dns-query"
+    )]
+    doh_url: DohServer,
+
+    /// DNS-over-TLS server of `--dns dot` as host[:port]
+    #[arg(long, value_name = "server", default_value = "1.1.1.1")]
+    dns_server: DotServer,
+
+    /// Idle timeout of UDP sessions in seconds
+    #[arg(long, value_name = "seconds", default_value = "30")]
+    udp_timeout: u64,
+
+    /// Idle timeout of TCP connections in seconds
+    #[arg(long, value_name = "seconds", default_value = "7440")]
+    tcp_timeout: u64,
+
+    /// Timeout of connecting to the proxy in seconds
+    #[arg(long, value_name = "seconds", default_value = "10")]
+    connect_timeout: u64,
+
+    /// Retries of connecting through the proxy before giving up
+    #[arg(long, value_name = "count", default_value = "0")]
+    connect_retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubling with each retry
+    #[arg(long, value_name = "ms", default_value = "500")]
+    retry_delay: u64,
+
+    /// Buffer of each TCP connection for the data from the client, e.g. 16K
+    #[arg(long, value_name = "size", default_value = "128K", value_parser = parse_size)]
+    tcp_rx_buffer: usize,
+
+    /// Buffer of each TCP connection for the data to the client
+    #[arg(long, value_name = "size", default_value = "128K", value_parser = parse_size)]
+    tcp_tx_buffer: usize,
+
+    /// Memory which the buffers of all connections may take together, e.g. 64M
+    #[arg(long, value_name = "size", value_parser = parse_size)]
+    memory_budget: Option<usize>,
+
+    /// Keep Nagle's algorithm on the connections to the proxy, which delays small writes
+    #[arg(long)]
+    nagle: bool,
+
+    /// Send the first data to the proxy along with the SYN (TCP Fast Open, Linux only)
+    #[arg(long)]
+    fast_open: bool,
+
+    /// Keep-alive of the connections to the proxy as idle[,interval[,count]], e.g. 60,15,4
+    #[arg(long, value_name = "timing", value_parser = parse_keep_alive)]
+    keep_alive: Option<(u32, u32, u32)>,
+
+    /// Largest TCP segment announced to the clients and accepted from them, e.g. 1360
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u16).range(536..))]
+    mss: Option<u16>,
+
+    /// Proxy selection: failover, round-robin or least-connections
+    #[arg(
+        long,
+        value_name = "strategy",
+        value_enum,
+        default_value = "failover",
+        hide_possible_values = true
+    )]
+    balance: ArgBalance,
+
+    /// Send a PROXY protocol header ahead of each connection: v1 or v2
+    #[arg(long, value_name = "version", value_enum, hide_possible_values = true)]
+    proxy_protocol: Option<ArgProxyProtocol>,
+
+    /// Connections kept open to the proxy ahead of time
+    #[arg(long, value_name = "count", default_value = "0")]
+    warm_pool: usize,
+
+    /// CA certificates in PEM format trusted for TLS connections to the proxy
+    #[arg(long, value_name = "file")]
+    proxy_ca: Option<PathBuf>,
+
+    /// SHA-256 hash of the certificate of the proxy to accept (repeatable)
+    #[arg(long, value_name = "hash")]
+    proxy_pin_sha256: Vec<CertificatePin>,
+
     /// Routing and system setup
     #[arg(short, long, value_name = "method", value_enum)]
     setup: Option<ArgSetup>,
@@ -48,66 +326,660 @@ struct Args {
     /// Public proxy IP used in routing setup
     #[arg(long, value_name = "IP")]
     setup_ip: Option<IpAddr>,
+
+    /// Range the setup routes around the tunnel, e.g. the local network (repeatable)
+    #[arg(long, value_name = "CIDR", value_parser = parse_cidr)]
+    bypass: Vec<IpCidr>,
+
+    /// Network namespace into which the setup moves the tun interface
+    #[arg(long, value_name = "name", requires = "setup")]
+    netns: Option<String>,
+
+    /// Network interface through which the proxies are reached, whatever the routes are
+    #[arg(long, value_name = "name")]
+    out_interface: Option<String>,
+
+    /// Local address of the sockets to the proxies, one per address family (repeatable)
+    #[arg(long, value_name = "IP")]
+    bind_addr: Vec<IpAddr>,
+
+    /// Mark of the sockets to the proxies, which the setup routes around the tunnel
+    #[arg(long, value_name = "mark")]
+    fwmark: Option<u32>,
+
+    /// User to switch to once the tun interface is open and the setup is done
+    #[arg(long, value_name = "name")]
+    user: Option<String>,
+
+    /// Group to switch to instead of the primary group of the user
+    #[arg(long, value_name = "name", requires = "user")]
+    group: Option<String>,
+
+    /// Keep the CAP_NET_ADMIN capability when switching to the user
+    #[arg(long, requires = "user")]
+    keep_net_admin: bool,
+
+    /// Confine the process through seccomp and Landlock once it is set up (Linux only)
+    #[arg(long)]
+    sandbox: bool,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 enum ArgDns {
     Virtual,
+    Doh,
+    Dot,
+    OverTcp,
     None,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, clap::Subcommand)]
+enum ArgCommand {
+    /// Set up the tun interface and the routes, and leave them in place for `run` (Linux only)
+    Setup,
+    /// Run the tunnel without setting anything up, e.g. without privileges after `setup`
+    Run,
+    /// Remove what `setup` has set up for the tun interface
+    Cleanup,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgTransparent {
+    Redirect,
+    Tproxy,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 enum ArgSetup {
     Auto,
+    Container,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgDnsFilter {
+    Ipv4Only,
+    Ipv6Only,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgDnsEviction {
+    Lru,
+    None,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgDnssec {
+    Strip,
+    Forward,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgLocalDns {
+    Drop,
+    Pass,
+    Answer,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgBalance {
+    Failover,
+    RoundRobin,
+    LeastConnections,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum ArgProxyProtocol {
+    V1,
+    V2,
+}
+
+fn parse_cidr(s: &str) -> Result<IpCidr, String> {
+    IpCidr::from_str(s).map_err(|_| format!("`{s}` is not a range in CIDR notation"))
+}
+
+// A size in bytes, or in KiB or MiB with the suffix K or M.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .filter(|size| (1024..=1024 * 1024 * 1024).contains(size))
+        .ok_or_else(|| format!("`{s}` is not a size between 1K and 1024M, e.g. 16K or 1M"))
+}
+
+// The seconds of idleness before the first keep-alive probe, and optionally the seconds between
+// the probes, 15 unless given, and how many go unanswered before the connection is closed, 4.
+fn parse_keep_alive(s: &str) -> Result<(u32, u32, u32), String> {
+    let e = || format!("`{s}` is not of the form idle[,interval[,count]], e.g. 60,15,4");
+    let mut values = s
+        .split(',')
+        .map(|value| value.parse::<u32>().ok().filter(|v| *v > 0));
+    let idle = values.next().flatten().ok_or_else(e)?;
+    let interval = values.next().unwrap_or(Some(15)).ok_or_else(e)?;
+    let count = values.next().unwrap_or(Some(4)).ok_or_else(e)?;
+    match values.next() {
+        Some(_) => Err(e()),
+        None => Ok((idle, interval, count)),
+    }
+}
+
+// The setup of the tun interface and of the routes around it for the proxies and name servers.
+#[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+fn new_setup(args: &Args, nameservers: &[IpAddr], over_tcp: bool, excluding: bool) -> Setup {
+    let bypass_tun_ip = match args.setup_ip {
+        Some(addr) => addr,
+        None => args.proxy[0].addr.ip(),
+    };
+    let mut setup = Setup::new(
+        &args.tun,
+        &bypass_tun_ip,
+        get_default_cidrs(),
+        args.setup_ip.is_some(),
+    );
+    // Connections may be made to any address a proxy resolved to, unless the first
+    // proxy is only reached through the address given by `--setup-ip`.
+    let skip = usize::from(args.setup_ip.is_some());
+    for proxy in &args.proxy[skip..] {
+        if !matches!(proxy.transport, Transport::Unix(_)) && proxy.proxy_type != ProxyType::Direct {
+            for addr in &proxy.resolved_addrs {
+                setup = setup.with_bypass_addr(addr);
+            }
+        }
+    }
+    // Queries forwarded over TCP reach the name servers through the proxy.
+    for nameserver in nameservers {
+        if !nameserver.is_loopback() && !over_tcp {
+            setup = setup.with_bypass_addr(nameserver);
+        }
+    }
+    if let Some(nameserver) = args.dns_exclude_server.filter(|_| excluding) {
+        if !nameserver.is_loopback() {
+            setup = setup.with_bypass_addr(&nameserver);
+        }
+    }
+
+    for cidr in &args.bypass {
+        setup = setup.with_bypass_cidr(cidr);
+    }
+    for address in &args.tun_address {
+        setup = setup.with_tun_addr(&address.addr, address.prefix_len);
+    }
+    if let Some(mtu) = args.tun_mtu {
+        setup = setup.with_tun_mtu(mtu.into());
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = args.fwmark {
+        setup = setup.with_fwmark(mark);
+    }
+    #[cfg(target_os = "linux")]
+    if args.setup == Some(ArgSetup::Container) {
+        setup = setup.with_container();
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(netns) = &args.netns {
+        setup = setup.with_netns(netns.as_str());
+    }
+    setup
+}
+
+// Set up the tun interface and the routes to be left in place, or remove them again.
+#[cfg(target_os = "linux")]
+fn run_command(
+    command: ArgCommand,
+    args: &Args,
+    nameservers: &[IpAddr],
+    over_tcp: bool,
+    excluding: bool,
+) -> Result<(), Error> {
+    if args.netns.is_some() {
+        return Err("A tun interface in a network namespace is only set up while running".into());
+    }
+    match command {
+        ArgCommand::Setup => {
+            let mut setup = new_setup(args, nameservers, over_tcp, excluding);
+            if let Some(owner) = &args.tun_owner {
+                setup = setup.with_tun_owner(owner);
+            }
+            if let Some(group) = &args.tun_group {
+                setup = setup.with_tun_group(group);
+            }
+            setup.persist()
+        }
+        ArgCommand::Cleanup => {
+            let unspecified = IpAddr::from([0, 0, 0, 0]);
+            Setup::new(&args.tun, &unspecified, get_default_cidrs(), false).cleanup()
+        }
+        ArgCommand::Run => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_command(_: ArgCommand, _: &Args, _: &[IpAddr], _: bool, _: bool) -> Result<(), Error> {
+    Err("Setting up the tun interface apart from running is only supported on Linux".into())
 }
 
 fn main() -> ExitCode {
     dotenvy::dotenv().ok();
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // Only the cleanup goes without proxies, for which the requirement is lifted by subcommands.
+    if args.proxy.is_empty() && args.command != Some(ArgCommand::Cleanup) {
+        let message = "the following required arguments were not provided:\n  --proxy <URL>";
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, message)
+            .exit();
+    }
+    // Queries over TCP would be hijacked on their way to the resolvers they are forwarded to, while
+    // without DNS handling there is nothing to answer them.
+    if args.dns_hijack && matches!(args.dns, ArgDns::OverTcp | ArgDns::None) {
+        let dns = match args.dns {
+            ArgDns::OverTcp => "over-tcp",
+            _ => "none",
+        };
+        let message = format!("the argument '--dns-hijack' cannot be used with '--dns {dns}'");
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
 
-    let addr = args.proxy.addr;
-    let proxy_type = args.proxy.proxy_type;
-    log::info!("Proxy {proxy_type} server: {addr}");
+    for proxy in &args.proxy {
+        let proxy_type = proxy.proxy_type;
+        match &proxy.transport {
+            _ if proxy_type == ProxyType::Direct => log::info!("Direct connections"),
+            Transport::Unix(path) => log::info!("Proxy {proxy_type} server: {}", path.display()),
+            _ => log::info!("Proxy {proxy_type} server: {}", proxy.addr),
+        }
+    }
 
-    let mut options = Options::new();
-    if args.dns == ArgDns::Virtual {
-        options = options.with_virtual_dns();
+    let mut options = Options::new()
+        .with_udp_timeout(args.udp_timeout)
+        .with_tcp_timeout(args.tcp_timeout)
+        .with_connect_timeout(args.connect_timeout)
+        .with_connect_retries(args.connect_retries)
+        .with_retry_delay(args.retry_delay)
+        .with_tcp_buffers(args.tcp_rx_buffer, args.tcp_tx_buffer)
+        .with_nagle(args.nagle)
+        .with_fast_open(args.fast_open)
+        .with_warm_pool(args.warm_pool)
+        .with_balance(match args.balance {
+            ArgBalance::Failover => Balance::Failover,
+            ArgBalance::RoundRobin => Balance::RoundRobin,
+            ArgBalance::LeastConnections => Balance::LeastConnections,
+        });
+    if let Some(size) = args.memory_budget {
+        options = options.with_memory_budget(size);
+    }
+    if let Some((idle, interval, count)) = args.keep_alive {
+        options = options.with_keep_alive(idle, interval, count);
     }
+    if let Some(mtu) = args.tun_mtu {
+        options = options.with_mtu(mtu.into());
+    }
+    if let Some(mss) = args.mss {
+        options = options.with_mss(mss);
+    }
+    if let Some(path) = &args.proxy_ca {
+        options = options.with_proxy_ca(path.clone());
+    }
+    for pin in &args.proxy_pin_sha256 {
+        options = options.with_proxy_pin(*pin);
+    }
+    for gateway in &args.tun_gateway {
+        options = options.with_gateway(*gateway);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = args.fwmark {
+        options = options.with_socket_mark(mark);
+    }
+    for addr in &args.bind_addr {
+        options = options.with_bind_addr(*addr);
+    }
+    for cidr in &args.bypass {
+        options = options.with_bypass(*cidr);
+    }
+    if let Some(interface) = &args.out_interface {
+        options = options.with_out_interface(interface);
+    }
+    if let Some(version) = args.proxy_protocol {
+        options = options.with_proxy_protocol(match version {
+            ArgProxyProtocol::V1 => ProxyProtocol::V1,
+            ArgProxyProtocol::V2 => ProxyProtocol::V2,
+        });
+    }
+    match args.dns {
+        ArgDns::Virtual => {
+            options = options
+                .with_virtual_dns()
+                .with_virtual_dns_ttl(args.dns_ttl)
+                .with_virtual_dns_lifetime(args.dns_lifetime);
+            if let Some(prefix) = args.dns_ipv6_prefix {
+                options = options.with_virtual_dns_ipv6(prefix);
+            }
+            if let Some(filter) = args.dns_filter {
+                options = options.with_virtual_dns_filter(match filter {
+                    ArgDnsFilter::Ipv4Only => DnsFilter::Ipv4Only,
+                    ArgDnsFilter::Ipv6Only => DnsFilter::Ipv6Only,
+                });
+            }
+            if let Some(max) = args.dns_max_mappings {
+                let eviction = match args.dns_eviction {
+                    ArgDnsEviction::Lru => DnsEviction::Lru,
+                    ArgDnsEviction::None => DnsEviction::None,
+                };
+                options = options.with_virtual_dns_max_mappings(max, eviction);
+            }
+            if args.dns_hashed {
+                options = options.with_virtual_dns_hashing();
+            }
+            for record in &args.dns_record {
+                options = options.with_virtual_dns_record(record.clone());
+            }
+            for domain in &args.dns_exclude {
+                options = options.with_virtual_dns_exclusion(domain);
+            }
+            if let Some(nameserver) = args.dns_exclude_server {
+                options = options.with_dns_exclusion_server(nameserver);
+            }
+            if let Some(mode) = args.dns_dnssec {
+                options = options.with_virtual_dns_dnssec(match mode {
+                    ArgDnssec::Strip => DnssecMode::Strip,
+                    ArgDnssec::Forward => DnssecMode::Forward,
+                });
+            }
+        }
+        ArgDns::Doh => options = options.with_dns_over_https(args.doh_url.clone()),
+        ArgDns::Dot => options = options.with_dns_over_tls(args.dns_server.clone()),
+        ArgDns::OverTcp => options = options.with_dns_over_tcp(),
+        ArgDns::None => {}
+    }
+    if args.dns_hijack {
+        options = options.with_dns_hijack();
+    }
+    for rule in &args.dns_rule {
+        options = options.with_dns_rule(rule.clone());
+    }
+    if !args.dns_port.is_empty() {
+        options = options.with_dns_ports(&args.dns_port);
+    }
+    if let Some(policy) = args.local_dns {
+        options = options.with_local_dns(match policy {
+            ArgLocalDns::Drop => LocalDnsPolicy::Drop,
+            ArgLocalDns::Pass => {
+                LocalDnsPolicy::Pass(args.local_dns_dev.clone().unwrap_or_default())
+            }
+            ArgLocalDns::Answer => LocalDnsPolicy::Answer,
+        });
+    }
+    if args.dns_log {
+        options = options.with_dns_query_handler(|query| log::info!("DNS query {query}"));
+    }
+    let dns_stats = args
+        .dns_stats
+        .map(|interval| (interval, Arc::new(DnsStats::default())));
+    if let Some((_, stats)) = &dns_stats {
+        options = options.with_dns_stats(stats.clone());
+    }
+    // Proxies given by hostname are resolved again through the name servers in use before the
+    // setup points the system to the virtual DNS, to which queries over TCP and queries for
+    // excluded domains or with DNSSEC are forwarded as well. So are the destinations of direct
+    // connections and of SOCKS4 servers which cannot resolve hostnames, and DoH resolvers.
+    let over_tcp = args.dns == ArgDns::OverTcp;
+    let excluding = args.dns == ArgDns::Virtual
+        && (!args.dns_exclude.is_empty() || args.dns_dnssec == Some(ArgDnssec::Forward));
+    let resolving = args.proxy.iter().any(|proxy| {
+        proxy.hostname.is_some()
+            || matches!(proxy.proxy_type, ProxyType::Socks4 | ProxyType::Direct)
+    }) || args.dns == ArgDns::Doh
+        || args
+            .dns_rule
+            .iter()
+            .any(|rule| matches!(rule.target(), DnsTarget::Https(_)));
+    let nameservers = match resolving || over_tcp || excluding {
+        true => system_nameservers(),
+        false => Vec::new(),
+    };
+    options = options.with_nameservers(nameservers.clone());
 
-    let interface = match args.tun_fd {
-        None => NetworkInterface::Named(args.tun.clone()),
-        Some(fd) => {
-            options = options.with_mtu(args.tun_mtu);
-            NetworkInterface::Fd(fd)
+    if let Some(command) = args.command.filter(|command| *command != ArgCommand::Run) {
+        if let Err(e) = run_command(command, &args, &nameservers, over_tcp, excluding) {
+            log::error!("{e}");
+            return ExitCode::FAILURE;
         }
+        return ExitCode::SUCCESS;
+    }
+
+    // Passwords which are not part of the proxy URL are loaded before privileges are dropped.
+    let password = std::env::var("TUN2PROXY_PROXY_PASSWORD").ok();
+    let credentials = match (&args.proxy_cred_file, password) {
+        (Some(path), _) => Some(CredentialSource::File(path.clone())),
+        (None, Some(password)) => Some(CredentialSource::Password(password)),
+        (None, None) if args.proxy_cred_keyring => Some(CredentialSource::Keyring),
+        (None, None) => None,
     };
 
     if let Err(e) = (|| -> Result<(), Error> {
+        if let Some(source) = &credentials {
+            for proxy in &mut args.proxy {
+                proxy.load_credentials(source)?;
+            }
+        }
+        if let Some(path) = args
+            .dns_hosts
+            .as_ref()
+            .filter(|_| args.dns == ArgDns::Virtual)
+        {
+            for record in DnsRecord::read_hosts(path)? {
+                options = options.with_virtual_dns_record(record);
+            }
+        }
+        // The sandbox still lets the state file be replaced.
+        let mut writable = Vec::new();
+        if let Some(path) = args
+            .dns_state
+            .as_ref()
+            .filter(|_| args.dns == ArgDns::Virtual)
+        {
+            let state = VirtualDnsState::open(path)?;
+            writable.push(state.directory().to_path_buf());
+            options = options.with_virtual_dns_state(state);
+        }
+
+        // Without a file descriptor given, one may have been passed by systemd, in which case
+        // tun2proxy needs no privileges of its own.
+        let tun_fd = match (args.tun_fd, &args.tun_socket) {
+            (Some(fd), _) => Some(fd),
+            (None, Some(path)) => Some(receive_tun_fd(&UnixStream::connect(path)?)?),
+            (None, None) => systemd_tun_fd(),
+        };
+        #[cfg(target_os = "linux")]
+        let tun_fd = match args.tun_create {
+            true => {
+                let mut config = TunConfig::new(&args.tun)
+                    .with_persistent(args.tun_persist)
+                    .with_offload(args.tun_offload);
+                if let Some(mtu) = args.tun_mtu {
+                    config = config.with_mtu(mtu.into());
+                }
+                if let Some(owner) = &args.tun_owner {
+                    config = config.with_owner(owner);
+                }
+                if let Some(group) = &args.tun_group {
+                    config = config.with_group(group);
+                }
+                for address in &args.tun_address {
+                    config = config.with_address(*address);
+                }
+                Some(config.create()?)
+            }
+            false => tun_fd,
+        };
+        if args.command == Some(ArgCommand::Run) && args.setup.is_some() {
+            return Err("Nothing is set up when running after `tun2proxy setup`".into());
+        }
+        if (args.tun_owner.is_some() || args.tun_group.is_some()) && !args.tun_create {
+            return Err("The owner is only given to a tun interface created or set up".into());
+        }
+        if !args.tun_address.is_empty() && !args.tun_create && args.setup.is_none() {
+            return Err("Addresses are only given to a tun interface created or set up".into());
+        }
+        if args.fwmark.is_some() && args.user.is_some() && !args.keep_net_admin {
+            return Err("Marking the sockets to the proxies requires --keep-net-admin".into());
+        }
+        if args.nfqueue.is_some() && args.user.is_some() && !args.keep_net_admin {
+            return Err("The verdicts on queued packets require --keep-net-admin".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.out_interface.is_some() {
+            return Err("Binding to an interface is only supported on Linux".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.fwmark.is_some() {
+            return Err("Socket marks are only supported on Linux".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.tun_create {
+            return Err("Creating the tun interface is only supported on Linux".into());
+        }
+        #[allow(unused_mut)]
+        let mut interface = match tun_fd {
+            None => NetworkInterface::Named(args.tun.clone()),
+            Some(fd) => NetworkInterface::Fd(fd),
+        };
         #[cfg(target_os = "linux")]
+        if let Some(target) = args.transparent {
+            let mode = match target {
+                ArgTransparent::Redirect => TransparentMode::Redirect,
+                ArgTransparent::Tproxy => TransparentMode::Tproxy,
+            };
+            let listener = TransparentListener::bind(args.transparent_listen, mode)?;
+            interface = NetworkInterface::Packets(listener.start()?);
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.transparent.is_some() {
+            return Err("Diverted connections are only accepted on Linux".into());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(number) = args.nfqueue {
+            let queue = NfQueue::bind(number)?;
+            if let Some(mark) = args.fwmark {
+                queue.set_mark(mark)?;
+            }
+            interface = NetworkInterface::Packets(queue.start()?);
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.nfqueue.is_some() {
+            return Err("Netfilter queues are only supported on Linux".into());
+        }
+
+        if !args.bypass.is_empty() && args.netns.is_some() {
+            return Err("Nothing is routed around a tunnel in a network namespace".into());
+        }
+        if args.setup == Some(ArgSetup::Container) && args.netns.is_some() {
+            return Err("The container setup stays within the namespace it runs in".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.setup == Some(ArgSetup::Container) {
+            return Err("The container setup is only supported on Linux".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.netns.is_some() {
+            return Err("Network namespaces are only supported on Linux".into());
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+        let mut configured: Option<Setup> = None;
+        #[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
         {
-            let mut setup: Setup;
-            if args.setup == Some(ArgSetup::Auto) {
-                let bypass_tun_ip = match args.setup_ip {
-                    Some(addr) => addr,
-                    None => args.proxy.addr.ip(),
-                };
-                setup = Setup::new(
-                    &args.tun,
-                    &bypass_tun_ip,
-                    get_default_cidrs(),
-                    args.setup_ip.is_some(),
-                );
+            if args.setup.is_some() && tun_fd.is_some() {
+                // Whoever opened the interface is in charge of its routing as well.
+                log::warn!("Skipping the setup of a tun interface passed as file descriptor");
+            } else if args.setup.is_some() {
+                let mut setup = new_setup(&args, &nameservers, over_tcp, excluding);
 
                 setup.configure()?;
 
-                setup.drop_privileges()?;
+                #[cfg(target_os = "linux")]
+                if let Some(fd) = setup.tun_fd() {
+                    interface = NetworkInterface::Fd(fd);
+                }
+
+                let routes = setup.clone();
+                options = options.with_proxy_moved_handler(move |addr| {
+                    if let Err(e) = routes.add_bypass_route(&addr) {
+                        log::error!("{e}");
+                    }
+                });
+
+                configured = Some(setup);
             }
         }
 
-        main_entry(&interface, &args.proxy, options)?;
+        #[cfg(not(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd")))]
+        if args.user.is_some() {
+            return Err("Switching the user is not supported on this platform".into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.sandbox {
+            return Err("The sandbox is only supported on Linux".into());
+        }
+        // Privileges are only given up once the tun interface is open, and the sandbox is entered
+        // before the tunnel spawns any thread, so that it confines all of them.
+        #[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+        {
+            let (user, group) = (args.user.clone(), args.group.clone());
+            let (keep_net_admin, marking) = (args.keep_net_admin, args.fwmark.is_some());
+            let setup = configured.clone();
+            #[cfg(target_os = "linux")]
+            let sandbox = args.sandbox;
+            options = options.with_tun_opened_handler(move || {
+                match (user, setup) {
+                    (Some(user), _) => drop_privileges(&user, group.as_deref(), keep_net_admin)?,
+                    // Marking sockets takes the CAP_NET_ADMIN capability.
+                    (None, Some(_)) if marking => drop_privileges("65534", None, true)?,
+                    (None, Some(setup)) => setup.drop_privileges()?,
+                    (None, None) => {}
+                }
+                #[cfg(target_os = "linux")]
+                if sandbox {
+                    let writable: Vec<_> = writable.iter().map(|path| path.as_path()).collect();
+                    enter_sandbox(&writable)?;
+                }
+                Ok(())
+            });
+        }
+
+        // SIGINT and SIGTERM stop the tunnel, after which the network configuration is restored.
+        let result = tun_to_proxy_multi(&interface, &args.proxy, options).and_then(|mut ttp| {
+            if let Some((interval, stats)) = dns_stats {
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(Duration::from_secs(interval.max(1)));
+                    log::info!("DNS stats {stats}");
+                });
+            }
+            let shutdown = ttp.shutdown_handle()?;
+            ctrlc::set_handler(move || {
+                log::info!("Shutting down");
+                if let Err(e) = shutdown.shutdown() {
+                    log::error!("{e}");
+                }
+            })?;
+            ttp.run()
+        });
+
+        #[cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+        if let Some(mut setup) = configured {
+            setup.restore()?;
+        }
 
-        Ok(())
+        result
     })() {
         log::error!("{e}");
         return ExitCode::FAILURE;
@@ -0,0 +1,1115 @@
+use crate::bridge::{self, BridgeConnections, BridgeHandle};
+use crate::error::Error;
+use crate::quic::QuicClient;
+use crate::socks::{
+    decapsulate_udp_datagram, encapsulate_udp_datagram, parse_destination, SocksManager,
+    SocksVersion,
+};
+use crate::tls::TlsConfig;
+use crate::tun2proxy::{Connection, ConnectionManager, Destination, DestinationHost, TcpProxy};
+use crate::Credentials;
+use base64::Engine;
+use bytes::Bytes;
+use mio::net::{UdpSocket, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token};
+use quinn_proto::{Dir, ReadError, Side, StreamEvent, StreamId, VarInt};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::time::Instant;
+
+const FRAME_DATA: u64 = 0x0;
+const FRAME_HEADERS: u64 = 0x1;
+const FRAME_SETTINGS: u64 = 0x4;
+const STREAM_TYPE_CONTROL: u64 = 0x0;
+const SETTINGS_H3_DATAGRAM: u64 = 0x33;
+const H3_REQUEST_CANCELLED: u32 = 0x10c;
+
+const MAX_QUEUED_DATAGRAMS: usize = 32;
+
+fn extend_varint(buffer: &mut Vec<u8>, value: u64) {
+    if value < 1 << 6 {
+        buffer.push(value as u8);
+    } else if value < 1 << 14 {
+        buffer.extend((value as u16 | 0x4000).to_be_bytes());
+    } else if value < 1 << 30 {
+        buffer.extend((value as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        buffer.extend((value | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+/// Parse a QUIC variable-length integer and return it along with its encoded size.
+/// `None` is returned if the data is incomplete.
+fn parse_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let size = 1 << (first >> 6);
+    if data.len() < size {
+        return None;
+    }
+    let value = data[1..size]
+        .iter()
+        .fold((first & 0x3f) as u64, |value, byte| {
+            value << 8 | *byte as u64
+        });
+    Some((value, size))
+}
+
+fn extend_frame(buffer: &mut Vec<u8>, frame_type: u64, payload: &[u8]) {
+    extend_varint(buffer, frame_type);
+    extend_varint(buffer, payload.len() as u64);
+    buffer.extend(payload);
+}
+
+/// Parse an HTTP/3 frame and return its type, its payload and its encoded size.
+fn parse_frame(data: &[u8]) -> Option<(u64, &[u8], usize)> {
+    let (frame_type, type_size) = parse_varint(data)?;
+    let (length, length_size) = parse_varint(&data[type_size..])?;
+    let start = type_size + length_size;
+    let end = start.checked_add(usize::try_from(length).ok()?)?;
+    Some((frame_type, data.get(start..end)?, end))
+}
+
+fn extend_prefixed_int(buffer: &mut Vec<u8>, flags: u8, prefix_bits: u32, mut value: usize) {
+    let max = (1 << prefix_bits) - 1;
+    if value < max {
+        buffer.push(flags | value as u8);
+        return;
+    }
+    buffer.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn parse_prefixed_int(data: &[u8], prefix_bits: u32) -> Option<(usize, usize)> {
+    let max = (1 << prefix_bits) - 1;
+    let mut value = *data.first()? as usize & max;
+    if value < max {
+        return Some((value, 1));
+    }
+    for (index, byte) in data.iter().enumerate().skip(1).take(4) {
+        value += ((byte & 0x7f) as usize) << (7 * (index - 1));
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+/// Encode a QPACK field section using literal field lines only, so that neither the static
+/// nor the dynamic table are involved.
+fn encode_field_section(headers: &[(&str, String)]) -> Vec<u8> {
+    // Required Insert Count and Delta Base
+    let mut block = vec![0, 0];
+    for (name, value) in headers {
+        extend_prefixed_int(&mut block, 0x20, 3, name.len());
+        block.extend(name.as_bytes());
+        extend_prefixed_int(&mut block, 0, 7, value.len());
+        block.extend(value.as_bytes());
+    }
+    block
+}
+
+/// Values of the `:status` entries of the QPACK static table.
+fn static_status(index: usize) -> Option<&'static [u8]> {
+    let status: &[u8] = match index {
+        24 => b"103",
+        25 => b"200",
+        26 => b"304",
+        27 => b"404",
+        28 => b"503",
+        63 => b"100",
+        64 => b"204",
+        65 => b"206",
+        66 => b"302",
+        67 => b"400",
+        68 => b"403",
+        69 => b"421",
+        70 => b"425",
+        71 => b"500",
+        _ => return None,
+    };
+    Some(status)
+}
+
+fn parse_string(data: &[u8], prefix_bits: u32) -> Result<(Vec<u8>, usize), Error> {
+    let malformed = "malformed HTTP/3 field section";
+    let huffman = data.first().ok_or(malformed)? & (1 << prefix_bits) != 0;
+    let (length, size) = parse_prefixed_int(data, prefix_bits).ok_or(malformed)?;
+    let raw = data.get(size..size + length).ok_or(malformed)?;
+    let value = if huffman {
+        hpack::huffman::HuffmanDecoder::new()
+            .decode(raw)
+            .map_err(|e| Error::from(format!("HTTP/3 field decoding failed: {e:?}")))?
+    } else {
+        raw.to_vec()
+    };
+    Ok((value, size + length))
+}
+
+/// Extract the status from a response field section. As no dynamic table capacity is
+/// announced to the server, only the static table can be referenced.
+fn decode_status(block: &[u8]) -> Result<u16, Error> {
+    let malformed = "malformed HTTP/3 field section";
+    let (_, insert_count_size) = parse_prefixed_int(block, 8).ok_or(malformed)?;
+    let (_, delta_base_size) =
+        parse_prefixed_int(&block[insert_count_size..], 7).ok_or(malformed)?;
+    let mut data = &block[insert_count_size + delta_base_size..];
+    while let Some(first) = data.first() {
+        let (status, size) = if first & 0xc0 == 0xc0 {
+            // Indexed field line referencing the static table
+            let (index, size) = parse_prefixed_int(data, 6).ok_or(malformed)?;
+            (static_status(index).map(|status| status.to_vec()), size)
+        } else if first & 0xd0 == 0x50 {
+            // Literal field line with a name reference into the static table
+            let (index, size) = parse_prefixed_int(data, 4).ok_or(malformed)?;
+            let (value, value_size) = parse_string(&data[size..], 7)?;
+            (static_status(index).map(|_| value), size + value_size)
+        } else if first & 0xe0 == 0x20 {
+            // Literal field line with a literal name
+            let (name, name_size) = parse_string(data, 3)?;
+            let (value, value_size) = parse_string(&data[name_size..], 7)?;
+            (
+                (name == b":status").then_some(value),
+                name_size + value_size,
+            )
+        } else {
+            return Err("HTTP/3 response references the dynamic table".into());
+        };
+        if let Some(status) = status {
+            return std::str::from_utf8(&status)
+                .ok()
+                .and_then(|status| status.parse().ok())
+                .ok_or_else(|| "invalid HTTP/3 response status".into());
+        }
+        data = &data[size..];
+    }
+    Err("HTTP/3 response does not contain a status".into())
+}
+
+/// The connection of the tunnel interface or the UDP flow a request stream belongs to.
+#[derive(Clone, Debug)]
+enum Owner {
+    Local(Token),
+    Flow(Token, Destination),
+}
+
+#[derive(Debug)]
+enum RequestEvent {
+    Response(u16),
+    Data(Vec<u8>),
+    End,
+    Reset,
+}
+
+struct Request {
+    owner: Owner,
+    outbuf: VecDeque<u8>,
+    inbuf: Vec<u8>,
+    responded: bool,
+    finish: bool,
+    finish_sent: bool,
+    remote_finished: bool,
+}
+
+const QUIC_TOKEN: Token = Token(1);
+
+/// An HTTP/3 session carrying CONNECT and CONNECT-UDP requests.
+struct Http3 {
+    quic: QuicClient,
+    connected: bool,
+    authority: String,
+    authorization: Option<String>,
+    requests: HashMap<StreamId, Request>,
+}
+
+impl Http3 {
+    fn new(
+        server: SocketAddr,
+        credentials: &Option<Credentials>,
+        tls: &TlsConfig,
+        registry: &Registry,
+    ) -> Result<Self, Error> {
+        let authorization = credentials.as_ref().map(|credentials| {
+            let mut auth_plain = credentials.username.clone();
+            auth_plain.extend(b":".iter());
+            auth_plain.extend(&credentials.password);
+            let auth_b64 = base64::engine::general_purpose::STANDARD.encode(auth_plain);
+            format!("Basic {auth_b64}")
+        });
+        let authority = match tls.server_name().parse::<IpAddr>() {
+            Ok(IpAddr::V6(addr)) => format!("[{addr}]:{}", server.port()),
+            _ => format!("{}:{}", tls.server_name(), server.port()),
+        };
+        Ok(Self {
+            quic: QuicClient::connect(server, tls, registry, QUIC_TOKEN)?,
+            connected: false,
+            authority,
+            authorization,
+            requests: HashMap::new(),
+        })
+    }
+
+    fn open_control_stream(&mut self) -> Result<(), Error> {
+        let connection = self.quic.connection();
+        let id = connection
+            .streams()
+            .open(Dir::Uni)
+            .ok_or("HTTP/3 control stream cannot be opened")?;
+        let mut settings = Vec::new();
+        extend_varint(&mut settings, SETTINGS_H3_DATAGRAM);
+        extend_varint(&mut settings, 1);
+        let mut data = Vec::new();
+        extend_varint(&mut data, STREAM_TYPE_CONTROL);
+        extend_frame(&mut data, FRAME_SETTINGS, &settings);
+        connection
+            .send_stream(id)
+            .write(&data)
+            .map_err(|e| Error::from(format!("HTTP/3 control stream: {e}")))?;
+        Ok(())
+    }
+
+    fn connect_headers(&self, dst: &Destination) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            (":method", "CONNECT".into()),
+            (":authority", dst.to_string()),
+        ];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("proxy-authorization", authorization.clone()));
+        }
+        headers
+    }
+
+    /// Headers of a CONNECT-UDP request (RFC 9298) using the default URI template.
+    fn connect_udp_headers(&self, dst: &Destination) -> Vec<(&'static str, String)> {
+        let host = match &dst.host {
+            DestinationHost::Address(IpAddr::V6(addr)) => addr.to_string().replace(':', "%3A"),
+            host => host.to_string(),
+        };
+        let mut headers = vec![
+            (":method", "CONNECT".into()),
+            (":protocol", "connect-udp".into()),
+            (":scheme", "https".into()),
+            (":authority", self.authority.clone()),
+            (
+                ":path",
+                format!("/.well-known/masque/udp/{host}/{}/", dst.port),
+            ),
+            ("capsule-protocol", "?1".into()),
+        ];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("proxy-authorization", authorization.clone()));
+        }
+        headers
+    }
+
+    /// Open a request stream, which is only possible once the session is established.
+    fn open_request(
+        &mut self,
+        owner: Owner,
+        headers: &[(&str, String)],
+    ) -> Result<Option<StreamId>, Error> {
+        if !self.connected {
+            return Ok(None);
+        }
+        let id = match self.quic.connection().streams().open(Dir::Bi) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let mut frame = Vec::new();
+        extend_frame(&mut frame, FRAME_HEADERS, &encode_field_section(headers));
+        let request = Request {
+            owner,
+            outbuf: frame.into(),
+            inbuf: Vec::new(),
+            responded: false,
+            finish: false,
+            finish_sent: false,
+            remote_finished: false,
+        };
+        self.requests.insert(id, request);
+        self.flush_request(id)?;
+        Ok(Some(id))
+    }
+
+    fn send(&mut self, id: StreamId, data: &[u8]) -> Result<(), Error> {
+        if let Some(request) = self.requests.get_mut(&id) {
+            let mut frame = Vec::new();
+            extend_frame(&mut frame, FRAME_DATA, data);
+            request.outbuf.extend(frame);
+            self.flush_request(id)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, id: StreamId) -> Result<(), Error> {
+        if let Some(request) = self.requests.get_mut(&id) {
+            request.finish = true;
+            self.flush_request(id)?;
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: StreamId) {
+        if self.requests.remove(&id).is_some() {
+            let connection = self.quic.connection();
+            let _ = connection
+                .send_stream(id)
+                .reset(VarInt::from_u32(H3_REQUEST_CANCELLED));
+            let _ = connection
+                .recv_stream(id)
+                .stop(VarInt::from_u32(H3_REQUEST_CANCELLED));
+        }
+    }
+
+    fn reset_request(&mut self, id: StreamId, events: &mut Vec<(Owner, RequestEvent)>) {
+        if let Some(request) = self.requests.get(&id) {
+            events.push((request.owner.clone(), RequestEvent::Reset));
+        }
+        self.cancel(id);
+    }
+
+    fn has_request(&self, id: StreamId) -> bool {
+        self.requests.contains_key(&id)
+    }
+
+    fn flush_request(&mut self, id: StreamId) -> Result<(), Error> {
+        let request = match self.requests.get_mut(&id) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let mut stream = self.quic.connection().send_stream(id);
+        while !request.outbuf.is_empty() {
+            match stream.write(request.outbuf.make_contiguous()) {
+                Ok(written) => {
+                    request.outbuf.drain(0..written);
+                }
+                Err(quinn_proto::WriteError::Blocked) => break,
+                Err(error) => return Err(format!("HTTP/3 request stream: {error}").into()),
+            }
+        }
+        if request.finish && !request.finish_sent && request.outbuf.is_empty() {
+            request.finish_sent = true;
+            let _ = stream.finish();
+            if request.remote_finished {
+                self.requests.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send an HTTP datagram (RFC 9297) on the context of a CONNECT-UDP request.
+    fn send_datagram(&mut self, id: StreamId, payload: &[u8]) {
+        let mut datagram = Vec::new();
+        extend_varint(&mut datagram, u64::from(VarInt::from(id)) / 4);
+        extend_varint(&mut datagram, 0); // Context ID of UDP payloads
+        datagram.extend(payload);
+        if let Err(error) = self
+            .quic
+            .connection()
+            .datagrams()
+            .send(Bytes::from(datagram))
+        {
+            log::debug!("HTTP/3 datagram dropped: {error}");
+        }
+    }
+
+    fn read_stream(&mut self, id: StreamId) -> (Vec<u8>, bool, bool) {
+        let (mut data, mut finished, mut reset) = (Vec::new(), false, false);
+        let mut stream = self.quic.connection().recv_stream(id);
+        let mut chunks = match stream.read(true) {
+            Ok(chunks) => chunks,
+            Err(_) => return (data, finished, reset),
+        };
+        loop {
+            match chunks.next(usize::MAX) {
+                Ok(Some(chunk)) => data.extend(chunk.bytes),
+                Ok(None) => {
+                    finished = true;
+                    break;
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(_)) => {
+                    reset = true;
+                    break;
+                }
+            }
+        }
+        let _ = chunks.finalize();
+        (data, finished, reset)
+    }
+
+    fn receive_request(
+        &mut self,
+        id: StreamId,
+        events: &mut Vec<(Owner, RequestEvent)>,
+    ) -> Result<(), Error> {
+        let (data, finished, reset) = self.read_stream(id);
+        let request = match self.requests.get_mut(&id) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        request.inbuf.extend(data);
+        let mut offset = 0;
+        while let Some((frame_type, payload, size)) = parse_frame(&request.inbuf[offset..]) {
+            match frame_type {
+                FRAME_HEADERS if !request.responded => {
+                    let status = decode_status(payload)?;
+                    // Informational responses precede the actual one.
+                    if !(100..200).contains(&status) {
+                        request.responded = true;
+                        events.push((request.owner.clone(), RequestEvent::Response(status)));
+                    }
+                }
+                // Capsules of CONNECT-UDP requests are ignored.
+                FRAME_DATA if matches!(request.owner, Owner::Local(_)) => {
+                    let data = payload.to_vec();
+                    events.push((request.owner.clone(), RequestEvent::Data(data)));
+                }
+                _ => {}
+            }
+            offset += size;
+        }
+        request.inbuf.drain(0..offset);
+
+        if reset {
+            let request = self.requests.remove(&id).ok_or("no request")?;
+            events.push((request.owner, RequestEvent::Reset));
+        } else if finished {
+            request.remote_finished = true;
+            events.push((request.owner.clone(), RequestEvent::End));
+            if request.finish_sent {
+                self.requests.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_datagrams(&mut self, events: &mut Vec<(Owner, RequestEvent)>) {
+        while let Some(datagram) = self.quic.connection().datagrams().recv() {
+            let (quarter_id, id_size) = match parse_varint(&datagram) {
+                Some(result) => result,
+                None => continue,
+            };
+            let (context, context_size) = match parse_varint(&datagram[id_size..]) {
+                Some(result) => result,
+                None => continue,
+            };
+            let id = StreamId::new(Side::Client, Dir::Bi, quarter_id);
+            match self.requests.get(&id) {
+                Some(request) if context == 0 => {
+                    let payload = datagram[id_size + context_size..].to_vec();
+                    events.push((request.owner.clone(), RequestEvent::Data(payload)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn poll_events(&mut self, events: &mut Vec<(Owner, RequestEvent)>) -> Result<(), Error> {
+        while let Some(event) = self.quic.connection().poll() {
+            match event {
+                quinn_proto::Event::Connected => {
+                    self.connected = true;
+                    self.open_control_stream()?;
+                }
+                quinn_proto::Event::ConnectionLost { reason } => {
+                    return Err(format!("HTTP/3 session lost: {reason}").into());
+                }
+                quinn_proto::Event::Stream(StreamEvent::Opened { dir: Dir::Uni }) => {
+                    // Control and QPACK streams of the server do not need to be interpreted.
+                    while let Some(id) = self.quic.connection().streams().accept(Dir::Uni) {
+                        self.read_stream(id);
+                    }
+                }
+                quinn_proto::Event::Stream(StreamEvent::Readable { id }) => {
+                    if id.dir() == Dir::Uni {
+                        self.read_stream(id);
+                    } else if let Err(error) = self.receive_request(id, events) {
+                        log::debug!("{error}");
+                        self.reset_request(id, events);
+                    }
+                }
+                quinn_proto::Event::Stream(StreamEvent::Writable { id }) => {
+                    if let Err(error) = self.flush_request(id) {
+                        log::debug!("{error}");
+                        self.reset_request(id, events);
+                    }
+                }
+                quinn_proto::Event::Stream(StreamEvent::Stopped { id, .. }) => {
+                    self.reset_request(id, events);
+                }
+                quinn_proto::Event::DatagramReceived => self.receive_datagrams(events),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LocalState {
+    Greeting,
+    Request,
+    Waiting(Destination),
+    Connecting(StreamId),
+    Established(StreamId),
+    Associated(Token),
+}
+
+/// A connection of the tunnel interface, handed to the bridge through SOCKS5.
+struct Local {
+    stream: UnixStream,
+    state: LocalState,
+    inbuf: Vec<u8>,
+    outbuf: VecDeque<u8>,
+    eof: bool,
+    close_after_write: bool,
+}
+
+impl Local {
+    fn flush(&mut self) -> Result<(), Error> {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(self.outbuf.make_contiguous()) {
+                Ok(0) => return Err("local stream closed".into()),
+                Ok(written) => {
+                    self.outbuf.drain(0..written);
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error.into()),
+            }
+        }
+        if self.close_after_write {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, status: u8, bound: SocketAddr) {
+        self.outbuf.extend([5, status, 0]);
+        match bound {
+            SocketAddr::V4(addr) => {
+                self.outbuf.push_back(1);
+                self.outbuf.extend(addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                self.outbuf.push_back(4);
+                self.outbuf.extend(addr.ip().octets());
+            }
+        }
+        self.outbuf.extend(bound.port().to_be_bytes());
+    }
+}
+
+#[derive(Default)]
+struct Flow {
+    stream: Option<StreamId>,
+    queue: VecDeque<Vec<u8>>,
+}
+
+/// The UDP relay of a SOCKS5 association, whose datagrams are proxied through CONNECT-UDP. It
+/// belongs to the first client sending to it.
+struct Relay {
+    socket: UdpSocket,
+    client: Option<SocketAddr>,
+    flows: HashMap<Destination, Flow>,
+}
+
+const WAKER_TOKEN: Token = Token(0);
+
+/// Proxies the connections handed over to it as SOCKS5 requests, and their UDP flows, through
+/// a MASQUE proxy server.
+struct Bridge {
+    poll: Poll,
+    connections: BridgeConnections,
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+    tls: TlsConfig,
+    http3: Option<Http3>,
+    locals: HashMap<Token, Local>,
+    relays: HashMap<Token, Relay>,
+    next_token: usize,
+}
+
+impl Bridge {
+    fn new_token(&mut self) -> Token {
+        self.next_token += 1;
+        Token(self.next_token)
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = self
+                .http3
+                .as_mut()
+                .and_then(|http3| http3.quic.next_timer())
+                .map(|timer| timer.saturating_duration_since(Instant::now()));
+            if let Err(error) = self.poll.poll(&mut events, timeout) {
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            for event in events.iter() {
+                let result = match event.token() {
+                    WAKER_TOKEN => match self.connections.take() {
+                        None => return Ok(()),
+                        Some(streams) => self.accept(streams),
+                    },
+                    QUIC_TOKEN => match &mut self.http3 {
+                        Some(http3) => http3.quic.receive(),
+                        None => Ok(()),
+                    },
+                    token if self.relays.contains_key(&token) => self.relay_event(token),
+                    token => {
+                        if let Err(error) = self.local_event(token) {
+                            log::debug!("MASQUE connection: {error}");
+                            self.remove_local(token);
+                        }
+                        Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    log::error!("MASQUE: {error}");
+                    self.close_http3();
+                }
+            }
+            self.process_http3();
+        }
+    }
+
+    fn ensure_http3(&mut self) -> Result<&mut Http3, Error> {
+        if self.http3.is_none() {
+            let registry = self.poll.registry();
+            let http3 = Http3::new(self.server, &self.credentials, &self.tls, registry)?;
+            self.http3 = Some(http3);
+        }
+        self.http3
+            .as_mut()
+            .ok_or_else(|| "no HTTP/3 session".into())
+    }
+
+    fn close_http3(&mut self) {
+        if let Some(mut http3) = self.http3.take() {
+            http3.quic.deregister(self.poll.registry());
+        }
+        let tokens: Vec<Token> = self
+            .locals
+            .iter()
+            .filter(|(_, local)| {
+                matches!(
+                    local.state,
+                    LocalState::Connecting(_) | LocalState::Established(_)
+                )
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.remove_local(token);
+        }
+        for relay in self.relays.values_mut() {
+            relay.flows.clear();
+        }
+    }
+
+    fn process_http3(&mut self) {
+        let http3 = match &mut self.http3 {
+            Some(http3) => http3,
+            None => return,
+        };
+        http3.quic.update_timers();
+        let mut events = Vec::new();
+        let result = http3.poll_events(&mut events);
+        for (owner, event) in events {
+            self.dispatch(owner, event);
+        }
+        if let Err(error) = result.and_then(|_| self.open_waiting()) {
+            log::error!("MASQUE: {error}");
+            self.close_http3();
+        }
+        if let Some(http3) = &mut self.http3 {
+            http3.quic.flush();
+        }
+    }
+
+    /// Open the requests of connections and flows waiting for the session.
+    fn open_waiting(&mut self) -> Result<(), Error> {
+        let http3 = match &mut self.http3 {
+            Some(http3) => http3,
+            None => return Ok(()),
+        };
+        for (token, local) in self.locals.iter_mut() {
+            if let LocalState::Waiting(dst) = &local.state {
+                let headers = http3.connect_headers(dst);
+                match http3.open_request(Owner::Local(*token), &headers)? {
+                    Some(id) => local.state = LocalState::Connecting(id),
+                    None => return Ok(()),
+                }
+            }
+        }
+        for (token, relay) in self.relays.iter_mut() {
+            for (dst, flow) in relay.flows.iter_mut() {
+                if flow.stream.is_some() {
+                    continue;
+                }
+                let headers = http3.connect_udp_headers(dst);
+                let id = match http3.open_request(Owner::Flow(*token, dst.clone()), &headers)? {
+                    Some(id) => id,
+                    None => return Ok(()),
+                };
+                flow.stream = Some(id);
+                // Datagrams may be sent before the response is received.
+                for payload in flow.queue.drain(..) {
+                    http3.send_datagram(id, &payload);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, owner: Owner, event: RequestEvent) {
+        match owner {
+            Owner::Local(token) => {
+                if let Err(error) = self.dispatch_local(token, event) {
+                    log::debug!("MASQUE connection: {error}");
+                    self.remove_local(token);
+                } else {
+                    self.finish_local(token);
+                }
+            }
+            Owner::Flow(token, dst) => {
+                let relay = match self.relays.get_mut(&token) {
+                    Some(relay) => relay,
+                    None => return,
+                };
+                match event {
+                    RequestEvent::Response(status) if (200..300).contains(&status) => {}
+                    RequestEvent::Data(payload) => {
+                        if let Some(client) = relay.client {
+                            let datagram = encapsulate_udp_datagram(&dst, &payload);
+                            let _ = relay.socket.send_to(&datagram, client);
+                        }
+                    }
+                    event => {
+                        log::debug!("MASQUE flow to {dst} closed: {event:?}");
+                        if let Some(Flow {
+                            stream: Some(id), ..
+                        }) = relay.flows.remove(&dst)
+                        {
+                            if let Some(http3) = &mut self.http3 {
+                                http3.cancel(id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn dispatch_local(&mut self, token: Token, event: RequestEvent) -> Result<(), Error> {
+        let local = match self.locals.get_mut(&token) {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        match event {
+            RequestEvent::Response(status) => {
+                let id = match local.state {
+                    LocalState::Connecting(id) => id,
+                    _ => return Ok(()),
+                };
+                if !(200..300).contains(&status) {
+                    local.reply(1, unspecified);
+                    local.close_after_write = true;
+                    return local.flush();
+                }
+                local.reply(0, unspecified);
+                local.state = LocalState::Established(id);
+                let data = std::mem::take(&mut local.inbuf);
+                let http3 = self.http3.as_mut().ok_or("no HTTP/3 session")?;
+                if !data.is_empty() {
+                    http3.send(id, &data)?;
+                }
+                if local.eof {
+                    http3.finish(id)?;
+                }
+            }
+            RequestEvent::Data(data) => local.outbuf.extend(data),
+            RequestEvent::End => local.close_after_write = true,
+            RequestEvent::Reset => return Err("request was reset".into()),
+        }
+        local.flush()
+    }
+
+    fn accept(&mut self, streams: Vec<UnixStream>) -> Result<(), Error> {
+        for mut stream in streams {
+            let token = self.new_token();
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let local = Local {
+                stream,
+                state: LocalState::Greeting,
+                inbuf: Vec::new(),
+                outbuf: VecDeque::new(),
+                eof: false,
+                close_after_write: false,
+            };
+            self.locals.insert(token, local);
+        }
+        Ok(())
+    }
+
+    fn local_event(&mut self, token: Token) -> Result<(), Error> {
+        let local = match self.locals.get_mut(&token) {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        let mut data = Vec::new();
+        match local.stream.read_to_end(&mut data) {
+            Ok(_) => local.eof = true,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error.into()),
+        }
+        local.flush()?;
+
+        match local.state.clone() {
+            LocalState::Established(id) => {
+                if let Some(http3) = &mut self.http3 {
+                    if !data.is_empty() {
+                        http3.send(id, &data)?;
+                    }
+                    if local.eof {
+                        http3.finish(id)?;
+                    }
+                }
+            }
+            LocalState::Greeting | LocalState::Request => {
+                local.inbuf.extend(data);
+                self.receive_socks_request(token)?;
+            }
+            _ => local.inbuf.extend(data),
+        }
+        self.finish_local(token);
+        Ok(())
+    }
+
+    fn receive_socks_request(&mut self, token: Token) -> Result<(), Error> {
+        let local = self.locals.get_mut(&token).ok_or("no local connection")?;
+        if local.state == LocalState::Greeting {
+            if local.inbuf.len() < 2 || local.inbuf.len() < 2 + local.inbuf[1] as usize {
+                return Ok(());
+            }
+            if local.inbuf[0] != 5 {
+                return Err("unexpected SOCKS version".into());
+            }
+            local.inbuf.drain(0..2 + local.inbuf[1] as usize);
+            local.outbuf.extend([5, 0]); // No authentication required
+            local.state = LocalState::Request;
+        }
+        if local.inbuf.len() < 4 {
+            return local.flush();
+        }
+        let (dst, size) = match parse_destination(&local.inbuf[3..])? {
+            Some(result) => result,
+            None => return local.flush(),
+        };
+        let command = local.inbuf[1];
+        local.inbuf.drain(0..3 + size);
+        match command {
+            1 => {
+                local.state = LocalState::Waiting(dst);
+                local.flush()?;
+                self.ensure_http3()?;
+            }
+            3 => {
+                let mut socket = UdpSocket::bind("127.0.0.1:0".parse()?)?;
+                let bound = socket.local_addr()?;
+                let relay_token = self.new_token();
+                self.poll
+                    .registry()
+                    .register(&mut socket, relay_token, Interest::READABLE)?;
+                let relay = Relay {
+                    socket,
+                    client: None,
+                    flows: HashMap::new(),
+                };
+                self.relays.insert(relay_token, relay);
+                let local = self.locals.get_mut(&token).ok_or("no local connection")?;
+                local.reply(0, bound);
+                local.state = LocalState::Associated(relay_token);
+                local.flush()?;
+            }
+            _ => {
+                local.reply(7, SocketAddr::from(([0, 0, 0, 0], 0))); // Command not supported
+                local.close_after_write = true;
+                local.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn relay_event(&mut self, token: Token) -> Result<(), Error> {
+        let mut buffer = [0u8; 65535];
+        let mut open_flows = false;
+        loop {
+            let relay = self.relays.get_mut(&token).ok_or("no relay")?;
+            let (size, addr) = match relay.socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            };
+            // Datagrams of anyone else who has found the port are dropped.
+            match relay.client {
+                Some(client) if client != addr => continue,
+                Some(_) => {}
+                None => relay.client = Some(addr),
+            }
+            let (dst, payload) = match decapsulate_udp_datagram(&buffer[..size]) {
+                Ok(result) => result,
+                Err(error) => {
+                    log::debug!("MASQUE relay: {error}");
+                    continue;
+                }
+            };
+            let flow = relay.flows.entry(dst).or_default();
+            match (flow.stream, &mut self.http3) {
+                (Some(id), Some(http3)) => http3.send_datagram(id, payload),
+                _ if flow.queue.len() < MAX_QUEUED_DATAGRAMS => {
+                    flow.queue.push_back(payload.to_vec());
+                    open_flows = true;
+                }
+                _ => {}
+            }
+        }
+        if open_flows {
+            self.ensure_http3()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the connection once both directions of its request are closed.
+    fn finish_local(&mut self, token: Token) {
+        let finished = match self.locals.get(&token) {
+            Some(local) if local.eof && local.outbuf.is_empty() => match local.state {
+                LocalState::Established(id) => {
+                    local.close_after_write
+                        && !matches!(&self.http3, Some(http3) if http3.has_request(id))
+                }
+                _ => true,
+            },
+            _ => false,
+        };
+        if finished {
+            self.remove_local(token);
+        }
+    }
+
+    fn remove_local(&mut self, token: Token) {
+        let mut local = match self.locals.remove(&token) {
+            Some(local) => local,
+            None => return,
+        };
+        let _ = self.poll.registry().deregister(&mut local.stream);
+        let _ = local.stream.shutdown(Shutdown::Both);
+        match local.state {
+            LocalState::Connecting(id) | LocalState::Established(id) => {
+                if let Some(http3) = &mut self.http3 {
+                    http3.cancel(id);
+                }
+            }
+            LocalState::Associated(relay_token) => {
+                if let Some(mut relay) = self.relays.remove(&relay_token) {
+                    let _ = self.poll.registry().deregister(&mut relay.socket);
+                    for flow in relay.flows.values() {
+                        if let (Some(id), Some(http3)) = (flow.stream, &mut self.http3) {
+                            http3.cancel(id);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Proxies TCP connections through CONNECT and UDP flows through CONNECT-UDP requests over
+/// HTTP/3. The traffic is handed over to a bridge thread, which owns the QUIC connection,
+/// through socket pairs as SOCKS5 requests, and UDP flows through loopback relays.
+pub(crate) struct MasqueManager {
+    socks: Rc<SocksManager>,
+    bridge: BridgeHandle,
+}
+
+impl ConnectionManager for MasqueManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        self.socks.handles_connection(connection)
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        self.socks.new_connection(connection, manager)
+    }
+
+    fn close_connection(&self, connection: &Connection) {
+        self.socks.close_connection(connection)
+    }
+
+    fn get_server(&self) -> SocketAddr {
+        self.socks.get_server()
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        // The bridge authenticates the HTTP/3 requests.
+        &None
+    }
+
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        Some(&self.bridge)
+    }
+}
+
+impl MasqueManager {
+    pub fn new(
+        server: SocketAddr,
+        credentials: Option<Credentials>,
+        tls: TlsConfig,
+    ) -> Result<Rc<Self>, Error> {
+        let poll = Poll::new()?;
+        let (handle, connections) = bridge::channel(poll.registry(), WAKER_TOKEN)?;
+
+        let mut bridge = Bridge {
+            poll,
+            connections,
+            server,
+            credentials,
+            tls: tls.with_alpn(&[b"h3"]),
+            http3: None,
+            locals: HashMap::new(),
+            relays: HashMap::new(),
+            next_token: usize::from(QUIC_TOKEN),
+        };
+        std::thread::spawn(move || {
+            if let Err(error) = bridge.run() {
+                log::error!("MASQUE bridge: {error}");
+            }
+        });
+
+        Ok(Rc::new(Self {
+            socks: SocksManager::new(server, SocksVersion::V5, None, None, None, None),
+            bridge: handle,
+        }))
+    }
+}
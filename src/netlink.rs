@@ -0,0 +1,215 @@
+#![cfg(target_os = "linux")]
+
+use crate::error::Error;
+use nix::sys::socket::{
+    self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// The size of `struct nlmsghdr`, after which the payload of a message follows.
+const HEADER_SIZE: usize = 16;
+
+const IFLA_MTU: u16 = 4;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// The bits of the type of an attribute which tell whether it nests others or is big-endian.
+const ATTRIBUTE_FLAGS: u16 = 0xc000;
+
+// Messages and their attributes are aligned to four bytes.
+fn align(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+pub(crate) fn push_attribute(message: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    message.extend(((4 + data.len()) as u16).to_ne_bytes());
+    message.extend(kind.to_ne_bytes());
+    message.extend(data);
+    message.resize(align(message.len()), 0);
+}
+
+/// The messages in `buffer`, as received from the kernel, as their types, sequence numbers and
+/// payloads.
+pub(crate) fn messages(buffer: &[u8]) -> impl Iterator<Item = (u16, u32, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = buffer.get(offset..offset + HEADER_SIZE)?;
+        let length = u32::from_ne_bytes(header[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes([header[4], header[5]]);
+        let sequence = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+        let payload = buffer.get(offset + HEADER_SIZE..offset + length.max(HEADER_SIZE))?;
+        offset += align(length.max(HEADER_SIZE));
+        Some((kind, sequence, payload))
+    })
+}
+
+/// The attributes in `data`, which follow the fixed part of a payload, as their types and data.
+pub(crate) fn attributes(data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..offset + 4)?;
+        let length = u16::from_ne_bytes([header[0], header[1]]) as usize;
+        let kind = u16::from_ne_bytes([header[2], header[3]]) & !ATTRIBUTE_FLAGS;
+        let value = data.get(offset + 4..offset + length.max(4))?;
+        offset += align(length.max(4));
+        Some((kind, value))
+    })
+}
+
+/// The error an `NLMSG_ERROR` message with the given payload reports, if it is not an
+/// acknowledgement.
+pub(crate) fn error_of(payload: &[u8]) -> Option<nix::errno::Errno> {
+    let error = i32::from_ne_bytes(payload.get(0..4)?.try_into().unwrap());
+    match error {
+        0 => None,
+        error => Some(nix::errno::Errno::from_i32(-error)),
+    }
+}
+
+/// A socket to the routing subsystem of the kernel, through which links and addresses are
+/// configured without resorting to `ip`, or to one of its other subsystems.
+pub(crate) struct Netlink {
+    fd: RawFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    pub(crate) fn new() -> Result<Self, Error> {
+        Self::open(SockProtocol::NetlinkRoute)
+    }
+
+    /// A socket to netfilter, e.g. to take the packets of a queue.
+    pub(crate) fn netfilter() -> Result<Self, Error> {
+        Self::open(SockProtocol::NetlinkNetFilter)
+    }
+
+    fn open(protocol: SockProtocol) -> Result<Self, Error> {
+        let fd = socket::socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            protocol,
+        )?;
+        Ok(Self { fd, sequence: 0 })
+    }
+
+    /// A socket receiving the notifications of the multicast `groups`, e.g. `RTMGRP_IPV4_ROUTE`,
+    /// which do not block once all have been read.
+    pub(crate) fn subscribe(groups: u32) -> Result<Self, Error> {
+        let fd = socket::socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+            SockProtocol::NetlinkRoute,
+        )?;
+        let netlink = Self { fd, sequence: 0 };
+        socket::bind(fd, &NetlinkAddr::new(0, groups))?;
+        Ok(netlink)
+    }
+
+    /// Read all pending notifications, returning whether there were any. Notifications which did
+    /// not fit into the buffer of the socket are lost, which counts as well.
+    pub(crate) fn drain(&self) -> Result<bool, Error> {
+        let mut buffer = [0; 4096];
+        let mut any = false;
+        loop {
+            match socket::recv(self.fd, &mut buffer, MsgFlags::empty()) {
+                Ok(_) | Err(nix::errno::Errno::ENOBUFS) => any = true,
+                Err(nix::errno::Errno::EAGAIN) => return Ok(any),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Send a request of the given type without waiting for the kernel to act on it.
+    pub(crate) fn send(&mut self, kind: u16, flags: u16, payload: &[u8]) -> Result<(), Error> {
+        self.sequence += 1;
+        let flags = flags | libc::NLM_F_REQUEST as u16;
+        let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+        message.extend(((HEADER_SIZE + payload.len()) as u32).to_ne_bytes());
+        message.extend(kind.to_ne_bytes());
+        message.extend(flags.to_ne_bytes());
+        message.extend(self.sequence.to_ne_bytes());
+        message.extend(0u32.to_ne_bytes());
+        message.extend(payload);
+        let kernel = NetlinkAddr::new(0, 0);
+        socket::sendto(self.fd, &message, &kernel, MsgFlags::empty())?;
+        Ok(())
+    }
+
+    /// Send a request of the given type and wait for the kernel to acknowledge it.
+    pub(crate) fn request(&mut self, kind: u16, flags: u16, payload: &[u8]) -> Result<(), Error> {
+        self.send(kind, flags | libc::NLM_F_ACK as u16, payload)?;
+
+        let mut buffer = [0; 4096];
+        loop {
+            let size = socket::recv(self.fd, &mut buffer, MsgFlags::empty())?;
+            for (kind, sequence, payload) in messages(&buffer[..size]) {
+                if kind == libc::NLMSG_ERROR as u16 && sequence == self.sequence {
+                    return match error_of(payload) {
+                        None => Ok(()),
+                        Some(error) => Err(error.into()),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Set the MTU of the link with the given index, unless `None`, and bring it up.
+    pub(crate) fn set_link_up(&mut self, index: u32, mtu: Option<u32>) -> Result<(), Error> {
+        self.set_link(index, libc::IFF_UP as u32, mtu)
+    }
+
+    /// Set the MTU of the link with the given index, which stays up or down.
+    pub(crate) fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<(), Error> {
+        self.set_link(index, 0, Some(mtu))
+    }
+
+    // Set the given flags of the link, leaving the others alone, and its MTU unless `None`.
+    fn set_link(&mut self, index: u32, flags: u32, mtu: Option<u32>) -> Result<(), Error> {
+        // struct ifinfomsg: family, padding, type, index, flags and the flags to change.
+        let mut payload = vec![libc::AF_UNSPEC as u8, 0];
+        payload.extend(0u16.to_ne_bytes());
+        payload.extend((index as i32).to_ne_bytes());
+        payload.extend(flags.to_ne_bytes());
+        payload.extend(flags.to_ne_bytes());
+        if let Some(mtu) = mtu {
+            push_attribute(&mut payload, IFLA_MTU, &mtu.to_ne_bytes());
+        }
+        self.request(libc::RTM_NEWLINK, 0, &payload)
+    }
+
+    /// Assign an address to the link with the given index.
+    pub(crate) fn add_address(
+        &mut self,
+        index: u32,
+        addr: IpAddr,
+        prefix_len: u8,
+    ) -> Result<(), Error> {
+        let (family, address) = match addr {
+            IpAddr::V4(addr) => (libc::AF_INET, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (libc::AF_INET6, addr.octets().to_vec()),
+        };
+        // struct ifaddrmsg: family, prefix length, flags, scope and index.
+        let mut payload = vec![family as u8, prefix_len, 0, 0];
+        payload.extend(index.to_ne_bytes());
+        push_attribute(&mut payload, IFA_LOCAL, &address);
+        push_attribute(&mut payload, IFA_ADDRESS, &address);
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        self.request(libc::RTM_NEWADDR, flags as u16, &payload)
+    }
+}
+
+impl AsRawFd for Netlink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
@@ -0,0 +1,210 @@
+#![cfg(target_os = "linux")]
+
+use crate::error::Error;
+use crate::netlink::{self, push_attribute, Netlink};
+use crate::packet_source::{PacketHandle, PacketSource};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use nix::errno::Errno;
+use nix::sys::socket::{
+    self, sockopt, AddressFamily, MsgFlags, SockFlag, SockProtocol, SockType, SockaddrIn,
+    SockaddrIn6,
+};
+use smoltcp::wire::{IpVersion, Ipv4Packet, Ipv6Packet};
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+const NETLINK_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+
+// Room for the largest packet along with the attributes describing it.
+const RECEIVE_BUFFER_SIZE: usize = 0x10000 + 0x1000;
+
+// The messages of the queue subsystem of nfnetlink carry it in their types.
+fn message_type(kind: libc::c_int) -> u16 {
+    ((libc::NFNL_SUBSYS_QUEUE << 8) | kind) as u16
+}
+
+// struct nfgenmsg, which precedes the attributes: the family, the version and the queue number.
+fn header(queue: u16) -> Vec<u8> {
+    let mut payload = vec![libc::AF_UNSPEC as u8, libc::NFNETLINK_V0 as u8];
+    payload.extend(queue.to_be_bytes());
+    payload
+}
+
+fn raw_socket(family: AddressFamily) -> Result<OwnedFd, Error> {
+    let fd = socket::socket(
+        family,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::Raw,
+    )?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// A netfilter queue, e.g. filled through the `NFQUEUE` target of iptables, whose packets are
+/// taken in place of those read from a tun interface, so that the routes stay as they are. The
+/// packets taken are dropped, and those sent in return go out as they are through raw sockets.
+pub struct NfQueue {
+    netlink: Netlink,
+    queue: u16,
+    raw4: OwnedFd,
+    raw6: OwnedFd,
+}
+
+impl NfQueue {
+    /// Bind to the queue with the given number, which takes the `CAP_NET_ADMIN` capability, as do
+    /// the verdicts on the packets later on.
+    pub fn bind(queue: u16) -> Result<Self, Error> {
+        let mut netlink = Netlink::netfilter()?;
+        let mut payload = header(queue);
+        // struct nfqnl_msg_config_cmd: the command, padding and the protocol family, now unused.
+        let command = [libc::NFQNL_CFG_CMD_BIND as u8, 0, 0, 0];
+        push_attribute(&mut payload, libc::NFQA_CFG_CMD as u16, &command);
+        // struct nfqnl_msg_config_params: how many bytes of each packet to copy, and how.
+        let mut params = u32::from(u16::MAX).to_be_bytes().to_vec();
+        params.push(libc::NFQNL_COPY_PACKET as u8);
+        push_attribute(&mut payload, libc::NFQA_CFG_PARAMS as u16, &params);
+        netlink.request(message_type(libc::NFQNL_MSG_CONFIG), 0, &payload)?;
+
+        let raw4 = raw_socket(AddressFamily::Inet)?;
+        let raw6 = raw_socket(AddressFamily::Inet6)?;
+        log::info!("Taking the packets of netfilter queue {queue}");
+        Ok(Self {
+            netlink,
+            queue,
+            raw4,
+            raw6,
+        })
+    }
+
+    /// Mark the packets sent in return, e.g. so that the rule filling the queue skips them.
+    pub fn set_mark(&self, mark: u32) -> Result<(), Error> {
+        socket::setsockopt(self.raw4.as_raw_fd(), sockopt::Mark, &mark)?;
+        socket::setsockopt(self.raw6.as_raw_fd(), sockopt::Mark, &mark)?;
+        Ok(())
+    }
+
+    /// Take the packets in a thread of their own, started along with the tunnel, which passes them
+    /// to the returned source, to be passed as [`NetworkInterface::Packets`](crate::NetworkInterface).
+    pub fn start(self) -> Result<PacketSource, Error> {
+        let (source, packets) = PacketSource::new();
+        let poll = Poll::new()?;
+        let fd = self.netlink.as_raw_fd();
+        poll.registry()
+            .register(&mut SourceFd(&fd), NETLINK_TOKEN, Interest::READABLE)?;
+        packets.attach(Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?));
+        Ok(source.with_worker(move || {
+            if let Err(e) = self.run(poll, packets) {
+                log::error!("{e}");
+            }
+        }))
+    }
+
+    // Drop the packet with the given ID, which the engine has taken over.
+    fn drop_packet(&mut self, id: u32) -> Result<(), Error> {
+        let mut payload = header(self.queue);
+        // struct nfqnl_msg_verdict_hdr: the verdict and the ID of the packet.
+        let mut verdict = (libc::NF_DROP as u32).to_be_bytes().to_vec();
+        verdict.extend(id.to_be_bytes());
+        push_attribute(&mut payload, libc::NFQA_VERDICT_HDR as u16, &verdict);
+        self.netlink
+            .send(message_type(libc::NFQNL_MSG_VERDICT), 0, &payload)
+    }
+
+    // Take the packets queued so far, returning whether the engine is still there to take them.
+    fn receive(&mut self, buffer: &mut [u8], packets: &PacketHandle) -> Result<bool, Error> {
+        loop {
+            let fd = self.netlink.as_raw_fd();
+            let size = match socket::recv(fd, buffer, MsgFlags::MSG_DONTWAIT) {
+                Ok(size) => size,
+                Err(Errno::EAGAIN) => return Ok(true),
+                // The kernel drops the packets which did not fit into the buffer of the socket.
+                Err(Errno::ENOBUFS) => {
+                    log::warn!("Packets of netfilter queue {} have been lost", self.queue);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            for (kind, _, payload) in netlink::messages(&buffer[..size]) {
+                if kind == libc::NLMSG_ERROR as u16 {
+                    match netlink::error_of(payload) {
+                        Some(e) => return Err(format!("Verdict on a queued packet: {e}").into()),
+                        None => continue,
+                    }
+                }
+                if kind != message_type(libc::NFQNL_MSG_PACKET) {
+                    continue;
+                }
+                let mut id = None;
+                let mut packet = None;
+                for (attribute, data) in netlink::attributes(payload.get(4..).unwrap_or_default()) {
+                    match attribute as libc::c_int {
+                        // struct nfqnl_msg_packet_hdr, which starts with the ID of the packet.
+                        libc::NFQA_PACKET_HDR if data.len() >= 4 => {
+                            id = Some(u32::from_be_bytes(data[..4].try_into().unwrap()));
+                        }
+                        libc::NFQA_PAYLOAD => packet = Some(data),
+                        _ => {}
+                    }
+                }
+                if let Some(id) = id {
+                    self.drop_packet(id)?;
+                }
+                if let Some(packet) = packet {
+                    if packets.inject(packet.to_vec()).is_err() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+
+    // Send a packet of the engine to the address it is destined for.
+    fn send(&self, packet: &[u8]) -> Result<(), Error> {
+        let flags = MsgFlags::empty();
+        let malformed = |_| Error::from("Malformed packet");
+        match IpVersion::of_packet(packet).map_err(malformed)? {
+            IpVersion::Ipv4 => {
+                let ip = Ipv4Packet::new_checked(packet).map_err(malformed)?;
+                let dst = Ipv4Addr::from(ip.dst_addr());
+                let addr = SockaddrIn::from(SocketAddrV4::new(dst, 0));
+                socket::sendto(self.raw4.as_raw_fd(), packet, &addr, flags)?;
+            }
+            IpVersion::Ipv6 => {
+                let ip = Ipv6Packet::new_checked(packet).map_err(malformed)?;
+                let dst = Ipv6Addr::from(ip.dst_addr());
+                let addr = SockaddrIn6::from(SocketAddrV6::new(dst, 0, 0, 0));
+                socket::sendto(self.raw6.as_raw_fd(), packet, &addr, flags)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self, mut poll: Poll, packets: PacketHandle) -> Result<(), Error> {
+        let mut events = Events::with_capacity(16);
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            if let Err(e) = poll.poll(&mut events, None) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            if events.iter().any(|event| event.token() == NETLINK_TOKEN)
+                && !self.receive(&mut buffer, &packets)?
+            {
+                // The engine is gone once it has dropped the packet source.
+                return Ok(());
+            }
+            while let Some(packet) = packets.try_extract() {
+                if let Err(e) = self.send(&packet) {
+                    log::debug!("Cannot send a packet of the engine: {e}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::error::Error;
+use crate::Credentials;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIGNATURE: &[u8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSION_SECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSION_SECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+// Seconds between 1601-01-01, the epoch of Windows file times, and the Unix epoch.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The NEGOTIATE_MESSAGE which starts the authentication.
+pub(crate) fn negotiate_message() -> Vec<u8> {
+    let mut message = SIGNATURE.to_vec();
+    message.extend(1u32.to_le_bytes());
+    message.extend(FLAGS.to_le_bytes());
+    // Neither a domain nor a workstation is supplied.
+    message.extend([0u8; 16]);
+    message
+}
+
+/// The contents of a CHALLENGE_MESSAGE needed to answer it.
+pub(crate) struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    pub fn parse(message: &[u8]) -> Result<Self, Error> {
+        let e = "malformed NTLM challenge";
+        if message.len() < 32 || !message.starts_with(SIGNATURE) {
+            return Err(e.into());
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes(message[offset..offset + 4].try_into().expect("four bytes"))
+        };
+        if read_u32(8) != 2 {
+            return Err(e.into());
+        }
+        let flags = read_u32(20);
+        let server_challenge = message[24..32].try_into().expect("eight bytes");
+
+        let mut target_info = Vec::new();
+        if flags & NEGOTIATE_TARGET_INFO != 0 && message.len() >= 48 {
+            let len = u16::from_le_bytes([message[40], message[41]]) as usize;
+            let offset = read_u32(44) as usize;
+            target_info = message.get(offset..offset + len).ok_or(e)?.to_vec();
+        }
+        Ok(Self {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    fn timestamp(&self) -> Option<[u8; 8]> {
+        let mut info = self.target_info.as_slice();
+        while info.len() >= 4 {
+            let id = u16::from_le_bytes([info[0], info[1]]);
+            let len = u16::from_le_bytes([info[2], info[3]]) as usize;
+            let value = info.get(4..4 + len)?;
+            match id {
+                AV_EOL => break,
+                AV_TIMESTAMP => return value.try_into().ok(),
+                _ => info = &info[4 + len..],
+            }
+        }
+        None
+    }
+}
+
+/// The AUTHENTICATE_MESSAGE answering the challenge with NTLMv2 responses. A domain can be
+/// supplied through a user name of the form `DOMAIN\user`.
+pub(crate) fn authenticate_message(
+    credentials: &Credentials,
+    challenge: &Challenge,
+) -> Result<Vec<u8>, Error> {
+    let mut client_challenge = [0u8; 8];
+    getrandom::getrandom(&mut client_challenge)
+        .map_err(|_| Error::from("failed to obtain random bytes"))?;
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let filetime = (since_epoch.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000
+        + since_epoch.subsec_nanos() as u64 / 100;
+    Ok(answer(
+        credentials,
+        challenge,
+        client_challenge,
+        filetime.to_le_bytes(),
+    ))
+}
+
+/// The AUTHENTICATE_MESSAGE for the given client challenge, taking the time of the server if it
+/// supplies one and `now` otherwise.
+fn answer(
+    credentials: &Credentials,
+    challenge: &Challenge,
+    client_challenge: [u8; 8],
+    now: [u8; 8],
+) -> Vec<u8> {
+    let username = String::from_utf8_lossy(&credentials.username);
+    let password = String::from_utf8_lossy(&credentials.password);
+    let (domain, user) = username.split_once('\\').unwrap_or(("", &username));
+
+    let nt_hash = Md4::digest(utf16le(&password));
+    let identity = utf16le(&(user.to_uppercase() + domain));
+    let response_key = hmac_md5(&nt_hash, &[&identity]);
+
+    let server_timestamp = challenge.timestamp();
+    let timestamp = server_timestamp.unwrap_or(now);
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend(timestamp);
+    blob.extend(client_challenge);
+    blob.extend([0u8; 4]);
+    blob.extend(&challenge.target_info);
+    blob.extend([0u8; 4]);
+
+    let mut nt_response = hmac_md5(&response_key, &[&challenge.server_challenge, &blob]).to_vec();
+    nt_response.extend(&blob);
+    // The LMv2 response is omitted when the server supplies a timestamp.
+    let lm_response = match server_timestamp {
+        Some(_) => vec![0u8; 24],
+        None => {
+            let mut response = hmac_md5(
+                &response_key,
+                &[&challenge.server_challenge, &client_challenge],
+            )
+            .to_vec();
+            response.extend(client_challenge);
+            response
+        }
+    };
+
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16le(domain),
+        utf16le(user),
+        Vec::new(),
+        Vec::new(),
+    ];
+    let mut message = SIGNATURE.to_vec();
+    message.extend(3u32.to_le_bytes());
+    let mut offset = 64u32;
+    for field in &fields {
+        message.extend((field.len() as u16).to_le_bytes());
+        message.extend((field.len() as u16).to_le_bytes());
+        message.extend(offset.to_le_bytes());
+        offset += field.len() as u32;
+    }
+    let flags = challenge.flags & FLAGS & !NEGOTIATE_OEM | NEGOTIATE_UNICODE;
+    message.extend(flags.to_le_bytes());
+    for field in fields {
+        message.extend(field);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The NTLMv2 example of MS-NLMP, section 4.2.4, for the user `Domain\User` with the password
+    // `Password`, the server challenge 0123456789abcdef and the client challenge aa..aa.
+    const CHALLENGE_MESSAGE: &str = "4e544c4d53535000020000000c000c003800000033828ae20123456789ab\
+        cdef00000000000000002400240044000000060070170000000f53006500720076006500720002000c0044006f\
+        006d00610069006e0001000c0053006500720076006500720000000000";
+    const TARGET_INFO: &str = "02000c0044006f006d00610069006e0001000c0053006500720076006500720000\
+        000000";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn credentials() -> Credentials {
+        Credentials::new("Domain\\User", "Password")
+    }
+
+    // The fields of an AUTHENTICATE_MESSAGE in the order of their descriptors.
+    fn fields(message: &[u8]) -> Vec<&[u8]> {
+        (0..6)
+            .map(|i| {
+                let descriptor = &message[12 + 8 * i..20 + 8 * i];
+                let len = u16::from_le_bytes([descriptor[0], descriptor[1]]) as usize;
+                let offset = u32::from_le_bytes(descriptor[4..].try_into().unwrap()) as usize;
+                &message[offset..offset + len]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_challenge() {
+        let challenge = Challenge::parse(&from_hex(CHALLENGE_MESSAGE)).unwrap();
+        assert_eq!(challenge.flags, 0xe28a8233);
+        assert_eq!(
+            challenge.server_challenge,
+            [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
+        );
+        assert_eq!(challenge.target_info, from_hex(TARGET_INFO));
+        assert_eq!(challenge.timestamp(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_challenges() {
+        let message = from_hex(CHALLENGE_MESSAGE);
+        assert!(Challenge::parse(&message[..31]).is_err());
+        assert!(Challenge::parse(&message[..70]).is_err());
+        let mut negotiate = message.clone();
+        negotiate[8] = 1;
+        assert!(Challenge::parse(&negotiate).is_err());
+    }
+
+    #[test]
+    fn nlmp_ntlmv2_responses() {
+        let challenge = Challenge::parse(&from_hex(CHALLENGE_MESSAGE)).unwrap();
+        let message = answer(&credentials(), &challenge, [0xaa; 8], [0; 8]);
+        assert_eq!(message[..12], *b"NTLMSSP\0\x03\0\0\0");
+        let fields = fields(&message);
+
+        // LMv2 response, section 4.2.4.2.1
+        let mut lm_response = from_hex("86c35097ac9cec102554764a57cccc19");
+        lm_response.extend([0xaa; 8]);
+        assert_eq!(fields[0], lm_response);
+
+        // NTProofStr, section 4.2.4.2.2, followed by the blob of section 4.2.4.1.3
+        let mut nt_response = from_hex("68cd0ab851e51c96aabc927bebef6a1c");
+        nt_response.extend([1, 1, 0, 0, 0, 0, 0, 0]);
+        nt_response.extend([0; 8]);
+        nt_response.extend([0xaa; 8]);
+        nt_response.extend([0; 4]);
+        nt_response.extend(from_hex(TARGET_INFO));
+        nt_response.extend([0; 4]);
+        assert_eq!(fields[1], nt_response);
+
+        assert_eq!(fields[2], utf16le("Domain"));
+        assert_eq!(fields[3], utf16le("User"));
+        assert!(fields[4].is_empty() && fields[5].is_empty());
+        let flags = u32::from_le_bytes(message[60..64].try_into().unwrap());
+        assert_eq!(flags, 0xa0888201);
+        assert_eq!(
+            message.len(),
+            64 + fields.iter().map(|f| f.len()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn takes_timestamp_of_server() {
+        let target_info = from_hex("02000c0044006f006d00610069006e00070008000102030405060708");
+        let challenge = Challenge {
+            flags: FLAGS,
+            server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            target_info: [target_info, vec![0; 4]].concat(),
+        };
+        assert_eq!(challenge.timestamp(), Some([1, 2, 3, 4, 5, 6, 7, 8]));
+        let message = answer(&credentials(), &challenge, [0xaa; 8], [0xff; 8]);
+        let fields = fields(&message);
+        // The LMv2 response is left out, and the NTLMv2 blob holds the time of the server.
+        assert_eq!(fields[0], [0; 24]);
+        assert_eq!(
+            fields[1][..16],
+            from_hex("164d746a0100fbd6c2b1bcb5014e9fec")
+        );
+        assert_eq!(fields[1][24..32], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
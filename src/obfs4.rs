@@ -0,0 +1,651 @@
+use crate::error::Error;
+use crate::obfuscation::Obfuscation;
+use base64::Engine;
+use crypto_secretbox::aead::{AeadInPlace, KeyInit};
+use crypto_secretbox::XSalsa20Poly1305;
+use curve25519_dalek::constants::EIGHT_TORSION;
+use curve25519_dalek::EdwardsPoint;
+use hmac::{Hmac, Mac};
+use num_bigint::BigUint;
+use sha2::Sha256;
+use siphasher::sip::SipHasher24;
+use std::convert::TryInto;
+use std::hash::Hasher;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:key_verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+const PROTO_ID: &[u8] = b"ntor-curve25519-sha256-1";
+
+const NODE_ID_LENGTH: usize = 20;
+const KEY_LENGTH: usize = 32;
+const MARK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 16;
+const MAX_HANDSHAKE_LENGTH: usize = 8192;
+// The client handshake is padded to at least the length of the server handshake, including the
+// frame carrying the PRNG seed of the server.
+const CLIENT_MIN_PAD_LENGTH: usize = 77;
+const CLIENT_MAX_PAD_LENGTH: usize = MAX_HANDSHAKE_LENGTH - (KEY_LENGTH + MARK_LENGTH + MAC_LENGTH);
+
+const MAX_SEGMENT_LENGTH: usize = 1448;
+const LENGTH_LENGTH: usize = 2;
+const TAG_LENGTH: usize = 16;
+const PACKET_OVERHEAD: usize = 3;
+const MAX_PACKET_PAYLOAD_LENGTH: usize =
+    MAX_SEGMENT_LENGTH - LENGTH_LENGTH - TAG_LENGTH - PACKET_OVERHEAD;
+const PACKET_PAYLOAD: u8 = 0;
+// Secretbox key, nonce prefix and seed of the length obfuscation.
+const FRAMING_KEY_LENGTH: usize = 32 + 16 + 24;
+
+// The coefficient A of Curve25519 in Montgomery form.
+const CURVE_A: u32 = 486_662;
+
+/// Parameters of an obfs4 bridge, taken from the `cert` parameter of its bridge line.
+#[derive(Clone, Debug)]
+pub struct Obfs4Options {
+    /// Identity of the bridge
+    pub node_id: [u8; NODE_ID_LENGTH],
+    /// Long-term public key of the bridge
+    pub public_key: [u8; KEY_LENGTH],
+}
+
+impl Obfs4Options {
+    /// Parse the base64-encoded node ID and public key of a `cert` parameter.
+    pub(crate) fn from_cert(cert: &str) -> Result<Self, Error> {
+        let e = || Error::from(format!("`{cert}` is not a valid obfs4 certificate"));
+        // A `+` which has not been percent-encoded in the URL is decoded as a space.
+        let encoded = cert.replace(' ', "+");
+        let bytes = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|_| e())?;
+        if bytes.len() != NODE_ID_LENGTH + KEY_LENGTH {
+            return Err(e());
+        }
+        let mut options = Self {
+            node_id: [0; NODE_ID_LENGTH],
+            public_key: [0; KEY_LENGTH],
+        };
+        options.node_id.copy_from_slice(&bytes[..NODE_ID_LENGTH]);
+        options.public_key.copy_from_slice(&bytes[NODE_ID_LENGTH..]);
+        Ok(options)
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// HKDF with SHA-256, salted with `T_KEY` and expanded with `M_EXPAND`.
+fn kdf(seed: &[u8], length: usize) -> Vec<u8> {
+    let secret = hmac_sha256(T_KEY, &[seed]);
+    let mut result = Vec::new();
+    let mut previous = Vec::new();
+    let mut counter = 1u8;
+    while result.len() < length {
+        previous = hmac_sha256(&secret, &[&previous, M_EXPAND, &[counter]]).to_vec();
+        result.extend_from_slice(&previous);
+        counter += 1;
+    }
+    result.truncate(length);
+    result
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    Ok(bytes)
+}
+
+// A random number in `min..=max`.
+fn random_range(min: usize, max: usize) -> Result<usize, Error> {
+    let random = u64::from_le_bytes(random_bytes()?);
+    Ok(min + (random % (max - min + 1) as u64) as usize)
+}
+
+fn prime() -> BigUint {
+    (BigUint::from(1u8) << 255u32) - 19u32
+}
+
+fn is_square(x: &BigUint, p: &BigUint) -> bool {
+    x.modpow(&((p - 1u8) >> 1u32), p) != p - 1u8
+}
+
+fn invert(x: &BigUint, p: &BigUint) -> BigUint {
+    x.modpow(&(p - 2u8), p)
+}
+
+// The square root of a square, using that p = 5 (mod 8).
+fn sqrt(x: &BigUint, p: &BigUint) -> BigUint {
+    let root = x.modpow(&((p + 3u8) >> 3u32), p);
+    if (&root * &root) % p == *x {
+        root
+    } else {
+        let sqrt_minus_one = BigUint::from(2u8).modpow(&((p - 1u8) >> 2u32), p);
+        (root * sqrt_minus_one) % p
+    }
+}
+
+fn to_bytes(x: &BigUint) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    let le = x.to_bytes_le();
+    bytes[..le.len()].copy_from_slice(&le);
+    bytes
+}
+
+/// The Elligator2 representative of a Curve25519 public key, which cannot be told apart from
+/// random bytes. Only about half of all public keys have one.
+fn representative(public_key: &[u8; 32]) -> Option<[u8; 32]> {
+    let p = prime();
+    let u = BigUint::from_bytes_le(public_key) % &p;
+    let u_plus_a = (&u + CURVE_A) % &p;
+    if u == BigUint::default() || u_plus_a == BigUint::default() {
+        return None;
+    }
+    // A representative exists if -2u(u + A) is a square.
+    let product = (&u * &u_plus_a * 2u8) % &p;
+    if !is_square(&(&p - product), &p) {
+        return None;
+    }
+    let r2 = ((&p - &u) * invert(&(u_plus_a * 2u8), &p)) % &p;
+    let r = sqrt(&r2, &p);
+    // The representatives r and -r map to the same key, the smaller one is chosen.
+    let r = if r > (&p - 1u8) >> 1u32 { &p - r } else { r };
+    Some(to_bytes(&r))
+}
+
+/// The public key of `private_key` with the point of low order chosen by `tweak` added, and its
+/// representative with its two unused most significant bits set as chosen by `tweak`, if there
+/// is one. Otherwise, the public keys would all be in the prime-order subgroup, and the high bits
+/// clear, which tells the representatives apart from random bytes. The point of low order does
+/// not change the shared secrets, as private keys are multiples of the cofactor.
+fn dirty_public_key(private_key: &[u8; 32], tweak: u8) -> Option<([u8; 32], [u8; 32])> {
+    let point = EdwardsPoint::mul_base_clamped(*private_key) + EIGHT_TORSION[(tweak & 7) as usize];
+    let public_key = point.to_montgomery().to_bytes();
+    let mut representative = representative(&public_key)?;
+    representative[31] |= tweak & 0xc0;
+    Some((public_key, representative))
+}
+
+/// The public key mapped to by an Elligator2 representative.
+fn public_key(representative: &[u8; 32]) -> [u8; 32] {
+    let p = prime();
+    let mut bytes = *representative;
+    // The two most significant bits may be set randomly.
+    bytes[31] &= 0x3f;
+    let r = BigUint::from_bytes_le(&bytes);
+    let a = BigUint::from(CURVE_A);
+    // v = -A / (1 + 2r^2)
+    let denominator = (&r * &r * 2u8 + 1u8) % &p;
+    let v = (&p - (&a * invert(&denominator, &p)) % &p) % &p;
+    // Either v or -v - A is the coordinate of a point on the curve.
+    let g = (&v * &v * &v + &a * &v * &v + &v) % &p;
+    let u = if is_square(&g, &p) {
+        v
+    } else {
+        (&p * 2u8 - v - a) % &p
+    };
+    to_bytes(&u)
+}
+
+/// SipHash-2-4 in OFB mode, which masks the lengths of frames. Like the reference
+/// implementation, the hash state is kept across blocks.
+struct Drbg {
+    hasher: SipHasher24,
+    ofb: [u8; 8],
+}
+
+impl Drbg {
+    fn new(seed: &[u8]) -> Self {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&seed[..16]);
+        let mut ofb = [0u8; 8];
+        ofb.copy_from_slice(&seed[16..24]);
+        Self {
+            hasher: SipHasher24::new_with_key(&key),
+            ofb,
+        }
+    }
+
+    fn next_block(&mut self) -> [u8; 8] {
+        self.hasher.write(&self.ofb);
+        self.ofb = self.hasher.finish().to_le_bytes();
+        self.ofb
+    }
+}
+
+/// Seals frames in one direction, each of which carries a packet.
+struct Framing {
+    cipher: XSalsa20Poly1305,
+    nonce_prefix: [u8; 16],
+    counter: u64,
+    drbg: Drbg,
+    // Length of the frame being received, once its length field has been read.
+    next_length: Option<usize>,
+}
+
+impl Framing {
+    fn new(key: &[u8]) -> Self {
+        let mut nonce_prefix = [0u8; 16];
+        nonce_prefix.copy_from_slice(&key[32..48]);
+        Self {
+            cipher: XSalsa20Poly1305::new_from_slice(&key[..32]).expect("valid key length"),
+            nonce_prefix,
+            counter: 1,
+            drbg: Drbg::new(&key[48..FRAMING_KEY_LENGTH]),
+            next_length: None,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 24] {
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(&self.nonce_prefix);
+        nonce[16..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let mut buffer = packet.to_vec();
+        let nonce = self.nonce();
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce.into(), b"", &mut buffer)
+            .expect("encryption does not fail");
+        let mask = self.drbg.next_block();
+        let length = ((TAG_LENGTH + buffer.len()) as u16).to_be_bytes();
+        out.extend([length[0] ^ mask[0], length[1] ^ mask[1]]);
+        out.extend_from_slice(&tag);
+        out.extend(buffer);
+    }
+
+    // Open the next frame at the front of `data`, if it has been received completely.
+    fn open(&mut self, data: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        let length = match self.next_length {
+            Some(length) => length,
+            None if data.len() < LENGTH_LENGTH => return Ok(None),
+            None => {
+                let mask = self.drbg.next_block();
+                let length = u16::from_be_bytes([data[0] ^ mask[0], data[1] ^ mask[1]]) as usize;
+                if !(TAG_LENGTH..=MAX_SEGMENT_LENGTH - LENGTH_LENGTH).contains(&length) {
+                    return Err("invalid obfs4 frame length".into());
+                }
+                data.drain(..LENGTH_LENGTH);
+                self.next_length = Some(length);
+                length
+            }
+        };
+        if data.len() < length {
+            return Ok(None);
+        }
+        let mut buffer: Vec<u8> = data.drain(..length).collect();
+        let tag = buffer.drain(..TAG_LENGTH).collect::<Vec<u8>>();
+        let nonce = self.nonce();
+        self.cipher
+            .decrypt_in_place_detached(&nonce.into(), b"", &mut buffer, tag.as_slice().into())
+            .map_err(|_| Error::from("obfs4 frame authentication failed"))?;
+        self.next_length = None;
+        Ok(Some(buffer))
+    }
+}
+
+/// The client side of obfs4, which authenticates the bridge through an ntor handshake and then
+/// carries the stream in encrypted frames of obfuscated length.
+pub(crate) struct Obfs4 {
+    options: Obfs4Options,
+    private_key: [u8; 32],
+    public_key: [u8; 32],
+    representative: [u8; 32],
+    // Hours since the epoch as a decimal string, which the server has to use for its MAC as well.
+    epoch_hour: Vec<u8>,
+    // The server handshake, and afterwards the frames, which have not been processed yet.
+    inbuf: Vec<u8>,
+    encoder: Option<Framing>,
+    decoder: Option<Framing>,
+}
+
+impl Obfs4 {
+    pub fn new(options: &Obfs4Options) -> Result<Self, Error> {
+        // Retry until the public key has a representative.
+        let (private_key, public_key, representative) = loop {
+            let private_key: [u8; 32] = random_bytes()?;
+            let [tweak] = random_bytes()?;
+            if let Some((public_key, representative)) = dirty_public_key(&private_key, tweak) {
+                break (private_key, public_key, representative);
+            }
+        };
+        let hours = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 3600;
+        Ok(Self {
+            options: options.clone(),
+            private_key,
+            public_key,
+            representative,
+            epoch_hour: hours.to_string().into_bytes(),
+            inbuf: Vec::new(),
+            encoder: None,
+            decoder: None,
+        })
+    }
+
+    fn mac_key(&self) -> Vec<u8> {
+        [&self.options.public_key[..], &self.options.node_id[..]].concat()
+    }
+
+    // Verify the handshake of the server, returning its length and the derived key seed once it
+    // has been received completely.
+    fn parse_server_handshake(&self) -> Result<Option<(usize, [u8; 32])>, Error> {
+        let key = self.mac_key();
+        let data = &self.inbuf;
+        if data.len() < 2 * KEY_LENGTH + MARK_LENGTH + MAC_LENGTH {
+            return Ok(None);
+        }
+        let server_representative: [u8; 32] = data[..KEY_LENGTH].try_into().unwrap();
+        let auth = &data[KEY_LENGTH..2 * KEY_LENGTH];
+        let mark = &hmac_sha256(&key, &[&server_representative])[..MARK_LENGTH];
+
+        let end = data.len().min(MAX_HANDSHAKE_LENGTH);
+        let position = (2 * KEY_LENGTH..=end - MARK_LENGTH - MAC_LENGTH)
+            .find(|&i| &data[i..i + MARK_LENGTH] == mark);
+        let position = match position {
+            Some(position) => position,
+            None if data.len() >= MAX_HANDSHAKE_LENGTH => {
+                return Err("invalid obfs4 handshake".into())
+            }
+            None => return Ok(None),
+        };
+        let mac = hmac_sha256(&key, &[&data[..position + MARK_LENGTH], &self.epoch_hour]);
+        let received = &data[position + MARK_LENGTH..position + MARK_LENGTH + MAC_LENGTH];
+        if mac[..MAC_LENGTH] != *received {
+            return Err("obfs4 handshake authentication failed".into());
+        }
+
+        // Complete the ntor handshake.
+        let server_public = public_key(&server_representative);
+        let mut secret_input = x25519_dalek::x25519(self.private_key, server_public).to_vec();
+        secret_input.extend(x25519_dalek::x25519(
+            self.private_key,
+            self.options.public_key,
+        ));
+        if secret_input[..32] == [0; 32] || secret_input[32..] == [0; 32] {
+            return Err("obfs4 key exchange failed".into());
+        }
+        let common: &[&[u8]] = &[
+            &self.options.node_id,
+            &self.options.public_key,
+            &self.public_key,
+            &server_public,
+            PROTO_ID,
+        ];
+        secret_input.extend(common.concat());
+        let seed = hmac_sha256(T_KEY, &[&secret_input]);
+        let verify = hmac_sha256(T_VERIFY, &[&secret_input]);
+        let expected = hmac_sha256(
+            T_MAC,
+            &[
+                &verify,
+                &self.options.node_id,
+                &self.options.public_key,
+                &server_public,
+                &self.public_key,
+                PROTO_ID,
+                b"Server",
+            ],
+        );
+        if expected[..] != *auth {
+            return Err("obfs4 bridge could not be authenticated".into());
+        }
+        Ok(Some((position + MARK_LENGTH + MAC_LENGTH, seed)))
+    }
+}
+
+impl Obfuscation for Obfs4 {
+    fn start(&mut self) -> Result<Vec<u8>, Error> {
+        // The client handshake is X | P_C | M_C | MAC(X | P_C | M_C | E).
+        let key = self.mac_key();
+        let mut handshake = self.representative.to_vec();
+        let mut padding = vec![0u8; random_range(CLIENT_MIN_PAD_LENGTH, CLIENT_MAX_PAD_LENGTH)?];
+        getrandom::getrandom(&mut padding)
+            .map_err(|_| Error::from("failed to obtain random bytes"))?;
+        handshake.extend(padding);
+        handshake.extend_from_slice(&hmac_sha256(&key, &[&self.representative])[..MARK_LENGTH]);
+        let mac = hmac_sha256(&key, &[&handshake, &self.epoch_hour]);
+        handshake.extend_from_slice(&mac[..MAC_LENGTH]);
+        Ok(handshake)
+    }
+
+    fn receive(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.inbuf.extend_from_slice(data);
+        if self.decoder.is_none() {
+            let (length, seed) = match self.parse_server_handshake()? {
+                Some(handshake) => handshake,
+                None => return Ok(Vec::new()),
+            };
+            self.inbuf.drain(..length);
+            let keys = kdf(&seed, 2 * FRAMING_KEY_LENGTH);
+            self.encoder = Some(Framing::new(&keys[..FRAMING_KEY_LENGTH]));
+            self.decoder = Some(Framing::new(&keys[FRAMING_KEY_LENGTH..]));
+        }
+
+        let decoder = self.decoder.as_mut().expect("handshake is complete");
+        let mut result = Vec::new();
+        while let Some(packet) = decoder.open(&mut self.inbuf)? {
+            if packet.len() < PACKET_OVERHEAD {
+                return Err("invalid obfs4 packet".into());
+            }
+            let length = u16::from_be_bytes([packet[1], packet[2]]) as usize;
+            let payload = packet
+                .get(PACKET_OVERHEAD..PACKET_OVERHEAD + length)
+                .ok_or("invalid obfs4 packet length")?;
+            // Other packets, e.g. the PRNG seed of the server, only shape the traffic.
+            if packet[0] == PACKET_PAYLOAD {
+                result.extend_from_slice(payload);
+            }
+        }
+        Ok(result)
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let encoder = self
+            .encoder
+            .as_mut()
+            .ok_or("obfs4 handshake is not complete")?;
+        let mut result = Vec::new();
+        let mut chunks = data.chunks(MAX_PACKET_PAYLOAD_LENGTH).peekable();
+        while let Some(chunk) = chunks.next() {
+            // The last packet is padded to a random length.
+            let padding = match chunks.peek() {
+                Some(_) => 0,
+                None => random_range(0, MAX_PACKET_PAYLOAD_LENGTH - chunk.len())?,
+            };
+            let mut packet = vec![PACKET_PAYLOAD];
+            packet.extend((chunk.len() as u16).to_be_bytes());
+            packet.extend_from_slice(chunk);
+            packet.resize(packet.len() + padding, 0);
+            encoder.seal(&packet, &mut result);
+        }
+        Ok(result)
+    }
+
+    fn is_established(&self) -> bool {
+        self.encoder.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex<const N: usize>(hex: &str) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn base_point_mult(private_key: [u8; 32]) -> [u8; 32] {
+        x25519_dalek::x25519(private_key, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+
+    #[test]
+    fn elligator_maps_representatives() {
+        let vectors = [
+            (
+                "20bb2a0adcbd7af72c2f29f5bf94eb016f08518db9678310516cf15f20be6bed",
+                "b2bf059f27c050134b926494fbf17b241fb3b9eb7c90b8dc05b5186264654e61",
+            ),
+            (
+                "6a779130ca39ee062bc3b2ad35a893a813ae6ed613c774d55ef30a26c687e07c",
+                "7e8477b44ba4d26fcf5d78e14b4a1f476e60aedb03301442c810118717d9d93d",
+            ),
+        ];
+        for (representative, expected) in vectors {
+            assert_eq!(public_key(&from_hex(representative)), from_hex(expected));
+        }
+    }
+
+    #[test]
+    fn elligator_inverts_public_keys() {
+        let private_key =
+            from_hex("90530ccfd841dfe7521375e3a01bad7316a1edb9b426fb6e8fdd952c60f00dde");
+        let public_key = base_point_mult(private_key);
+        assert_eq!(
+            public_key,
+            from_hex("d4af319174d82b9aa5cb3f2c22d8133cf22a13f97f2a60299c44840d5eec3f55")
+        );
+        assert_eq!(
+            representative(&public_key),
+            Some(from_hex(
+                "2a487d31d9e3b4c51d129381b869969b9016bfc4abbd46dd318fc5e80cf62132"
+            ))
+        );
+    }
+
+    #[test]
+    fn dirty_public_keys_round_trip() {
+        let peer = [7u8; 32];
+        // Each point of low order, with each combination of the high bits.
+        let tweaks: Vec<u8> = (0..32u8).map(|i| (i & 7) | (i & 0x18) << 3).collect();
+        let mut found = vec![false; tweaks.len()];
+        for seed in 0..16u8 {
+            let private_key = [seed; 32];
+            let clean = base_point_mult(private_key);
+            for (i, &tweak) in tweaks.iter().enumerate() {
+                let (dirty, representative) = match dirty_public_key(&private_key, tweak) {
+                    Some(keys) => keys,
+                    None => continue,
+                };
+                found[i] = true;
+                assert_eq!(public_key(&representative), dirty);
+                assert_eq!(representative[31] & 0xc0, tweak & 0xc0);
+                assert_eq!(dirty == clean, tweak & 7 == 0);
+                // The point of low order does not change the shared secret.
+                assert_eq!(
+                    x25519_dalek::x25519(peer, dirty),
+                    x25519_dalek::x25519(peer, clean)
+                );
+            }
+        }
+        assert!(found.iter().all(|&found| found));
+    }
+
+    #[test]
+    fn client_handshake_is_marked() {
+        let options = Obfs4Options {
+            node_id: [1; NODE_ID_LENGTH],
+            public_key: base_point_mult([2; 32]),
+        };
+        let mut client = Obfs4::new(&options).unwrap();
+        let handshake = client.start().unwrap();
+        let key = client.mac_key();
+        let representative: [u8; 32] = handshake[..KEY_LENGTH].try_into().unwrap();
+        assert_eq!(public_key(&representative), client.public_key);
+        let mark_end = handshake.len() - MAC_LENGTH;
+        let mark = &hmac_sha256(&key, &[&representative])[..MARK_LENGTH];
+        assert_eq!(&handshake[mark_end - MARK_LENGTH..mark_end], mark);
+        let mac = hmac_sha256(&key, &[&handshake[..mark_end], &client.epoch_hour]);
+        assert_eq!(&handshake[mark_end..], &mac[..MAC_LENGTH]);
+    }
+
+    #[test]
+    fn server_handshake_is_authenticated() {
+        let private_key =
+            from_hex("948fe603f61dc036b5c596dc09fe3ce3f3d30dc90f024c85f3c82db2ccab679d");
+        let options = Obfs4Options {
+            node_id: from_hex("545ea538461003efdc8c81c244531b003f6f26cf"),
+            public_key: from_hex(
+                "dd33f3ca32a19b803848943bdb9d42426a04054b1ee799e5ac41025f44d67344",
+            ),
+        };
+        let mut client = Obfs4 {
+            options,
+            private_key,
+            public_key: base_point_mult(private_key),
+            representative: [0; 32],
+            epoch_hour: b"500000".to_vec(),
+            inbuf: Vec::new(),
+            encoder: None,
+            decoder: None,
+        };
+        let seed: [u8; 32] =
+            from_hex("15fd494e2aa335969d5fec92fcf79504f6ab1d6656d03250c0c91c97e7819a9e");
+
+        // The server handshake is Y | AUTH | P_S | M_S | MAC(Y | AUTH | P_S | M_S | E).
+        let key = client.mac_key();
+        let server_representative: [u8; 32] =
+            from_hex("03eabc259695cbb7dd9904037e28220f78539fdab10610f528f5eb631fe88a15");
+        let mut handshake = server_representative.to_vec();
+        handshake.extend_from_slice(&from_hex::<32>(
+            "6ca0e2cd12c32e5f9447431281588d6aad8e033c88ff7a97cd3ae1eebb0d24e7",
+        ));
+        handshake.extend([0u8; 100]);
+        handshake.extend_from_slice(&hmac_sha256(&key, &[&server_representative])[..MARK_LENGTH]);
+        let mac = hmac_sha256(&key, &[&handshake, &client.epoch_hour]);
+        handshake.extend_from_slice(&mac[..MAC_LENGTH]);
+
+        // Followed by a frame of the server.
+        let keys = kdf(&seed, 2 * FRAMING_KEY_LENGTH);
+        let mut server_encoder = Framing::new(&keys[FRAMING_KEY_LENGTH..]);
+        let mut server_decoder = Framing::new(&keys[..FRAMING_KEY_LENGTH]);
+        let packet = [&[PACKET_PAYLOAD, 0, 5][..], &b"hello"[..], &[0; 10][..]].concat();
+        server_encoder.seal(&packet, &mut handshake);
+
+        // Split, so that the handshake is only complete with the second part.
+        assert!(client.receive(&handshake[..150]).unwrap().is_empty());
+        assert!(!client.is_established());
+        assert_eq!(client.receive(&handshake[150..]).unwrap(), b"hello");
+
+        let mut frames = client.send(b"world").unwrap();
+        let packet = server_decoder.open(&mut frames).unwrap().unwrap();
+        assert_eq!(&packet[..PACKET_OVERHEAD + 5], b"\x00\x00\x05world");
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn forged_server_handshake_is_rejected() {
+        let options = Obfs4Options {
+            node_id: [1; NODE_ID_LENGTH],
+            public_key: base_point_mult([2; 32]),
+        };
+        let mut client = Obfs4::new(&options).unwrap();
+        let key = client.mac_key();
+        let (_, server_representative) = (0..=255u8)
+            .find_map(|seed| dirty_public_key(&[seed; 32], 0))
+            .unwrap();
+        let mut handshake = server_representative.to_vec();
+        handshake.extend([0u8; KEY_LENGTH]);
+        handshake.extend_from_slice(&hmac_sha256(&key, &[&server_representative])[..MARK_LENGTH]);
+        let mac = hmac_sha256(&key, &[&handshake, &client.epoch_hour]);
+        handshake.extend_from_slice(&mac[..MAC_LENGTH]);
+        assert!(client.receive(&handshake).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::Error;
+use crate::tun2proxy::{
+    Direction, IncomingDataEvent, IncomingDirection, OutgoingDataEvent, OutgoingDirection,
+    OutgoingSlices, TcpProxy,
+};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Transforms the stream of a connection to the proxy server so that it cannot be recognized by
+/// deep packet inspection, e.g. obfs4. Implementations only see the bytes of the stream, while
+/// the proxy protocol is handled by the wrapped handler.
+pub(crate) trait Obfuscation {
+    /// Data sent to the server ahead of everything else, e.g. a handshake.
+    fn start(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Process data received from the server, returning the data for the wrapped handler.
+    fn receive(&mut self, data: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Obfuscate data of the wrapped handler. Only called once the obfuscation is established.
+    fn send(&mut self, data: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Whether data of the wrapped handler can be sent.
+    fn is_established(&self) -> bool;
+}
+
+/// Passes the connection to the proxy server through an obfuscation. Data of the wrapped handler
+/// is held back until the obfuscation is established.
+pub(crate) struct ObfuscatedConnection {
+    inner: Box<dyn TcpProxy>,
+    obfuscation: Box<dyn Obfuscation>,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl ObfuscatedConnection {
+    pub fn new(
+        inner: Box<dyn TcpProxy>,
+        mut obfuscation: Box<dyn Obfuscation>,
+    ) -> Result<Self, Error> {
+        let server_outbuf = obfuscation.start()?.into();
+        let mut result = Self {
+            inner,
+            obfuscation,
+            server_outbuf,
+        };
+        result.pump()?;
+        Ok(result)
+    }
+
+    fn pump(&mut self) -> Result<(), Error> {
+        if !self.obfuscation.is_established() {
+            return Ok(());
+        }
+        let event = self.inner.peek_data(OutgoingDirection::ToServer);
+        if event.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = self.obfuscation.send(event.buffer)?;
+        let size = event.buffer.len();
+        self.inner.consume_data(OutgoingDirection::ToServer, size);
+        self.server_outbuf.extend(data);
+        Ok(())
+    }
+}
+
+impl TcpProxy for ObfuscatedConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => {
+                let data = self.obfuscation.receive(event.buffer)?;
+                if !data.is_empty() {
+                    self.inner.push_data(IncomingDataEvent {
+                        direction: IncomingDirection::FromServer,
+                        buffer: &data,
+                    })?;
+                }
+            }
+            IncomingDirection::FromClient => self.inner.push_data(event)?,
+        }
+        self.pump()
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        if dir == OutgoingDirection::ToServer {
+            self.server_outbuf.drain(0..size);
+        } else {
+            self.inner.consume_data(dir, size);
+        }
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        if dir == OutgoingDirection::ToServer {
+            OutgoingDataEvent {
+                direction: dir,
+                buffer: self.server_outbuf.make_contiguous(),
+            }
+        } else {
+            self.inner.peek_data(dir)
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        if dir == OutgoingDirection::ToServer {
+            (&self.server_outbuf).into()
+        } else {
+            self.inner.peek_slices(dir)
+        }
+    }
+
+    fn connection_established(&self) -> bool {
+        self.obfuscation.is_established() && self.inner.connection_established()
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Outgoing(OutgoingDirection::ToServer) => {
+                !self.server_outbuf.is_empty()
+                    || self.obfuscation.is_established()
+                        && self
+                            .inner
+                            .have_data(Direction::Outgoing(OutgoingDirection::ToServer))
+            }
+            _ => self.inner.have_data(dir),
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.inner.get_udp_associate()
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.inner.get_bind_address()
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.inner.hostname_refused()
+    }
+}
@@ -0,0 +1,376 @@
+#![cfg(target_os = "linux")]
+
+use smoltcp::phy::{Device, DeviceCapabilities, TunTapInterface};
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv4Packet, Ipv6Address, TcpPacket, TcpSeqNumber};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// struct virtio_net_hdr, which precedes the packets of a tun device with IFF_VNET_HDR, and the
+// values of its fields of linux/virtio_net.h used here.
+const HEADER_SIZE: usize = 10;
+const NEEDS_CSUM: u8 = 1;
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_ECN: u8 = 0x80;
+
+// The largest packet exchanged with the kernel, a TCP segment it segments or has coalesced.
+const MAX_PACKET_SIZE: usize = 0xffff;
+
+// The flags of TCP which tell the segments coalesced apart.
+const FIN: u8 = 0x01;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const CWR: u8 = 0x80;
+
+// The fields are in the byte order of the host, unless set up otherwise with TUNSETVNETLE.
+#[derive(Default)]
+struct Header {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Self {
+        let field = |at: usize| u16::from_ne_bytes([bytes[at], bytes[at + 1]]);
+        Self {
+            flags: bytes[0],
+            gso_type: bytes[1],
+            hdr_len: field(2),
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        bytes
+    }
+}
+
+// The ones' complement sum of the 16-bit words of `data` on top of `initial`, folded.
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = u64::from(initial);
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u64::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// The lengths of the IP and TCP headers of a TCP segment, unless the packet is none. Segments
+// with extension headers of IPv6 are not taken for such.
+fn tcp_headers(packet: &[u8]) -> Option<(usize, usize)> {
+    let ip_len = match packet.first()? >> 4 {
+        4 if packet.get(9) == Some(&6) => usize::from(packet[0] & 0xf) * 4,
+        6 if packet.get(6) == Some(&6) => 40,
+        _ => return None,
+    };
+    let tcp_len = usize::from(packet.get(ip_len + 12)? >> 4) * 4;
+    (ip_len >= 20 && tcp_len >= 20 && packet.len() >= ip_len + tcp_len).then_some((ip_len, tcp_len))
+}
+
+// The source and destination addresses, which are next to each other in either version of IP.
+fn addresses(packet: &[u8]) -> &[u8] {
+    match packet[0] >> 4 {
+        4 => &packet[12..20],
+        _ => &packet[8..40],
+    }
+}
+
+fn ip_addresses(packet: &[u8]) -> (IpAddress, IpAddress) {
+    match packet[0] >> 4 {
+        4 => (
+            Ipv4Address::from_bytes(&packet[12..16]).into(),
+            Ipv4Address::from_bytes(&packet[16..20]).into(),
+        ),
+        _ => (
+            Ipv6Address::from_bytes(&packet[8..24]).into(),
+            Ipv6Address::from_bytes(&packet[24..40]).into(),
+        ),
+    }
+}
+
+// Set the length of the IP packet, along with the checksum of the header of IPv4.
+fn set_ip_length(packet: &mut [u8]) {
+    let length = packet.len();
+    match packet[0] >> 4 {
+        4 => {
+            let mut ip = Ipv4Packet::new_unchecked(packet);
+            ip.set_total_len(length as u16);
+            ip.fill_checksum();
+        }
+        _ => packet[4..6].copy_from_slice(&((length - 40) as u16).to_be_bytes()),
+    }
+}
+
+// Whether two segments of a flow only differ in the fields which change from one segment to the
+// next, leaving aside the flags.
+fn same_headers(a: &[u8], b: &[u8], ip_len: usize, tcp_len: usize) -> bool {
+    let ip = match a[0] >> 4 {
+        4 => a[..2] == b[..2] && a[6..10] == b[6..10] && a[12..ip_len] == b[12..ip_len],
+        _ => a[..4] == b[..4] && a[6..ip_len] == b[6..ip_len],
+    };
+    let (a, b) = (&a[ip_len..], &b[ip_len..]);
+    ip && a[..4] == b[..4]
+        && a[8..13] == b[8..13]
+        && a[14..16] == b[14..16]
+        && a[18..tcp_len] == b[18..tcp_len]
+}
+
+// Split a TCP segment coalesced by the kernel into segments of at most `size` bytes of data, as
+// the kernel would have had it not handed the segmentation over.
+fn segment(packet: &[u8], size: usize, segments: &mut VecDeque<Vec<u8>>) {
+    let (ip_len, tcp_len) = match tcp_headers(packet) {
+        Some(lengths) if size > 0 => lengths,
+        _ => {
+            log::debug!("Dropping a malformed TCP segment of the tun device");
+            return;
+        }
+    };
+    let (headers, data) = packet.split_at(ip_len + tcp_len);
+    let (src, dst) = ip_addresses(packet);
+    let seq = TcpPacket::new_unchecked(&packet[ip_len..]).seq_number();
+    let count = data.len().div_ceil(size);
+    for (index, chunk) in data.chunks(size).enumerate() {
+        let mut segment = Vec::with_capacity(headers.len() + chunk.len());
+        segment.extend_from_slice(headers);
+        segment.extend_from_slice(chunk);
+        if segment[0] >> 4 == 4 {
+            let mut ip = Ipv4Packet::new_unchecked(&mut segment[..]);
+            ip.set_ident(ip.ident().wrapping_add(index as u16));
+        }
+        set_ip_length(&mut segment);
+        let flags = &mut segment[ip_len + 13];
+        if index + 1 < count {
+            *flags &= !(FIN | PSH);
+        }
+        if index > 0 {
+            *flags &= !CWR;
+        }
+        let mut tcp = TcpPacket::new_unchecked(&mut segment[ip_len..]);
+        tcp.set_seq_number(seq + index * size);
+        tcp.fill_checksum(&src, &dst);
+        segments.push_back(segment);
+    }
+}
+
+// The segments of a flow sent on a wakeup, coalesced into one for the kernel to segment again.
+struct Coalesced {
+    packet: Vec<u8>,
+    ip_len: usize,
+    tcp_len: usize,
+    // The data of each segment, of which only the last may carry less.
+    size: usize,
+    count: usize,
+    next_seq: TcpSeqNumber,
+    // Whether the following segment of the flow may still be appended.
+    open: bool,
+}
+
+impl Coalesced {
+    fn new(packet: Vec<u8>) -> Self {
+        let (ip_len, tcp_len) = tcp_headers(&packet).unwrap_or((0, 0));
+        let size = packet.len() - ip_len - tcp_len;
+        // Only plain segments carrying data, which are not fragments of IPv4.
+        let open = tcp_len > 0
+            && size > 0
+            && packet[ip_len + 13] == ACK
+            && (packet[0] >> 4 == 6 || (packet[6] & 0x3f == 0 && packet[7] == 0));
+        let next_seq = if open {
+            TcpPacket::new_unchecked(&packet[ip_len..]).seq_number() + size
+        } else {
+            TcpSeqNumber(0)
+        };
+        Self {
+            packet,
+            ip_len,
+            tcp_len,
+            size,
+            count: 1,
+            next_seq,
+            open,
+        }
+    }
+
+    // Append the segment following those so far, unless it does not fit in with them.
+    fn append(&mut self, packet: &[u8]) -> bool {
+        let (ip_len, tcp_len) = (self.ip_len, self.tcp_len);
+        if tcp_headers(packet) != Some((ip_len, tcp_len)) {
+            return false;
+        }
+        let data = &packet[ip_len + tcp_len..];
+        let flags = packet[ip_len + 13];
+        if data.is_empty()
+            || data.len() > self.size
+            || self.packet.len() + data.len() > MAX_PACKET_SIZE
+            || (flags != ACK && flags != ACK | PSH)
+            || TcpPacket::new_unchecked(&packet[ip_len..]).seq_number() != self.next_seq
+            || !same_headers(&self.packet, packet, ip_len, tcp_len)
+        {
+            return false;
+        }
+        self.packet.extend_from_slice(data);
+        self.packet[ip_len + 13] = flags;
+        self.count += 1;
+        self.next_seq += data.len();
+        // The kernel takes the data of the last segment for the size of the others.
+        self.open = data.len() == self.size && flags == ACK;
+        true
+    }
+
+    // The packet along with its header for the kernel.
+    fn finish(mut self) -> (Header, Vec<u8>) {
+        if self.count == 1 {
+            return (Header::default(), self.packet);
+        }
+        let ip_len = self.ip_len;
+        set_ip_length(&mut self.packet);
+        // The kernel completes the checksum from that of the pseudo-header.
+        let length = (self.packet.len() - ip_len) as u32;
+        let pseudo_header = checksum(addresses(&self.packet), 6 + length);
+        self.packet[ip_len + 16..ip_len + 18].copy_from_slice(&pseudo_header.to_be_bytes());
+        let header = Header {
+            flags: NEEDS_CSUM,
+            gso_type: match self.packet[0] >> 4 {
+                4 => GSO_TCPV4,
+                _ => GSO_TCPV6,
+            },
+            hdr_len: (ip_len + self.tcp_len) as u16,
+            gso_size: self.size as u16,
+            csum_start: ip_len as u16,
+            csum_offset: 16,
+        };
+        (header, self.packet)
+    }
+}
+
+/// A tun device whose packets are preceded by a virtio-net header, through which the kernel hands
+/// over TCP segments of up to 64 KiB, which are segmented here, and takes them back, as the
+/// segments sent on a wakeup of the event loop are coalesced through [`flush`](Self::flush).
+pub(crate) struct OffloadTun {
+    device: TunTapInterface,
+    buffer: Vec<u8>,
+    received: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+}
+
+impl OffloadTun {
+    pub(crate) fn new(device: TunTapInterface) -> Self {
+        log::info!("Exchanging TCP segments of up to 64 KiB with the tun device");
+        Self {
+            device,
+            buffer: vec![0; HEADER_SIZE + MAX_PACKET_SIZE],
+            received: VecDeque::new(),
+            sent: Vec::new(),
+        }
+    }
+
+    pub(crate) fn capabilities(&self) -> DeviceCapabilities {
+        self.device.capabilities()
+    }
+
+    // Take the packet read from the device, which may be made up of several segments.
+    fn unpack(&mut self, size: usize) {
+        if size < HEADER_SIZE {
+            return;
+        }
+        let header = Header::parse(&self.buffer);
+        let packet = &mut self.buffer[HEADER_SIZE..size];
+        match header.gso_type & !GSO_ECN {
+            GSO_NONE => {
+                let start = usize::from(header.csum_start);
+                let at = start + usize::from(header.csum_offset);
+                if header.flags & NEEDS_CSUM != 0 && at + 2 <= packet.len() {
+                    // The field holds the checksum of the pseudo-header so far.
+                    let sum = !checksum(&packet[start..], 0);
+                    packet[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+                }
+                self.received.push_back(packet.to_vec());
+            }
+            GSO_TCPV4 | GSO_TCPV6 => {
+                segment(packet, usize::from(header.gso_size), &mut self.received);
+            }
+            gso_type => log::debug!("Dropping a packet of GSO type {gso_type} of the tun device"),
+        }
+    }
+
+    pub(crate) fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while self.received.is_empty() {
+            match nix::unistd::read(self.device.as_raw_fd(), &mut self.buffer) {
+                Ok(size) => self.unpack(size),
+                Err(nix::errno::Errno::EAGAIN) => return Ok(None),
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(self.received.pop_front())
+    }
+
+    pub(crate) fn send(&mut self, packet: Vec<u8>) {
+        self.sent.push(packet);
+    }
+
+    /// Write the packets sent since the last flush, coalescing the consecutive segments of each
+    /// flow, as long as their headers only differ in the sequence numbers.
+    pub(crate) fn flush(&mut self) {
+        let mut packets: Vec<Coalesced> = Vec::new();
+        // The packets still open to the following segments of their flows, by addresses and ports.
+        let mut open = HashMap::new();
+        for packet in self.sent.drain(..) {
+            let flow = tcp_headers(&packet).map(|(ip_len, _)| {
+                let mut flow = addresses(&packet).to_vec();
+                flow.extend_from_slice(&packet[ip_len..ip_len + 4]);
+                flow
+            });
+            if let Some((flow, index)) = flow.as_ref().and_then(|flow| open.remove_entry(flow)) {
+                let coalesced: &mut Coalesced = &mut packets[index];
+                if coalesced.append(&packet) {
+                    if coalesced.open {
+                        open.insert(flow, index);
+                    }
+                    continue;
+                }
+            }
+            let coalesced = Coalesced::new(packet);
+            if let (true, Some(flow)) = (coalesced.open, flow) {
+                open.insert(flow, packets.len());
+            }
+            packets.push(coalesced);
+        }
+        let fd = self.device.as_raw_fd();
+        for coalesced in packets {
+            let (header, packet) = coalesced.finish();
+            let header = header.to_bytes();
+            // Like a physical link, the device may drop packets under pressure.
+            let iov = [IoSlice::new(&header), IoSlice::new(&packet)];
+            if let Err(error) = nix::sys::uio::writev(fd, &iov) {
+                log::debug!("Write to tun device: {error}");
+            }
+        }
+    }
+}
+
+impl AsRawFd for OffloadTun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.device.as_raw_fd()
+    }
+}
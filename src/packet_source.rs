@@ -0,0 +1,111 @@
+use crate::error::Error;
+use mio::Waker;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+type Worker = Box<dyn FnOnce() + Send>;
+
+/// The packets of a tunnel without a file descriptor, e.g. those of `NEPacketTunnelFlow` in a
+/// Packet Tunnel Provider on iOS, passed as [`NetworkInterface::Packets`](crate::NetworkInterface)
+/// in place of a tun device. The embedder exchanges the packets through the [`PacketHandle`].
+#[derive(Clone)]
+pub struct PacketSource {
+    inbound: Arc<Mutex<Receiver<Vec<u8>>>>,
+    outbound: Sender<Vec<u8>>,
+    waker: Arc<Mutex<Option<Arc<Waker>>>>,
+    handle_waker: Arc<Mutex<Option<Arc<Waker>>>>,
+    worker: Arc<Mutex<Option<Worker>>>,
+}
+
+/// The end of a [`PacketSource`] held by the embedder, which may be used from any thread.
+pub struct PacketHandle {
+    inbound: Sender<Vec<u8>>,
+    outbound: Receiver<Vec<u8>>,
+    waker: Arc<Mutex<Option<Arc<Waker>>>>,
+    handle_waker: Arc<Mutex<Option<Arc<Waker>>>>,
+}
+
+impl PacketSource {
+    pub fn new() -> (Self, PacketHandle) {
+        let (inbound_sender, inbound) = mpsc::channel();
+        let (outbound, outbound_receiver) = mpsc::channel();
+        let waker = Arc::new(Mutex::new(None));
+        let handle_waker = Arc::new(Mutex::new(None));
+        let source = Self {
+            inbound: Arc::new(Mutex::new(inbound)),
+            outbound,
+            waker: waker.clone(),
+            handle_waker: handle_waker.clone(),
+            worker: Arc::new(Mutex::new(None)),
+        };
+        let handle = PacketHandle {
+            inbound: inbound_sender,
+            outbound: outbound_receiver,
+            waker,
+            handle_waker,
+        };
+        (source, handle)
+    }
+
+    // Run `worker` in a thread of its own once the tunnel has been set up, so that the thread is
+    // confined by the sandbox the tunnel is set up with.
+    pub(crate) fn with_worker(self, worker: impl FnOnce() + Send + 'static) -> Self {
+        *self.worker.lock().unwrap() = Some(Box::new(worker));
+        self
+    }
+
+    pub(crate) fn start_worker(&self) {
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            std::thread::spawn(worker);
+        }
+    }
+
+    // Wake the event loop through `waker` whenever a packet is injected, including those injected
+    // before it started.
+    pub(crate) fn attach(&self, waker: Arc<Waker>) -> std::io::Result<()> {
+        let waker = self.waker.lock().unwrap().insert(waker).clone();
+        waker.wake()
+    }
+
+    pub(crate) fn receive(&self) -> Option<Vec<u8>> {
+        self.inbound.lock().unwrap().try_recv().ok()
+    }
+
+    pub(crate) fn send(&self, packet: Vec<u8>) {
+        // Packets are dropped once the embedder has let go of its handle, as the tunnel is gone.
+        let _ = self.outbound.send(packet);
+        if let Some(waker) = self.handle_waker.lock().unwrap().as_ref() {
+            let _ = waker.wake();
+        }
+    }
+}
+
+impl PacketHandle {
+    // Wake an event loop of the embedder through `waker` whenever the engine sends a packet, for
+    // which it then checks through `try_extract`.
+    pub(crate) fn attach(&self, waker: Arc<Waker>) {
+        *self.handle_waker.lock().unwrap() = Some(waker);
+    }
+
+    /// Hand a packet read from the tunnel, e.g. through `readPackets`, to the engine.
+    pub fn inject(&self, packet: Vec<u8>) -> Result<(), Error> {
+        self.inbound
+            .send(packet)
+            .map_err(|_| Error::from("The packet source has been dropped"))?;
+        if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+            waker.wake()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next packet the engine sends into the tunnel, e.g. through `writePackets`.
+    /// Returns `None` once the engine and every clone of the packet source are gone.
+    pub fn extract(&self) -> Option<Vec<u8>> {
+        self.outbound.recv().ok()
+    }
+
+    /// The next packet the engine sends into the tunnel, unless there is none yet.
+    pub fn try_extract(&self) -> Option<Vec<u8>> {
+        self.outbound.try_recv().ok()
+    }
+}
@@ -0,0 +1,253 @@
+use crate::bridge::BridgeHandle;
+use crate::error::Error;
+use crate::tun2proxy::{Connection, ConnectionManager, TcpProxy};
+use crate::Credentials;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive failed connections after which a proxy is considered unavailable.
+const MAX_FAILURES: u32 = 3;
+/// Interval at which an unavailable proxy is given another connection to check whether it has
+/// recovered.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval at which the statistics of the proxies are logged while connections are made.
+const STATISTICS_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How new connections are distributed over several proxies. Unavailable proxies are skipped by
+/// every strategy.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Use the first available proxy.
+    #[default]
+    Failover,
+    /// Use the available proxies in turn.
+    RoundRobin,
+    /// Use the available proxy with the fewest open connections.
+    LeastConnections,
+}
+
+#[derive(Default)]
+struct Upstream {
+    failures: u32,
+    // Set while the proxy is unavailable.
+    recheck: Option<Instant>,
+    active: usize,
+    connections: u64,
+    failed: u64,
+}
+
+/// Hands connections to a pool of proxies according to the balancing strategy, failing over to
+/// the other proxies while a proxy repeatedly refuses connections or does not respond.
+pub(crate) struct PoolManager {
+    managers: Vec<Rc<dyn ConnectionManager>>,
+    balance: Balance,
+    upstreams: RefCell<Vec<Upstream>>,
+    assigned: RefCell<HashMap<Connection, usize>>,
+    selected: Cell<usize>,
+    next_statistics: Cell<Instant>,
+}
+
+impl PoolManager {
+    pub fn new(managers: Vec<Rc<dyn ConnectionManager>>, balance: Balance) -> Rc<Self> {
+        let upstreams = managers.iter().map(|_| Upstream::default()).collect();
+        Rc::new(Self {
+            managers,
+            balance,
+            upstreams: RefCell::new(upstreams),
+            assigned: RefCell::new(HashMap::new()),
+            selected: Cell::new(0),
+            next_statistics: Cell::new(Instant::now() + STATISTICS_INTERVAL),
+        })
+    }
+
+    fn log_statistics(&self) {
+        for (manager, upstream) in self.managers.iter().zip(self.upstreams.borrow().iter()) {
+            log::info!(
+                "Proxy {}: {} connections, {} failed, {} open",
+                manager.get_server(),
+                upstream.connections,
+                upstream.failed,
+                upstream.active
+            );
+        }
+    }
+
+    fn select(&self, connection: &Connection) -> Option<usize> {
+        let now = Instant::now();
+        let mut upstreams = self.upstreams.borrow_mut();
+        let candidates: Vec<usize> = (0..self.managers.len())
+            .filter(|&i| self.managers[i].handles_connection(connection))
+            .collect();
+        let primary = *candidates.first()?;
+
+        // A proxy due for a recheck takes precedence so that it is found out when it recovers.
+        for &i in &candidates {
+            if matches!(upstreams[i].recheck, Some(recheck) if recheck <= now) {
+                let server = self.managers[i].get_server();
+                log::info!("Checking whether proxy {server} is available again");
+                upstreams[i].recheck = Some(now + RECHECK_INTERVAL);
+                return Some(i);
+            }
+        }
+
+        let mut available = candidates
+            .into_iter()
+            .filter(|&i| upstreams[i].recheck.is_none());
+        let selected = match self.balance {
+            Balance::Failover => available.next(),
+            Balance::RoundRobin => {
+                let last = self.selected.get();
+                let available: Vec<usize> = available.collect();
+                available
+                    .iter()
+                    .find(|&&i| i > last)
+                    .or_else(|| available.first())
+                    .copied()
+            }
+            Balance::LeastConnections => available.min_by_key(|&i| upstreams[i].active),
+        };
+        // None of the proxies is available, so keep trying the primary one.
+        Some(selected.unwrap_or(primary))
+    }
+}
+
+impl ConnectionManager for PoolManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        self.managers
+            .iter()
+            .any(|manager| manager.handles_connection(connection))
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        let i = match self.select(connection) {
+            None => return Ok(None),
+            Some(i) => i,
+        };
+        if self.next_statistics.get() <= Instant::now() {
+            self.next_statistics
+                .set(Instant::now() + STATISTICS_INTERVAL);
+            self.log_statistics();
+        }
+        self.selected.set(i);
+        let manager = &self.managers[i];
+        let handler = manager.new_connection(connection, manager.clone())?;
+        if handler.is_some() {
+            let upstream = &mut self.upstreams.borrow_mut()[i];
+            upstream.active += 1;
+            upstream.connections += 1;
+            self.assigned.borrow_mut().insert(connection.clone(), i);
+        }
+        Ok(handler)
+    }
+
+    // The proxy has to accept the connection on behalf of the client using the proxy of the
+    // control connection, since the peer is told to connect to the address bound there.
+    fn new_bind(
+        &self,
+        connection: &Connection,
+        control: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        let i = match self.assigned.borrow().get(control) {
+            None => return Ok(None),
+            Some(&i) => i,
+        };
+        self.selected.set(i);
+        let manager = &self.managers[i];
+        let handler = manager.new_bind(connection, control, manager.clone())?;
+        if handler.is_some() {
+            let upstream = &mut self.upstreams.borrow_mut()[i];
+            upstream.active += 1;
+            upstream.connections += 1;
+            self.assigned.borrow_mut().insert(connection.clone(), i);
+        }
+        Ok(handler)
+    }
+
+    fn close_connection(&self, connection: &Connection) {
+        if let Some(i) = self.assigned.borrow_mut().remove(connection) {
+            self.upstreams.borrow_mut()[i].active -= 1;
+            self.managers[i].close_connection(connection);
+        }
+    }
+
+    // The server and credentials are those of the proxy chosen for the latest connection.
+    fn get_server(&self) -> SocketAddr {
+        self.managers[self.selected.get()].get_server()
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        self.managers[self.selected.get()].get_credentials()
+    }
+
+    fn get_unix_socket(&self) -> Option<&Path> {
+        self.managers[self.selected.get()].get_unix_socket()
+    }
+
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        self.managers[self.selected.get()].get_bridge()
+    }
+
+    fn get_server_for(&self, connection: &Connection) -> Result<SocketAddr, Error> {
+        self.managers[self.selected.get()].get_server_for(connection)
+    }
+
+    // The proxy is only chosen once the connection is handed over, so the hostname is resolved if
+    // any of those which may be chosen cannot resolve it.
+    fn resolves_locally(&self, connection: &Connection) -> bool {
+        self.managers.iter().any(|manager| {
+            manager.handles_connection(connection) && manager.resolves_locally(connection)
+        })
+    }
+
+    fn get_interface(&self) -> Option<&str> {
+        self.managers[self.selected.get()].get_interface()
+    }
+
+    fn report_refused_hostname(&self, connection: &Connection, name: &str) {
+        if let Some(&i) = self.assigned.borrow().get(connection) {
+            self.managers[i].report_refused_hostname(connection, name);
+        }
+    }
+
+    fn report_health(&self, server: SocketAddr, healthy: bool) {
+        let mut upstreams = self.upstreams.borrow_mut();
+        for (manager, upstream) in self.managers.iter().zip(upstreams.iter_mut()) {
+            if manager.get_server() != server {
+                continue;
+            }
+            manager.report_health(server, healthy);
+            if healthy {
+                if upstream.recheck.is_some() {
+                    log::info!("Proxy {server} is available again");
+                }
+                upstream.failures = 0;
+                upstream.recheck = None;
+                continue;
+            }
+            upstream.failed += 1;
+            upstream.failures += 1;
+            if upstream.failures < MAX_FAILURES {
+                continue;
+            }
+            if upstream.recheck.is_none() {
+                log::warn!("Proxy {server} is unavailable, failing over");
+            }
+            upstream.recheck = Some(Instant::now() + RECHECK_INTERVAL);
+        }
+    }
+}
+
+impl Drop for PoolManager {
+    fn drop(&mut self) {
+        self.log_statistics();
+    }
+}
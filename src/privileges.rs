@@ -0,0 +1,88 @@
+#![cfg(any(target_os = "linux", target_os = "openbsd", target_os = "netbsd"))]
+
+use crate::error::Error;
+use nix::unistd::{Gid, Group, Uid, User};
+
+// The capability allowing to change the network configuration, e.g. routes and socket marks.
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+pub(crate) fn find_user(user: &str) -> Result<(Uid, Gid), Error> {
+    if let Some(user) = User::from_name(user)? {
+        return Ok((user.uid, user.gid));
+    }
+    // Numeric IDs work even without an entry in the user database, e.g. in containers.
+    let uid = user
+        .parse()
+        .map_err(|_| Error::from(format!("There is no user `{user}`")))?;
+    Ok((Uid::from_raw(uid), Gid::from_raw(uid)))
+}
+
+pub(crate) fn find_group(group: &str) -> Result<Gid, Error> {
+    if let Some(group) = Group::from_name(group)? {
+        return Ok(group.gid);
+    }
+    let gid = group
+        .parse()
+        .map_err(|_| Error::from(format!("There is no group `{group}`")))?;
+    Ok(Gid::from_raw(gid))
+}
+
+/// Switch to `user`, given by name or ID, and to `group`, or to the primary group of the user
+/// unless given, dropping the supplementary groups. With `keep_net_admin`, the `CAP_NET_ADMIN`
+/// capability is kept on Linux, while all other privileges are given up.
+pub fn drop_privileges(user: &str, group: Option<&str>, keep_net_admin: bool) -> Result<(), Error> {
+    let (uid, primary_gid) = find_user(user)?;
+    let gid = match group {
+        Some(group) => find_group(group)?,
+        None => primary_gid,
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    if keep_net_admin {
+        return Err("Capabilities are only supported on Linux".into());
+    }
+    // The permitted capabilities survive the change of the user ID only if asked for.
+    #[cfg(target_os = "linux")]
+    if keep_net_admin && unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    nix::unistd::setgroups(&[gid])?;
+    nix::unistd::setgid(gid)?;
+    nix::unistd::setuid(uid)?;
+
+    #[cfg(target_os = "linux")]
+    if keep_net_admin {
+        let header = CapabilityHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapabilityData::default(); 2];
+        data[0].effective = 1 << CAP_NET_ADMIN;
+        data[0].permitted = 1 << CAP_NET_ADMIN;
+        if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    log::info!("Running as user {uid} and group {gid}");
+    Ok(())
+}
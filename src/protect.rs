@@ -0,0 +1,218 @@
+use mio::net::{TcpStream, UdpSocket};
+use mio::{Events, Interest, Poll, Token};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Called with each socket to a proxy before it connects, so that its traffic can be kept out of
+/// the tunnel, e.g. through `VpnService.protect()` on Android. Returns whether that succeeded.
+pub type SocketProtector = Arc<dyn Fn(RawFd) -> bool + Send + Sync>;
+
+// The sockets are created all over the crate, partly on threads of their own, while a VPN service
+// protects the sockets of the whole process anyway.
+static PROTECTOR: RwLock<Option<SocketProtector>> = RwLock::new(None);
+
+// The local addresses the sockets to the proxies are bound to, at most one per address family.
+static SOURCE_ADDRS: RwLock<Vec<IpAddr>> = RwLock::new(Vec::new());
+
+pub(crate) fn set_protector(protector: Option<SocketProtector>) {
+    *PROTECTOR.write().unwrap() = protector;
+}
+
+pub(crate) fn set_source_addrs(addrs: Vec<IpAddr>) {
+    *SOURCE_ADDRS.write().unwrap() = addrs;
+}
+
+// The local address to bind a socket to `peer` to, if one is given for its address family.
+// Sockets to loopback addresses, e.g. to the bridges, stay on the host.
+fn source_addr(peer: SocketAddr) -> Option<SocketAddr> {
+    if peer.ip().is_loopback() {
+        return None;
+    }
+    let addrs = SOURCE_ADDRS.read().unwrap();
+    let addr = addrs.iter().find(|addr| addr.is_ipv4() == peer.is_ipv4())?;
+    Some(SocketAddr::new(*addr, 0))
+}
+
+pub(crate) fn protect(fd: RawFd) -> std::io::Result<()> {
+    match PROTECTOR.read().unwrap().as_ref() {
+        Some(protector) if !protector(fd) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "The socket could not be protected from the tunnel",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Protect the socket `fd` to `peer`, unless `peer` is a loopback address, which the tunnel
+/// never carries, and which a protector binding the socket to an interface makes unreachable.
+pub(crate) fn protect_to(fd: RawFd, peer: IpAddr) -> std::io::Result<()> {
+    match peer.is_loopback() {
+        true => Ok(()),
+        false => protect(fd),
+    }
+}
+
+// Send the traffic of the socket `fd` through `interface` only, which the BSDs lack a socket
+// option for.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn bind_to_device(fd: RawFd, interface: &str) -> std::io::Result<()> {
+    use nix::sys::socket::{self, sockopt};
+    Ok(socket::setsockopt(
+        fd,
+        sockopt::BindToDevice,
+        &interface.into(),
+    )?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn bind_to_device(_fd: RawFd, interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("Binding to the interface {interface} is not supported on this platform"),
+    ))
+}
+
+/// A protector giving each socket the firewall mark `mark`, by which policy routing can keep its
+/// traffic out of the tunnel. Setting the mark requires the `CAP_NET_ADMIN` capability.
+#[cfg(target_os = "linux")]
+pub(crate) fn mark_protector(mark: u32) -> SocketProtector {
+    use nix::sys::socket::{setsockopt, sockopt::Mark};
+    Arc::new(move |fd| match setsockopt(fd, Mark, &mark) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Cannot mark the socket: {e}");
+            false
+        }
+    })
+}
+
+/// A protector sending the traffic of each socket through `interface` only, e.g. the physical one
+/// while the default route points to the tunnel.
+pub(crate) fn device_protector(interface: String) -> SocketProtector {
+    Arc::new(move |fd| match bind_to_device(fd, &interface) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Cannot bind the socket to {interface}: {e}");
+            false
+        }
+    })
+}
+
+/// A protector applying `second` after `first`, if any.
+pub(crate) fn chain(first: Option<SocketProtector>, second: SocketProtector) -> SocketProtector {
+    match first {
+        Some(first) => Arc::new(move |fd| first(fd) && second(fd)),
+        None => second,
+    }
+}
+
+fn is_protecting() -> bool {
+    PROTECTOR.read().unwrap().is_some()
+}
+
+/// Have the first data written to a socket which is about to connect go out along with the SYN,
+/// like `sendto` with `MSG_FASTOPEN`, once the server has handed out a cookie on an earlier
+/// connection. Until the SYN is sent, `connect` returns at once and the socket is writable. The
+/// first write fails with `EINPROGRESS` if no data could be sent along.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_fast_open(fd: RawFd) -> std::io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Elsewhere, the data is sent once connected.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn set_fast_open(_fd: RawFd) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Connect to `server` after protecting the socket from the tunnel, from the local address given
+/// for its address family, if any, and with TCP Fast Open if `fast_open` on Linux and Android.
+pub(crate) fn connect_tcp(server: SocketAddr, fast_open: bool) -> std::io::Result<TcpStream> {
+    use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, SockaddrStorage};
+    let source = source_addr(server);
+    if (!is_protecting() || server.ip().is_loopback()) && source.is_none() && !fast_open {
+        return TcpStream::connect(server);
+    }
+    let family = match server {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+    let fd = socket::socket(family, SockType::Stream, flags, None)?;
+    // The stream owns the socket from here on, so that it is closed on errors.
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    protect_to(fd, server.ip())?;
+    if let Some(source) = source {
+        socket::bind(fd, &SockaddrStorage::from(source))?;
+    }
+    if fast_open {
+        set_fast_open(fd)?;
+    }
+    match socket::connect(fd, &SockaddrStorage::from(server)) {
+        Ok(()) | Err(nix::errno::Errno::EINPROGRESS) => Ok(TcpStream::from_std(stream)),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Connect to `server` like `connect_tcp`, waiting up to `timeout` for the connection to be
+/// established, and hand out a blocking stream, e.g. to a worker thread.
+pub(crate) fn connect_tcp_blocking(
+    server: SocketAddr,
+    timeout: Duration,
+) -> std::io::Result<std::net::TcpStream> {
+    let mut stream = connect_tcp(server, false)?;
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut stream, Token(0), Interest::WRITABLE)?;
+    let mut events = Events::with_capacity(1);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            let e = format!("Connecting to {server} has timed out");
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e));
+        }
+        match poll.poll(&mut events, Some(remaining)) {
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+        if let Some(error) = stream.take_error()? {
+            return Err(error);
+        }
+        if !events.is_empty() && stream.peer_addr().is_ok() {
+            break;
+        }
+    }
+    poll.registry().deregister(&mut stream)?;
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) };
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Bind a UDP socket to send to `peer` from the local address given for its address family, if
+/// any, and protect it from the tunnel.
+pub(crate) fn bind_udp(peer: SocketAddr) -> std::io::Result<UdpSocket> {
+    let addr = match (source_addr(peer), peer) {
+        (Some(source), _) => source,
+        (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(addr)?;
+    protect_to(socket.as_raw_fd(), peer.ip())?;
+    Ok(socket)
+}
@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::tun2proxy::{
+    Connection, DestinationHost, Direction, IncomingDataEvent, OutgoingDataEvent,
+    OutgoingDirection, OutgoingSlices, TcpProxy,
+};
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const SIGNATURE_V2: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const VERSION_PROXY: u8 = 0x21;
+const INET_STREAM: u8 = 0x11;
+const INET6_STREAM: u8 = 0x21;
+const TYPE_AUTHORITY: u8 = 0x02;
+
+/// Version of the HAProxy PROXY protocol header which is sent ahead of every connection to the
+/// proxy, telling it the source and destination of the connection within the tunnel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// The human-readable header.
+    V1,
+    /// The binary header, which additionally carries hostnames.
+    V2,
+}
+
+// Both addresses have to be of the same family, so IPv4 addresses are mapped to IPv6 if needed.
+fn same_family(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V6(dst)) => (src.to_ipv6_mapped().into(), dst.into()),
+        (IpAddr::V6(src), IpAddr::V4(dst)) => (src.into(), dst.to_ipv6_mapped().into()),
+        _ => (src, dst),
+    }
+}
+
+fn header_v1(connection: &Connection) -> Vec<u8> {
+    let src = connection.src;
+    let header = match connection.dst.host {
+        // The header cannot convey a hostname.
+        DestinationHost::Hostname(_) => String::from("PROXY UNKNOWN\r\n"),
+        DestinationHost::Address(dst) => match same_family(src.ip(), dst) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst)) => format!(
+                "PROXY TCP4 {src_ip} {dst} {} {}\r\n",
+                src.port(),
+                connection.dst.port
+            ),
+            (src_ip, dst) => format!(
+                "PROXY TCP6 {src_ip} {dst} {} {}\r\n",
+                src.port(),
+                connection.dst.port
+            ),
+        },
+    };
+    header.into_bytes()
+}
+
+fn header_v2(connection: &Connection) -> Vec<u8> {
+    let src = connection.src;
+    let (dst, authority) = match &connection.dst.host {
+        DestinationHost::Address(dst) => (*dst, None),
+        // The hostname is passed as the authority, leaving the destination address unspecified.
+        DestinationHost::Hostname(name) => match src {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED.into(), Some(name)),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED.into(), Some(name)),
+        },
+    };
+
+    let mut addresses = Vec::new();
+    let family = match same_family(src.ip(), dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            addresses.extend(src.octets());
+            addresses.extend(dst.octets());
+            INET_STREAM
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            addresses.extend(src.octets());
+            addresses.extend(dst.octets());
+            INET6_STREAM
+        }
+        _ => unreachable!("addresses are of the same family"),
+    };
+    addresses.extend(src.port().to_be_bytes());
+    addresses.extend(connection.dst.port.to_be_bytes());
+    if let Some(name) = authority {
+        addresses.push(TYPE_AUTHORITY);
+        addresses.extend((name.len() as u16).to_be_bytes());
+        addresses.extend(name.as_bytes());
+    }
+
+    let mut header = SIGNATURE_V2.to_vec();
+    header.extend([VERSION_PROXY, family]);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+/// Sends a PROXY protocol header describing the connection ahead of everything the inner
+/// handler sends to the proxy.
+pub(crate) struct ProxyProtocolConnection {
+    inner: Box<dyn TcpProxy>,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl ProxyProtocolConnection {
+    pub fn new(inner: Box<dyn TcpProxy>, connection: &Connection, version: ProxyProtocol) -> Self {
+        let header = match version {
+            ProxyProtocol::V1 => header_v1(connection),
+            ProxyProtocol::V2 => header_v2(connection),
+        };
+        Self {
+            inner,
+            server_outbuf: header.into(),
+        }
+    }
+}
+
+impl TcpProxy for ProxyProtocolConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        self.inner.push_data(event)
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        if dir == OutgoingDirection::ToServer {
+            // The header goes first, followed by the data the inner handler has left to send.
+            let header_size = size.min(self.server_outbuf.len());
+            self.server_outbuf.drain(0..header_size);
+            if size > header_size {
+                self.inner.consume_data(dir, size - header_size);
+            }
+        } else {
+            self.inner.consume_data(dir, size);
+        }
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        if dir == OutgoingDirection::ToServer {
+            // Move the data of the inner handler behind the header.
+            let data = self.inner.peek_data(OutgoingDirection::ToServer).buffer;
+            let size = data.len();
+            self.server_outbuf.extend(data);
+            self.inner.consume_data(OutgoingDirection::ToServer, size);
+            OutgoingDataEvent {
+                direction: dir,
+                buffer: self.server_outbuf.make_contiguous(),
+            }
+        } else {
+            self.inner.peek_data(dir)
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        if dir == OutgoingDirection::ToServer {
+            // The data of the inner handler is written along with the header, where it is.
+            let mut slices = OutgoingSlices::from(&self.server_outbuf);
+            slices.append(self.inner.peek_slices(dir));
+            slices
+        } else {
+            self.inner.peek_slices(dir)
+        }
+    }
+
+    fn connection_established(&self) -> bool {
+        self.inner.connection_established()
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Outgoing(OutgoingDirection::ToServer) => {
+                !self.server_outbuf.is_empty()
+                    || self
+                        .inner
+                        .have_data(Direction::Outgoing(OutgoingDirection::ToServer))
+            }
+            _ => self.inner.have_data(dir),
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.inner.get_udp_associate()
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.inner.get_bind_address()
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.inner.hostname_refused()
+    }
+}
@@ -0,0 +1,410 @@
+use crate::bridge::{self, BridgeConnections, BridgeHandle};
+use crate::error::Error;
+use crate::protect;
+use crate::tls::TlsConfig;
+use bytes::BytesMut;
+use mio::net::{UdpSocket, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token};
+use quinn_proto::{
+    ClientConfig, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig, ReadError,
+    StreamEvent, StreamId, TransportConfig, VarInt,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Drives a single QUIC connection to the proxy server over a non-blocking UDP socket.
+pub(crate) struct QuicClient {
+    endpoint: Endpoint,
+    handle: ConnectionHandle,
+    connection: quinn_proto::Connection,
+    socket: UdpSocket,
+}
+
+impl QuicClient {
+    pub fn connect(
+        server: SocketAddr,
+        tls: &TlsConfig,
+        registry: &Registry,
+        token: Token,
+    ) -> Result<Self, Error> {
+        let mut socket = protect::bind_udp(server)?;
+        registry.register(&mut socket, token, Interest::READABLE)?;
+
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        let mut config = ClientConfig::new(tls.client_config());
+        config.transport_config(Arc::new(transport));
+
+        let mut endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None, false);
+        let (handle, connection) = endpoint
+            .connect(config, server, &tls.server_name())
+            .map_err(|e| Error::from(format!("QUIC connection failed: {e}")))?;
+        let mut client = Self {
+            endpoint,
+            handle,
+            connection,
+            socket,
+        };
+        client.flush();
+        Ok(client)
+    }
+
+    pub fn connection(&mut self) -> &mut quinn_proto::Connection {
+        &mut self.connection
+    }
+
+    /// Feed all datagrams waiting on the socket into the connection.
+    pub fn receive(&mut self) -> Result<(), Error> {
+        let mut buffer = [0u8; 65535];
+        loop {
+            let (size, remote) = match self.socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            };
+            let data = BytesMut::from(&buffer[..size]);
+            let event = self
+                .endpoint
+                .handle(Instant::now(), remote, None, None, data);
+            if let Some((_, DatagramEvent::ConnectionEvent(event))) = event {
+                self.connection.handle_event(event);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_timer(&mut self) -> Option<Instant> {
+        self.connection.poll_timeout()
+    }
+
+    pub fn update_timers(&mut self) {
+        let now = Instant::now();
+        if self
+            .connection
+            .poll_timeout()
+            .is_some_and(|timeout| timeout <= now)
+        {
+            self.connection.handle_timeout(now);
+        }
+    }
+
+    /// Process the events between the connection and the endpoint and send pending datagrams.
+    pub fn flush(&mut self) {
+        while let Some(event) = self.connection.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(self.handle, event) {
+                self.connection.handle_event(event);
+            }
+        }
+        while let Some(transmit) = self.endpoint.poll_transmit() {
+            let _ = self
+                .socket
+                .send_to(&transmit.contents, transmit.destination);
+        }
+        while let Some(transmit) = self.connection.poll_transmit(Instant::now(), 1) {
+            // Lost datagrams are retransmitted by QUIC.
+            let _ = self
+                .socket
+                .send_to(&transmit.contents, transmit.destination);
+        }
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.socket);
+    }
+}
+
+/// A connection handed over to the stream bridge and the QUIC stream carrying it.
+struct Local {
+    stream: UnixStream,
+    quic_stream: Option<StreamId>,
+    inbuf: VecDeque<u8>,
+    outbuf: VecDeque<u8>,
+    eof: bool,
+    finish_sent: bool,
+    remote_finished: bool,
+}
+
+const WAKER_TOKEN: Token = Token(0);
+const QUIC_TOKEN: Token = Token(1);
+
+/// Carries each connection handed over to it through a bidirectional stream of a single QUIC
+/// connection to the proxy server.
+struct StreamBridge {
+    poll: Poll,
+    connections: BridgeConnections,
+    server: SocketAddr,
+    tls: TlsConfig,
+    quic: Option<QuicClient>,
+    connected: bool,
+    locals: HashMap<Token, Local>,
+    next_token: usize,
+}
+
+impl StreamBridge {
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = self
+                .quic
+                .as_mut()
+                .and_then(|quic| quic.next_timer())
+                .map(|timer| timer.saturating_duration_since(Instant::now()));
+            if let Err(error) = self.poll.poll(&mut events, timeout) {
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            for event in events.iter() {
+                let result = match event.token() {
+                    WAKER_TOKEN => match self.connections.take() {
+                        None => return Ok(()),
+                        Some(streams) => self.accept(streams),
+                    },
+                    QUIC_TOKEN => match &mut self.quic {
+                        Some(quic) => quic.receive(),
+                        None => Ok(()),
+                    },
+                    token => {
+                        if let Err(error) = self.local_event(token) {
+                            log::debug!("QUIC stream: {error}");
+                            self.remove_local(token);
+                        }
+                        Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    log::error!("QUIC transport: {error}");
+                    self.close_quic();
+                }
+            }
+            if let Err(error) = self.process_quic() {
+                log::error!("QUIC transport: {error}");
+                self.close_quic();
+            }
+        }
+    }
+
+    fn accept(&mut self, streams: Vec<UnixStream>) -> Result<(), Error> {
+        if streams.is_empty() {
+            return Ok(());
+        }
+        for mut stream in streams {
+            self.next_token += 1;
+            let token = Token(self.next_token);
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let local = Local {
+                stream,
+                quic_stream: None,
+                inbuf: VecDeque::new(),
+                outbuf: VecDeque::new(),
+                eof: false,
+                finish_sent: false,
+                remote_finished: false,
+            };
+            self.locals.insert(token, local);
+        }
+        if self.quic.is_none() {
+            let registry = self.poll.registry();
+            self.quic = Some(QuicClient::connect(
+                self.server,
+                &self.tls,
+                registry,
+                QUIC_TOKEN,
+            )?);
+            self.connected = false;
+        }
+        Ok(())
+    }
+
+    fn local_event(&mut self, token: Token) -> Result<(), Error> {
+        let local = match self.locals.get_mut(&token) {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        let mut data = Vec::new();
+        match local.stream.read_to_end(&mut data) {
+            Ok(_) => local.eof = true,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error.into()),
+        }
+        local.inbuf.extend(data);
+        Ok(())
+    }
+
+    fn process_quic(&mut self) -> Result<(), Error> {
+        let Self {
+            quic,
+            locals,
+            connected,
+            ..
+        } = self;
+        let quic = match quic {
+            Some(quic) => quic,
+            None => return Ok(()),
+        };
+        quic.update_timers();
+        let streams: HashMap<StreamId, Token> = locals
+            .iter()
+            .filter_map(|(token, local)| Some((local.quic_stream?, *token)))
+            .collect();
+        while let Some(event) = quic.connection().poll() {
+            match event {
+                quinn_proto::Event::Connected => *connected = true,
+                quinn_proto::Event::ConnectionLost { reason } => {
+                    return Err(format!("connection lost: {reason}").into());
+                }
+                quinn_proto::Event::Stream(StreamEvent::Readable { id }) => {
+                    let local = match streams.get(&id).and_then(|t| locals.get_mut(t)) {
+                        Some(local) => local,
+                        None => continue,
+                    };
+                    let mut stream = quic.connection().recv_stream(id);
+                    let mut chunks = match stream.read(true) {
+                        Ok(chunks) => chunks,
+                        Err(_) => continue,
+                    };
+                    loop {
+                        match chunks.next(usize::MAX) {
+                            Ok(Some(chunk)) => local.outbuf.extend(chunk.bytes),
+                            Ok(None) => {
+                                local.remote_finished = true;
+                                break;
+                            }
+                            Err(ReadError::Blocked) => break,
+                            // Closing both directions drops the connection.
+                            Err(ReadError::Reset(_)) => {
+                                local.remote_finished = true;
+                                local.eof = true;
+                                local.finish_sent = true;
+                                local.inbuf.clear();
+                                break;
+                            }
+                        }
+                    }
+                    let _ = chunks.finalize();
+                }
+                quinn_proto::Event::Stream(StreamEvent::Stopped { id, .. }) => {
+                    if let Some(local) = streams.get(&id).and_then(|t| locals.get_mut(t)) {
+                        local.inbuf.clear();
+                        local.eof = true;
+                        local.finish_sent = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut finished = Vec::new();
+        for (token, local) in locals.iter_mut() {
+            if local.quic_stream.is_none() && *connected {
+                local.quic_stream = quic.connection().streams().open(Dir::Bi);
+            }
+            if let Some(id) = local.quic_stream {
+                let mut stream = quic.connection().send_stream(id);
+                while !local.inbuf.is_empty() {
+                    match stream.write(local.inbuf.make_contiguous()) {
+                        Ok(written) => {
+                            local.inbuf.drain(0..written);
+                        }
+                        Err(quinn_proto::WriteError::Blocked) => break,
+                        Err(_) => {
+                            local.inbuf.clear();
+                            local.finish_sent = true;
+                        }
+                    }
+                }
+                if local.eof && local.inbuf.is_empty() && !local.finish_sent {
+                    local.finish_sent = true;
+                    let _ = stream.finish();
+                }
+            }
+            while !local.outbuf.is_empty() {
+                match local.stream.write(local.outbuf.make_contiguous()) {
+                    Ok(written) => {
+                        local.outbuf.drain(0..written);
+                    }
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        local.outbuf.clear();
+                        local.eof = true;
+                    }
+                }
+            }
+            if local.remote_finished && local.outbuf.is_empty() {
+                let _ = local.stream.shutdown(Shutdown::Write);
+                if local.finish_sent {
+                    finished.push(*token);
+                }
+            }
+        }
+        for token in finished {
+            self.remove_local(token);
+        }
+        if let Some(quic) = &mut self.quic {
+            quic.flush();
+        }
+        Ok(())
+    }
+
+    fn remove_local(&mut self, token: Token) {
+        if let Some(mut local) = self.locals.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut local.stream);
+            let _ = local.stream.shutdown(Shutdown::Both);
+            if let (Some(id), Some(quic)) = (local.quic_stream, &mut self.quic) {
+                if !local.finish_sent {
+                    let _ = quic.connection().send_stream(id).reset(VarInt::from_u32(0));
+                }
+                if !local.remote_finished {
+                    let _ = quic.connection().recv_stream(id).stop(VarInt::from_u32(0));
+                }
+            }
+        }
+    }
+
+    fn close_quic(&mut self) {
+        if let Some(mut quic) = self.quic.take() {
+            quic.deregister(self.poll.registry());
+        }
+        let tokens: Vec<Token> = self.locals.keys().copied().collect();
+        for token in tokens {
+            self.remove_local(token);
+        }
+    }
+}
+
+/// Start a bridge thread carrying the connections made through the returned handle through
+/// streams of a QUIC connection. The thread stops once the handle is dropped.
+pub(crate) fn spawn_stream_bridge(
+    server: SocketAddr,
+    tls: TlsConfig,
+) -> Result<BridgeHandle, Error> {
+    let poll = Poll::new()?;
+    let (handle, connections) = bridge::channel(poll.registry(), WAKER_TOKEN)?;
+
+    let mut bridge = StreamBridge {
+        poll,
+        connections,
+        server,
+        tls,
+        quic: None,
+        connected: false,
+        locals: HashMap::new(),
+        next_token: usize::from(QUIC_TOKEN),
+    };
+    std::thread::spawn(move || {
+        if let Err(error) = bridge.run() {
+            log::error!("QUIC transport: {error}");
+        }
+    });
+    Ok(handle)
+}
@@ -0,0 +1,181 @@
+use crate::error::Error;
+use crate::tun2proxy::{
+    Connection, ConnectionManager, DestinationHost, Direction, IncomingDataEvent,
+    IncomingDirection, OutgoingDataEvent, OutgoingDirection, OutgoingSlices, TcpProxy,
+};
+use crate::Credentials;
+use smoltcp::wire::IpProtocol;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+
+/// Forwards the byte stream of a connection to the upstream unchanged, without any handshake.
+#[derive(Default)]
+pub(crate) struct RedirectConnection {
+    client_outbuf: VecDeque<u8>,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl TcpProxy for RedirectConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        let buffer = match event.direction {
+            IncomingDirection::FromServer => &mut self.client_outbuf,
+            IncomingDirection::FromClient => &mut self.server_outbuf,
+        };
+        buffer.extend(event.buffer.iter());
+        Ok(())
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        buffer.drain(0..size);
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        OutgoingDataEvent {
+            direction: dir,
+            buffer: buffer.make_contiguous(),
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &self.server_outbuf
+        } else {
+            &self.client_outbuf
+        };
+        buffer.into()
+    }
+
+    fn connection_established(&self) -> bool {
+        true
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Incoming(_) => false,
+            Direction::Outgoing(outgoing) => match outgoing {
+                OutgoingDirection::ToServer => !self.server_outbuf.is_empty(),
+                OutgoingDirection::ToClient => !self.client_outbuf.is_empty(),
+            },
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Connects every TCP connection to the same upstream, e.g. a relay which already knows where
+/// to send the traffic.
+pub(crate) struct RedirectManager {
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+}
+
+impl ConnectionManager for RedirectManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        connection.proto == IpProtocol::Tcp
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if connection.proto != IpProtocol::Tcp {
+            return Ok(None);
+        }
+        Ok(Some(Box::<RedirectConnection>::default()))
+    }
+
+    fn close_connection(&self, _: &Connection) {}
+
+    fn get_server(&self) -> SocketAddr {
+        self.server
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        &self.credentials
+    }
+}
+
+impl RedirectManager {
+    pub fn new(server: SocketAddr) -> Rc<Self> {
+        Rc::new(Self {
+            server,
+            credentials: None,
+        })
+    }
+}
+
+/// Connects every TCP connection straight to its destination instead of going through a proxy,
+/// optionally through a given network interface.
+pub(crate) struct DirectManager {
+    credentials: Option<Credentials>,
+    interface: Option<String>,
+}
+
+impl ConnectionManager for DirectManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        connection.proto == IpProtocol::Tcp
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if connection.proto != IpProtocol::Tcp {
+            return Ok(None);
+        }
+        Ok(Some(Box::<RedirectConnection>::default()))
+    }
+
+    fn close_connection(&self, _: &Connection) {}
+
+    fn get_server(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        &self.credentials
+    }
+
+    fn get_server_for(&self, connection: &Connection) -> Result<SocketAddr, Error> {
+        let dst = &connection.dst;
+        match &dst.host {
+            DestinationHost::Address(ip) => Ok(SocketAddr::new(*ip, dst.port)),
+            DestinationHost::Hostname(name) => {
+                Err(format!("`{name}` has not been resolved").into())
+            }
+        }
+    }
+
+    // Hostnames determined through virtual DNS are resolved before the connection is made.
+    fn resolves_locally(&self, connection: &Connection) -> bool {
+        matches!(connection.dst.host, DestinationHost::Hostname(_))
+    }
+
+    fn get_interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+}
+
+impl DirectManager {
+    pub fn new(interface: Option<String>) -> Rc<Self> {
+        Rc::new(Self {
+            credentials: None,
+            interface,
+        })
+    }
+}
@@ -0,0 +1,437 @@
+use crate::bridge::BridgeHandle;
+use crate::error::Error;
+use crate::protect;
+use crate::tcp_dns;
+use crate::tun2proxy::{Connection, ConnectionManager, TcpProxy};
+use crate::Credentials;
+use mio::Waker;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The hostname is resolved again this often, and sooner once this many connections in a row have
+// failed, but not more often than the failure interval.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+const FAILURE_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_FAILURES: u32 = 3;
+
+// The hostnames of destinations are resolved again once they have been used this long, or this
+// long after they could not be resolved. Only so many are resolved at the same time.
+const HOST_TTL: Duration = Duration::from_secs(300);
+const FAILED_HOST_TTL: Duration = Duration::from_secs(10);
+const MAX_LOOKUPS: usize = 8;
+
+/// The name servers of `/etc/resolv.conf`, which have to be captured before the setup replaces
+/// the file to point to the virtual DNS.
+pub fn system_nameservers() -> Vec<IpAddr> {
+    let content = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn build_query(id: u16, host: &str, record_type: u16) -> Result<Vec<u8>, Error> {
+    let mut message = Vec::new();
+    message.extend(id.to_be_bytes());
+    // Recursion desired, one question.
+    message.extend([0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("`{host}` is not a valid hostname").into());
+        }
+        message.push(label.len() as u8);
+        message.extend(label.as_bytes());
+    }
+    message.push(0);
+    message.extend(record_type.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+pub(crate) fn skip_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *data.get(offset)? as usize;
+        match length {
+            0 => return Some(offset + 1),
+            // A pointer ends the name.
+            _ if length & 0xc0 == 0xc0 => return Some(offset + 2),
+            _ => offset += length + 1,
+        }
+    }
+}
+
+fn parse_response(data: &[u8], record_type: u16) -> Option<Vec<IpAddr>> {
+    let count = |offset: usize| {
+        Some(u16::from_be_bytes([
+            *data.get(offset)?,
+            *data.get(offset + 1)?,
+        ]))
+    };
+    let questions = count(4)?;
+    let answers = count(6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(data, offset)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(data, offset)?;
+        let header = data.get(offset..offset + 10)?;
+        let answer_type = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = data.get(offset + 10..offset + 10 + length)?;
+        offset += 10 + length;
+        if answer_type != record_type {
+            // E.g. a CNAME leading to the address.
+            continue;
+        }
+        match rdata.len() {
+            4 => addresses.push(IpAddr::from(<[u8; 4]>::try_from(rdata).ok()?)),
+            16 => addresses.push(IpAddr::from(<[u8; 16]>::try_from(rdata).ok()?)),
+            _ => return None,
+        }
+    }
+    Some(addresses)
+}
+
+fn query(host: &str, nameserver: IpAddr, record_type: u16) -> Result<Vec<IpAddr>, Error> {
+    let mut id = [0; 2];
+    getrandom::getrandom(&mut id).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    let id = u16::from_be_bytes(id);
+    let message = build_query(id, host, record_type)?;
+
+    let bind_addr = match nameserver {
+        IpAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((bind_addr, 0))?;
+    protect::protect_to(socket.as_raw_fd(), nameserver)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect((nameserver, 53))?;
+    socket.send(&message)?;
+
+    let mut buffer = [0; 1500];
+    loop {
+        let size = socket.recv(&mut buffer)?;
+        let response = &buffer[..size];
+        // Responses to earlier queries are skipped.
+        if size < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+            continue;
+        }
+        if response[3] & 0x0f != 0 {
+            return Err(format!("`{host}` could not be resolved").into());
+        }
+        let e = format!("Invalid DNS response from {nameserver}");
+        return parse_response(response, record_type).ok_or(Error::from(e));
+    }
+}
+
+/// Resolve `host` to all its addresses through the given name servers, or through the system
+/// resolver if there are none. IPv4 addresses come first.
+pub(crate) fn resolve(host: &str, nameservers: &[IpAddr]) -> Result<Vec<IpAddr>, Error> {
+    let e = format!("`{host}` does not resolve to a usable IP address");
+    let mut addrs = Vec::new();
+    if nameservers.is_empty() {
+        addrs.extend((host, 0).to_socket_addrs()?.map(|addr| addr.ip()));
+    }
+    let mut error = Error::from(e);
+    for &nameserver in nameservers {
+        for record_type in [TYPE_A, TYPE_AAAA] {
+            match query(host, nameserver, record_type) {
+                Ok(addresses) => addrs.extend(addresses),
+                Err(e) => error = e,
+            }
+        }
+        // The other name servers are only asked if this one has not answered.
+        if !addrs.is_empty() {
+            break;
+        }
+    }
+    let mut unique = Vec::new();
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    unique.sort_by_key(IpAddr::is_ipv6);
+    match unique.is_empty() {
+        true => Err(error),
+        false => Ok(unique),
+    }
+}
+
+// The addresses a hostname resolved to, or why it could not be resolved.
+pub(crate) type Resolution = Result<Vec<IpAddr>, String>;
+
+enum Lookup {
+    Pending,
+    Done(Resolution, Instant),
+}
+
+/// Resolves the hostnames of destinations which the proxy cannot resolve itself. The lookups run
+/// in the background and go to the name servers in use before the setup, since the system
+/// resolver may by now be the virtual DNS, which is served by the very loop waiting for them.
+/// The waker signals each finished lookup.
+pub(crate) struct HostResolver {
+    nameservers: Vec<IpAddr>,
+    waker: Arc<Waker>,
+    lookups: Arc<Mutex<HashMap<String, Lookup>>>,
+}
+
+impl HostResolver {
+    pub(crate) fn new(nameservers: Vec<IpAddr>, waker: Arc<Waker>) -> Self {
+        Self {
+            nameservers,
+            waker,
+            lookups: Arc::default(),
+        }
+    }
+
+    /// The addresses of `host`, IPv4 addresses first, or `None` while they are being looked up.
+    pub(crate) fn lookup(&self, host: &str) -> Option<Resolution> {
+        let now = Instant::now();
+        let mut lookups = self.lookups.lock().unwrap();
+        lookups.retain(|_, lookup| !matches!(lookup, Lookup::Done(_, expiry) if *expiry <= now));
+        match lookups.get(host) {
+            Some(Lookup::Pending) => return None,
+            Some(Lookup::Done(result, _)) => return Some(result.clone()),
+            None => {}
+        }
+        // The lookup is started once another one has finished.
+        let running = lookups
+            .values()
+            .filter(|lookup| matches!(lookup, Lookup::Pending));
+        if running.count() >= MAX_LOOKUPS {
+            return None;
+        }
+        lookups.insert(host.into(), Lookup::Pending);
+
+        let host = host.to_string();
+        let nameservers = self.nameservers.clone();
+        let waker = self.waker.clone();
+        let lookups = self.lookups.clone();
+        std::thread::spawn(move || {
+            // Without name servers to ask, the system resolver may answer with an address of the
+            // virtual DNS, which leads back into the tunnel.
+            let result = resolve(&host, &nameservers)
+                .map_err(|e| e.to_string())
+                .and_then(|addrs| {
+                    let addrs: Vec<IpAddr> = addrs
+                        .into_iter()
+                        .filter(|addr| !tcp_dns::is_virtual(*addr))
+                        .collect();
+                    match addrs.is_empty() {
+                        true => Err(format!("`{host}` only resolves to virtual addresses")),
+                        false => Ok(addrs),
+                    }
+                });
+            let ttl = match result {
+                Ok(_) => HOST_TTL,
+                Err(_) => FAILED_HOST_TTL,
+            };
+            let lookup = Lookup::Done(result, Instant::now() + ttl);
+            lookups.lock().unwrap().insert(host, lookup);
+            _ = waker.wake();
+        });
+        None
+    }
+}
+
+/// Reaches a proxy given by hostname at the address the hostname currently resolves to. The
+/// hostname is resolved again periodically and when connections to the proxy keep failing, e.g.
+/// because the proxy has moved to another address. Resolving happens in the background so that
+/// connections are not held up, and takes effect for the connections made afterwards. Each
+/// address the hostname newly resolves to is reported, so that it can bypass the tunnel.
+pub(crate) struct ResolvingManager {
+    inner: Rc<dyn ConnectionManager>,
+    host: String,
+    nameservers: Vec<IpAddr>,
+    server: Cell<SocketAddr>,
+    failures: Cell<u32>,
+    resolved_at: Cell<Instant>,
+    pending: RefCell<Option<Receiver<Resolution>>>,
+    // The addresses reported so far, including those the hostname no longer resolves to.
+    addrs: RefCell<Vec<IpAddr>>,
+    on_change: Option<Rc<dyn Fn(IpAddr)>>,
+}
+
+impl ResolvingManager {
+    pub(crate) fn new(
+        inner: Rc<dyn ConnectionManager>,
+        host: &str,
+        server: SocketAddr,
+        addrs: Vec<IpAddr>,
+        nameservers: Vec<IpAddr>,
+        on_change: Option<Rc<dyn Fn(IpAddr)>>,
+    ) -> Rc<Self> {
+        Rc::new(Self {
+            inner,
+            host: host.into(),
+            nameservers,
+            server: Cell::new(server),
+            failures: Cell::new(0),
+            resolved_at: Cell::new(Instant::now()),
+            pending: RefCell::new(None),
+            addrs: RefCell::new(addrs),
+            on_change,
+        })
+    }
+
+    fn start_resolving(&self) {
+        if self.pending.borrow().is_some() {
+            return;
+        }
+        self.resolved_at.set(Instant::now());
+        let (sender, receiver) = mpsc::channel();
+        let host = self.host.clone();
+        let nameservers = self.nameservers.clone();
+        std::thread::spawn(move || {
+            let result = resolve(&host, &nameservers).map_err(|e| e.to_string());
+            let _ = sender.send(result);
+        });
+        *self.pending.borrow_mut() = Some(receiver);
+    }
+
+    // Report the addresses not seen before, and move on to the first one unless the proxy is still
+    // reachable at the current one.
+    fn update(&self, addrs: &[IpAddr]) {
+        for &addr in addrs {
+            if self.addrs.borrow().contains(&addr) {
+                continue;
+            }
+            self.addrs.borrow_mut().push(addr);
+            if let Some(on_change) = &self.on_change {
+                on_change(addr);
+            }
+        }
+        let current = self.server.get();
+        if addrs.contains(&current.ip()) {
+            return;
+        }
+        let server = SocketAddr::new(addrs[0], current.port());
+        log::info!("Proxy {} has moved from {current} to {server}", self.host);
+        self.server.set(server);
+        self.failures.set(0);
+    }
+
+    /// The current address of the proxy, taking the result of a finished resolution into account.
+    fn server(&self) -> SocketAddr {
+        let result = match self.pending.borrow().as_ref().map(Receiver::try_recv) {
+            None | Some(Err(TryRecvError::Empty)) => None,
+            Some(Err(TryRecvError::Disconnected)) => Some(Err("resolver has stopped".into())),
+            Some(Ok(result)) => Some(result),
+        };
+        if let Some(result) = result {
+            self.pending.borrow_mut().take();
+            match result {
+                Ok(addrs) => self.update(&addrs),
+                Err(e) => log::warn!("Cannot resolve proxy {} again: {e}", self.host),
+            }
+        }
+        if self.resolved_at.get().elapsed() >= RESOLVE_INTERVAL {
+            self.start_resolving();
+        }
+        self.server.get()
+    }
+}
+
+impl ConnectionManager for ResolvingManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        self.inner.handles_connection(connection)
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        self.inner.new_connection(connection, manager)
+    }
+
+    fn new_bind(
+        &self,
+        connection: &Connection,
+        control: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        self.inner.new_bind(connection, control, manager)
+    }
+
+    fn close_connection(&self, connection: &Connection) {
+        self.inner.close_connection(connection)
+    }
+
+    fn get_server(&self) -> SocketAddr {
+        self.server()
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        self.inner.get_credentials()
+    }
+
+    fn get_unix_socket(&self) -> Option<&Path> {
+        self.inner.get_unix_socket()
+    }
+
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        self.inner.get_bridge()
+    }
+
+    fn get_server_for(&self, _: &Connection) -> Result<SocketAddr, Error> {
+        Ok(self.server())
+    }
+
+    fn resolves_locally(&self, connection: &Connection) -> bool {
+        self.inner.resolves_locally(connection)
+    }
+
+    fn report_refused_hostname(&self, connection: &Connection, name: &str) {
+        self.inner.report_refused_hostname(connection, name)
+    }
+
+    fn get_interface(&self) -> Option<&str> {
+        self.inner.get_interface()
+    }
+
+    fn warm_handshake(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.inner.warm_handshake()
+    }
+
+    fn report_health(&self, server: SocketAddr, healthy: bool) {
+        self.inner.report_health(server, healthy);
+        if healthy {
+            self.failures.set(0);
+            return;
+        }
+        self.failures.set(self.failures.get() + 1);
+        if self.failures.get() >= MAX_FAILURES
+            && self.resolved_at.get().elapsed() >= FAILURE_RESOLVE_INTERVAL
+        {
+            log::info!(
+                "Connections to proxy {server} keep failing, resolving {} again",
+                self.host
+            );
+            self.failures.set(0);
+            self.start_resolving();
+        }
+    }
+}
@@ -0,0 +1,460 @@
+#![cfg(target_os = "linux")]
+
+use crate::error::Error;
+use libc::{sock_filter, sock_fprog};
+use std::path::Path;
+
+// The architecture the syscall numbers below belong to, as reported to seccomp filters.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// The offsets of the syscall number and of the architecture in `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// Syscalls made with the x32 ABI on x86_64 have this bit set, and are denied altogether.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// The syscalls made once the tunnel is set up, by the event loop, the threads of the bridges,
+// resolvers and DNS forwarders, and the C library on their behalf, e.g. to resolve names. All
+// others are denied, e.g. running programs, inspecting other processes, changing privileges,
+// namespaces or mounts and reaching into the kernel.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Files and descriptors
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    // Polling
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads, signals and time
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_uname,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_prlimit64,
+    // The older variants, which only exist on x86_64
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_accept,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_time,
+];
+
+// The offsets of the lower halves of the first two arguments in `struct seccomp_data`.
+const SECCOMP_DATA_ARG0: u32 = 16;
+const SECCOMP_DATA_ARG1: u32 = 24;
+
+// The flags of `clone` creating namespaces, which threads never take.
+const CLONE_NEW_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+// The option of `prctl` with which the C library names the memory of thread stacks.
+const PR_SET_VMA: u32 = 0x5356_4d41;
+
+// The syscalls only allowed with some values of one of their arguments: the requests of `ioctl`
+// making sockets non-blocking, opening queues of the tun device and reading or setting its MTU,
+// and the options of `prctl` naming threads and their memory.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const FILTERED_SYSCALLS: &[(libc::c_long, u32, &[u32])] = &[
+    (
+        libc::SYS_ioctl,
+        SECCOMP_DATA_ARG1,
+        &[
+            libc::FIONBIO as u32,
+            crate::tun_config::TUNSETIFF as u32,
+            libc::SIOCGIFMTU as u32,
+            libc::SIOCSIFMTU as u32,
+        ],
+    ),
+    (
+        libc::SYS_prctl,
+        SECCOMP_DATA_ARG0,
+        &[libc::PR_SET_NAME as u32, PR_SET_VMA],
+    ),
+];
+
+// The file system accesses known to the first version of Landlock, from executing files to
+// creating symbolic links, of which only reading is allowed, and replacing files in the given
+// directories.
+const LANDLOCK_ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+fn statement(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter() -> Vec<sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut filter = vec![
+        statement(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET | BPF_K, deny),
+    ]);
+    // The C library falls back to `clone` when `clone3` is missing, whose flags can be checked
+    // unlike those `clone3` reads from memory: threads are created, but no namespaces.
+    let missing = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+    filter.extend([
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+        statement(BPF_RET | BPF_K, missing),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 4),
+        statement(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARG0),
+        jump(BPF_JMP | libc::BPF_JSET | BPF_K, CLONE_NEW_FLAGS, 0, 1),
+        statement(BPF_RET | BPF_K, deny),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+    ]);
+    // Each of these syscalls is followed by the check of its argument, skipped for the others.
+    for &(nr, argument, values) in FILTERED_SYSCALLS {
+        let length = values.len() as u8;
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, length + 3));
+        filter.push(statement(BPF_LD | BPF_W | BPF_ABS, argument));
+        for (i, &value) in values.iter().enumerate() {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, value, length - i as u8, 0));
+        }
+        filter.push(statement(BPF_RET | BPF_K, deny));
+        filter.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    // Each match jumps past the remaining ones and the verdict denying the syscall.
+    for (i, &nr) in ALLOWED_SYSCALLS.iter().enumerate() {
+        let remaining = (ALLOWED_SYSCALLS.len() - i) as u8;
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, remaining, 0));
+    }
+    filter.push(statement(BPF_RET | BPF_K, deny));
+    filter.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp_filter() -> Vec<sock_filter> {
+    Vec::new()
+}
+
+// Install the filter without allocating, so that it can also be done in a forked child.
+fn install_seccomp(filter: &mut [sock_filter]) -> std::io::Result<()> {
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // The filter applies to all threads, e.g. those resolving proxies in the background.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn apply_seccomp() -> Result<(), Error> {
+    let mut filter = seccomp_filter();
+    if filter.is_empty() {
+        return Err("The seccomp filter is not available on this architecture".into());
+    }
+    install_seccomp(&mut filter)?;
+    Ok(())
+}
+
+// Allow `access` to the files beneath `path` in the Landlock ruleset.
+fn add_landlock_rule(ruleset: libc::c_int, path: &Path, access: u64) -> Result<(), Error> {
+    let parent_fd = nix::fcntl::open(
+        path,
+        nix::fcntl::OFlag::O_PATH | nix::fcntl::OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )
+    .map_err(|e| Error::from(format!("{}: {e}", path.display())))?;
+    let rule = LandlockPathBeneathAttr {
+        allowed_access: access,
+        parent_fd,
+    };
+    let rule_type = LANDLOCK_RULE_PATH_BENEATH;
+    let added = unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset, rule_type, &rule, 0) };
+    let error = std::io::Error::last_os_error();
+    nix::unistd::close(parent_fd)?;
+    if added != 0 {
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+// Only let the files opened from now on be read, except for replacing files in `writable`, e.g.
+// the state of the virtual DNS. Files opened before can still be written to.
+fn apply_landlock(writable: &[&Path]) -> Result<(), Error> {
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: LANDLOCK_ACCESS_FS_ALL,
+    };
+    let size = std::mem::size_of::<LandlockRulesetAttr>();
+    let ruleset = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, &attr, size, 0) };
+    if ruleset < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let ruleset = ruleset as libc::c_int;
+    let result = (|| -> Result<(), Error> {
+        let read = LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR;
+        add_landlock_rule(ruleset, Path::new("/"), read)?;
+        let replace = LANDLOCK_ACCESS_FS_WRITE_FILE
+            | LANDLOCK_ACCESS_FS_REMOVE_FILE
+            | LANDLOCK_ACCESS_FS_MAKE_REG;
+        for path in writable {
+            add_landlock_rule(ruleset, path, read | replace)?;
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    })();
+    nix::unistd::close(ruleset)?;
+    result
+}
+
+/// Confine the process once it is set up, as a defense in depth for the event loop parsing the
+/// packets of the tunnel: a seccomp filter only allows the syscalls it makes, which excludes e.g.
+/// running programs or changing privileges, and Landlock denies writing to files opened from now
+/// on, other than creating and replacing files beneath the directories `writable`.
+/// Landlock only confines the calling thread and the threads it spawns later on, so the sandbox
+/// is to be entered before any other thread is spawned, e.g. through
+/// [`Options::with_tun_opened_handler`](crate::Options::with_tun_opened_handler). It is skipped
+/// with a warning on kernels lacking Landlock.
+pub fn enter_sandbox(writable: &[&Path]) -> Result<(), Error> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if let Err(e) = apply_landlock(writable) {
+        log::warn!("Cannot restrict the file system access through Landlock: {e}");
+    }
+    apply_seccomp()?;
+    log::info!("Entered the sandbox");
+    Ok(())
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    // The outcomes of syscalls made in a child confined by the filter: 0 when allowed, or errno.
+    fn errors_in_sandbox(calls: &[fn() -> libc::c_long]) -> Vec<i32> {
+        let mut filter = seccomp_filter();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = (fds[0], fds[1]);
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            // Only async-signal-safe calls are made here, the test harness having other threads.
+            unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+            if install_seccomp(&mut filter).is_err() {
+                unsafe { libc::_exit(1) };
+            }
+            for call in calls {
+                let errno = match call() {
+                    -1 => unsafe { *libc::__errno_location() },
+                    _ => 0,
+                };
+                unsafe { libc::write(writer, &errno as *const i32 as *const _, 4) };
+            }
+            unsafe { libc::_exit(0) };
+        }
+        unsafe { libc::close(writer) };
+        let mut errors = vec![0i32; calls.len()];
+        let size = errors.len() * 4;
+        let mut read = 0;
+        while read < size {
+            let buffer = unsafe { (errors.as_mut_ptr() as *mut u8).add(read) };
+            match unsafe { libc::read(reader, buffer as *mut _, size - read) } {
+                n if n > 0 => read += n as usize,
+                _ => break,
+            }
+        }
+        let mut status = 0;
+        unsafe {
+            libc::close(reader);
+            libc::waitpid(child, &mut status, 0);
+        }
+        assert_eq!(libc::WEXITSTATUS(status), 0, "the filter was not installed");
+        assert_eq!(read, size);
+        errors
+    }
+
+    #[test]
+    fn denies_syscalls_and_arguments() {
+        let errors = errors_in_sandbox(&[
+            || unsafe { libc::syscall(libc::SYS_getpid) },
+            || unsafe { libc::syscall(libc::SYS_unshare, libc::CLONE_NEWUSER) },
+            || unsafe { libc::syscall(libc::SYS_clone3, 0, 0) },
+            // A new network namespace is refused before any child is created.
+            || unsafe { libc::syscall(libc::SYS_clone, libc::CLONE_NEWNET | libc::SIGCHLD, 0) },
+            || unsafe { libc::ioctl(0, libc::FIOCLEX) as libc::c_long },
+            || unsafe { libc::prctl(libc::PR_SET_NAME, b"sandboxed\0".as_ptr()) as libc::c_long },
+            || unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1) as libc::c_long },
+        ]);
+        let expected = [
+            0,
+            libc::EPERM,
+            libc::ENOSYS,
+            libc::EPERM,
+            libc::EPERM,
+            0,
+            libc::EPERM,
+        ];
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn allows_non_blocking_sockets() {
+        let errors = errors_in_sandbox(&[|| {
+            let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+            let mut enabled: libc::c_int = 1;
+            unsafe { libc::ioctl(socket, libc::FIONBIO, &mut enabled) as libc::c_long }
+        }]);
+        assert_eq!(errors, [0]);
+    }
+}
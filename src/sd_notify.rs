@@ -0,0 +1,84 @@
+use crate::protect;
+use nix::poll::{PollFd, PollFlags};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+// A proxy which has not accepted a connection in time is checked again after the interval.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Whether tun2proxy runs as a systemd service of `Type=notify`, which expects to be notified.
+pub(crate) fn is_supervised() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+// Send the state, e.g. `READY=1`, to the service manager, as with `sd_notify`.
+pub(crate) fn notify(state: &str) {
+    if let Err(e) = send(state) {
+        log::warn!("Cannot notify the service manager: {e}");
+    }
+}
+
+fn send(state: &str) -> std::io::Result<()> {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+// The interval at which the service manager expects `WATCHDOG=1`, of which half is used to be on
+// the safe side, as recommended for `sd_watchdog_enabled`.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+// Send `READY=1` once any of the proxies accepts a connection, or right away if there is none
+// to check, e.g. for proxies reached through Unix domain sockets.
+pub(crate) fn notify_ready_when_reachable(servers: Vec<SocketAddr>) {
+    if servers.is_empty() {
+        notify("READY=1");
+        return;
+    }
+    std::thread::spawn(move || loop {
+        if let Some(server) = servers.iter().find(|&&server| probe(server)) {
+            log::info!("Proxy {server} is reachable, notifying the service manager");
+            notify("READY=1");
+            return;
+        }
+        std::thread::sleep(PROBE_INTERVAL);
+    });
+}
+
+fn probe(server: SocketAddr) -> bool {
+    let stream = match protect::connect_tcp(server, false) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let mut fds = [PollFd::new(stream.as_raw_fd(), PollFlags::POLLOUT)];
+    let timeout = PROBE_TIMEOUT.as_millis() as i32;
+    matches!(nix::poll::poll(&mut fds, timeout), Ok(1))
+        && matches!(stream.take_error(), Ok(None))
+        && stream.peer_addr().is_ok()
+}
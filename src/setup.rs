@@ -1,6 +1,9 @@
 #![cfg(target_os = "linux")]
 
 use crate::error::Error;
+use crate::fd_passing::{receive_tun_fd, send_tun_fd};
+use crate::netlink::Netlink;
+use smoltcp::phy::{Medium, TunTapInterface};
 use smoltcp::wire::IpCidr;
 use std::convert::TryFrom;
 
@@ -8,25 +11,50 @@ use std::ffi::OsStr;
 use std::io::BufRead;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use std::os::unix::io::RawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 
 use std::process::{Command, Output};
 
 use std::str::FromStr;
 
 use fork::Fork;
+use nix::poll::{PollFd, PollFlags};
 
 #[derive(Clone)]
 pub struct Setup {
     routes: Vec<IpCidr>,
-    tunnel_bypass_addr: IpAddr,
+    tunnel_bypass_addrs: Vec<IpAddr>,
     allow_private: bool,
     tun: String,
     set_up: bool,
-    delete_proxy_route: bool,
+    proxy_routes: Vec<IpAddr>,
     child: libc::pid_t,
+    route_pipe: Option<RawFd>,
+    netns: Option<String>,
+    created_netns: bool,
+    created_resolv_conf: bool,
+    tun_fd: Option<RawFd>,
+    tun_addrs: Vec<IpCidr>,
+    fwmark: Option<u32>,
+    container: bool,
+    original_resolv_conf: Option<Vec<u8>>,
+    tun_user: Option<String>,
+    tun_group: Option<String>,
+    tun_mtu: Option<u32>,
 }
 
+// The device node through which tun interfaces are created and opened.
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+// Where the setup left in place by `Setup::persist` is recorded for `Setup::cleanup`.
+const STATE_DIR: &str = "/run/tun2proxy";
+
+// The addresses of the tun interface within a network namespace, where it is the only link.
+const NETNS_TUN_ADDRS: [&str; 2] = ["169.254.19.1/30", "fdc6:7475:6e32::1/64"];
+
 pub fn get_default_cidrs() -> [IpCidr; 4] {
     [
         IpCidr::new(Ipv4Addr::from_str("0.0.0.0").unwrap().into(), 1),
@@ -36,6 +64,56 @@ pub fn get_default_cidrs() -> [IpCidr; 4] {
     ]
 }
 
+// The notifications of changed links and routes, after which the routes to the proxies are
+// checked once no more changes arrived for a while.
+const ROUTE_CHANGE_GROUPS: u32 =
+    (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE) as u32;
+const ROUTE_CHANGE_DELAY_MS: libc::c_int = 500;
+
+// The types of routes listed by `ip route show` in place of the destination, which reject traffic.
+const UNREACHABLE_ROUTE_TYPES: [&str; 4] = ["unreachable", "blackhole", "prohibit", "throw"];
+
+// Drop what `ip route show` lists but `ip route add` rejects: the flags reporting the state of
+// the route, and the expiry of routes learned through IPv6 router advertisements, which would
+// also let the cloned route expire while the original is refreshed.
+fn clonable_route_components(route_components: Vec<String>) -> Vec<String> {
+    let mut clonable = Vec::new();
+    let mut components = route_components.into_iter();
+    while let Some(component) = components.next() {
+        match component.as_str() {
+            "expires" => {
+                components.next();
+            }
+            "dead" | "linkdown" | "offload" | "trap" | "offload_failed" => {}
+            _ => clonable.push(component),
+        }
+    }
+    clonable
+}
+
+// IPv6 routes cannot be added while IPv6 is disabled, e.g. through the `ipv6.disable` kernel
+// parameter or the `net.ipv6.conf.all.disable_ipv6` sysctl.
+// Whether the route is the one to the single address `addr`, as added for the proxies.
+fn is_host_route(cidr: &IpCidr, addr: IpAddr) -> bool {
+    let host_prefix_len = if addr.is_ipv6() { 128 } else { 32 };
+    cidr.prefix_len() == host_prefix_len && cidr.contains_addr(&addr.into())
+}
+
+// The gateways and devices through which a route leads, leaving out its other attributes, which
+// `ip route show` does not list the same way they were given to `ip route add`.
+fn next_hops(route_components: &[String]) -> Vec<&str> {
+    route_components
+        .windows(2)
+        .filter(|pair| pair[0] == "via" || pair[0] == "dev")
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+fn ipv6_enabled() -> bool {
+    std::fs::read_to_string("/proc/sys/net/ipv6/conf/all/disable_ipv6")
+        .is_ok_and(|disabled| disabled.trim() == "0")
+}
+
 fn run_iproute<I, S>(args: I, error: &str, require_success: bool) -> Result<Output, Error>
 where
     I: IntoIterator<Item = S>,
@@ -89,17 +167,122 @@ impl Setup {
         let routes_cidr = routes.into_iter().collect();
         Self {
             tun: tun.into(),
-            tunnel_bypass_addr: *tunnel_bypass_addr,
+            tunnel_bypass_addrs: vec![*tunnel_bypass_addr],
             allow_private,
             routes: routes_cidr,
             set_up: false,
-            delete_proxy_route: false,
+            proxy_routes: Vec::new(),
             child: 0,
+            route_pipe: None,
+            netns: None,
+            created_netns: false,
+            created_resolv_conf: false,
+            tun_fd: None,
+            tun_addrs: Vec::new(),
+            fwmark: None,
+            container: false,
+            original_resolv_conf: None,
+            tun_user: None,
+            tun_group: None,
+            tun_mtu: None,
         }
     }
 
-    fn route_proxy_address(&mut self) -> Result<bool, Error> {
-        let route_show_args = if self.tunnel_bypass_addr.is_ipv6() {
+    /// Move the tun interface into the network namespace `name`, which is created unless it
+    /// exists, and route all traffic within it through the tunnel, leaving the routes of the host
+    /// alone. The sockets to the proxies stay in the namespace of tun2proxy.
+    pub fn with_netns(mut self, name: impl Into<String>) -> Self {
+        self.netns = Some(name.into());
+        self
+    }
+
+    /// The file descriptor of the tun interface opened during the setup, which has to be used in
+    /// place of its name, as the interface is no longer visible once moved into a network
+    /// namespace.
+    pub fn tun_fd(&self) -> Option<RawFd> {
+        self.tun_fd
+    }
+
+    /// Instead of a route around the tunnel for each proxy address, route the traffic through the
+    /// tunnel by means of policy routing, except for the traffic of the sockets carrying `mark`,
+    /// see [`crate::Options::with_socket_mark`]. The routes to the tunnel go into the routing
+    /// table with the number `mark`, which is consulted for unmarked traffic only, and only once
+    /// no more specific route than a default route of the main table matches.
+    pub fn with_fwmark(mut self, mark: u32) -> Self {
+        self.fwmark = Some(mark);
+        self
+    }
+
+    /// Set up a container, or another network namespace of its own, which tun2proxy runs in with
+    /// the `CAP_NET_ADMIN` capability but without the privileges to mount files: the device node
+    /// `/dev/net/tun` is created if missing, and `/etc/resolv.conf` is overwritten rather than
+    /// mounted over, and restored on exit.
+    pub fn with_container(mut self) -> Self {
+        self.container = true;
+        self
+    }
+
+    /// Let `user` open the tun interface without privileges, as after [`Setup::persist`].
+    pub fn with_tun_owner(mut self, user: &str) -> Self {
+        self.tun_user = Some(user.to_string());
+        self
+    }
+
+    /// Let the members of `group` open the tun interface without privileges.
+    pub fn with_tun_group(mut self, group: &str) -> Self {
+        self.tun_group = Some(group.to_string());
+        self
+    }
+
+    /// Give the tun interface an MTU of `mtu` in place of the default of 1500 bytes.
+    pub fn with_tun_mtu(mut self, mtu: u32) -> Self {
+        self.tun_mtu = Some(mtu);
+        self
+    }
+
+    /// Give the tun interface the address `addr` with the prefix length `prefix_len`, which takes
+    /// the place of the default address of its family within a network namespace.
+    pub fn with_tun_addr(mut self, addr: &IpAddr, prefix_len: u8) -> Self {
+        let cidr = IpCidr::new((*addr).into(), prefix_len);
+        self.tun_addrs
+            .retain(|other| matches!(other, IpCidr::Ipv4(_)) != addr.is_ipv4());
+        self.tun_addrs.push(cidr);
+        self
+    }
+
+    // The addresses of the tun interface within a network namespace, those given taking the place
+    // of the defaults of their family.
+    fn netns_tun_addrs(&self) -> Vec<IpCidr> {
+        let is_ipv4 = |addr: &IpCidr| matches!(addr, IpCidr::Ipv4(_));
+        let mut addrs = self.tun_addrs.clone();
+        for default in NETNS_TUN_ADDRS {
+            let default = IpCidr::from_str(default).unwrap();
+            if !addrs.iter().any(|addr| is_ipv4(addr) == is_ipv4(&default)) {
+                addrs.push(default);
+            }
+        }
+        addrs
+    }
+
+    /// Let traffic to another address bypass the tunnel, e.g. that of a fallback proxy.
+    pub fn with_bypass_addr(mut self, tunnel_bypass_addr: &IpAddr) -> Self {
+        if !self.tunnel_bypass_addrs.contains(tunnel_bypass_addr) {
+            self.tunnel_bypass_addrs.push(*tunnel_bypass_addr);
+        }
+        self
+    }
+
+    /// Leave a range out of the routes to the tunnel, so that its traffic follows the routes
+    /// otherwise in place, e.g. to the local network.
+    pub fn with_bypass_cidr(mut self, cidr: &IpCidr) -> Self {
+        self.routes = crate::cidr::exclude(&self.routes, cidr);
+        self
+    }
+
+    // The routes of the address family of `tunnel_bypass_addr` which do not lead into the tunnel,
+    // the most specific first.
+    fn routes(&self, tunnel_bypass_addr: IpAddr) -> Result<Vec<(IpCidr, Vec<String>)>, Error> {
+        let route_show_args = if tunnel_bypass_addr.is_ipv6() {
             ["ip", "-6", "route", "show"]
         } else {
             ["ip", "-4", "route", "show"]
@@ -114,14 +297,24 @@ impl Setup {
                 break;
             }
             let line = line.unwrap();
+            // The next hops of a multipath route, as for routers learned through IPv6 router
+            // advertisements, follow on lines of their own.
             if line.starts_with([' ', '\t']) {
+                if let Some((_, route_components)) = route_info.last_mut() {
+                    route_components.extend(line.split_whitespace().map(String::from));
+                }
                 continue;
             }
 
             let mut split = line.split_whitespace();
             let mut dst_str = split.next().unwrap();
+            // Routes which reject the traffic, e.g. for unassigned parts of a delegated IPv6
+            // prefix, cannot be cloned.
+            if UNREACHABLE_ROUTE_TYPES.contains(&dst_str) {
+                continue;
+            }
             if dst_str == "default" {
-                dst_str = if self.tunnel_bypass_addr.is_ipv6() {
+                dst_str = if tunnel_bypass_addr.is_ipv6() {
                     "::/0"
                 } else {
                     "0.0.0.0/0"
@@ -131,7 +324,7 @@ impl Setup {
             let (addr_str, prefix_len_str) = match dst_str.split_once(['/']) {
                 None => (
                     dst_str,
-                    if self.tunnel_bypass_addr.is_ipv6() {
+                    if tunnel_bypass_addr.is_ipv6() {
                         "128"
                     } else {
                         "32"
@@ -147,28 +340,174 @@ impl Setup {
             let route_components: Vec<String> = split.map(String::from).collect();
             route_info.push((cidr, route_components))
         }
+        // Once set up, the tunnel routes would otherwise swallow the proxy address.
+        route_info.retain(|(_, route_components)| {
+            !route_components
+                .windows(2)
+                .any(|pair| pair[0] == "dev" && pair[1] == self.tun)
+        });
 
         // Sort routes by prefix length, the most specific route comes first.
         route_info.sort_by(|entry1, entry2| entry2.0.prefix_len().cmp(&entry1.0.prefix_len()));
 
-        for (cidr, route_components) in route_info {
-            if !cidr.contains_addr(&smoltcp::wire::IpAddress::from(self.tunnel_bypass_addr)) {
+        Ok(route_info)
+    }
+
+    // The route to clone from the default route for `tunnel_bypass_addr`, unless the address is
+    // routed through a more specific route, leaving out the route added for it before.
+    fn proxy_route(
+        &self,
+        routes: &[(IpCidr, Vec<String>)],
+        tunnel_bypass_addr: IpAddr,
+    ) -> Option<Vec<String>> {
+        let owned = self.proxy_routes.contains(&tunnel_bypass_addr);
+        for (cidr, route_components) in routes {
+            if !cidr.contains_addr(&smoltcp::wire::IpAddress::from(tunnel_bypass_addr)) {
+                continue;
+            }
+            if owned && is_host_route(cidr, tunnel_bypass_addr) {
                 continue;
             }
 
             // The IP address is routed through a more specific route than the default route.
             // In this case, there is nothing to do.
             if cidr.prefix_len() != 0 {
-                break;
+                return None;
+            }
+            return Some(clonable_route_components(route_components.clone()));
+        }
+        None
+    }
+
+    fn route_proxy_address(&mut self, tunnel_bypass_addr: IpAddr) -> Result<bool, Error> {
+        let routes = self.routes(tunnel_bypass_addr)?;
+        let route_components = match self.proxy_route(&routes, tunnel_bypass_addr) {
+            Some(route_components) => route_components,
+            None => return Ok(false),
+        };
+        let mut proxy_route = vec!["ip".into(), "route".into(), "add".into()];
+        proxy_route.push(tunnel_bypass_addr.to_string());
+        proxy_route.extend(route_components);
+        run_iproute(proxy_route, "failed to clone route for proxy", false)?;
+        self.proxy_routes.push(tunnel_bypass_addr);
+        Ok(true)
+    }
+
+    // Follow changes of the default route, e.g. when roaming to another network or when DHCP
+    // hands out another gateway, as the cloned routes to the proxies would otherwise point to the
+    // old gateway or vanish along with their interface, letting the traffic to the proxies loop
+    // through the tunnel.
+    fn refresh_proxy_routes(&mut self) -> Result<(), Error> {
+        for tunnel_bypass_addr in self.tunnel_bypass_addrs.clone() {
+            let routes = self.routes(tunnel_bypass_addr)?;
+            let wanted = self.proxy_route(&routes, tunnel_bypass_addr);
+            let owned = self.proxy_routes.contains(&tunnel_bypass_addr);
+            let current = routes
+                .iter()
+                .find(|(cidr, _)| is_host_route(cidr, tunnel_bypass_addr))
+                .filter(|_| owned);
+            match (&wanted, current) {
+                (None, None) if !owned => continue,
+                (Some(wanted), Some((_, current))) if next_hops(wanted) == next_hops(current) => {
+                    continue
+                }
+                _ => {}
+            }
+            if current.is_some() {
+                let addr = tunnel_bypass_addr.to_string();
+                run_iproute(
+                    ["ip", "route", "del", addr.as_str()],
+                    "failed to delete route",
+                    false,
+                )?;
+            }
+            self.proxy_routes.retain(|addr| *addr != tunnel_bypass_addr);
+            if self.route_proxy_address(tunnel_bypass_addr)? {
+                log::info!(
+                    "[{}] Rerouting {} around the tunnel",
+                    nix::unistd::getpid(),
+                    tunnel_bypass_addr
+                );
             }
+        }
+        Ok(())
+    }
 
-            let mut proxy_route = vec!["ip".into(), "route".into(), "add".into()];
-            proxy_route.push(self.tunnel_bypass_addr.to_string());
-            proxy_route.extend(route_components.into_iter());
-            run_iproute(proxy_route, "failed to clone route for proxy", false)?;
-            return Ok(true);
+    fn add_proxy_route(&mut self, tunnel_bypass_addr: IpAddr) -> Result<(), Error> {
+        // Traffic to the proxies never reaches a tunnel in another network namespace, nor is the
+        // marked traffic to them routed through the tunnel.
+        if self.netns.is_some()
+            || self.fwmark.is_some()
+            || self.proxy_routes.contains(&tunnel_bypass_addr)
+        {
+            return Ok(());
+        }
+        if !self.tunnel_bypass_addrs.contains(&tunnel_bypass_addr) {
+            self.tunnel_bypass_addrs.push(tunnel_bypass_addr);
+        }
+        if self.route_proxy_address(tunnel_bypass_addr)? {
+            log::info!(
+                "[{}] Routing {} around the tunnel",
+                nix::unistd::getpid(),
+                tunnel_bypass_addr
+            );
         }
-        Ok(false)
+        Ok(())
+    }
+
+    // Add the routes for the addresses written to the pipe, one per line. Lines which are not an
+    // address are skipped, lest they end the setup.
+    fn read_route_requests(
+        &mut self,
+        read_routes: RawFd,
+        pending: &mut Vec<u8>,
+    ) -> Result<bool, Error> {
+        let mut buf = [0; 256];
+        let size = nix::unistd::read(read_routes, &mut buf)?;
+        if size == 0 {
+            return Ok(false);
+        }
+        pending.extend(&buf[..size]);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let addr = match line.trim().parse::<IpAddr>() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    log::warn!("Ignoring route request `{}`", line.trim().escape_debug());
+                    continue;
+                }
+            };
+            if let Err(e) = self.add_proxy_route(addr) {
+                log::error!("{e}");
+            }
+        }
+        Ok(true)
+    }
+
+    // Containers often come without the device node, which the tun interfaces are created through.
+    fn create_tun_device() -> Result<(), Error> {
+        if Path::new(TUN_DEVICE).exists() {
+            return Ok(());
+        }
+        log::info!("Creating {TUN_DEVICE}");
+        std::fs::create_dir_all(Path::new(TUN_DEVICE).parent().unwrap())?;
+        nix::sys::stat::mknod(
+            TUN_DEVICE,
+            nix::sys::stat::SFlag::S_IFCHR,
+            nix::sys::stat::Mode::from_bits(0o666).unwrap(),
+            nix::sys::stat::makedev(10, 200),
+        )?;
+        // The mode given to mknod is subject to the umask.
+        std::fs::set_permissions(TUN_DEVICE, std::fs::Permissions::from_mode(0o666))?;
+        Ok(())
+    }
+
+    // Without the privileges to mount files, the content of resolv.conf is replaced instead.
+    fn replace_resolv_conf(&mut self) -> Result<(), Error> {
+        self.original_resolv_conf = Some(std::fs::read("/etc/resolv.conf")?);
+        std::fs::write("/etc/resolv.conf", "nameserver 198.18.0.1\n")?;
+        Ok(())
     }
 
     fn setup_resolv_conf() -> Result<(), Error> {
@@ -198,21 +537,114 @@ impl Setup {
         Ok(())
     }
 
-    fn add_tunnel_routes(&self) -> Result<(), Error> {
-        for route in &self.routes {
-            run_iproute(
-                [
-                    "ip",
-                    "route",
-                    "add",
-                    route.to_string().as_str(),
-                    "dev",
-                    self.tun.as_str(),
-                ],
+    fn netns_resolv_conf(netns: &str) -> String {
+        format!("/etc/netns/{netns}/resolv.conf")
+    }
+
+    // Create the network namespace unless it exists, move the tun interface into it and route all
+    // traffic there through the tunnel. Processes started through `ip netns exec` see the
+    // resolv.conf of the namespace in place of the one of the host.
+    fn setup_netns(&mut self, netns: &str) -> Result<(), Error> {
+        run_iproute(
+            ["ip", "link", "set", self.tun.as_str(), "netns", netns],
+            "failed to move tunnel device into network namespace",
+            true,
+        )?;
+        let in_netns = |args: &[&str], error: &str| {
+            let mut command = vec!["ip", "-n", netns];
+            command.extend_from_slice(args);
+            run_iproute(command, error, true)
+        };
+        in_netns(&["link", "set", "lo", "up"], "failed to bring up loopback")?;
+        let mtu = self.tun_mtu.map(|mtu| mtu.to_string());
+        let mut command = vec!["link", "set", self.tun.as_str(), "up"];
+        if let Some(mtu) = &mtu {
+            command.extend(["mtu", mtu]);
+        }
+        in_netns(&command, "failed to bring up tunnel device")?;
+        for addr in self.netns_tun_addrs() {
+            if matches!(addr, IpCidr::Ipv6(_)) && !ipv6_enabled() {
+                continue;
+            }
+            in_netns(
+                &["addr", "add", &addr.to_string(), "dev", self.tun.as_str()],
+                "failed to add address to tunnel device",
+            )?;
+        }
+        for route in self.tunnel_routes() {
+            in_netns(
+                &["route", "add", &route.to_string(), "dev", self.tun.as_str()],
                 "failed to add route",
-                true,
             )?;
         }
+
+        let resolv_conf = Self::netns_resolv_conf(netns);
+        if !Path::new(&resolv_conf).exists() {
+            std::fs::create_dir_all(Path::new(&resolv_conf).parent().unwrap())?;
+            std::fs::write(&resolv_conf, "nameserver 198.18.0.1\n")?;
+            self.created_resolv_conf = true;
+        } else {
+            log::warn!("Keeping {resolv_conf}, which may not point to the virtual DNS");
+        }
+        Ok(())
+    }
+
+    // The tunnel routes which can be added, leaving out those for IPv6 if it is disabled.
+    fn tunnel_routes(&self) -> impl Iterator<Item = &IpCidr> {
+        let ipv6 = ipv6_enabled();
+        if !ipv6
+            && self
+                .routes
+                .iter()
+                .any(|route| matches!(route, IpCidr::Ipv6(_)))
+        {
+            log::warn!("IPv6 is disabled, so no IPv6 traffic is routed through the tunnel");
+        }
+        self.routes
+            .iter()
+            .filter(move |route| ipv6 || !matches!(route, IpCidr::Ipv6(_)))
+    }
+
+    fn add_tunnel_routes(&self) -> Result<(), Error> {
+        for route in self.tunnel_routes() {
+            let mut command = vec!["ip".to_string(), "route".into(), "add".into()];
+            command.extend([route.to_string(), "dev".into(), self.tun.clone()]);
+            if let Some(mark) = self.fwmark {
+                command.extend(["table".into(), mark.to_string()]);
+            }
+            run_iproute(command, "failed to add route", true)?;
+        }
+        Ok(())
+    }
+
+    // The rules sending the unmarked traffic to the table of the tunnel, unless the main table has
+    // a route for it other than a default route, for each address family routed to the tunnel.
+    fn fwmark_rules(&self, mark: u32) -> Vec<(&'static str, Vec<String>)> {
+        let mark = mark.to_string();
+        let mut rules = Vec::new();
+        for (family, ipv6) in [("-4", false), ("-6", true)] {
+            if (ipv6 && !ipv6_enabled())
+                || !self
+                    .routes
+                    .iter()
+                    .any(|route| matches!(route, IpCidr::Ipv6(_)) == ipv6)
+            {
+                continue;
+            }
+            let rule = ["not", "fwmark", &mark, "table", &mark];
+            rules.push((family, rule.iter().map(|arg| arg.to_string()).collect()));
+            let rule = ["table", "main", "suppress_prefixlength", "0"];
+            rules.push((family, rule.iter().map(|arg| arg.to_string()).collect()));
+        }
+        rules
+    }
+
+    fn add_fwmark_rules(&self, mark: u32) -> Result<(), Error> {
+        for (family, rule) in self.fwmark_rules(mark) {
+            let mut command = vec!["ip".to_string(), family.into(), "rule".into(), "add".into()];
+            command.extend(rule);
+            run_iproute(command, "failed to add routing rule", true)?;
+        }
         Ok(())
     }
 
@@ -222,49 +654,140 @@ impl Setup {
             "[{}] Restoring network configuration",
             nix::unistd::getpid()
         );
+        if let Some(netns) = self.netns.clone() {
+            let _ = Command::new("ip")
+                .args(["-n", netns.as_str(), "link", "del", self.tun.as_str()])
+                .output();
+            if std::mem::take(&mut self.created_resolv_conf) {
+                let resolv_conf = Self::netns_resolv_conf(&netns);
+                let _ = std::fs::remove_file(&resolv_conf);
+                // The directory is only removed if nothing else has been placed there.
+                let _ = std::fs::remove_dir(Path::new(&resolv_conf).parent().unwrap());
+            }
+            if std::mem::take(&mut self.created_netns) {
+                let _ = Command::new("ip")
+                    .args(["netns", "del", netns.as_str()])
+                    .output();
+            }
+            return Ok(());
+        }
         let _ = Command::new("ip")
             .args(["link", "del", self.tun.as_str()])
             .output();
-        if self.delete_proxy_route {
+        if let Some(mark) = self.fwmark {
+            for (family, rule) in self.fwmark_rules(mark) {
+                let _ = Command::new("ip")
+                    .args([family, "rule", "del"])
+                    .args(rule)
+                    .output();
+            }
+        }
+        for proxy_route in std::mem::take(&mut self.proxy_routes) {
             let _ = Command::new("ip")
-                .args(["route", "del", self.tunnel_bypass_addr.to_string().as_str()])
+                .args(["route", "del", proxy_route.to_string().as_str()])
                 .output();
         }
-        nix::mount::umount("/etc/resolv.conf")?;
+        match self.original_resolv_conf.take() {
+            Some(original) => std::fs::write("/etc/resolv.conf", original)?,
+            None if self.container => {}
+            None => match nix::mount::umount("/etc/resolv.conf") {
+                // The mount is gone already if set up from another mount namespace.
+                Err(nix::errno::Errno::EINVAL) => {}
+                result => result?,
+            },
+        }
         Ok(())
     }
 
-    fn setup_and_handle_signals(&mut self, read_from_child: RawFd, write_to_parent: RawFd) {
-        if let Err(e) = (|| -> Result<(), Error> {
-            nix::unistd::close(read_from_child)?;
-            run_iproute(
-                [
-                    "ip",
-                    "tuntap",
-                    "add",
-                    "name",
-                    self.tun.as_str(),
-                    "mode",
-                    "tun",
-                ],
-                "failed to create tunnel device",
-                true,
-            )?;
+    // Create the tun interface and configure the routes through it, passing the interface over
+    // `fd_socket` if it is moved into a network namespace.
+    fn apply(&mut self, fd_socket: Option<&UnixStream>) -> Result<(), Error> {
+        if let Some(netns) = &self.netns {
+            if !Path::new("/run/netns").join(netns).exists() {
+                run_iproute(
+                    ["ip", "netns", "add", netns.as_str()],
+                    "failed to create network namespace",
+                    true,
+                )?;
+                self.created_netns = true;
+            }
+        }
+        if self.container {
+            Self::create_tun_device()?;
+        }
+        let mut command = vec!["ip", "tuntap", "add", "name", &self.tun, "mode", "tun"];
+        if let Some(user) = &self.tun_user {
+            command.extend(["user", user]);
+        }
+        if let Some(group) = &self.tun_group {
+            command.extend(["group", group]);
+        }
+        run_iproute(command, "failed to create tunnel device", true)?;
 
-            self.set_up = true;
-            let _tun_name = self.tun.clone();
-            let _proxy_ip = self.tunnel_bypass_addr;
+        self.set_up = true;
 
-            run_iproute(
-                ["ip", "link", "set", self.tun.as_str(), "up"],
-                "failed to bring up tunnel device",
-                true,
-            )?;
+        if let Some(netns) = self.netns.clone() {
+            // The interface is attached before it moves, as it cannot be found by its name
+            // from the namespace of the parent afterwards.
+            let tun = TunTapInterface::new(&self.tun, Medium::Ip)?;
+            let fd_socket = fd_socket.ok_or("The tun interface cannot be passed on")?;
+            send_tun_fd(fd_socket, tun.as_raw_fd())?;
+            drop(tun);
+            self.setup_netns(&netns)?;
+        } else {
+            let mtu = self.tun_mtu.map(|mtu| mtu.to_string());
+            let mut command = vec!["ip", "link", "set", self.tun.as_str(), "up"];
+            if let Some(mtu) = &mtu {
+                command.extend(["mtu", mtu]);
+            }
+            run_iproute(command, "failed to bring up tunnel device", true)?;
+            for addr in &self.tun_addrs {
+                run_iproute(
+                    [
+                        "ip",
+                        "addr",
+                        "add",
+                        &addr.to_string(),
+                        "dev",
+                        self.tun.as_str(),
+                    ],
+                    "failed to add address to tunnel device",
+                    true,
+                )?;
+            }
 
-            let delete_proxy_route = self.route_proxy_address()?;
-            self.delete_proxy_route = delete_proxy_route;
-            Self::setup_resolv_conf()?;
+            match self.fwmark {
+                Some(mark) => self.add_fwmark_rules(mark)?,
+                None => {
+                    for tunnel_bypass_addr in self.tunnel_bypass_addrs.clone() {
+                        self.route_proxy_address(tunnel_bypass_addr)?;
+                    }
+                }
+            }
+            if self.container {
+                self.replace_resolv_conf()?;
+            } else {
+                Self::setup_resolv_conf()?;
+            }
             self.add_tunnel_routes()?;
+        }
+        Ok(())
+    }
+
+    fn setup_and_handle_signals(
+        &mut self,
+        read_from_child: RawFd,
+        write_to_parent: RawFd,
+        read_routes: RawFd,
+        fd_socket: UnixStream,
+    ) {
+        if let Err(e) = (|| -> Result<(), Error> {
+            nix::unistd::close(read_from_child)?;
+            if let Some(write_routes) = self.route_pipe.take() {
+                nix::unistd::close(write_routes)?;
+            }
+            self.apply(Some(&fd_socket))?;
+            drop(fd_socket);
 
             // Signal to child that we are done setting up everything.
             if nix::unistd::write(write_to_parent, &[1])? != 1 {
@@ -279,8 +802,47 @@ impl Setup {
             mask.add(nix::sys::signal::SIGQUIT);
             mask.thread_block().unwrap();
 
+            // Routes for new proxy addresses are requested through the pipe until the parent
+            // closes it, be it on purpose or by exiting, which restores the configuration as well.
             let mut fd = nix::sys::signalfd::SignalFd::new(&mask).unwrap();
+            let mut pending = Vec::new();
+            // Changes of the routes or links of the host are applied once they settle down.
+            let changes = match (&self.netns, self.fwmark) {
+                (None, None) => Some(Netlink::subscribe(ROUTE_CHANGE_GROUPS)?),
+                _ => None,
+            };
+            let mut changed = false;
             loop {
+                let mut fds = vec![
+                    PollFd::new(fd.as_raw_fd(), PollFlags::POLLIN),
+                    PollFd::new(read_routes, PollFlags::POLLIN),
+                ];
+                if let Some(changes) = &changes {
+                    fds.push(PollFd::new(changes.as_raw_fd(), PollFlags::POLLIN));
+                }
+                let timeout = if changed { ROUTE_CHANGE_DELAY_MS } else { -1 };
+                if nix::poll::poll(&mut fds, timeout)? == 0 {
+                    changed = false;
+                    if let Err(e) = self.refresh_proxy_routes() {
+                        log::error!("{e}");
+                    }
+                    continue;
+                }
+                if let Some(changes) = &changes {
+                    if fds[2].revents().is_some_and(|events| !events.is_empty()) {
+                        changed |= changes.drain()?;
+                    }
+                }
+                if fds[1].revents().is_some_and(|events| !events.is_empty())
+                    && !self.read_route_requests(read_routes, &mut pending)?
+                {
+                    nix::unistd::close(read_routes)?;
+                    break;
+                }
+                let signalled = fds[0].revents().is_some_and(|events| !events.is_empty());
+                if !signalled {
+                    continue;
+                }
                 let res = fd.read_signal().unwrap().unwrap();
                 let signo = nix::sys::signal::Signal::try_from(res.ssi_signo as i32).unwrap();
                 if signo == nix::sys::signal::SIGINT
@@ -317,24 +879,40 @@ impl Setup {
             return Err("Automatic setup requires root privileges".into());
         }
 
-        if self.tunnel_bypass_addr.is_loopback() && !self.allow_private {
-            log::warn!(
-                "The proxy address {} is a loopback address. You may need to manually \
-                provide --setup-ip to specify the server IP bypassing the tunnel",
-                self.tunnel_bypass_addr
-            )
+        for tunnel_bypass_addr in &self.tunnel_bypass_addrs {
+            if tunnel_bypass_addr.is_loopback() && !self.allow_private && self.netns.is_none() {
+                log::warn!(
+                    "The proxy address {} is a loopback address. You may need to manually \
+                    provide --setup-ip to specify the server IP bypassing the tunnel",
+                    tunnel_bypass_addr
+                )
+            }
         }
 
         let (read_from_child, write_to_parent) = nix::unistd::pipe()?;
+        let (read_routes, write_routes) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+        self.route_pipe = Some(write_routes);
+        let (receive_fd, send_fd) = UnixStream::pair()?;
         match fork::fork() {
             Ok(Fork::Child) => {
                 prctl::set_death_signal(nix::sys::signal::SIGINT as isize).unwrap();
-                self.setup_and_handle_signals(read_from_child, write_to_parent);
+                drop(receive_fd);
+                self.setup_and_handle_signals(
+                    read_from_child,
+                    write_to_parent,
+                    read_routes,
+                    send_fd,
+                );
                 std::process::exit(0);
             }
             Ok(Fork::Parent(child)) => {
                 self.child = child;
                 nix::unistd::close(write_to_parent)?;
+                nix::unistd::close(read_routes)?;
+                drop(send_fd);
+                if self.netns.is_some() {
+                    self.tun_fd = Some(receive_tun_fd(&receive_fd)?);
+                }
                 let mut buf = [0];
                 if nix::unistd::read(read_from_child, &mut buf)? != 1 {
                     return Err("Failed to read from pipe".into());
@@ -347,12 +925,102 @@ impl Setup {
         }
     }
 
+    /// Let traffic to another address bypass the tunnel once it is set up, e.g. the new address
+    /// of a proxy which has moved. The route is added by the privileged process.
+    pub fn add_bypass_route(&self, tunnel_bypass_addr: &IpAddr) -> Result<(), Error> {
+        let pipe = self
+            .route_pipe
+            .ok_or("The network configuration is not set up")?;
+        let data = format!("{tunnel_bypass_addr}\n");
+        if nix::unistd::write(pipe, data.as_bytes())? != data.len() {
+            return Err("Failed to write to pipe".into());
+        }
+        Ok(())
+    }
+
+    /// Restore the network configuration and wait for the privileged process to finish. Unlike
+    /// a signal, this also works once privileges have been dropped.
     pub fn restore(&mut self) -> Result<(), Error> {
-        nix::sys::signal::kill(
-            nix::unistd::Pid::from_raw(self.child),
-            nix::sys::signal::SIGINT,
-        )?;
+        if let Some(pipe) = self.route_pipe.take() {
+            nix::unistd::close(pipe)?;
+        }
         nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(self.child), None)?;
         Ok(())
     }
+
+    fn state_path(&self) -> PathBuf {
+        Path::new(STATE_DIR).join(&self.tun)
+    }
+
+    // The original resolv.conf, which is replaced rather than mounted over in containers.
+    fn resolv_conf_backup_path(&self) -> PathBuf {
+        Path::new(STATE_DIR).join(format!("{}.resolv.conf", self.tun))
+    }
+
+    /// Set up the tun interface and the routes and leave them in place, so that tun2proxy can run
+    /// without privileges, until [`Setup::cleanup`] removes them. What has been set up is recorded
+    /// in `/run/tun2proxy`. Unlike [`Setup::configure`], the routes to the proxies are not
+    /// updated later on.
+    pub fn persist(&mut self) -> Result<(), Error> {
+        if nix::unistd::getuid() != 0.into() {
+            return Err("The setup requires root privileges".into());
+        }
+        if self.netns.is_some() {
+            return Err(
+                "A tun interface in a network namespace is only set up while running".into(),
+            );
+        }
+        if self.state_path().exists() {
+            return Err(format!("{} is set up already", self.tun).into());
+        }
+        if let Err(e) = self.apply(None).and_then(|_| self.save_state()) {
+            if let Err(e) = self.shutdown() {
+                log::error!("{e}");
+            }
+            return Err(e);
+        }
+        log::info!("Set up {}, run `tun2proxy cleanup` to remove it", self.tun);
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<(), Error> {
+        let mut state = String::new();
+        for route in &self.proxy_routes {
+            state.push_str(&format!("route {route}\n"));
+        }
+        if let Some(mark) = self.fwmark {
+            state.push_str(&format!("fwmark {mark}\n"));
+        }
+        if self.container {
+            state.push_str("container\n");
+        }
+        std::fs::create_dir_all(STATE_DIR)?;
+        if let Some(original) = &self.original_resolv_conf {
+            std::fs::write(self.resolv_conf_backup_path(), original)?;
+        }
+        std::fs::write(self.state_path(), state)?;
+        Ok(())
+    }
+
+    /// Remove what [`Setup::persist`] has set up for the tun interface.
+    pub fn cleanup(&mut self) -> Result<(), Error> {
+        let state = std::fs::read_to_string(self.state_path())
+            .map_err(|_| Error::from(format!("{} has not been set up", self.tun)))?;
+        for line in state.lines() {
+            match line.split_once(' ') {
+                Some(("route", addr)) => self.proxy_routes.push(addr.parse()?),
+                Some(("fwmark", mark)) => self.fwmark = Some(mark.parse()?),
+                None if line == "container" => self.container = true,
+                _ => log::warn!("Ignoring `{line}` in {}", self.state_path().display()),
+            }
+        }
+        let resolv_conf = self.resolv_conf_backup_path();
+        if self.container {
+            self.original_resolv_conf = Some(std::fs::read(&resolv_conf)?);
+        }
+        self.shutdown()?;
+        let _ = std::fs::remove_file(resolv_conf);
+        std::fs::remove_file(self.state_path())?;
+        Ok(())
+    }
 }
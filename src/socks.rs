@@ -1,14 +1,17 @@
+use std::cell::Cell;
 use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
 
 use smoltcp::wire::IpProtocol;
 
 use crate::error::Error;
+use crate::gssapi::SecurityContext;
+use crate::tls::{TlsConfig, TlsConnection};
 use crate::tun2proxy::{
-    Connection, ConnectionManager, DestinationHost, Direction, IncomingDataEvent,
-    IncomingDirection, OutgoingDataEvent, OutgoingDirection, TcpProxy,
+    Connection, ConnectionManager, Destination, DestinationHost, Direction, IncomingDataEvent,
+    IncomingDirection, OutgoingDataEvent, OutgoingDirection, OutgoingSlices, TcpProxy,
 };
 use crate::Credentials;
 
@@ -19,8 +22,11 @@ enum SocksState {
     ServerHello,
     SendAuthData,
     ReceiveAuthResponse,
+    ReceiveGssApiToken,
+    ReceiveGssApiProtection,
     SendRequest,
     ReceiveResponse,
+    ReceiveBindConnection,
     Established,
 }
 
@@ -76,6 +82,18 @@ enum SocksAuthentication {
     Unassigned100 = 100,
 }
 
+// Message types of the GSSAPI authentication, see RFC 1961.
+const GSSAPI_AUTHENTICATION: u8 = 1;
+const GSSAPI_PROTECTION: u8 = 2;
+const GSSAPI_ENCAPSULATION: u8 = 3;
+const GSSAPI_ABORT: u8 = 0xff;
+
+const GSSAPI_INTEGRITY: u8 = 1;
+const GSSAPI_CONFIDENTIALITY: u8 = 2;
+const GSSAPI_SELECTIVE: u8 = 3;
+
+const GSSAPI_MAX_PAYLOAD: usize = 0x4000;
+
 #[allow(dead_code)]
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq)]
@@ -96,8 +114,55 @@ impl std::fmt::Display for SocksReplies {
     }
 }
 
+/// Policy by which connections are given distinct credentials, so that a Tor SOCKS port, which
+/// isolates streams by their credentials (`IsolateSOCKSAuth`), uses separate circuits for them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Isolation {
+    /// Connections to different destination hosts are isolated.
+    Destination,
+    /// Connections from different source ports are isolated.
+    SourcePort,
+}
+
+impl std::str::FromStr for Isolation {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "destination" => Ok(Isolation::Destination),
+            "source_port" => Ok(Isolation::SourcePort),
+            _ => Err(format!("`{s}` is an invalid isolation policy").into()),
+        }
+    }
+}
+
+impl Isolation {
+    // The configured user name is kept, while the password is derived from the connection.
+    fn credentials(
+        self,
+        credentials: &Option<Credentials>,
+        connection: &Connection,
+    ) -> Credentials {
+        let username = match credentials {
+            Some(credentials) if !credentials.username.is_empty() => credentials.username.clone(),
+            _ => b"tun2proxy".to_vec(),
+        };
+        let password = match self {
+            Isolation::Destination => connection.dst.host.to_string(),
+            Isolation::SourcePort => connection.src.port().to_string(),
+        };
+        Credentials {
+            username,
+            password: password.into_bytes(),
+        }
+    }
+}
+
 pub(crate) struct SocksConnection {
     connection: Connection,
+    server: SocketAddr,
+    command: SocksCommand,
+    udp_associate: Option<SocketAddr>,
+    bind_address: Option<SocketAddr>,
     state: SocksState,
     client_inbuf: VecDeque<u8>,
     server_inbuf: VecDeque<u8>,
@@ -106,6 +171,16 @@ pub(crate) struct SocksConnection {
     data_buf: VecDeque<u8>,
     version: SocksVersion,
     credentials: Option<Credentials>,
+    gssapi: Option<String>,
+    security: Option<SecurityContext>,
+    // The GSSAPI protection level, where a level other than zero encapsulates all further data.
+    protection: u8,
+    gssapi_inbuf: VecDeque<u8>,
+    // Whether the SOCKS4 server is known to resolve hostnames (SOCKS4a), shared by the
+    // connections of a manager.
+    socks4a: Rc<Cell<Option<bool>>>,
+    // Whether the SOCKS4 server has refused the hostname of the destination.
+    hostname_refused: bool,
 }
 
 impl SocksConnection {
@@ -113,9 +188,60 @@ impl SocksConnection {
         connection: &Connection,
         manager: Rc<dyn ConnectionManager>,
         version: SocksVersion,
+        credentials: Option<Credentials>,
+        gssapi: Option<String>,
+        socks4a: Rc<Cell<Option<bool>>>,
+    ) -> Result<Self, Error> {
+        let command = if connection.proto == IpProtocol::Udp {
+            SocksCommand::UdpAssociate
+        } else {
+            SocksCommand::Connect
+        };
+        Self::with_command(
+            connection,
+            manager,
+            version,
+            credentials,
+            gssapi,
+            socks4a,
+            command,
+        )
+    }
+
+    /// Have the server accept a connection from the destination of `connection`, which is
+    /// relayed once established.
+    pub fn bind(
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+        credentials: Option<Credentials>,
+        gssapi: Option<String>,
+    ) -> Result<Self, Error> {
+        Self::with_command(
+            connection,
+            manager,
+            SocksVersion::V5,
+            credentials,
+            gssapi,
+            Rc::default(),
+            SocksCommand::Bind,
+        )
+    }
+
+    fn with_command(
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+        version: SocksVersion,
+        credentials: Option<Credentials>,
+        gssapi: Option<String>,
+        socks4a: Rc<Cell<Option<bool>>>,
+        command: SocksCommand,
     ) -> Result<Self, Error> {
         let mut result = Self {
             connection: connection.clone(),
+            server: manager.get_server(),
+            command,
+            udp_associate: None,
+            bind_address: None,
             state: SocksState::ServerHello,
             client_inbuf: VecDeque::default(),
             server_inbuf: VecDeque::default(),
@@ -123,7 +249,13 @@ impl SocksConnection {
             server_outbuf: VecDeque::default(),
             data_buf: VecDeque::default(),
             version,
-            credentials: manager.get_credentials().clone(),
+            credentials,
+            gssapi,
+            security: None,
+            protection: 0,
+            gssapi_inbuf: VecDeque::default(),
+            socks4a,
+            hostname_refused: false,
         };
         result.send_client_hello()?;
         Ok(result)
@@ -169,24 +301,18 @@ impl SocksConnection {
             SocksVersion::V5 => {
                 // Providing unassigned methods is supposed to bypass China's GFW.
                 // For details, refer to https://github.com/blechschmidt/tun2proxy/issues/35.
+                let mut methods = vec![SocksAuthentication::None as u8];
+                if self.gssapi.is_some() {
+                    methods.push(SocksAuthentication::GssApi as u8);
+                }
                 if credentials.is_some() {
-                    self.server_outbuf.extend(&[
-                        self.version as u8,
-                        4u8,
-                        SocksAuthentication::None as u8,
-                        SocksAuthentication::Password as u8,
-                        SocksAuthentication::Unassigned as u8,
-                        SocksAuthentication::Unassigned100 as u8,
-                    ]);
-                } else {
-                    self.server_outbuf.extend(&[
-                        self.version as u8,
-                        3u8,
-                        SocksAuthentication::None as u8,
-                        SocksAuthentication::Unassigned as u8,
-                        SocksAuthentication::Unassigned100 as u8,
-                    ]);
+                    methods.push(SocksAuthentication::Password as u8);
                 }
+                methods.push(SocksAuthentication::Unassigned as u8);
+                methods.push(SocksAuthentication::Unassigned100 as u8);
+                self.server_outbuf
+                    .extend(&[self.version as u8, methods.len() as u8]);
+                self.server_outbuf.extend(methods);
             }
         }
         self.state = SocksState::ServerHello;
@@ -198,9 +324,27 @@ impl SocksConnection {
             return Ok(());
         }
 
+        // Hostnames are sent as a request to connect to the address 0.0.0.x.
+        let socks4a = matches!(self.connection.dst.host, DestinationHost::Hostname(_));
         if self.server_inbuf[1] != 0x5a {
+            // A server without SOCKS4a support takes the request for one to connect to that
+            // invalid address, which it rejects as failed. Unless the server is known to resolve
+            // hostnames, the connection is then retried with the address resolved locally, which
+            // tells whether the server only refused the destination. Other replies concern the
+            // identity of the client.
+            if socks4a && self.server_inbuf[1] == 0x5b && self.socks4a.get().is_none() {
+                self.hostname_refused = true;
+                return Err(format!(
+                    "SOCKS4 server refused the hostname of {}",
+                    self.connection.dst
+                )
+                .into());
+            }
             return Err("SOCKS4 server replied with an unexpected reply code.".into());
         }
+        if socks4a {
+            self.socks4a.set(Some(true));
+        }
 
         self.server_inbuf.drain(0..8);
         self.server_outbuf.append(&mut self.data_buf);
@@ -220,11 +364,10 @@ impl SocksConnection {
 
         let auth_method = self.server_inbuf[1];
 
-        if auth_method != SocksAuthentication::None as u8 && self.credentials.is_none()
-            || (auth_method != SocksAuthentication::None as u8
-                && auth_method != SocksAuthentication::Password as u8)
-                && self.credentials.is_some()
-        {
+        let supported = auth_method == SocksAuthentication::None as u8
+            || auth_method == SocksAuthentication::Password as u8 && self.credentials.is_some()
+            || auth_method == SocksAuthentication::GssApi as u8 && self.gssapi.is_some();
+        if !supported {
             return Err("SOCKS5 server requires an unsupported authentication method.".into());
         }
 
@@ -232,6 +375,13 @@ impl SocksConnection {
 
         if auth_method == SocksAuthentication::Password as u8 {
             self.state = SocksState::SendAuthData;
+        } else if auth_method == SocksAuthentication::GssApi as u8 {
+            let service = self.gssapi.as_deref().unwrap_or_default();
+            let mut security = SecurityContext::new(service)?;
+            let token = security.step(&[])?;
+            self.security = Some(security);
+            send_gssapi_message(&mut self.server_outbuf, GSSAPI_AUTHENTICATION, &token)?;
+            self.state = SocksState::ReceiveGssApiToken;
         } else {
             self.state = SocksState::SendRequest;
         }
@@ -248,12 +398,7 @@ impl SocksConnection {
     fn send_auth_data(&mut self) -> Result<(), Error> {
         let tmp = Credentials::default();
         let credentials = self.credentials.as_ref().unwrap_or(&tmp);
-        self.server_outbuf
-            .extend(&[1u8, credentials.username.len() as u8]);
-        self.server_outbuf.extend(&credentials.username);
-        self.server_outbuf
-            .extend(&[credentials.password.len() as u8]);
-        self.server_outbuf.extend(&credentials.password);
+        extend_password_request(&mut self.server_outbuf, credentials);
         self.state = SocksState::ReceiveAuthResponse;
         self.state_change()
     }
@@ -270,14 +415,79 @@ impl SocksConnection {
         self.state_change()
     }
 
-    fn receive_connection_status(&mut self) -> Result<(), Error> {
-        if self.server_inbuf.len() < 4 {
+    fn receive_gssapi_token(&mut self) -> Result<(), Error> {
+        let token = match take_gssapi_message(&mut self.server_inbuf, GSSAPI_AUTHENTICATION)? {
+            None => return Ok(()),
+            Some(token) => token,
+        };
+        let security = self.security.as_mut().ok_or("GSSAPI context is missing")?;
+        let token = security.step(&token)?;
+        if !token.is_empty() {
+            send_gssapi_message(&mut self.server_outbuf, GSSAPI_AUTHENTICATION, &token)?;
+        }
+        if !security.is_established() {
             return Ok(());
         }
+
+        // Ask for the highest protection level, the server replies with the one it chose.
+        let token = security.wrap(&[GSSAPI_CONFIDENTIALITY], false)?;
+        send_gssapi_message(&mut self.server_outbuf, GSSAPI_PROTECTION, &token)?;
+        self.state = SocksState::ReceiveGssApiProtection;
+        self.state_change()
+    }
+
+    fn receive_gssapi_protection(&mut self) -> Result<(), Error> {
+        let token = match take_gssapi_message(&mut self.server_inbuf, GSSAPI_PROTECTION)? {
+            None => return Ok(()),
+            Some(token) => token,
+        };
+        let security = self.security.as_ref().ok_or("GSSAPI context is missing")?;
+        self.protection = match security.unwrap(&token)?[..] {
+            [level] if level <= GSSAPI_SELECTIVE => level,
+            _ => return Err("SOCKS5 server chose an invalid GSSAPI protection level.".into()),
+        };
+        if self.protection != 0 {
+            // Anything the server sent after its choice is already encapsulated.
+            self.gssapi_inbuf.append(&mut self.server_inbuf);
+            self.decapsulate()?;
+        }
+        self.state = SocksState::SendRequest;
+        self.state_change()
+    }
+
+    fn decapsulate(&mut self) -> Result<(), Error> {
+        let security = self.security.as_ref().ok_or("GSSAPI context is missing")?;
+        while let Some(token) = take_gssapi_message(&mut self.gssapi_inbuf, GSSAPI_ENCAPSULATION)? {
+            self.server_inbuf.extend(security.unwrap(&token)?);
+        }
+        Ok(())
+    }
+
+    // Send data through the SOCKS5 connection, encapsulated if GSSAPI protection is in effect.
+    fn send_to_server(&mut self, data: &[u8]) -> Result<(), Error> {
+        let security = match &self.security {
+            Some(security) if self.protection != 0 => security,
+            _ => {
+                self.server_outbuf.extend(data);
+                return Ok(());
+            }
+        };
+        let confidential = self.protection != GSSAPI_INTEGRITY;
+        for chunk in data.chunks(GSSAPI_MAX_PAYLOAD) {
+            let token = security.wrap(chunk, confidential)?;
+            send_gssapi_message(&mut self.server_outbuf, GSSAPI_ENCAPSULATION, &token)?;
+        }
+        Ok(())
+    }
+
+    // Parse a reply to the request, returning the address it carries.
+    fn receive_reply(&mut self) -> Result<Option<Destination>, Error> {
+        if self.server_inbuf.len() < 4 {
+            return Ok(None);
+        }
         let ver = self.server_inbuf[0];
         let rep = self.server_inbuf[1];
         let _rsv = self.server_inbuf[2];
-        let atyp = self.server_inbuf[3];
 
         if ver != 5 {
             return Err("SOCKS5 server replied with an unexpected version.".into());
@@ -287,62 +497,96 @@ impl SocksConnection {
             return Err("SOCKS5 connection unsuccessful.".into());
         }
 
-        let message_length = match SocksAddressType::try_from(atyp)? {
-            SocksAddressType::DomainName => {
-                if self.server_inbuf.len() < 5 {
-                    return Ok(());
-                }
-                if self.server_inbuf.len() < 7 + (self.server_inbuf[4] as usize) {
-                    return Ok(());
-                }
-                7 + (self.server_inbuf[4] as usize)
-            }
-            SocksAddressType::Ipv4 => 10,
-            SocksAddressType::Ipv6 => 22,
+        let (bound, address_length) =
+            match parse_destination(&self.server_inbuf.make_contiguous()[3..])? {
+                None => return Ok(None),
+                Some(result) => result,
+            };
+        self.server_inbuf.drain(0..3 + address_length);
+        Ok(Some(bound))
+    }
+
+    fn receive_connection_status(&mut self) -> Result<(), Error> {
+        let bound = match self.receive_reply()? {
+            None => return Ok(()),
+            Some(bound) => bound,
         };
 
-        self.server_inbuf.drain(0..message_length);
-        self.server_outbuf.append(&mut self.data_buf);
-        self.data_buf.clear();
+        if self.command == SocksCommand::UdpAssociate {
+            let relay = SocketAddr::try_from(bound.clone())
+                .map_err(|_| Error::from("SOCKS5 server replied with an unusable UDP relay."))?;
+            // Many servers reply with an unspecified address, meaning that the relay is
+            // reachable through the address we used to connect to the server.
+            self.udp_associate = Some(if relay.ip().is_unspecified() {
+                SocketAddr::new(self.server.ip(), relay.port())
+            } else {
+                relay
+            });
+        }
+
+        if self.command == SocksCommand::Bind {
+            let address = SocketAddr::try_from(bound).map_err(|_| {
+                Error::from("SOCKS5 server replied with an unusable bound address.")
+            })?;
+            // As with UDP relays, an unspecified address refers to the server itself.
+            self.bind_address = Some(if address.ip().is_unspecified() {
+                SocketAddr::new(self.server.ip(), address.port())
+            } else {
+                address
+            });
+            // The second reply follows once the peer has connected to the bound address.
+            self.state = SocksState::ReceiveBindConnection;
+            return self.state_change();
+        }
+
+        self.establish()
+    }
+
+    fn receive_bind_connection(&mut self) -> Result<(), Error> {
+        let peer = match self.receive_reply()? {
+            None => return Ok(()),
+            Some(peer) => peer,
+        };
+        log::debug!("SOCKS5 server accepted a connection from {}", peer);
+        self.establish()
+    }
+
+    fn establish(&mut self) -> Result<(), Error> {
+        let mut data = std::mem::take(&mut self.data_buf);
+        self.send_to_server(data.make_contiguous())?;
 
         self.state = SocksState::Established;
         self.state_change()
     }
 
     fn send_request(&mut self) -> Result<(), Error> {
-        self.server_outbuf.extend(&[5u8, 1, 0]);
-        match &self.connection.dst.host {
-            DestinationHost::Address(dst_ip) => {
-                let cmd = if dst_ip.is_ipv4() {
-                    SocksAddressType::Ipv4
-                } else {
-                    SocksAddressType::Ipv6
-                };
-                self.server_outbuf.extend(&[u8::from(cmd)]);
-                match dst_ip {
-                    IpAddr::V4(ip) => self.server_outbuf.extend(ip.octets().as_ref()),
-                    IpAddr::V6(ip) => self.server_outbuf.extend(ip.octets().as_ref()),
-                };
-            }
-            DestinationHost::Hostname(host) => {
-                self.server_outbuf
-                    .extend(&[u8::from(SocksAddressType::DomainName), host.len() as u8]);
-                self.server_outbuf.extend(host.as_bytes());
-            }
+        let mut request = vec![5u8, self.command as u8, 0];
+        if self.command == SocksCommand::UdpAssociate {
+            // We do not know the address the datagrams will be sent from in advance.
+            let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+            extend_destination(&mut request, &unspecified.into());
+        } else if self.command == SocksCommand::Bind {
+            // The peer may connect from any port.
+            let peer = Destination {
+                host: self.connection.dst.host.clone(),
+                port: 0,
+            };
+            extend_destination(&mut request, &peer);
+        } else {
+            extend_destination(&mut request, &self.connection.dst);
         }
-        self.server_outbuf.extend(&[
-            (self.connection.dst.port >> 8) as u8,
-            (self.connection.dst.port & 0xff) as u8,
-        ]);
+        self.send_to_server(&request)?;
         self.state = SocksState::ReceiveResponse;
         self.state_change()
     }
 
     fn relay_traffic(&mut self) -> Result<(), Error> {
         self.client_outbuf.extend(self.server_inbuf.iter());
-        self.server_outbuf.extend(self.client_inbuf.iter());
         self.server_inbuf.clear();
-        self.client_inbuf.clear();
+        let mut data = std::mem::take(&mut self.client_inbuf);
+        self.send_to_server(data.make_contiguous())?;
+        data.clear();
+        self.client_inbuf = data;
         Ok(())
     }
 
@@ -354,10 +598,16 @@ impl SocksConnection {
 
             SocksState::ReceiveAuthResponse => self.receive_auth_data(),
 
+            SocksState::ReceiveGssApiToken => self.receive_gssapi_token(),
+
+            SocksState::ReceiveGssApiProtection => self.receive_gssapi_protection(),
+
             SocksState::SendRequest => self.send_request(),
 
             SocksState::ReceiveResponse => self.receive_connection_status(),
 
+            SocksState::ReceiveBindConnection => self.receive_bind_connection(),
+
             SocksState::Established => self.relay_traffic(),
 
             _ => Ok(()),
@@ -370,6 +620,10 @@ impl TcpProxy for SocksConnection {
         let direction = event.direction;
         let buffer = event.buffer;
         match direction {
+            IncomingDirection::FromServer if self.protection != 0 => {
+                self.gssapi_inbuf.extend(buffer.iter());
+                self.decapsulate()?;
+            }
             IncomingDirection::FromServer => {
                 self.server_inbuf.extend(buffer.iter());
             }
@@ -406,10 +660,40 @@ impl TcpProxy for SocksConnection {
         }
     }
 
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &self.server_outbuf
+        } else {
+            &self.client_outbuf
+        };
+        buffer.into()
+    }
+
     fn connection_established(&self) -> bool {
         self.state == SocksState::Established
     }
 
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.udp_associate
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.bind_address
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.hostname_refused
+    }
+
+    fn skip_handshake(&mut self) -> Result<(), Error> {
+        if self.version != SocksVersion::V5 || self.state != SocksState::ServerHello {
+            return Err("The SOCKS handshake cannot be skipped anymore".into());
+        }
+        self.server_outbuf.clear();
+        self.state = SocksState::SendRequest;
+        self.state_change()
+    }
+
     fn have_data(&mut self, dir: Direction) -> bool {
         match dir {
             Direction::Incoming(incoming) => match incoming {
@@ -430,11 +714,19 @@ pub struct SocksManager {
     server: SocketAddr,
     credentials: Option<Credentials>,
     version: SocksVersion,
+    tls: Option<TlsConfig>,
+    gssapi: Option<String>,
+    isolation: Option<Isolation>,
+    socks4a: Rc<Cell<Option<bool>>>,
 }
 
 impl ConnectionManager for SocksManager {
     fn handles_connection(&self, connection: &Connection) -> bool {
+        // Datagrams are not encapsulated in case of GSSAPI authentication.
         connection.proto == IpProtocol::Tcp
+            || connection.proto == IpProtocol::Udp
+                && self.version == SocksVersion::V5
+                && self.gssapi.is_none()
     }
 
     fn new_connection(
@@ -442,14 +734,36 @@ impl ConnectionManager for SocksManager {
         connection: &Connection,
         manager: Rc<dyn ConnectionManager>,
     ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
-        if connection.proto != IpProtocol::Tcp {
+        if !self.handles_connection(connection) {
             return Ok(None);
         }
-        Ok(Some(Box::new(SocksConnection::new(
+        let handler = Box::new(SocksConnection::new(
             connection,
             manager,
             self.version,
-        )?)))
+            self.credentials_for(connection),
+            self.gssapi.clone(),
+            self.socks4a.clone(),
+        )?);
+        self.wrap(handler).map(Some)
+    }
+
+    fn new_bind(
+        &self,
+        connection: &Connection,
+        _: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if self.version != SocksVersion::V5 {
+            return Ok(None);
+        }
+        let handler = Box::new(SocksConnection::bind(
+            connection,
+            manager,
+            self.credentials_for(connection),
+            self.gssapi.clone(),
+        )?);
+        self.wrap(handler).map(Some)
     }
 
     fn close_connection(&self, _: &Connection) {}
@@ -461,18 +775,337 @@ impl ConnectionManager for SocksManager {
     fn get_credentials(&self) -> &Option<Credentials> {
         &self.credentials
     }
+
+    fn resolves_locally(&self, connection: &Connection) -> bool {
+        self.version == SocksVersion::V4
+            && self.socks4a.get() == Some(false)
+            && matches!(connection.dst.host, DestinationHost::Hostname(_))
+    }
+
+    // Connections kept warm are greeted and authenticated ahead of time, unless the credentials
+    // depend on the destination or the handshake goes through TLS or GSSAPI.
+    fn warm_handshake(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        if self.version != SocksVersion::V5
+            || self.tls.is_some()
+            || self.gssapi.is_some()
+            || self.isolation.is_some()
+        {
+            return Vec::new();
+        }
+        let method = match self.credentials {
+            None => SocksAuthentication::None as u8,
+            Some(_) => SocksAuthentication::Password as u8,
+        };
+        let mut handshake = vec![(vec![5, 1, method], vec![5, method])];
+        if let Some(credentials) = &self.credentials {
+            let mut request = Vec::new();
+            extend_password_request(&mut request, credentials);
+            handshake.push((request, vec![1, 0]));
+        }
+        handshake
+    }
+
+    // A server which has refused a hostname but accepted its address does not resolve hostnames.
+    fn report_refused_hostname(&self, connection: &Connection, name: &str) {
+        if self.version == SocksVersion::V4 && self.socks4a.get().is_none() {
+            log::warn!(
+                "SOCKS4 server {} refused {name} but accepted {}, resolving hostnames locally",
+                self.server,
+                connection.dst
+            );
+            self.socks4a.set(Some(false));
+        }
+    }
 }
 
 impl SocksManager {
+    fn credentials_for(&self, connection: &Connection) -> Option<Credentials> {
+        match self.isolation {
+            None => self.credentials.clone(),
+            Some(isolation) => Some(isolation.credentials(&self.credentials, connection)),
+        }
+    }
+
+    fn wrap(&self, handler: Box<dyn TcpProxy>) -> Result<Box<dyn TcpProxy>, Error> {
+        match &self.tls {
+            None => Ok(handler),
+            Some(tls) => Ok(Box::new(TlsConnection::new(handler, tls)?)),
+        }
+    }
+
     pub fn new(
         server: SocketAddr,
         version: SocksVersion,
         credentials: Option<Credentials>,
+        tls: Option<TlsConfig>,
+        gssapi: Option<String>,
+        isolation: Option<Isolation>,
     ) -> Rc<Self> {
         Rc::new(Self {
             server,
             credentials,
             version,
+            tls,
+            gssapi,
+            isolation,
+            socks4a: Rc::default(),
         })
     }
 }
+
+fn send_gssapi_message(
+    buffer: &mut VecDeque<u8>,
+    message_type: u8,
+    token: &[u8],
+) -> Result<(), Error> {
+    let len = u16::try_from(token.len()).map_err(|_| Error::from("GSSAPI token is too long"))?;
+    buffer.extend([1, message_type]);
+    buffer.extend(len.to_be_bytes());
+    buffer.extend(token);
+    Ok(())
+}
+
+/// Take a GSSAPI message of the given type from the buffer and return its token. `None` is
+/// returned if the message is incomplete.
+fn take_gssapi_message(
+    buffer: &mut VecDeque<u8>,
+    message_type: u8,
+) -> Result<Option<Vec<u8>>, Error> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    if buffer[0] != 1 {
+        return Err("SOCKS5 server replied with an unexpected GSSAPI version.".into());
+    }
+    if buffer[1] == GSSAPI_ABORT {
+        return Err("SOCKS5 server rejected the GSSAPI authentication.".into());
+    }
+    if buffer[1] != message_type {
+        return Err("SOCKS5 server replied with an unexpected GSSAPI message.".into());
+    }
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+    if buffer.len() < 4 + len {
+        return Ok(None);
+    }
+    Ok(Some(buffer.drain(0..4 + len).skip(4).collect()))
+}
+
+// The username/password authentication request of RFC 1929.
+fn extend_password_request<T: Extend<u8>>(buffer: &mut T, credentials: &Credentials) {
+    buffer.extend([1, credentials.username.len() as u8]);
+    buffer.extend(credentials.username.iter().copied());
+    buffer.extend([credentials.password.len() as u8]);
+    buffer.extend(credentials.password.iter().copied());
+}
+
+fn extend_destination<T: Extend<u8>>(buffer: &mut T, dst: &Destination) {
+    match &dst.host {
+        DestinationHost::Address(IpAddr::V4(ip)) => {
+            buffer.extend([u8::from(SocksAddressType::Ipv4)]);
+            buffer.extend(ip.octets());
+        }
+        DestinationHost::Address(IpAddr::V6(ip)) => {
+            buffer.extend([u8::from(SocksAddressType::Ipv6)]);
+            buffer.extend(ip.octets());
+        }
+        DestinationHost::Hostname(host) => {
+            buffer.extend([u8::from(SocksAddressType::DomainName), host.len() as u8]);
+            buffer.extend(host.bytes());
+        }
+    }
+    buffer.extend(dst.port.to_be_bytes());
+}
+
+/// Parse a SOCKS5 address (ATYP, ADDR, PORT) and return it along with its encoded size.
+/// `None` is returned if the data is incomplete.
+pub(crate) fn parse_destination(data: &[u8]) -> Result<Option<(Destination, usize)>, Error> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let (host, length) = match SocksAddressType::try_from(data[0])? {
+        SocksAddressType::Ipv4 => {
+            if data.len() < 7 {
+                return Ok(None);
+            }
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&data[1..5]);
+            (DestinationHost::Address(Ipv4Addr::from(octets).into()), 7)
+        }
+        SocksAddressType::Ipv6 => {
+            if data.len() < 19 {
+                return Ok(None);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[1..17]);
+            (DestinationHost::Address(Ipv6Addr::from(octets).into()), 19)
+        }
+        SocksAddressType::DomainName => {
+            if data.len() < 2 || data.len() < 4 + data[1] as usize {
+                return Ok(None);
+            }
+            let name_len = data[1] as usize;
+            let name = std::str::from_utf8(&data[2..2 + name_len])?;
+            (DestinationHost::Hostname(name.into()), 4 + name_len)
+        }
+    };
+    let port = (data[length - 2] as u16) << 8 | data[length - 1] as u16;
+    Ok(Some((Destination { host, port }, length)))
+}
+
+/// Prepend the SOCKS5 UDP request header to a datagram destined for `dst`.
+pub(crate) fn encapsulate_udp_datagram(dst: &Destination, payload: &[u8]) -> Vec<u8> {
+    // RSV (2 bytes) and FRAG (1 byte).
+    let mut datagram = vec![0u8, 0, 0];
+    extend_destination(&mut datagram, dst);
+    datagram.extend(payload);
+    datagram
+}
+
+/// Strip the SOCKS5 UDP request header from a datagram received from the relay.
+pub(crate) fn decapsulate_udp_datagram(datagram: &[u8]) -> Result<(Destination, &[u8]), Error> {
+    if datagram.len() < 3 {
+        return Err("SOCKS5 UDP datagram too short.".into());
+    }
+    if datagram[2] != 0 {
+        return Err("Fragmented SOCKS5 UDP datagrams are not supported.".into());
+    }
+    let (src, length) =
+        parse_destination(&datagram[3..])?.ok_or("SOCKS5 UDP datagram too short.")?;
+    Ok((src, &datagram[3 + length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(host: DestinationHost) -> Connection {
+        Connection {
+            src: "10.0.0.1:40000".parse().unwrap(),
+            dst: Destination { host, port: 80 },
+            proto: IpProtocol::Tcp,
+        }
+    }
+
+    fn hostname() -> Connection {
+        connection(DestinationHost::Hostname("example.org".into()))
+    }
+
+    fn address() -> Connection {
+        connection(DestinationHost::Address([192, 0, 2, 80].into()))
+    }
+
+    fn socks4() -> Rc<SocksManager> {
+        let server = "192.0.2.1:1080".parse().unwrap();
+        SocksManager::new(server, SocksVersion::V4, None, None, None, None)
+    }
+
+    // Open `connection` through `manager`, to which the server replies with `code`.
+    fn reply(
+        manager: &Rc<SocksManager>,
+        connection: &Connection,
+        code: u8,
+    ) -> (Box<dyn TcpProxy>, Result<(), Error>) {
+        let mut handler = manager
+            .new_connection(connection, manager.clone())
+            .unwrap()
+            .unwrap();
+        let result = handler.push_data(IncomingDataEvent {
+            direction: IncomingDirection::FromServer,
+            buffer: &[0, code, 0, 0, 0, 0, 0, 0],
+        });
+        (handler, result)
+    }
+
+    #[test]
+    fn sends_hostnames_as_socks4a() {
+        let manager = socks4();
+        let mut handler = manager
+            .new_connection(&hostname(), manager.clone())
+            .unwrap()
+            .unwrap();
+        let request = handler.peek_data(OutgoingDirection::ToServer).buffer;
+        assert_eq!(
+            request,
+            b"\x04\x01\x00\x50\x00\x00\x00\x0b\x00example.org\x00"
+        );
+    }
+
+    #[test]
+    fn resolves_hostnames_once_address_accepted() {
+        let manager = socks4();
+        let (handler, result) = reply(&manager, &hostname(), 0x5b);
+        assert!(result.is_err());
+        assert!(handler.hostname_refused());
+        // A single refusal may concern the destination alone.
+        assert!(!manager.resolves_locally(&hostname()));
+
+        let (handler, result) = reply(&manager, &address(), 0x5a);
+        assert!(result.is_ok());
+        assert!(handler.connection_established());
+        manager.report_refused_hostname(&address(), "example.org");
+        assert!(manager.resolves_locally(&hostname()));
+        assert!(!manager.resolves_locally(&address()));
+    }
+
+    #[test]
+    fn keeps_resolving_hostnames_once_accepted() {
+        let manager = socks4();
+        let (handler, result) = reply(&manager, &hostname(), 0x5a);
+        assert!(result.is_ok());
+        assert!(handler.connection_established());
+
+        let (handler, result) = reply(&manager, &hostname(), 0x5b);
+        assert!(result.is_err());
+        assert!(!handler.hostname_refused());
+        manager.report_refused_hostname(&address(), "example.org");
+        assert!(!manager.resolves_locally(&hostname()));
+    }
+
+    #[test]
+    fn retries_only_refused_hostnames() {
+        let manager = socks4();
+        // The server rejected the identity of the client.
+        let (handler, result) = reply(&manager, &hostname(), 0x5d);
+        assert!(result.is_err());
+        assert!(!handler.hostname_refused());
+        let (handler, result) = reply(&manager, &address(), 0x5b);
+        assert!(result.is_err());
+        assert!(!handler.hostname_refused());
+        assert!(!manager.resolves_locally(&hostname()));
+    }
+
+    #[test]
+    fn skips_handshake_completed_ahead_of_time() {
+        let server = "192.0.2.1:1080".parse().unwrap();
+        let credentials = Some(Credentials::new("john", "secret"));
+        let manager = SocksManager::new(server, SocksVersion::V5, credentials, None, None, None);
+        let handshake = manager.warm_handshake();
+        assert_eq!(
+            handshake,
+            [
+                (b"\x05\x01\x02".to_vec(), b"\x05\x02".to_vec()),
+                (b"\x01\x04john\x06secret".to_vec(), b"\x01\x00".to_vec()),
+            ]
+        );
+
+        let mut handler = manager
+            .new_connection(&address(), manager.clone())
+            .unwrap()
+            .unwrap();
+        handler.skip_handshake().unwrap();
+        let request = handler.peek_data(OutgoingDirection::ToServer).buffer;
+        assert_eq!(request, b"\x05\x01\x00\x01\xc0\x00\x02\x50\x00\x50");
+    }
+
+    #[test]
+    fn keeps_isolated_handshakes() {
+        let server = "192.0.2.1:1080".parse().unwrap();
+        let isolation = Some(Isolation::Destination);
+        let manager = SocksManager::new(server, SocksVersion::V5, None, None, None, isolation);
+        assert!(manager.warm_handshake().is_empty());
+        assert!(socks4().warm_handshake().is_empty());
+    }
+}
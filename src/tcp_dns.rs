@@ -0,0 +1,407 @@
+use crate::error::Error;
+use crate::tls::TlsConfig;
+use crate::tun2proxy::{Connection, DnsForwarder};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ClientConnection;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WAKER_TOKEN: Token = Token(0);
+const STREAM_TOKEN: Token = Token(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PENDING: usize = 1024;
+
+// A query is sent this many times at most, as the connection may break before it is answered.
+const MAX_ATTEMPTS: u32 = 2;
+
+/// Where the queries are sent.
+pub(crate) enum Upstream {
+    /// The resolver each query is addressed to, over plain TCP. Queries addressed to the virtual
+    /// DNS go to the first of the name servers which is not a loopback address instead.
+    Original(Vec<IpAddr>),
+    /// A resolver over plain TCP.
+    Tcp(SocketAddr),
+    /// A DNS-over-TLS server.
+    Tls(SocketAddr, Box<TlsConfig>),
+}
+
+// The range of the virtual DNS, to which the setup points the system for DNS.
+pub(crate) fn is_virtual(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.octets()[0] == 198 && addr.octets()[1] & 0xfe == 18,
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Answers DNS queries through resolvers reached over TCP as defined by RFC 7766, optionally
+/// secured by TLS as defined by RFC 7858. A worker thread keeps one connection per resolver, on
+/// which the queries are pipelined under IDs of its own, and connects again when a connection
+/// breaks. The connections are routed through the tunnel and therefore through the proxy, unless
+/// the resolver is excluded from the routes to the tunnel.
+pub(crate) struct TcpDnsClient {
+    queries: Sender<(Connection, Vec<u8>)>,
+    responses: Receiver<(Connection, Vec<u8>)>,
+    worker_waker: Arc<Waker>,
+}
+
+impl TcpDnsClient {
+    pub fn new(upstream: Upstream, waker: Arc<Waker>) -> Result<Self, Error> {
+        let poll = Poll::new()?;
+        let worker_waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let (queries, worker_queries) = mpsc::channel();
+        let (worker_responses, responses) = mpsc::channel();
+        let mut worker = Worker {
+            poll,
+            upstream,
+            queries: worker_queries,
+            responses: worker_responses,
+            waker,
+            sessions: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+        };
+        std::thread::spawn(move || {
+            if let Err(error) = worker.run() {
+                log::error!("DNS forwarding: {error}");
+            }
+        });
+        Ok(Self {
+            queries,
+            responses,
+            worker_waker,
+        })
+    }
+}
+
+impl DnsForwarder for TcpDnsClient {
+    fn send_query(&self, connection: &Connection, query: &[u8]) -> Result<(), Error> {
+        self.queries
+            .send((connection.clone(), query.to_vec()))
+            .map_err(|_| Error::from("The DNS forwarding has stopped"))?;
+        Ok(self.worker_waker.wake()?)
+    }
+
+    fn receive_response(&self) -> Option<(Connection, Vec<u8>)> {
+        self.responses.try_recv().ok()
+    }
+}
+
+impl Drop for TcpDnsClient {
+    fn drop(&mut self) {
+        let _ = self.worker_waker.wake();
+    }
+}
+
+struct Pending {
+    connection: Connection,
+    server: SocketAddr,
+    // The query as received, with the ID of the client.
+    query: Vec<u8>,
+    attempts: u32,
+    sent_at: Instant,
+}
+
+struct Session {
+    stream: TcpStream,
+    tls: Option<ClientConnection>,
+    outgoing: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl Session {
+    fn connect(poll: &Poll, server: SocketAddr, tls: Option<&TlsConfig>) -> Result<Self, Error> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.set_nodelay(true)?;
+        poll.registry().register(
+            &mut stream,
+            STREAM_TOKEN,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        Ok(Self {
+            stream,
+            tls: tls.map(TlsConfig::client_connection).transpose()?,
+            outgoing: Vec::new(),
+            received: Vec::new(),
+        })
+    }
+
+    fn queue(&mut self, message: &[u8]) -> Result<(), Error> {
+        match &mut self.tls {
+            Some(tls) => tls.writer().write_all(message)?,
+            None => self.outgoing.extend(message),
+        }
+        Ok(())
+    }
+
+    // Read what has arrived, returning whether the server has closed the connection.
+    fn read(&mut self) -> Result<bool, Error> {
+        loop {
+            let closed = match &mut self.tls {
+                Some(tls) => {
+                    let closed = match tls.read_tls(&mut self.stream) {
+                        Ok(size) => size == 0,
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(error) => return Err(error.into()),
+                    };
+                    tls.process_new_packets()?;
+                    match tls.reader().read_to_end(&mut self.received) {
+                        Ok(_) => true,
+                        Err(error) if error.kind() == ErrorKind::UnexpectedEof => true,
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => closed,
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+                None => {
+                    let mut buffer = [0; 4096];
+                    match self.stream.read(&mut buffer) {
+                        Ok(size) => {
+                            self.received.extend(&buffer[..size]);
+                            size == 0
+                        }
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+            };
+            if closed {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.tls {
+            Some(tls) => {
+                while tls.wants_write() {
+                    match tls.write_tls(&mut self.stream) {
+                        Ok(_) => {}
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+            }
+            None => {
+                while !self.outgoing.is_empty() {
+                    match self.stream.write(&self.outgoing) {
+                        Ok(size) => {
+                            self.outgoing.drain(..size);
+                        }
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Worker {
+    poll: Poll,
+    upstream: Upstream,
+    queries: Receiver<(Connection, Vec<u8>)>,
+    responses: Sender<(Connection, Vec<u8>)>,
+    waker: Arc<Waker>,
+    sessions: HashMap<SocketAddr, Session>,
+    pending: HashMap<u16, Pending>,
+    next_id: u16,
+}
+
+impl Worker {
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(16);
+        loop {
+            let timeout = (!self.pending.is_empty()).then_some(TIMEOUT / 5);
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            }
+            loop {
+                match self.queries.try_recv() {
+                    Ok((connection, query)) => self.enqueue(connection, query),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            let servers = self.sessions.keys().copied().collect::<Vec<_>>();
+            for server in servers {
+                if let Err(error) = self.receive(server).and_then(|_| self.flush(server)) {
+                    self.reconnect(server, error);
+                }
+            }
+            self.expire();
+        }
+    }
+
+    fn server_for(&self, connection: &Connection) -> Result<SocketAddr, Error> {
+        match &self.upstream {
+            Upstream::Tcp(server) | Upstream::Tls(server, _) => Ok(*server),
+            Upstream::Original(nameservers) => {
+                let server = SocketAddr::try_from(connection.dst.clone())?;
+                if !is_virtual(server.ip()) {
+                    return Ok(server);
+                }
+                let nameserver = nameservers.iter().find(|addr| !addr.is_loopback());
+                let e = "There is no name server to forward DNS queries to";
+                Ok(SocketAddr::new(*nameserver.ok_or(e)?, server.port()))
+            }
+        }
+    }
+
+    fn enqueue(&mut self, connection: Connection, query: Vec<u8>) {
+        if query.len() < 12 {
+            return;
+        }
+        let result = self.server_for(&connection).and_then(|server| {
+            self.send(Pending {
+                connection,
+                server,
+                query,
+                attempts: 0,
+                sent_at: Instant::now(),
+            })
+        });
+        if let Err(error) = result {
+            log::warn!("DNS forwarding: {error}");
+        }
+    }
+
+    // Queue the query on the connection to its server, which is established first if need be.
+    fn send(&mut self, mut pending: Pending) -> Result<(), Error> {
+        if self.pending.len() >= MAX_PENDING {
+            return Err("Too many DNS queries are pending".into());
+        }
+        let session = match self.sessions.entry(pending.server) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let tls = match &self.upstream {
+                    Upstream::Tls(_, tls) => Some(tls.as_ref()),
+                    Upstream::Original(_) | Upstream::Tcp(_) => None,
+                };
+                entry.insert(Session::connect(&self.poll, pending.server, tls)?)
+            }
+        };
+
+        let mut id = self.next_id;
+        while self.pending.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+
+        let length = u16::try_from(pending.query.len())
+            .map_err(|_| Error::from("The DNS query is too large"))?;
+        let mut message = Vec::with_capacity(pending.query.len() + 2);
+        message.extend(length.to_be_bytes());
+        message.extend(id.to_be_bytes());
+        message.extend(&pending.query[2..]);
+        session.queue(&message)?;
+
+        pending.attempts += 1;
+        pending.sent_at = Instant::now();
+        self.pending.insert(id, pending);
+        Ok(())
+    }
+
+    fn receive(&mut self, server: SocketAddr) -> Result<(), Error> {
+        let session = match self.sessions.get_mut(&server) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let closed = session.read()?;
+
+        // Each message is preceded by its length.
+        let mut offset = 0;
+        while let Some(header) = session.received.get(offset..offset + 2) {
+            let length = u16::from_be_bytes([header[0], header[1]]) as usize;
+            let message = match session.received.get(offset + 2..offset + 2 + length) {
+                Some(message) => message,
+                None => break,
+            };
+            offset += 2 + length;
+            if length < 12 {
+                continue;
+            }
+            let id = u16::from_be_bytes([message[0], message[1]]);
+            match self.pending.get(&id) {
+                Some(pending) if pending.server == server => {}
+                _ => continue,
+            }
+            let pending = self.pending.remove(&id).unwrap();
+            let mut response = message.to_vec();
+            response[..2].copy_from_slice(&pending.query[..2]);
+            if self.responses.send((pending.connection, response)).is_ok() {
+                let _ = self.waker.wake();
+            }
+        }
+        session.received.drain(..offset);
+
+        if closed {
+            return Err("The connection is closed".into());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, server: SocketAddr) -> Result<(), Error> {
+        match self.sessions.get_mut(&server) {
+            Some(session) => session.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Drop the connection to the server and send the unanswered queries again on a new one.
+    fn reconnect(&mut self, server: SocketAddr, error: Error) {
+        if let Some(mut session) = self.sessions.remove(&server) {
+            let _ = self.poll.registry().deregister(&mut session.stream);
+        }
+        let ids = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.server == server)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            log::debug!("DNS server {server}: {error}");
+            return;
+        }
+        log::warn!("DNS server {server}: {error}, connecting again");
+        for id in ids {
+            let pending = self.pending.remove(&id).unwrap();
+            if pending.attempts >= MAX_ATTEMPTS {
+                log::debug!("Giving up on DNS query from {}", pending.connection.src);
+                continue;
+            }
+            if let Err(error) = self.send(pending) {
+                log::warn!("DNS server {server}: {error}");
+                break;
+            }
+        }
+        if let Err(error) = self.flush(server) {
+            log::warn!("DNS server {server}: {error}");
+        }
+    }
+
+    // Queries which are not answered in time are given up on, and their connections are
+    // replaced as they may have stalled.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let mut stalled = HashSet::new();
+        self.pending.retain(|_, pending| {
+            let expired = now.duration_since(pending.sent_at) >= TIMEOUT;
+            if expired {
+                stalled.insert(pending.server);
+            }
+            !expired
+        });
+        for server in stalled {
+            self.reconnect(server, "Queries have timed out".into());
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use crate::error::Error;
+use crate::tun2proxy::{
+    Direction, IncomingDataEvent, IncomingDirection, OutgoingDataEvent, OutgoingDirection,
+    OutgoingSlices, TcpProxy,
+};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName,
+};
+use sha2::Digest;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::SystemTime;
+
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// Name sent through SNI and used for certificate verification
+    pub server_name: String,
+    /// Whether the certificate presented by the proxy is verified
+    pub verify: bool,
+    /// Client certificate presented to the proxy, in PEM or PKCS#12 format
+    pub client_cert: Option<PathBuf>,
+    /// Private key of the client certificate in PEM format, unless contained in `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Password of a client certificate in PKCS#12 format
+    pub client_cert_password: Option<String>,
+    /// CA certificates in PEM format which are trusted instead of the system trust store
+    pub ca: Option<PathBuf>,
+    /// Hashes one of which the certificate of the proxy has to match
+    pub pins: Vec<CertificatePin>,
+}
+
+/// SHA-256 hash of the DER encoding of a certificate, given in hexadecimal, optionally with
+/// colons between the bytes as printed by `openssl x509 -fingerprint -sha256`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CertificatePin(pub [u8; 32]);
+
+impl FromStr for CertificatePin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || Error::from(format!("`{s}` is not a valid SHA-256 hash"));
+        let digits = s.replace(':', "");
+        if digits.len() != 64 || !digits.is_ascii() {
+            return Err(e());
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| e())?;
+        }
+        Ok(Self(hash))
+    }
+}
+
+impl TlsOptions {
+    pub fn new(server_name: &str) -> Self {
+        Self {
+            server_name: server_name.into(),
+            verify: true,
+            client_cert: None,
+            client_key: None,
+            client_cert_password: None,
+            ca: None,
+            pins: Vec::new(),
+        }
+    }
+
+    /// Whether the query parameter of the proxy URL configures TLS.
+    pub(crate) fn is_option(key: &str) -> bool {
+        matches!(
+            key,
+            "sni" | "insecure" | "client_cert" | "client_key" | "client_cert_password"
+        )
+    }
+
+    pub(crate) fn set_option(&mut self, key: &str, value: &str) {
+        match key {
+            "sni" => self.server_name = value.into(),
+            "insecure" => self.verify = !matches!(value, "1" | "true"),
+            "client_cert" => self.client_cert = Some(value.into()),
+            "client_key" => self.client_key = Some(value.into()),
+            "client_cert_password" => self.client_cert_password = Some(value.into()),
+            _ => {}
+        }
+    }
+}
+
+// Accepts a certificate whose hash is pinned. Its chain is additionally verified if CA
+// certificates have been given.
+struct PinnedCertificateVerification {
+    pins: Vec<CertificatePin>,
+    chain: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let hash: [u8; 32] = sha2::Sha256::digest(&end_entity.0).into();
+        if !self.pins.contains(&CertificatePin(hash)) {
+            let e = "The certificate of the proxy does not match a pinned hash";
+            return Err(rustls::Error::General(e.into()));
+        }
+        match &self.chain {
+            Some(chain) => chain.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+// Number of SIGHUP signals received, which request the client certificates to be reloaded.
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn handle_sighup() -> Result<(), Error> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGHUP};
+    static INSTALL: Once = Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        let action = SigAction::new(
+            SigHandler::Handler(request_reload),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // The handler only increments an atomic counter, which is async-signal-safe.
+        result = unsafe { sigaction(SIGHUP, &action) }.map(|_| ());
+    });
+    Ok(result?)
+}
+
+// Read the client certificate chain and its private key. A file in PEM format may contain both.
+fn load_client_certificate(
+    options: &TlsOptions,
+) -> Result<Option<(Vec<Certificate>, PrivateKey)>, Error> {
+    let path = match &options.client_cert {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let read = |path: &PathBuf| {
+        std::fs::read(path)
+            .map_err(|e| Error::from(format!("Cannot read `{}`: {e}", path.display())))
+    };
+    let data = read(path)?;
+
+    if !data.windows(11).any(|window| window == b"-----BEGIN ") {
+        let password = options.client_cert_password.as_deref().unwrap_or_default();
+        let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)
+            .map_err(|e| format!("Cannot read PKCS#12 file `{}`: {e}", path.display()))?;
+        let (_, chain) = keystore
+            .private_key_chain()
+            .ok_or_else(|| format!("`{}` does not contain a private key", path.display()))?;
+        let certs = chain
+            .chain()
+            .iter()
+            .map(|cert| Certificate(cert.as_der().to_vec()))
+            .collect();
+        return Ok(Some((certs, PrivateKey(chain.key().to_vec()))));
+    }
+
+    let mut items = rustls_pemfile::read_all(&mut data.as_slice())?;
+    if let Some(path) = &options.client_key {
+        items.extend(rustls_pemfile::read_all(&mut read(path)?.as_slice())?);
+    }
+    let (mut certs, mut key) = (Vec::new(), None);
+    for item in items {
+        match item {
+            rustls_pemfile::Item::X509Certificate(der) => certs.push(Certificate(der)),
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => key = key.or(Some(PrivateKey(der))),
+            _ => {}
+        }
+    }
+    if certs.is_empty() {
+        return Err(format!("`{}` does not contain a certificate", path.display()).into());
+    }
+    let key = key.ok_or("The private key of the client certificate is missing")?;
+    Ok(Some((certs, key)))
+}
+
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// The client configuration along with the number of reloads it reflects.
+struct LoadedConfig {
+    config: Arc<ClientConfig>,
+    reloads: usize,
+}
+
+#[derive(Clone)]
+pub(crate) struct TlsConfig {
+    options: TlsOptions,
+    alpn: Vec<Vec<u8>>,
+    loaded: Arc<Mutex<LoadedConfig>>,
+    server_name: ServerName,
+}
+
+impl TlsConfig {
+    pub fn new(options: &TlsOptions) -> Result<Self, Error> {
+        if !options.verify {
+            log::warn!("Certificate verification of the proxy server is disabled");
+        }
+        if options.client_cert.is_some() {
+            handle_sighup()?;
+        }
+        let reloads = RELOADS.load(Ordering::Relaxed);
+        let config = Arc::new(Self::build(options, &[])?);
+
+        let server_name = ServerName::try_from(options.server_name.as_str()).map_err(|_| {
+            Error::from(format!(
+                "`{}` is not a valid server name",
+                options.server_name
+            ))
+        })?;
+
+        Ok(Self {
+            options: options.clone(),
+            alpn: Vec::new(),
+            loaded: Arc::new(Mutex::new(LoadedConfig { config, reloads })),
+            server_name,
+        })
+    }
+
+    fn build(options: &TlsOptions, alpn: &[Vec<u8>]) -> Result<ClientConfig, Error> {
+        let mut root_store = RootCertStore::empty();
+        match &options.ca {
+            Some(path) => {
+                let data = std::fs::read(path)
+                    .map_err(|e| format!("Cannot read `{}`: {e}", path.display()))?;
+                for der in rustls_pemfile::certs(&mut data.as_slice())? {
+                    root_store.add(&Certificate(der))?;
+                }
+                if root_store.is_empty() {
+                    let e = format!("`{}` does not contain a certificate", path.display());
+                    return Err(e.into());
+                }
+            }
+            None => root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+        }
+        let verifier = (!options.pins.is_empty()).then(|| PinnedCertificateVerification {
+            pins: options.pins.clone(),
+            chain: options
+                .ca
+                .is_some()
+                .then(|| WebPkiVerifier::new(root_store.clone(), None)),
+        });
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store);
+        let mut config = match load_client_certificate(options)? {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+            None => builder.with_no_client_auth(),
+        };
+        if !options.verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertificateVerification));
+        } else if let Some(verifier) = verifier {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+        }
+        config.alpn_protocols = alpn.to_vec();
+        Ok(config)
+    }
+
+    pub fn with_alpn(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        let (mut config, reloads) = {
+            let loaded = self.loaded.lock().unwrap();
+            ((*loaded.config).clone(), loaded.reloads)
+        };
+        config.alpn_protocols = self.alpn.clone();
+        let config = Arc::new(config);
+        self.loaded = Arc::new(Mutex::new(LoadedConfig { config, reloads }));
+        self
+    }
+
+    /// The client configuration, which is rebuilt with the client certificate read anew once
+    /// SIGHUP has been received. The previous configuration is kept if this fails.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let reloads = RELOADS.load(Ordering::Relaxed);
+        if loaded.reloads != reloads {
+            loaded.reloads = reloads;
+            match Self::build(&self.options, &self.alpn) {
+                Ok(config) => {
+                    log::info!("Reloaded the client certificate of the proxy connection");
+                    loaded.config = Arc::new(config);
+                }
+                Err(error) => log::error!("Cannot reload the client certificate: {error}"),
+            }
+        }
+        loaded.config.clone()
+    }
+
+    pub fn server_name(&self) -> String {
+        match &self.server_name {
+            ServerName::DnsName(name) => name.as_ref().into(),
+            ServerName::IpAddress(addr) => addr.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn client_connection(&self) -> Result<ClientConnection, Error> {
+        Ok(ClientConnection::new(
+            self.client_config(),
+            self.server_name.clone(),
+        )?)
+    }
+}
+
+/// Wraps the connection to the proxy server in a TLS session. The wrapped handler only sees the
+/// plaintext stream, so proxy protocols work unchanged on top of TLS.
+pub(crate) struct TlsConnection {
+    inner: Box<dyn TcpProxy>,
+    session: ClientConnection,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl TlsConnection {
+    pub fn new(inner: Box<dyn TcpProxy>, config: &TlsConfig) -> Result<Self, Error> {
+        let session = config.client_connection()?;
+        let mut result = Self {
+            inner,
+            session,
+            server_outbuf: VecDeque::default(),
+        };
+        result.pump()?;
+        Ok(result)
+    }
+
+    // Hand the data the wrapped handler wants to send to the TLS session and collect the
+    // resulting TLS records.
+    fn pump(&mut self) -> Result<(), Error> {
+        loop {
+            let event = self.inner.peek_data(OutgoingDirection::ToServer);
+            if event.buffer.is_empty() {
+                break;
+            }
+            let written = self.session.writer().write(event.buffer)?;
+            self.inner
+                .consume_data(OutgoingDirection::ToServer, written);
+            if written == 0 {
+                break;
+            }
+        }
+
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.server_outbuf)?;
+        }
+        Ok(())
+    }
+
+    fn receive_tls(&mut self, mut buffer: &[u8]) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            self.session.read_tls(&mut buffer)?;
+            self.session.process_new_packets()?;
+
+            let mut plaintext = Vec::new();
+            match self.session.reader().read_to_end(&mut plaintext) {
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error.into()),
+            }
+            if !plaintext.is_empty() {
+                self.inner.push_data(IncomingDataEvent {
+                    direction: IncomingDirection::FromServer,
+                    buffer: &plaintext,
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TcpProxy for TlsConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => self.receive_tls(event.buffer)?,
+            IncomingDirection::FromClient => self.inner.push_data(event)?,
+        }
+        self.pump()
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        if dir == OutgoingDirection::ToServer {
+            self.server_outbuf.drain(0..size);
+        } else {
+            self.inner.consume_data(dir, size);
+        }
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        if dir == OutgoingDirection::ToServer {
+            OutgoingDataEvent {
+                direction: dir,
+                buffer: self.server_outbuf.make_contiguous(),
+            }
+        } else {
+            self.inner.peek_data(dir)
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        if dir == OutgoingDirection::ToServer {
+            (&self.server_outbuf).into()
+        } else {
+            self.inner.peek_slices(dir)
+        }
+    }
+
+    fn connection_established(&self) -> bool {
+        !self.session.is_handshaking() && self.inner.connection_established()
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Outgoing(OutgoingDirection::ToServer) => {
+                !self.server_outbuf.is_empty()
+                    || self
+                        .inner
+                        .have_data(Direction::Outgoing(OutgoingDirection::ToServer))
+            }
+            _ => self.inner.have_data(dir),
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.inner.get_udp_associate()
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.inner.get_bind_address()
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.inner.hostname_refused()
+    }
+}
@@ -0,0 +1,316 @@
+#![cfg(target_os = "linux")]
+
+use crate::error::Error;
+use crate::packet_source::{PacketHandle, PacketSource};
+use crate::virtdevice::VirtualTunDevice;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType, SockaddrStorage};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{HardwareAddress, IpCidr};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+
+const LISTENER_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+
+// The MTU of the packets exchanged with the engine, which is the default of a packet source.
+const MTU: usize = 1500;
+const SOCKET_BUFFER_SIZE: usize = 1024 * 128;
+// Seconds after which a connection is given up if the engine stops answering, e.g. since it has
+// dropped the connection without a reset, which the keep-alives tell.
+const ENGINE_TIMEOUT: u64 = 60;
+const KEEP_ALIVE_INTERVAL: u64 = 20;
+
+/// How the connections reaching a [`TransparentListener`] have been diverted by the firewall.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransparentMode {
+    /// The `REDIRECT` target, which rewrites the destination, as told by `SO_ORIGINAL_DST`.
+    Redirect,
+    /// The `TPROXY` target, which keeps the destination as the local address of the connection.
+    Tproxy,
+}
+
+/// A listener for the TCP connections which the firewall diverts to tun2proxy, e.g. on a router,
+/// in place of a tun interface. The connections are handed to the engine as packets through
+/// [`TransparentListener::start`], so that they are proxied like those from a tun interface.
+pub struct TransparentListener {
+    listener: TcpListener,
+    mode: TransparentMode,
+}
+
+impl TransparentListener {
+    pub fn bind(addr: SocketAddr, mode: TransparentMode) -> Result<Self, Error> {
+        let family = match addr {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+        let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+        let fd = socket::socket(family, SockType::Stream, flags, None)?;
+        // The listener owns the socket from here on, so that it is closed on errors.
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        socket::setsockopt(fd, sockopt::ReuseAddr, &true)?;
+        // Accepting connections to foreign addresses takes the CAP_NET_ADMIN capability.
+        if mode == TransparentMode::Tproxy {
+            socket::setsockopt(fd, sockopt::IpTransparent, &true)?;
+        }
+        socket::bind(fd, &SockaddrStorage::from(addr))?;
+        socket::listen(fd, 128)?;
+        log::info!("Listening on {addr} for diverted connections");
+        Ok(Self {
+            listener: TcpListener::from_std(listener),
+            mode,
+        })
+    }
+
+    /// Accept the connections in a thread of their own, started along with the tunnel, which turns
+    /// them into the packets of the returned source, to be passed as
+    /// [`NetworkInterface::Packets`](crate::NetworkInterface).
+    pub fn start(self) -> Result<PacketSource, Error> {
+        let (source, packets) = PacketSource::new();
+        let mut bridge = Bridge::new(self, packets)?;
+        Ok(source.with_worker(move || {
+            if let Err(e) = bridge.run() {
+                log::error!("{e}");
+            }
+        }))
+    }
+}
+
+// The address the client connected to before being diverted.
+fn original_destination(stream: &TcpStream, mode: TransparentMode) -> std::io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    if mode == TransparentMode::Tproxy {
+        return Ok(local);
+    }
+    let fd = stream.as_raw_fd();
+    Ok(match local.ip().to_canonical() {
+        IpAddr::V4(_) => {
+            let addr = socket::getsockopt(fd, sockopt::OriginalDst)?;
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            SocketAddr::new(ip.into(), u16::from_be(addr.sin_port))
+        }
+        IpAddr::V6(_) => {
+            let addr = socket::getsockopt(fd, sockopt::Ip6tOriginalDst)?;
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port))
+        }
+    })
+}
+
+// A connection of a client, which a socket of the stack of the bridge replays towards the engine
+// from the address of the client.
+struct Relay {
+    stream: TcpStream,
+    handle: SocketHandle,
+    // Whether the client is done sending, after which the socket is closed.
+    client_closed: bool,
+    // Whether the stream has been shut down for writing, as the engine is done sending.
+    engine_closed: bool,
+}
+
+// The client side of the connections, whose packets are exchanged with the engine.
+struct Bridge {
+    poll: Poll,
+    listener: TcpListener,
+    mode: TransparentMode,
+    packets: PacketHandle,
+    iface: Interface,
+    device: VirtualTunDevice,
+    sockets: SocketSet<'static>,
+    relays: HashMap<Token, Relay>,
+    next_token: usize,
+}
+
+impl Bridge {
+    fn new(listener: TransparentListener, packets: PacketHandle) -> Result<Self, Error> {
+        let poll = Poll::new()?;
+        let mut listener_socket = listener.listener;
+        poll.registry()
+            .register(&mut listener_socket, LISTENER_TOKEN, Interest::READABLE)?;
+        packets.attach(Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?));
+
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.max_transmission_unit = MTU;
+        capabilities.medium = Medium::Ip;
+        let mut device = VirtualTunDevice::new(capabilities);
+        // Like the engine, the stack sends from and accepts packets to any address, those of the
+        // clients.
+        let config = Config::new(HardwareAddress::Ip);
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        let gateway4 = Ipv4Addr::new(0, 0, 0, 1);
+        let gateway6 = Ipv6Addr::LOCALHOST;
+        iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.push(IpCidr::new(gateway4.into(), 0)).unwrap();
+            ip_addrs.push(IpCidr::new(gateway6.into(), 0)).unwrap()
+        });
+        iface.routes_mut().add_default_ipv4_route(gateway4.into())?;
+        iface.routes_mut().add_default_ipv6_route(gateway6.into())?;
+        iface.set_any_ip(true);
+
+        Ok(Self {
+            poll,
+            listener: listener_socket,
+            mode: listener.mode,
+            packets,
+            iface,
+            device,
+            sockets: SocketSet::new([]),
+            relays: HashMap::default(),
+            next_token: usize::from(WAKER_TOKEN) + 1,
+        })
+    }
+
+    fn accept(&mut self) -> Result<(), Error> {
+        loop {
+            let (mut stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let dst = match original_destination(&stream, self.mode) {
+                Ok(dst) => SocketAddr::new(dst.ip().to_canonical(), dst.port()),
+                Err(e) => {
+                    log::warn!("No original destination of the connection from {peer}: {e}");
+                    continue;
+                }
+            };
+            let src = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+            );
+            socket.set_ack_delay(None);
+            socket.set_timeout(Some(Duration::from_secs(ENGINE_TIMEOUT)));
+            socket.set_keep_alive(Some(Duration::from_secs(KEEP_ALIVE_INTERVAL)));
+            if let Err(e) = socket.connect(self.iface.context(), dst, src) {
+                log::warn!("Cannot relay the connection from {src} to {dst}: {e}");
+                continue;
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            let relay = Relay {
+                stream,
+                handle: self.sockets.add(socket),
+                client_closed: false,
+                engine_closed: false,
+            };
+            self.relays.insert(token, relay);
+            log::debug!("Relaying the connection from {src} to {dst}");
+        }
+    }
+
+    // Pass on what the engine and the clients have sent, as far as the other side takes it. The
+    // streams are edge-triggered, so all of them are served on every wakeup.
+    fn relay(&mut self) {
+        for relay in self.relays.values_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(relay.handle);
+            while socket.can_recv() {
+                let written = socket.recv(|data| match relay.stream.write(data) {
+                    Ok(written) => (written, Ok(written)),
+                    Err(e) => (0, Err(e)),
+                });
+                match written {
+                    Ok(Ok(written)) if written > 0 => {}
+                    Ok(Err(e)) if e.kind() != ErrorKind::WouldBlock => {
+                        log::debug!("Write to client: {e}");
+                        socket.abort();
+                    }
+                    _ => break,
+                }
+            }
+            while !relay.client_closed && socket.can_send() {
+                let read = socket.send(|buffer| match relay.stream.read(buffer) {
+                    Ok(read) => (read, Ok(read)),
+                    Err(e) => (0, Err(e)),
+                });
+                match read {
+                    Ok(Ok(0)) => {
+                        relay.client_closed = true;
+                        socket.close();
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                    Ok(Err(e)) => {
+                        log::debug!("Read from client: {e}");
+                        relay.client_closed = true;
+                        socket.abort();
+                    }
+                    Err(_) => break,
+                }
+            }
+            if !relay.engine_closed
+                && !socket.may_recv()
+                && socket.recv_queue() == 0
+                && !matches!(
+                    socket.state(),
+                    tcp::State::SynSent | tcp::State::SynReceived
+                )
+            {
+                relay.engine_closed = true;
+                let _ = relay.stream.shutdown(Shutdown::Write);
+            }
+        }
+    }
+
+    // Drop the connections which are closed on both sides, or reset by either.
+    fn remove_closed(&mut self) {
+        let sockets = &mut self.sockets;
+        let registry = self.poll.registry();
+        self.relays.retain(|_, relay| {
+            let state = sockets.get::<tcp::Socket>(relay.handle).state();
+            if !matches!(state, tcp::State::Closed | tcp::State::TimeWait) {
+                return true;
+            }
+            let _ = registry.deregister(&mut relay.stream);
+            sockets.remove(relay.handle);
+            false
+        });
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map(std::time::Duration::from);
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            if events.iter().any(|event| event.token() == LISTENER_TOKEN) {
+                self.accept()?;
+            }
+            while let Some(packet) = self.packets.try_extract() {
+                self.device.inject_packet(&packet);
+                self.device.pool().give_back(packet);
+            }
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            self.relay();
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            while let Some(packet) = self.device.exfiltrate_packet() {
+                // The engine is gone once it has dropped the packet source.
+                if self.packets.inject(packet).is_err() {
+                    return Ok(());
+                }
+            }
+            self.remove_closed();
+        }
+    }
+}
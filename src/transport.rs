@@ -0,0 +1,339 @@
+use crate::bridge::BridgeHandle;
+use crate::error::Error;
+use crate::grpc::{GrpcConnection, GrpcOptions};
+use crate::kcp;
+use crate::obfs4::{Obfs4, Obfs4Options};
+use crate::obfuscation::ObfuscatedConnection;
+use crate::quic;
+use crate::tls::{TlsConfig, TlsConnection, TlsOptions};
+use crate::tun2proxy::{Connection, ConnectionManager, TcpProxy};
+use crate::websocket::{WebSocketConnection, WebSocketOptions};
+use crate::Credentials;
+use smoltcp::wire::IpProtocol;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Clone, Debug)]
+pub struct QuicOptions {
+    pub tls: TlsOptions,
+    /// Application protocol negotiated through ALPN
+    pub alpn: Option<String>,
+}
+
+/// Carries the connections to the proxy server. The transport is selected through a prefix of
+/// the proxy scheme, e.g. `quic+socks5`.
+#[derive(Clone, Debug, Default)]
+pub enum Transport {
+    /// Each connection is made through its own TCP connection.
+    #[default]
+    Tcp,
+    /// Each connection is carried by a stream of a single QUIC connection.
+    Quic(QuicOptions),
+    /// Each connection is upgraded to a WebSocket connection.
+    WebSocket(WebSocketOptions),
+    /// Each connection is carried by a gRPC stream over its own HTTP/2 connection.
+    Grpc(GrpcOptions),
+    /// Each connection is carried by a smux stream of a single KCP session.
+    Kcp,
+    /// Each connection is obfuscated through obfs4.
+    Obfs4(Obfs4Options),
+    /// Each connection is made through its own connection to a Unix domain socket, given as
+    /// e.g. `socks5://unix:/run/proxy.sock`.
+    Unix(PathBuf),
+}
+
+impl Transport {
+    pub(crate) fn from_url(name: &str, host: &str, url: &url::Url) -> Result<Self, Error> {
+        let tls_option = |options: &mut TlsOptions, key: &str, value: &str| match key {
+            "sni" => options.server_name = value.into(),
+            "insecure" => options.verify = !matches!(value, "1" | "true"),
+            _ => {}
+        };
+        match name {
+            "quic" => {
+                let mut options = QuicOptions {
+                    tls: TlsOptions::new(host),
+                    alpn: None,
+                };
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "alpn" => options.alpn = Some(value.into_owned()),
+                        key => tls_option(&mut options.tls, key, &value),
+                    }
+                }
+                Ok(Transport::Quic(options))
+            }
+            "ws" | "wss" => {
+                let secure = name == "wss";
+                let port = url.port().unwrap_or_default();
+                let mut options = WebSocketOptions {
+                    path: String::from("/"),
+                    host: match (secure, port) {
+                        (false, 80) | (true, 443) => host.into(),
+                        _ => format!("{host}:{port}"),
+                    },
+                    tls: secure.then(|| TlsOptions::new(host)),
+                };
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "path" => options.path = value.into_owned(),
+                        "host" => options.host = value.into_owned(),
+                        key => {
+                            if let Some(tls) = &mut options.tls {
+                                tls_option(tls, key, &value);
+                            }
+                        }
+                    }
+                }
+                if !options.path.starts_with('/') {
+                    return Err(format!("`{}` is an invalid WebSocket path", options.path).into());
+                }
+                Ok(Transport::WebSocket(options))
+            }
+            "grpc" | "grpcs" => {
+                let secure = name == "grpcs";
+                let port = url.port().unwrap_or_default();
+                let mut options = GrpcOptions {
+                    service: String::from("GunService"),
+                    authority: match (secure, port) {
+                        (false, 80) | (true, 443) => host.into(),
+                        _ => format!("{host}:{port}"),
+                    },
+                    tls: secure.then(|| TlsOptions::new(host)),
+                };
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "service" => options.service = value.into_owned(),
+                        key => {
+                            if let Some(tls) = &mut options.tls {
+                                tls_option(tls, key, &value);
+                            }
+                        }
+                    }
+                }
+                Ok(Transport::Grpc(options))
+            }
+            "kcp" => Ok(Transport::Kcp),
+            "obfs4" => {
+                let mut cert = None;
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "cert" => cert = Some(Obfs4Options::from_cert(&value)?),
+                        // Only the client side of the inter-arrival time obfuscation differs.
+                        "iat-mode" if value == "0" => {}
+                        "iat-mode" => {
+                            return Err(format!("`iat-mode={value}` is not supported").into())
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Transport::Obfs4(
+                    cert.ok_or("obfs4 requires a `cert` parameter")?,
+                ))
+            }
+            _ => Err(format!("`{name}` is an invalid transport").into()),
+        }
+    }
+
+    /// TLS options of the connection to the proxy server, if the transport is secured.
+    pub(crate) fn tls_mut(&mut self) -> Option<&mut TlsOptions> {
+        match self {
+            Transport::Quic(options) => Some(&mut options.tls),
+            Transport::WebSocket(options) => options.tls.as_mut(),
+            Transport::Grpc(options) => options.tls.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Whether the query parameter of the proxy URL configures the transport.
+    pub(crate) fn is_option(&self, key: &str) -> bool {
+        match self {
+            Transport::Tcp | Transport::Kcp | Transport::Unix(_) => false,
+            Transport::Quic(_) => matches!(key, "sni" | "insecure" | "alpn"),
+            Transport::WebSocket(options) => match key {
+                "path" | "host" => true,
+                "sni" | "insecure" => options.tls.is_some(),
+                _ => false,
+            },
+            Transport::Grpc(options) => match key {
+                "service" => true,
+                "sni" | "insecure" => options.tls.is_some(),
+                _ => false,
+            },
+            Transport::Obfs4(_) => matches!(key, "cert" | "iat-mode"),
+        }
+    }
+}
+
+enum Layer {
+    /// Connections are handed to a bridge thread which carries them to the proxy.
+    Bridge(BridgeHandle),
+    /// Connections are made to a Unix domain socket.
+    Unix(PathBuf),
+    /// Connections to the proxy are wrapped by a WebSocket connection, optionally within TLS.
+    WebSocket(WebSocketOptions, Option<TlsConfig>),
+    /// Connections to the proxy are carried by a gRPC stream, optionally within TLS.
+    Grpc(GrpcOptions, Option<TlsConfig>),
+    /// Connections to the proxy are obfuscated through obfs4.
+    Obfs4(Obfs4Options),
+}
+
+/// Routes the connections of a connection manager through a transport other than TCP.
+pub(crate) struct TransportManager {
+    inner: Rc<dyn ConnectionManager>,
+    server: SocketAddr,
+    layer: Layer,
+}
+
+impl ConnectionManager for TransportManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        // UDP relays of the proxy are not reachable through the transport.
+        connection.proto == IpProtocol::Tcp && self.inner.handles_connection(connection)
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if !self.handles_connection(connection) {
+            return Ok(None);
+        }
+        match self.inner.new_connection(connection, manager)? {
+            Some(handler) => self.wrap(handler).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn new_bind(
+        &self,
+        connection: &Connection,
+        control: &Connection,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        match self.inner.new_bind(connection, control, manager)? {
+            Some(handler) => self.wrap(handler).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn close_connection(&self, connection: &Connection) {
+        self.inner.close_connection(connection)
+    }
+
+    fn get_server(&self) -> SocketAddr {
+        self.server
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        self.inner.get_credentials()
+    }
+
+    fn resolves_locally(&self, connection: &Connection) -> bool {
+        self.inner.resolves_locally(connection)
+    }
+
+    fn report_refused_hostname(&self, connection: &Connection, name: &str) {
+        self.inner.report_refused_hostname(connection, name)
+    }
+
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        match &self.layer {
+            Layer::Bridge(bridge) => Some(bridge),
+            _ => self.inner.get_bridge(),
+        }
+    }
+
+    fn get_unix_socket(&self) -> Option<&Path> {
+        match &self.layer {
+            Layer::Unix(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl TransportManager {
+    fn wrap(&self, handler: Box<dyn TcpProxy>) -> Result<Box<dyn TcpProxy>, Error> {
+        Ok(match &self.layer {
+            Layer::Bridge(_) | Layer::Unix(_) => handler,
+            Layer::WebSocket(options, tls) => {
+                let handler = Box::new(WebSocketConnection::new(handler, options)?);
+                match tls {
+                    Some(tls) => Box::new(TlsConnection::new(handler, tls)?),
+                    None => handler,
+                }
+            }
+            Layer::Grpc(options, tls) => {
+                let handler = Box::new(GrpcConnection::new(handler, options)?);
+                match tls {
+                    Some(tls) => Box::new(TlsConnection::new(handler, tls)?),
+                    None => handler,
+                }
+            }
+            Layer::Obfs4(options) => Box::new(ObfuscatedConnection::new(
+                handler,
+                Box::new(Obfs4::new(options)?),
+            )?),
+        })
+    }
+}
+
+/// Wrap the connection manager so that its connections to `server` use the transport.
+pub(crate) fn wrap_manager(
+    manager: Rc<dyn ConnectionManager>,
+    server: SocketAddr,
+    transport: &Transport,
+) -> Result<Rc<dyn ConnectionManager>, Error> {
+    match transport {
+        Transport::Tcp => Ok(manager),
+        Transport::Quic(options) => {
+            let mut tls = TlsConfig::new(&options.tls)?;
+            if let Some(alpn) = &options.alpn {
+                tls = tls.with_alpn(&[alpn.as_bytes()]);
+            }
+            Ok(Rc::new(TransportManager {
+                inner: manager,
+                server,
+                layer: Layer::Bridge(quic::spawn_stream_bridge(server, tls)?),
+            }))
+        }
+        Transport::WebSocket(options) => {
+            let tls = match &options.tls {
+                None => None,
+                Some(options) => Some(TlsConfig::new(options)?),
+            };
+            Ok(Rc::new(TransportManager {
+                inner: manager,
+                server,
+                layer: Layer::WebSocket(options.clone(), tls),
+            }))
+        }
+        Transport::Grpc(options) => {
+            let tls = match &options.tls {
+                None => None,
+                Some(options) => Some(TlsConfig::new(options)?.with_alpn(&[b"h2"])),
+            };
+            Ok(Rc::new(TransportManager {
+                inner: manager,
+                server,
+                layer: Layer::Grpc(options.clone(), tls),
+            }))
+        }
+        Transport::Obfs4(options) => Ok(Rc::new(TransportManager {
+            inner: manager,
+            server,
+            layer: Layer::Obfs4(options.clone()),
+        })),
+        Transport::Unix(path) => Ok(Rc::new(TransportManager {
+            inner: manager,
+            server,
+            layer: Layer::Unix(path.clone()),
+        })),
+        Transport::Kcp => Ok(Rc::new(TransportManager {
+            inner: manager,
+            server,
+            layer: Layer::Bridge(kcp::spawn_bridge(server)?),
+        })),
+    }
+}
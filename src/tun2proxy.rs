@@ -1,25 +1,54 @@
+use crate::bridge::BridgeHandle;
+use crate::dns_backend::BackendForwarder;
+use crate::dns_rule::{DnsRule, DnsTarget};
+use crate::doh::DohClient;
 use crate::error::Error;
+use crate::ftp::{self, ActiveMode};
+#[cfg(target_os = "linux")]
+use crate::offload::OffloadTun;
+use crate::packet_source::PacketSource;
+use crate::protect;
+use crate::proxy_protocol::ProxyProtocolConnection;
+use crate::resolve::HostResolver;
+use crate::sd_notify;
+use crate::socks::{decapsulate_udp_datagram, encapsulate_udp_datagram};
+use crate::tcp_dns::{self, TcpDnsClient, Upstream};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::{Ring, UringTun};
 use crate::virtdevice::VirtualTunDevice;
-use crate::{Credentials, NetworkInterface, Options};
+use crate::virtdns::{DnsQuery, LocalDnsPolicy, VirtualDns};
+use crate::wireguard::WireGuardTunnel;
+use crate::{Credentials, DnsUpstream, NetworkInterface, Options};
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+type TunDevice = crate::bsd_tun::BsdTun;
+#[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+type TunDevice = smoltcp::phy::TunTapInterface;
 use log::{error, info};
 use mio::event::Event;
-use mio::net::TcpStream;
+use mio::net::{TcpStream, UdpSocket, UnixStream};
 use mio::unix::SourceFd;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
-use smoltcp::phy::{Device, Medium, RxToken, TunTapInterface, TxToken};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium};
 use smoltcp::socket::tcp::State;
-use smoltcp::socket::{tcp, udp};
+use smoltcp::socket::{tcp, udp, AnySocket};
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpCidr, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
-use std::collections::{HashMap, HashSet};
+use smoltcp::wire::{
+    IpAddress, IpCidr, IpEndpoint, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::{From, TryFrom};
-use std::io::{Read, Write};
+use std::io::{IoSlice, Read, Write};
 use std::net::Shutdown::Both;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
-use std::os::unix::io::AsRawFd;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Hash, Clone, Eq, PartialEq, Debug)]
 pub(crate) enum DestinationHost {
@@ -95,6 +124,32 @@ impl std::fmt::Display for Connection {
     }
 }
 
+// The addresses of a connection on the tunnel interface, by which it is looked up. Unlike the
+// connection, whose destination the virtual DNS may have named, it is of a fixed size and copied
+// without allocating.
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Debug)]
+struct ConnectionKey {
+    src: SocketAddr,
+    dst: SocketAddr,
+    proto: IpProtocol,
+}
+
+impl From<ConnectionKey> for Connection {
+    fn from(key: ConnectionKey) -> Self {
+        Self {
+            src: key.src,
+            dst: key.dst.into(),
+            proto: key.proto,
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.src, self.dst)
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub(crate) enum IncomingDirection {
     FromServer,
@@ -127,6 +182,63 @@ pub(crate) struct DataEvent<'a, T> {
 pub(crate) type IncomingDataEvent<'a> = DataEvent<'a, IncomingDirection>;
 pub(crate) type OutgoingDataEvent<'a> = DataEvent<'a, OutgoingDirection>;
 
+// Slices of pending data which are written at once.
+const MAX_SLICES: usize = 4;
+
+/// Pending data made up of several slices, e.g. a header and the payload following it, which are
+/// written to the socket at once.
+#[derive(Default)]
+pub(crate) struct OutgoingSlices<'a> {
+    slices: [&'a [u8]; MAX_SLICES],
+    count: usize,
+}
+
+impl<'a> OutgoingSlices<'a> {
+    /// Append `slice` unless empty. Once all the slices are taken, those appended are left out,
+    /// to be peeked at once the data before them is consumed.
+    pub(crate) fn push(&mut self, slice: &'a [u8]) {
+        if !slice.is_empty() && self.count < MAX_SLICES {
+            self.slices[self.count] = slice;
+            self.count += 1;
+        }
+    }
+
+    pub(crate) fn append(&mut self, other: OutgoingSlices<'a>) {
+        for &slice in &other.slices[..other.count] {
+            self.push(slice);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.slices[..self.count]
+            .iter()
+            .map(|slice| slice.len())
+            .sum()
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<usize> {
+        let slices = self.slices.map(IoSlice::new);
+        writer.write_vectored(&slices[..self.count])
+    }
+}
+
+impl<'a> From<&'a [u8]> for OutgoingSlices<'a> {
+    fn from(buffer: &'a [u8]) -> Self {
+        let mut slices = Self::default();
+        slices.push(buffer);
+        slices
+    }
+}
+
+impl<'a> From<&'a VecDeque<u8>> for OutgoingSlices<'a> {
+    fn from(buffer: &'a VecDeque<u8>) -> Self {
+        let (front, back) = buffer.as_slices();
+        let mut slices = Self::from(front);
+        slices.push(back);
+        slices
+    }
+}
+
 fn get_transport_info(
     proto: IpProtocol,
     transport_offset: usize,
@@ -155,7 +267,7 @@ fn get_transport_info(
     }
 }
 
-fn connection_tuple(frame: &[u8]) -> Option<(Connection, bool, usize, usize)> {
+fn connection_tuple(frame: &[u8]) -> Option<(ConnectionKey, bool, usize, usize)> {
     if let Ok(packet) = Ipv4Packet::new_checked(frame) {
         let proto = packet.next_header();
 
@@ -170,12 +282,12 @@ fn connection_tuple(frame: &[u8]) -> Option<(Connection, bool, usize, usize)> {
             packet.header_len().into(),
             &frame[packet.header_len().into()..],
         ) {
-            let connection = Connection {
+            let key = ConnectionKey {
                 src: SocketAddr::new(src_addr, ports.0),
-                dst: SocketAddr::new(dst_addr, ports.1).into(),
+                dst: SocketAddr::new(dst_addr, ports.1),
                 proto,
             };
-            Some((connection, first_packet, payload_offset, payload_size))
+            Some((key, first_packet, payload_offset, payload_size))
         } else {
             None
         };
@@ -195,12 +307,12 @@ fn connection_tuple(frame: &[u8]) -> Option<(Connection, bool, usize, usize)> {
             if let Some((ports, first_packet, payload_offset, payload_size)) =
                 get_transport_info(proto, packet.header_len(), &frame[packet.header_len()..])
             {
-                let connection = Connection {
+                let key = ConnectionKey {
                     src: SocketAddr::new(src_addr, ports.0),
-                    dst: SocketAddr::new(dst_addr, ports.1).into(),
+                    dst: SocketAddr::new(dst_addr, ports.1),
                     proto,
                 };
-                Some((connection, first_packet, payload_offset, payload_size))
+                Some((key, first_packet, payload_offset, payload_size))
             } else {
                 None
             }
@@ -209,17 +321,448 @@ fn connection_tuple(frame: &[u8]) -> Option<(Connection, bool, usize, usize)> {
     }
 }
 
+// Lower the MSS announced in a TCP segment with the SYN flag to `mss`, like a router clamping it.
+fn clamp_mss(frame: &mut [u8], mss: u16) {
+    let (src, dst, header_len): (IpAddress, IpAddress, usize) =
+        if let Ok(packet) = Ipv4Packet::new_checked(&*frame) {
+            if packet.next_header() != IpProtocol::Tcp {
+                return;
+            }
+            let header_len = packet.header_len().into();
+            (
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                header_len,
+            )
+        } else if let Ok(packet) = Ipv6Packet::new_checked(&*frame) {
+            if packet.next_header() != IpProtocol::Tcp {
+                return;
+            }
+            let header_len = packet.header_len();
+            (
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                header_len,
+            )
+        } else {
+            return;
+        };
+    let mut segment = match TcpPacket::new_checked(&mut frame[header_len..]) {
+        Ok(segment) if segment.syn() => segment,
+        _ => return,
+    };
+    let options = segment.options_mut();
+    let mut offset = 0;
+    let mut clamped = false;
+    while offset < options.len() {
+        match options[offset..] {
+            // The end of the options, or padding.
+            [0, ..] => break,
+            [1, ..] => offset += 1,
+            [2, 4, high, low, ..] => {
+                if u16::from_be_bytes([high, low]) > mss {
+                    options[offset + 2..offset + 4].copy_from_slice(&mss.to_be_bytes());
+                    clamped = true;
+                }
+                break;
+            }
+            [_, len, ..] if len >= 2 => offset += usize::from(len),
+            _ => break,
+        }
+    }
+    if clamped {
+        segment.fill_checksum(&src, &dst);
+    }
+}
+
 const SERVER_WRITE_CLOSED: u8 = 1;
 const CLIENT_WRITE_CLOSED: u8 = 2;
 
+const UDP_TIMEOUT: u64 = 30; // Idle timeout of UDP sessions in seconds
+const MAX_UDP_DATA_CACHE: usize = 64; // Datagrams queued while a UDP association is set up
+const IDLE_STREAM_TIMEOUT: u64 = 10; // Seconds for which an idle connection to a proxy is reused
+const MAX_IDLE_STREAMS: usize = 8; // Idle connections kept per proxy
+const WARM_STREAM_TIMEOUT: u64 = 30; // Seconds after which a warm connection to a proxy is renewed
+const WARM_POOL_INTERVAL: u64 = 1; // Seconds between refills of the warm connections
+const DNS_PORT: u16 = 53; // Port treated as DNS unless others are given
+const MDNS_PORT: u16 = 5353;
+const LLMNR_PORT: u16 = 5355;
+// Sources of the answers to mDNS and LLMNR queries, which cannot be their multicast groups
+const LOCAL_DNS_RESPONDER_V4: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+const LOCAL_DNS_RESPONDER_V6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+const MAX_DNS_MESSAGE: usize = 65535; // Size limit of DNS messages over TCP
+const RETRY_DELAY: u64 = 500; // Milliseconds before the first retry of connecting to the proxy
+const MAX_RETRY_DATA: usize = 0x10000; // Client data kept to be replayed when retrying
+const PROXY_READ_CHUNK: usize = 0x4000; // Bytes read from the proxy at a time
+const TCP_BUFFER_SIZE: usize = 1024 * 128; // Buffers of the sockets facing the clients over TCP
+const MIN_TCP_BUFFER_SIZE: usize = 1024 * 4; // Down to which the buffers shrink within the budget
+const UDP_BUFFER_SIZE: usize = 1024 * 128; // Datagrams queued for the clients of a UDP session
+const TUN_BATCH: usize = 64; // Frames read from the tun device before they are processed
+const MIN_MTU: usize = 576; // Smallest MTU of the tun interface, which IPv4 hosts have to take
+const MAX_MTU: usize = 65535;
+const WIREGUARD_OVERHEAD: usize = 32 + 8; // Header and tag of WireGuard messages, and UDP header
+
+// Whether the idle connection `stream` to a proxy is still open and has not received anything.
+// A connection which is still being established counts as open.
+fn is_idle(stream: &TcpStream) -> bool {
+    matches!(stream.peek(&mut [0]),
+        Err(error) if error.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+// Whether the connection is to be retried once the hostname which the proxy has refused has been
+// resolved.
+fn is_resolving(state: &ConnectionState) -> bool {
+    state.refused_host.is_some()
+        && matches!(state.connection.dst.host, DestinationHost::Hostname(_))
+}
+
+// Whether `error` concerns the connection to the proxy, e.g. as it has been refused, reset or has
+// timed out, which is worth retrying, rather than the proxy refusing the request, e.g. for
+// failed authentication, which is bound to fail again.
+fn is_transport_error(error: &Error) -> bool {
+    use std::io::ErrorKind::*;
+    let (kind, code) = match error {
+        Error::Io(error) => (error.kind(), error.raw_os_error()),
+        Error::OSError(errno) => (std::io::Error::from(*errno).kind(), Some(*errno as i32)),
+        _ => return false,
+    };
+    matches!(
+        kind,
+        ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | TimedOut
+            | BrokenPipe
+            | UnexpectedEof
+    ) || matches!(code, Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH))
+}
+
+// Connect to `server` through the network interface `interface` only, e.g. the physical one while
+// the default route points to the tunnel.
+fn connect_bound(
+    server: SocketAddr,
+    interface: &str,
+    fast_open: bool,
+) -> std::io::Result<TcpStream> {
+    use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, SockaddrStorage};
+    let family = match server {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+    let fd = socket::socket(family, SockType::Stream, flags, None)?;
+    // The stream owns the socket from here on, so that it is closed on errors.
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    // The interface of the connection takes precedence over the one given for all sockets.
+    protect::protect(fd)?;
+    protect::bind_to_device(fd, interface)?;
+    if fast_open {
+        protect::set_fast_open(fd)?;
+    }
+    match socket::connect(fd, &SockaddrStorage::from(server)) {
+        Ok(()) | Err(nix::errno::Errno::EINPROGRESS) => Ok(TcpStream::from_std(stream)),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// A UDP socket sending through `interface` whatever the routes are, e.g. to the local network
+// while the routes point to the tunnel.
+fn bind_udp(destination: SocketAddr, interface: &str) -> std::io::Result<UdpSocket> {
+    use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, SockaddrStorage};
+    let (family, local): (_, SocketAddr) = match destination {
+        SocketAddr::V4(_) => (AddressFamily::Inet, (Ipv4Addr::UNSPECIFIED, 0).into()),
+        SocketAddr::V6(_) => (AddressFamily::Inet6, (Ipv6Addr::UNSPECIFIED, 0).into()),
+    };
+    let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+    let fd = socket::socket(family, SockType::Datagram, flags, None)?;
+    // The socket owns the file descriptor from here on, so that it is closed on errors.
+    let udp_socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    protect::protect(fd)?;
+    protect::bind_to_device(fd, interface)?;
+    socket::bind(fd, &SockaddrStorage::from(local))?;
+    Ok(UdpSocket::from_std(udp_socket))
+}
+
+// Whether `dst` is the multicast group of mDNS or LLMNR.
+fn is_local_dns(dst: SocketAddr) -> bool {
+    match (dst.ip(), dst.port()) {
+        (IpAddr::V4(ip), MDNS_PORT) => ip == Ipv4Addr::new(224, 0, 0, 251),
+        (IpAddr::V6(ip), MDNS_PORT) => ip == Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb),
+        (IpAddr::V4(ip), LLMNR_PORT) => ip == Ipv4Addr::new(224, 0, 0, 252),
+        (IpAddr::V6(ip), LLMNR_PORT) => ip == Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 3),
+        _ => false,
+    }
+}
+
+// The connection to the proxy, which is reached through TCP or a Unix domain socket.
+enum ProxyStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ProxyStream {
+    fn connect(
+        manager: &dyn ConnectionManager,
+        server: SocketAddr,
+        fast_open: bool,
+    ) -> std::io::Result<Self> {
+        if let Some(bridge) = manager.get_bridge() {
+            return bridge.connect().map(ProxyStream::Unix);
+        }
+        match manager.get_unix_socket() {
+            Some(path) => UnixStream::connect(path).map(ProxyStream::Unix),
+            None => Self::connect_tcp(manager, server, fast_open).map(ProxyStream::Tcp),
+        }
+    }
+
+    // Connect to `server` over TCP, through the network interface of the manager, if any.
+    fn connect_tcp(
+        manager: &dyn ConnectionManager,
+        server: SocketAddr,
+        fast_open: bool,
+    ) -> std::io::Result<TcpStream> {
+        match manager.get_interface() {
+            Some(interface) => connect_bound(server, interface, fast_open),
+            None => protect::connect_tcp(server, fast_open),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        match self {
+            ProxyStream::Tcp(stream) => stream.peer_addr().is_ok(),
+            ProxyStream::Unix(stream) => stream.peer_addr().is_ok(),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.shutdown(how),
+            ProxyStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    // Unless Nagle's algorithm is kept, small writes go out at once rather than wait for the
+    // acknowledgement of those before, which delays interactive traffic, e.g. of SSH. Keep-alive
+    // probes tell connections which have silently gone away, e.g. when a NAT has dropped them.
+    fn configure(&self, options: &Options) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => Self::configure_tcp(stream, options),
+            ProxyStream::Unix(_) => Ok(()),
+        }
+    }
+
+    fn configure_tcp(stream: &TcpStream, options: &Options) -> std::io::Result<()> {
+        use nix::sys::socket::{setsockopt, sockopt};
+        stream.set_nodelay(!options.nagle)?;
+        if let Some((_idle, _interval, _count)) = options.keep_alive {
+            let fd = stream.as_raw_fd();
+            setsockopt(fd, sockopt::KeepAlive, &true)?;
+            // Elsewhere, the probes are sent as the system is set up to.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                setsockopt(fd, sockopt::TcpKeepIdle, &_idle)?;
+                setsockopt(fd, sockopt::TcpKeepInterval, &_interval)?;
+                setsockopt(fd, sockopt::TcpKeepCount, &_count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.read(buf),
+            ProxyStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.write(buf),
+            ProxyStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.write_vectored(bufs),
+            ProxyStream::Unix(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.flush(),
+            ProxyStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl mio::event::Source for ProxyStream {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.register(registry, token, interests),
+            ProxyStream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.reregister(registry, token, interests),
+            ProxyStream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.deregister(registry),
+            ProxyStream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
 struct ConnectionState {
+    // The connection as handed to the handler, its destination possibly named by the virtual DNS.
+    connection: Rc<Connection>,
     smoltcp_handle: SocketHandle,
-    mio_stream: TcpStream,
+    mio_stream: ProxyStream,
     token: Token,
     handler: Box<dyn TcpProxy>,
     close_state: u8,
     wait_read: bool,
     wait_write: bool,
+    udp_socket: Option<UdpSocket>,
+    udp_token: Option<Token>,
+    udp_data_cache: VecDeque<Vec<u8>>,
+    // The size of the datagrams in `udp_data_cache`, which count towards the memory budget.
+    queued_datagrams: usize,
+    expiry: Option<std::time::Instant>,
+    // When the client or the server has last sent data, after which the connection is closed
+    // once idle for too long.
+    last_activity: std::time::Instant,
+    manager: Rc<dyn ConnectionManager>,
+    server: SocketAddr,
+    // Whether the server has been reported as healthy, i.e. has sent any data.
+    reported: bool,
+    // Set for FTP control connections.
+    ftp: Option<ActiveMode>,
+    // Set for connections accepted by the proxy until the smoltcp socket is connected.
+    bind: Option<Bind>,
+    // Number of failed attempts to connect through the proxy.
+    attempts: u32,
+    // The data the client has sent so far, kept while the connection may be retried.
+    client_data: Option<Vec<u8>>,
+    // When the connection through the proxy is retried after a failed attempt.
+    retry_at: Option<std::time::Instant>,
+    // The hostname of the destination and the proxy which has refused it, set while the
+    // connection is retried with the address resolved locally.
+    refused_host: Option<(String, SocketAddr)>,
+    // The memory taken by the buffers of the smoltcp socket, and by the data which the handler
+    // holds for the server and for the client as last seen, within the memory budget.
+    buffer_size: usize,
+    queued_to_server: usize,
+    queued_to_client: usize,
+}
+
+// A connection which the proxy accepts on behalf of the client, e.g. an FTP data connection in
+// active mode. Once the peer has connected to the proxy, the client is connected to from
+// `local`.
+struct Bind {
+    control: ConnectionKey,
+    local: SocketAddr,
+    announced: bool,
+}
+
+// A socket passing the mDNS and LLMNR queries of a client on to the local network, on which the
+// unicast responses to them arrive.
+struct LocalDnsSession {
+    socket: UdpSocket,
+    client: SocketAddr,
+    expiry: std::time::Instant,
+}
+
+// A connection to a proxy which is kept open for a later connection, either left idle by an
+// earlier one or opened ahead of time, in which case the handshake which does not depend on the
+// destination is completed ahead of time as well.
+struct IdleStream {
+    stream: TcpStream,
+    expiry: std::time::Instant,
+    // The requests of the handshake still to be sent in turn, with the reply expected to each.
+    handshake: VecDeque<(Vec<u8>, Vec<u8>)>,
+    // Whether the first request of `handshake` has been sent.
+    sent: bool,
+    // Whether the handshake is to be skipped once the connection is taken.
+    greeted: bool,
+}
+
+impl IdleStream {
+    fn new(
+        stream: TcpStream,
+        expiry: std::time::Instant,
+        handshake: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Self {
+        Self {
+            stream,
+            expiry,
+            greeted: !handshake.is_empty(),
+            handshake: handshake.into(),
+            sent: false,
+        }
+    }
+
+    // Go on with the handshake as far as the proxy has replied, and tell whether the connection
+    // is still open and has not received anything unexpected.
+    fn advance(&mut self) -> bool {
+        while let Some((request, reply)) = self.handshake.front() {
+            if !self.sent {
+                // The connection is still being established.
+                if self.stream.peer_addr().is_err() {
+                    break;
+                }
+                // The requests are small enough to be written at once.
+                match self.stream.write(request) {
+                    Ok(size) if size == request.len() => self.sent = true,
+                    _ => return false,
+                }
+            }
+            let mut received = vec![0; reply.len()];
+            match self.stream.peek(&mut received) {
+                Ok(size) if size == reply.len() && received == *reply => {
+                    if self.stream.read_exact(&mut received).is_err() {
+                        return false;
+                    }
+                    self.handshake.pop_front();
+                    self.sent = false;
+                }
+                Ok(size) if size > 0 && received[..size] == reply[..size] => return true,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return true,
+                _ => return false,
+            }
+        }
+        is_idle(&self.stream)
+    }
+}
+
+// A connection of a DNS client over TCP, which is answered without a proxy.
+struct DnsStream {
+    smoltcp_handle: SocketHandle,
+    // The size of the buffers of the socket, which count towards the memory budget.
+    buffer_size: usize,
+    // Received data which does not make up a complete query yet.
+    data: Vec<u8>,
 }
 
 pub(crate) trait TcpProxy {
@@ -228,6 +771,37 @@ pub(crate) trait TcpProxy {
     fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent;
     fn connection_established(&self) -> bool;
     fn have_data(&mut self, dir: Direction) -> bool;
+    fn get_udp_associate(&self) -> Option<SocketAddr>;
+
+    /// The data pending in direction `dir` like `peek_data`, as the slices it is made up of, so
+    /// that it does not have to be made contiguous to be written.
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        self.peek_data(dir).buffer.into()
+    }
+
+    /// The address on which the proxy accepts a connection on behalf of the client.
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Whether the connection to the proxy is idle once the handler has failed, e.g. after a
+    /// refused request, so that it can carry the next connection.
+    fn is_reusable(&self) -> bool {
+        false
+    }
+
+    /// Whether the handler has failed as the proxy has refused the hostname of the destination,
+    /// which it may be unable to resolve, so that the connection is retried with the address
+    /// resolved locally.
+    fn hostname_refused(&self) -> bool {
+        false
+    }
+
+    /// Go on from the handshake given by [`ConnectionManager::warm_handshake`], which has been
+    /// completed ahead of time on the connection to the proxy.
+    fn skip_handshake(&mut self) -> Result<(), Error> {
+        Err("The handler cannot skip the handshake with the proxy".into())
+    }
 }
 
 pub(crate) trait ConnectionManager {
@@ -240,142 +814,803 @@ pub(crate) trait ConnectionManager {
     fn close_connection(&self, connection: &Connection);
     fn get_server(&self) -> SocketAddr;
     fn get_credentials(&self) -> &Option<Credentials>;
-}
 
-const TUN_TOKEN: Token = Token(0);
-const UDP_TOKEN: Token = Token(1);
-const EXIT_TOKEN: Token = Token(2);
+    /// Called once `server` has responded on a connection or the connection has been closed
+    /// without a response, so that managers choosing between several proxies can track their
+    /// health.
+    fn report_health(&self, _server: SocketAddr, _healthy: bool) {}
 
-pub struct TunToProxy<'a> {
-    tun: TunTapInterface,
-    poll: Poll,
-    iface: Interface,
-    connections: HashMap<Connection, ConnectionState>,
-    connection_managers: Vec<Rc<dyn ConnectionManager>>,
-    next_token: usize,
-    token_to_connection: HashMap<Token, Connection>,
-    sockets: SocketSet<'a>,
-    device: VirtualTunDevice,
-    options: Options,
-    write_sockets: HashSet<Token>,
-    _exit_receiver: mio::unix::pipe::Receiver,
-    exit_sender: mio::unix::pipe::Sender,
-}
+    /// Called once the proxy has accepted `connection`, retried with the address resolved locally
+    /// after the proxy had refused the hostname `name` of its destination.
+    fn report_refused_hostname(&self, _connection: &Connection, _name: &str) {}
 
-impl<'a> TunToProxy<'a> {
-    pub fn new(interface: &NetworkInterface, options: Options) -> Result<Self, Error> {
-        let tun = match interface {
-            NetworkInterface::Named(name) => TunTapInterface::new(name.as_str(), Medium::Ip)?,
-            NetworkInterface::Fd(fd) => {
-                TunTapInterface::from_fd(*fd, Medium::Ip, options.mtu.unwrap_or(1500))?
-            }
-        };
-        let poll = Poll::new()?;
-        poll.registry().register(
-            &mut SourceFd(&tun.as_raw_fd()),
-            TUN_TOKEN,
-            Interest::READABLE,
-        )?;
+    /// The Unix domain socket through which the proxy is reached instead of the server address.
+    fn get_unix_socket(&self) -> Option<&Path> {
+        None
+    }
 
-        let (exit_sender, mut exit_receiver) = mio::unix::pipe::new()?;
-        poll.registry()
-            .register(&mut exit_receiver, EXIT_TOKEN, Interest::READABLE)?;
+    /// The bridge thread through which the proxy is reached instead of the server address.
+    fn get_bridge(&self) -> Option<&BridgeHandle> {
+        None
+    }
 
-        let config = match tun.capabilities().medium {
-            Medium::Ethernet => Config::new(
-                smoltcp::wire::EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]).into(),
-            ),
-            Medium::Ip => Config::new(smoltcp::wire::HardwareAddress::Ip),
-            Medium::Ieee802154 => todo!(),
-        };
-        let mut virt = VirtualTunDevice::new(tun.capabilities());
-        let gateway4: Ipv4Addr = Ipv4Addr::from_str("0.0.0.1")?;
-        let gateway6: Ipv6Addr = Ipv6Addr::from_str("::1")?;
-        let mut iface = Interface::new(config, &mut virt, Instant::now());
-        iface.update_ip_addrs(|ip_addrs| {
-            ip_addrs.push(IpCidr::new(gateway4.into(), 0)).unwrap();
-            ip_addrs.push(IpCidr::new(gateway6.into(), 0)).unwrap()
-        });
-        iface.routes_mut().add_default_ipv4_route(gateway4.into())?;
-        iface.routes_mut().add_default_ipv6_route(gateway6.into())?;
-        iface.set_any_ip(true);
+    /// The address to connect to for `connection`, which is the server unless connections are
+    /// made straight to their destination.
+    fn get_server_for(&self, _connection: &Connection) -> Result<SocketAddr, Error> {
+        Ok(self.get_server())
+    }
 
-        let tun = Self {
-            tun,
-            poll,
-            iface,
-            connections: HashMap::default(),
-            next_token: usize::from(EXIT_TOKEN) + 1,
-            token_to_connection: HashMap::default(),
-            connection_managers: Vec::default(),
-            sockets: SocketSet::new([]),
-            device: virt,
-            options,
-            write_sockets: HashSet::default(),
-            _exit_receiver: exit_receiver,
-            exit_sender,
-        };
-        Ok(tun)
+    /// Whether the hostname of `connection` is resolved before the connection is handed to the
+    /// manager, as the proxy cannot resolve it.
+    fn resolves_locally(&self, _connection: &Connection) -> bool {
+        false
     }
 
-    fn new_token(&mut self) -> Token {
-        let token = Token(self.next_token);
-        self.next_token += 1;
-        token
+    /// The network interface to which the connections to the server are bound.
+    fn get_interface(&self) -> Option<&str> {
+        None
     }
 
-    pub(crate) fn add_connection_manager(&mut self, manager: Rc<dyn ConnectionManager>) {
-        self.connection_managers.push(manager);
+    /// The handshake which connections kept warm go through ahead of time, as the requests sent
+    /// to the server in turn along with the reply expected to each. It is left empty unless the
+    /// handlers can skip it, which they cannot once wrapped into other protocols, or if it
+    /// depends on the destination.
+    fn warm_handshake(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        Vec::new()
     }
 
-    fn expect_smoltcp_send(&mut self) -> Result<(), Error> {
-        self.iface
-            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    /// Create a handler which has the proxy accept `connection` from its destination, for the
+    /// client which has requested it through the `control` connection.
+    fn new_bind(
+        &self,
+        _connection: &Connection,
+        _control: &Connection,
+        _manager: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        Ok(None)
+    }
+}
 
-        while let Some(vec) = self.device.exfiltrate_packet() {
-            let slice = vec.as_slice();
+/// Answers the DNS queries captured from the tunnel through an upstream resolver. Responses are
+/// picked up once the waker handed to the forwarder has signalled them.
+pub(crate) trait DnsForwarder {
+    fn send_query(&self, connection: &Connection, query: &[u8]) -> Result<(), Error>;
 
-            // TODO: Actual write. Replace.
-            self.tun
-                .transmit(Instant::now())
-                .ok_or("tx token not available")?
-                .consume(slice.len(), |buf| {
-                    buf[..].clone_from_slice(slice);
-                });
-        }
-        Ok(())
-    }
+    fn receive_response(&self) -> Option<(Connection, Vec<u8>)>;
+}
 
-    fn remove_connection(&mut self, connection: &Connection) -> Result<(), Error> {
-        if let Some(mut conn) = self.connections.remove(connection) {
-            let token = &conn.token;
-            self.token_to_connection.remove(token);
-            self.sockets.remove(conn.smoltcp_handle);
-            _ = self.poll.registry().deregister(&mut conn.mio_stream);
-            info!("CLOSE {}", connection);
+const TUN_TOKEN: Token = Token(0);
+const UDP_TOKEN: Token = Token(1);
+const EXIT_TOKEN: Token = Token(2);
+const WAKER_TOKEN: Token = Token(3);
+
+// The tokens of the sockets registered for connections and sessions, which are reused once
+// released, so that they neither grow without bound nor wrap around onto the tokens above. A
+// released token is only handed out again once the events polled along with its release have
+// been handled, and then with its generation in the high bits advanced, so that no event left
+// over for the socket that held it is taken for the socket reusing it.
+struct Tokens {
+    next: usize,
+    free: Vec<Token>,
+    released: Vec<Token>,
+}
+
+const TOKEN_GENERATION_BITS: u32 = usize::BITS / 4;
+const TOKEN_INDEX_BITS: u32 = usize::BITS - TOKEN_GENERATION_BITS;
+const TOKEN_INDEX_MASK: usize = (1 << TOKEN_INDEX_BITS) - 1;
+
+impl Tokens {
+    fn new() -> Self {
+        Self {
+            next: usize::from(WAKER_TOKEN) + 1,
+            free: Vec::new(),
+            released: Vec::new(),
         }
-        Ok(())
     }
 
-    fn get_connection_manager(&self, connection: &Connection) -> Option<Rc<dyn ConnectionManager>> {
-        for manager in self.connection_managers.iter() {
-            if manager.handles_connection(connection) {
-                return Some(manager.clone());
+    fn take(&mut self) -> Token {
+        match self.free.pop() {
+            // The generation wraps around as its carry is shifted out, while the index, which is
+            // never that of the tokens above, stays.
+            Some(Token(token)) => {
+                let generation = (token >> TOKEN_INDEX_BITS) + 1;
+                Token((generation << TOKEN_INDEX_BITS) | (token & TOKEN_INDEX_MASK))
+            }
+            None => {
+                assert!(self.next <= TOKEN_INDEX_MASK, "out of tokens");
+                self.next += 1;
+                Token(self.next - 1)
             }
         }
-        None
     }
 
-    fn check_change_close_state(&mut self, connection: &Connection) -> Result<(), Error> {
-        let state = self.connections.get_mut(connection);
-        if state.is_none() {
-            return Ok(());
+    fn release(&mut self, token: Token) {
+        self.released.push(token);
+    }
+
+    // Make the tokens released so far available again, as no events are pending for them.
+    fn recycle(&mut self) {
+        self.free.append(&mut self.released);
+    }
+}
+
+fn send_datagrams(socket: &UdpSocket, datagrams: &[Vec<u8>]) {
+    for datagram in datagrams {
+        // Like the tunnel interface itself, the tunnel may drop packets under pressure.
+        if let Err(error) = socket.send(datagram) {
+            log::debug!("Send to WireGuard peer: {error}");
         }
-        let state = state.unwrap();
-        let mut closed_ends = 0;
-        if (state.close_state & SERVER_WRITE_CLOSED) == SERVER_WRITE_CLOSED
-            && !state
-                .handler
+    }
+}
+
+// The MTU of the path a connected UDP socket sends along, as far as the kernel knows it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn path_mtu(socket: &UdpSocket) -> Option<usize> {
+    let (level, name) = match socket.peer_addr().ok()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0).then_some(mtu as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn path_mtu(_socket: &UdpSocket) -> Option<usize> {
+    None
+}
+
+// Set the MTU of the interface `name`, in place of which a tun device opened before has to be
+// reopened to take it.
+#[cfg(target_os = "linux")]
+fn set_interface_mtu(name: &str, mtu: usize) -> Result<(), Error> {
+    let name = std::ffi::CString::new(name).map_err(|_| "Invalid name of the tun interface")?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    crate::netlink::Netlink::new()?.set_link_mtu(index, mtu as u32)
+}
+
+#[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+fn open_tun(interface: &NetworkInterface, mtu: Option<usize>) -> Result<Tun, Error> {
+    Ok(match interface {
+        NetworkInterface::Named(name) => {
+            let device = TunDevice::new(name.as_str(), Medium::Ip)?;
+            match mtu {
+                // The device keeps the MTU the interface had when opened, so it is opened anew
+                // through a duplicate of its descriptor, which keeps the interface in place.
+                #[cfg(target_os = "linux")]
+                Some(mtu) if mtu != device.capabilities().max_transmission_unit => {
+                    set_interface_mtu(name, mtu)?;
+                    let fd = nix::fcntl::fcntl(
+                        device.as_raw_fd(),
+                        nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(0),
+                    )?;
+                    Tun::Device(TunDevice::from_fd(fd, Medium::Ip, mtu)?)
+                }
+                _ => Tun::Device(device),
+            }
+        }
+        NetworkInterface::Fd(fd) => {
+            Tun::Device(TunDevice::from_fd(*fd, Medium::Ip, mtu.unwrap_or(1500))?)
+        }
+        NetworkInterface::Packets(source) => Tun::Packets(source.clone(), mtu.unwrap_or(1500)),
+    })
+}
+
+// The tun devices of the BSDs don't tell their MTU, which is 1500 unless configured otherwise.
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+fn open_tun(interface: &NetworkInterface, mtu: Option<usize>) -> Result<Tun, Error> {
+    let mtu = mtu.unwrap_or(1500);
+    Ok(match interface {
+        NetworkInterface::Named(name) => Tun::Device(TunDevice::new(name.as_str(), mtu)?),
+        NetworkInterface::Fd(fd) => Tun::Device(TunDevice::from_fd(*fd, mtu)?),
+        NetworkInterface::Packets(source) => Tun::Packets(source.clone(), mtu),
+    })
+}
+
+// Read a frame of the tun device into `buffer`, unless there is none left.
+#[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+fn read_frame(device: &TunDevice, buffer: &mut [u8]) -> std::io::Result<Option<usize>> {
+    loop {
+        match nix::unistd::read(device.as_raw_fd(), buffer) {
+            Ok(size) => return Ok(Some(size)),
+            Err(nix::errno::Errno::EAGAIN) => return Ok(None),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+fn read_frame(device: &TunDevice, buffer: &mut [u8]) -> std::io::Result<Option<usize>> {
+    device.read_packet(buffer)
+}
+
+#[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+fn write_frame(device: &TunDevice, frame: &[u8]) -> std::io::Result<()> {
+    nix::unistd::write(device.as_raw_fd(), frame)?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+fn write_frame(device: &TunDevice, frame: &[u8]) -> std::io::Result<()> {
+    device.write_packet(frame)
+}
+
+// The frames read from the tunnel on a wakeup, into buffers which are kept from one wakeup to the
+// next rather than allocated for every frame.
+#[derive(Default)]
+struct Frames {
+    buffers: Vec<Vec<u8>>,
+    sizes: Vec<usize>,
+}
+
+impl Frames {
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        let sizes = &self.sizes;
+        self.buffers
+            .iter_mut()
+            .zip(sizes)
+            .map(|(buffer, size)| &mut buffer[..*size])
+    }
+}
+
+// With io_uring, the packets of a tun device are read and written in batches, unless the kernel
+// does not support it.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn with_uring(tun: Tun) -> std::io::Result<Tun> {
+    let device = match tun {
+        Tun::Device(device) => device,
+        tun => return Ok(tun),
+    };
+    match Ring::new() {
+        Ok(ring) => Ok(Tun::Uring(Box::new(UringTun::new(device, ring)?))),
+        Err(e) => {
+            log::warn!("Polling the tun device, as io_uring is not available: {e}");
+            Ok(Tun::Device(device))
+        }
+    }
+}
+
+// A tun device set up with a virtio-net header exchanges TCP segments of up to 64 KiB with the
+// kernel.
+#[cfg(target_os = "linux")]
+fn with_offload(tun: Tun) -> Tun {
+    match tun {
+        Tun::Device(device) if crate::tun_config::has_vnet_header(device.as_raw_fd()) => {
+            Tun::Offload(Box::new(OffloadTun::new(device)))
+        }
+        tun => tun,
+    }
+}
+
+// Account for the data which a handler holds in one direction, as now seen.
+fn set_queued(memory_used: &mut usize, queued: &mut usize, size: usize) {
+    *memory_used = *memory_used - *queued + size;
+    *queued = size;
+}
+
+// Where the packets of the tunnel come from and go to: a tun device, or the channels of a packet
+// source along with its MTU.
+enum Tun {
+    Device(TunDevice),
+    Packets(PacketSource, usize),
+    #[cfg(target_os = "linux")]
+    Offload(Box<OffloadTun>),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Box<UringTun>),
+}
+
+impl Tun {
+    fn capabilities(&self) -> DeviceCapabilities {
+        match self {
+            Tun::Device(device) => device.capabilities(),
+            Tun::Packets(_, mtu) => {
+                let mut capabilities = DeviceCapabilities::default();
+                capabilities.max_transmission_unit = *mtu;
+                capabilities.medium = Medium::Ip;
+                capabilities
+            }
+            #[cfg(target_os = "linux")]
+            Tun::Offload(tun) => tun.capabilities(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Tun::Uring(tun) => tun.capabilities(),
+        }
+    }
+
+    // Pass on a packet as it is, without copying it, returning its buffer if the tunnel is done
+    // with it.
+    fn send(&mut self, packet: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            // Like a physical link, the device may drop packets under pressure.
+            Tun::Device(device) => {
+                if let Err(error) = write_frame(device, &packet) {
+                    log::debug!("Write to tun device: {error}");
+                }
+                Some(packet)
+            }
+            Tun::Packets(source, _) => {
+                source.send(packet);
+                None
+            }
+            #[cfg(target_os = "linux")]
+            Tun::Offload(tun) => {
+                tun.send(packet);
+                None
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Tun::Uring(tun) => {
+                tun.send(packet);
+                None
+            }
+        }
+    }
+
+    // Read up to TUN_BATCH frames, returning whether there may be more left.
+    fn receive_batch(&mut self, frames: &mut Frames) -> std::io::Result<bool> {
+        let mtu = self.capabilities().max_transmission_unit;
+        frames.sizes.clear();
+        while frames.sizes.len() < TUN_BATCH {
+            let index = frames.sizes.len();
+            if frames.buffers.len() == index {
+                frames.buffers.push(Vec::new());
+            }
+            let buffer = &mut frames.buffers[index];
+            let frame = match self {
+                Tun::Device(device) => {
+                    buffer.resize(mtu, 0);
+                    match read_frame(device, buffer)? {
+                        Some(size) => {
+                            frames.sizes.push(size);
+                            continue;
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                Tun::Packets(source, _) => source.receive(),
+                #[cfg(target_os = "linux")]
+                Tun::Offload(tun) => tun.receive()?,
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                Tun::Uring(tun) => tun.receive(),
+            };
+            // The frames of the other sources take the place of the buffers.
+            match frame {
+                Some(frame) => {
+                    frames.sizes.push(frame.len());
+                    *buffer = frame;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    // Pass on the packets sent since the last wakeup, where they are written in batches.
+    fn flush(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Tun::Offload(tun) = self {
+            tun.flush();
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Tun::Uring(tun) = self {
+            tun.flush();
+        }
+    }
+}
+
+/// Stops the event loop of a [`TunToProxy`] from another thread, e.g. from a signal handler.
+pub struct ShutdownHandle(std::fs::File);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) -> Result<(), Error> {
+        (&self.0).write_all(&[1])?;
+        Ok(())
+    }
+}
+
+pub struct TunToProxy<'a> {
+    tun: Tun,
+    frames: Frames,
+    poll: Poll,
+    iface: Interface,
+    connections: HashMap<ConnectionKey, ConnectionState>,
+    connection_managers: Vec<Rc<dyn ConnectionManager>>,
+    tokens: Tokens,
+    // The memory taken by the buffers of all connections, see ConnectionState.
+    memory_used: usize,
+    token_to_connection: HashMap<Token, ConnectionKey>,
+    sockets: SocketSet<'a>,
+    device: VirtualTunDevice,
+    options: Options,
+    write_sockets: HashSet<Token>,
+    // Kept around to go through write_sockets, which is updated while writing to the clients.
+    write_tokens: Vec<Token>,
+    // The connections which are not read from until the client has taken what the proxy sent.
+    read_sockets: HashSet<Token>,
+    idle_streams: HashMap<SocketAddr, Vec<IdleStream>>,
+    next_warm_fill: std::time::Instant,
+    next_expiry_check: Option<std::time::Instant>,
+    wireguard: Option<(WireGuardTunnel, UdpSocket)>,
+    dns_forwarder: Option<Box<dyn DnsForwarder>>,
+    dns_rules: Vec<(DnsRule, Option<Box<dyn DnsForwarder>>)>,
+    dns_streams: HashMap<Connection, DnsStream>,
+    local_dns_sessions: HashMap<Token, LocalDnsSession>,
+    resolver: HostResolver,
+    // The frames of new connections whose destination is being resolved, received again once it
+    // has been.
+    unresolved: HashMap<ConnectionKey, Vec<Vec<u8>>>,
+    _exit_receiver: mio::unix::pipe::Receiver,
+    exit_sender: mio::unix::pipe::Sender,
+}
+
+impl<'a> TunToProxy<'a> {
+    pub fn new(interface: &NetworkInterface, mut options: Options) -> Result<Self, Error> {
+        protect::set_protector(options.protector.clone());
+        protect::set_source_addrs(options.bind_addrs.clone());
+        if let Some(mtu) = options.mtu.filter(|mtu| !(MIN_MTU..=MAX_MTU).contains(mtu)) {
+            return Err(format!("An MTU of {mtu} is not within {MIN_MTU} and {MAX_MTU}").into());
+        }
+        let tun = open_tun(interface, options.mtu)?;
+        #[cfg(target_os = "linux")]
+        let tun = with_offload(tun);
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let tun = with_uring(tun)?;
+        if let Some(handler) = options.on_tun_opened.take() {
+            handler()?;
+        }
+        let poll = Poll::new()?;
+
+        let (exit_sender, mut exit_receiver) = mio::unix::pipe::new()?;
+        poll.registry()
+            .register(&mut exit_receiver, EXIT_TOKEN, Interest::READABLE)?;
+
+        // The DNS forwarders, the packet source and the resolver share the waker, as there may only
+        // be one per poll.
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        match &tun {
+            Tun::Device(device) => poll.registry().register(
+                &mut SourceFd(&device.as_raw_fd()),
+                TUN_TOKEN,
+                Interest::READABLE,
+            )?,
+            Tun::Packets(source, _) => {
+                source.attach(waker.clone())?;
+                source.start_worker();
+            }
+            #[cfg(target_os = "linux")]
+            Tun::Offload(tun) => poll.registry().register(
+                &mut SourceFd(&tun.as_raw_fd()),
+                TUN_TOKEN,
+                Interest::READABLE,
+            )?,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Tun::Uring(tun) => poll.registry().register(
+                &mut SourceFd(&tun.as_raw_fd()),
+                TUN_TOKEN,
+                Interest::READABLE,
+            )?,
+        }
+
+        // Without an upstream resolver, the domains excluded from the virtual DNS are resolved by a
+        // name server of the system.
+        let exclusions = options
+            .virtdns
+            .as_ref()
+            .is_some_and(|dns| dns.has_exclusions());
+        let dns_forwarder = match &options.dns_upstream {
+            None if exclusions => {
+                let mut nameservers = options.nameservers.iter().copied();
+                let nameserver = match options.dns_exclusion_server {
+                    Some(nameserver) => nameserver,
+                    None => nameservers
+                        .find(|addr| !tcp_dns::is_virtual(*addr))
+                        .ok_or("There is no name server for the domains excluded from DNS")?,
+                };
+                let upstream = Upstream::Tcp(SocketAddr::new(nameserver, 53));
+                let forwarder: Box<dyn DnsForwarder> =
+                    Box::new(TcpDnsClient::new(upstream, waker.clone())?);
+                Some(forwarder)
+            }
+            None => None,
+            Some(upstream) => {
+                let waker = waker.clone();
+                let forwarder: Box<dyn DnsForwarder> = match upstream {
+                    DnsUpstream::Https(server) => {
+                        Box::new(DohClient::new(server, &options.nameservers, waker)?)
+                    }
+                    DnsUpstream::Tls(server) => {
+                        Box::new(TcpDnsClient::new(server.upstream()?, waker)?)
+                    }
+                    DnsUpstream::Tcp => {
+                        let upstream = Upstream::Original(options.nameservers.clone());
+                        Box::new(TcpDnsClient::new(upstream, waker)?)
+                    }
+                    DnsUpstream::Backend(backend) => {
+                        Box::new(BackendForwarder::new(backend.clone(), waker))
+                    }
+                };
+                Some(forwarder)
+            }
+        };
+
+        if let (Some(virtual_dns), Some(stats)) = (&mut options.virtdns, &options.dns_stats) {
+            virtual_dns.set_stats(stats.clone());
+        }
+
+        // Split DNS sends the queries matched by a rule to a resolver of its own.
+        let mut dns_rules = Vec::new();
+        for rule in &options.dns_rules {
+            let waker = waker.clone();
+            let forwarder: Option<Box<dyn DnsForwarder>> = match rule.target() {
+                DnsTarget::Virtual => None,
+                DnsTarget::Tcp(server) => {
+                    Some(Box::new(TcpDnsClient::new(Upstream::Tcp(*server), waker)?))
+                }
+                DnsTarget::Https(server) => {
+                    let client = DohClient::new(server, &options.nameservers, waker)?;
+                    Some(Box::new(client))
+                }
+                DnsTarget::Tls(server) => {
+                    Some(Box::new(TcpDnsClient::new(server.upstream()?, waker)?))
+                }
+            };
+            dns_rules.push((rule.clone(), forwarder));
+        }
+
+        let config = match tun.capabilities().medium {
+            Medium::Ethernet => Config::new(
+                smoltcp::wire::EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]).into(),
+            ),
+            Medium::Ip => Config::new(smoltcp::wire::HardwareAddress::Ip),
+            Medium::Ieee802154 => todo!(),
+        };
+        let mut virt = VirtualTunDevice::new(tun.capabilities());
+        // The addresses of the stack itself, unless they collide with those used elsewhere.
+        let mut gateway4: Ipv4Addr = Ipv4Addr::from_str("0.0.0.1")?;
+        let mut gateway6: Ipv6Addr = Ipv6Addr::from_str("::1")?;
+        for gateway in &options.gateways {
+            match *gateway {
+                IpAddr::V4(addr) => gateway4 = addr,
+                IpAddr::V6(addr) => gateway6 = addr,
+            }
+        }
+        let mut iface = Interface::new(config, &mut virt, Instant::now());
+        iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.push(IpCidr::new(gateway4.into(), 0)).unwrap();
+            ip_addrs.push(IpCidr::new(gateway6.into(), 0)).unwrap()
+        });
+        iface.routes_mut().add_default_ipv4_route(gateway4.into())?;
+        iface.routes_mut().add_default_ipv6_route(gateway6.into())?;
+        iface.set_any_ip(true);
+
+        let nameservers = options.nameservers.clone();
+        let tun = Self {
+            tun,
+            frames: Frames::default(),
+            poll,
+            iface,
+            connections: HashMap::default(),
+            tokens: Tokens::new(),
+            memory_used: 0,
+            token_to_connection: HashMap::default(),
+            connection_managers: Vec::default(),
+            sockets: SocketSet::new([]),
+            device: virt,
+            options,
+            write_sockets: HashSet::default(),
+            write_tokens: Vec::new(),
+            read_sockets: HashSet::default(),
+            idle_streams: HashMap::default(),
+            next_warm_fill: std::time::Instant::now(),
+            next_expiry_check: None,
+            wireguard: None,
+            dns_forwarder,
+            dns_rules,
+            dns_streams: HashMap::default(),
+            local_dns_sessions: HashMap::default(),
+            resolver: HostResolver::new(nameservers, waker),
+            unresolved: HashMap::default(),
+            _exit_receiver: exit_receiver,
+            exit_sender,
+        };
+        Ok(tun)
+    }
+
+    // Whether buffers of `size` bytes fit into what is left of the memory budget.
+    fn fits_memory_budget(&self, size: usize) -> bool {
+        let budget = self.options.memory_budget.unwrap_or(usize::MAX);
+        self.memory_used.saturating_add(size) <= budget
+    }
+
+    // A socket facing a client over TCP, with the buffers configured, which are halved as long as
+    // they exceed what is left of the memory budget. There is none once not even the smallest
+    // buffers fit, so that the client is refused.
+    fn new_tcp_socket(&self, connection: &Connection) -> Option<tcp::Socket<'static>> {
+        let (mut rx_size, mut tx_size) = self
+            .options
+            .tcp_buffers
+            .unwrap_or((TCP_BUFFER_SIZE, TCP_BUFFER_SIZE));
+        while !self.fits_memory_budget(rx_size + tx_size) {
+            if rx_size <= MIN_TCP_BUFFER_SIZE && tx_size <= MIN_TCP_BUFFER_SIZE {
+                log::warn!("Refusing {connection}, as the memory budget is used up");
+                return None;
+            }
+            rx_size = (rx_size / 2).max(MIN_TCP_BUFFER_SIZE);
+            tx_size = (tx_size / 2).max(MIN_TCP_BUFFER_SIZE);
+        }
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; rx_size]),
+            tcp::SocketBuffer::new(vec![0; tx_size]),
+        );
+        socket.set_ack_delay(None);
+        Some(socket)
+    }
+
+    fn new_token(&mut self) -> Token {
+        self.tokens.take()
+    }
+
+    pub(crate) fn add_connection_manager(&mut self, manager: Rc<dyn ConnectionManager>) {
+        self.connection_managers.push(manager);
+    }
+
+    /// Forward all packets through a WireGuard tunnel to the given peer instead of proxying
+    /// connections.
+    pub(crate) fn set_wireguard(
+        &mut self,
+        tunnel: WireGuardTunnel,
+        server: SocketAddr,
+    ) -> Result<(), Error> {
+        let mut socket = protect::bind_udp(server)?;
+        socket.connect(server)?;
+        // Packets which don't fit the path to the peer once encapsulated cannot be sent, so the
+        // TCP segments are clamped to fit unless an MSS is given, leaving room for IPv6 headers.
+        let overhead = WIREGUARD_OVERHEAD + if server.is_ipv4() { 20 } else { 40 };
+        let mtu = self.tun.capabilities().max_transmission_unit;
+        if let Some(path_mtu) = path_mtu(&socket).filter(|path| mtu + overhead > *path) {
+            let fitting = path_mtu.saturating_sub(overhead);
+            log::warn!(
+                "Lower the MTU of {mtu} to {fitting} for packets to fit the path to {server}"
+            );
+            if self.options.mss.is_none() {
+                self.options.mss = Some(fitting.saturating_sub(60).max(536) as u16);
+            }
+        }
+        self.poll
+            .registry()
+            .register(&mut socket, UDP_TOKEN, Interest::READABLE)?;
+        self.wireguard = Some((tunnel, socket));
+        Ok(())
+    }
+
+    fn expect_smoltcp_send(&mut self) -> Result<(), Error> {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+        while let Some(mut packet) = self.device.exfiltrate_packet() {
+            if let Some(mss) = self.options.mss {
+                clamp_mss(&mut packet, mss);
+            }
+            if let Some(buffer) = self.tun.send(packet) {
+                self.device.pool().give_back(buffer);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_connection(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        if let Some(mut conn) = self.connections.remove(&key) {
+            self.memory_used -= conn.buffer_size
+                + conn.queued_to_server
+                + conn.queued_to_client
+                + conn.queued_datagrams;
+            let token = &conn.token;
+            self.token_to_connection.remove(token);
+            self.write_sockets.remove(token);
+            self.read_sockets.remove(token);
+            self.tokens.release(*token);
+            self.sockets.remove(conn.smoltcp_handle);
+            _ = self.poll.registry().deregister(&mut conn.mio_stream);
+            if let Some(mut udp_socket) = conn.udp_socket {
+                _ = self.poll.registry().deregister(&mut udp_socket);
+            }
+            if let Some(udp_token) = &conn.udp_token {
+                self.token_to_connection.remove(udp_token);
+                self.tokens.release(*udp_token);
+            }
+            if !conn.reported {
+                conn.manager.report_health(conn.server, false);
+            }
+            conn.manager.close_connection(&conn.connection);
+            info!("CLOSE {}", conn.connection);
+
+            // The connection to the proxy is shut down for writing once the client is done.
+            let reusable = conn.handler.is_reusable()
+                && (conn.close_state & CLIENT_WRITE_CLOSED) != CLIENT_WRITE_CLOSED;
+            if let (true, ProxyStream::Tcp(stream)) = (reusable, conn.mio_stream) {
+                let streams = self.idle_streams.entry(conn.server).or_default();
+                if streams.len() < MAX_IDLE_STREAMS {
+                    let expiry =
+                        std::time::Instant::now() + Duration::from_secs(IDLE_STREAM_TIMEOUT);
+                    streams.push(IdleStream::new(stream, expiry, Vec::new()));
+                }
+            }
+
+            // The command requesting the connection must not be held back forever.
+            if let Some(bind) = conn.bind.filter(|bind| !bind.announced) {
+                self.release_ftp_command(bind.control)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reset the connections of the clients, which would otherwise be left waiting on a tunnel that
+    // is gone, and close those to the proxies.
+    fn close_connections(&mut self) -> Result<(), Error> {
+        let keys: Vec<ConnectionKey> = self.connections.keys().copied().collect();
+        for key in &keys {
+            if let Some(state) = self.connections.get_mut(key) {
+                // Connections cut short say nothing about the health of the proxy.
+                state.reported = true;
+                if key.proto == IpProtocol::Tcp {
+                    self.sockets
+                        .get_mut::<tcp::Socket>(state.smoltcp_handle)
+                        .abort();
+                }
+            }
+        }
+        self.expect_smoltcp_send()?;
+        for key in keys {
+            self.remove_connection(key)?;
+        }
+        Ok(())
+    }
+
+    fn get_connection_manager(&self, connection: &Connection) -> Option<Rc<dyn ConnectionManager>> {
+        for manager in self.connection_managers.iter() {
+            if manager.handles_connection(connection) {
+                return Some(manager.clone());
+            }
+        }
+        None
+    }
+
+    fn check_change_close_state(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let state = self.connections.get_mut(&key);
+        if state.is_none() {
+            return Ok(());
+        }
+        let state = state.unwrap();
+
+        if key.proto == IpProtocol::Udp {
+            // A UDP association lives as long as its control connection does.
+            if (state.close_state & SERVER_WRITE_CLOSED) == SERVER_WRITE_CLOSED {
+                self.remove_connection(key)?;
+            }
+            return Ok(());
+        }
+
+        let socket_state = self
+            .sockets
+            .get::<tcp::Socket>(state.smoltcp_handle)
+            .state();
+        let mut closed_ends = 0;
+        // A socket connecting to the client cannot be closed without losing the connection.
+        if (state.close_state & SERVER_WRITE_CLOSED) == SERVER_WRITE_CLOSED
+            && socket_state != State::SynSent
+            && !state
+                .handler
                 .have_data(Direction::Incoming(IncomingDirection::FromServer))
             && !state
                 .handler
@@ -399,237 +1634,1433 @@ impl<'a> TunToProxy<'a> {
         }
 
         if closed_ends == 2 {
-            self.remove_connection(connection)?;
+            self.remove_connection(key)?;
         }
         Ok(())
     }
 
-    fn tunsocket_read_and_forward(&mut self, connection: &Connection) -> Result<(), Error> {
+    fn tunsocket_read_and_forward(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let mut requested = None;
         // Scope for mutable borrow of self.
         {
-            let state = self.connections.get_mut(connection);
+            let state = self.connections.get_mut(&key);
             if state.is_none() {
                 return Ok(());
             }
             let state = state.unwrap();
             let socket = self.sockets.get_mut::<tcp::Socket>(state.smoltcp_handle);
+            if socket.can_recv() {
+                state.last_activity = std::time::Instant::now();
+            }
             let mut error = Ok(());
             while socket.can_recv() && error.is_ok() {
                 socket.recv(|data| {
+                    let forward;
+                    let buffer = match &mut state.ftp {
+                        None => data,
+                        Some(ftp) => {
+                            let (data, request) = ftp.client_data(data);
+                            forward = data;
+                            requested = requested.or(request);
+                            forward.as_slice()
+                        }
+                    };
                     let event = IncomingDataEvent {
                         direction: IncomingDirection::FromClient,
-                        buffer: data,
+                        buffer,
                     };
                     error = state.handler.push_data(event);
+                    if let Some(client_data) = &mut state.client_data {
+                        client_data.extend(buffer);
+                    }
                     (data.len(), ())
                 })?;
             }
+            if state.handler.connection_established()
+                || state
+                    .client_data
+                    .as_ref()
+                    .is_some_and(|data| data.len() > MAX_RETRY_DATA)
+            {
+                state.client_data = None;
+            }
 
             if !socket.may_recv()
                 && socket.state() != State::Listen
                 && socket.state() != State::SynSent
                 && socket.state() != State::SynReceived
             {
-                // We cannot yet close the write end of the mio stream here because we may still
-                // need to send data.
-                state.close_state |= CLIENT_WRITE_CLOSED;
+                // We cannot yet close the write end of the mio stream here because we may still
+                // need to send data.
+                state.close_state |= CLIENT_WRITE_CLOSED;
+            }
+
+            // Expect ACKs etc. from smoltcp sockets.
+            self.expect_smoltcp_send()?;
+        }
+
+        if let Some(client) = requested {
+            self.bind_ftp_data_connection(key, client)?;
+        }
+
+        self.check_change_close_state(key)?;
+
+        Ok(())
+    }
+
+    // The FTP client has requested a data connection to `client` in active mode, which has to
+    // be accepted by the proxy since the server cannot reach the client.
+    fn bind_ftp_data_connection(
+        &mut self,
+        control: ConnectionKey,
+        client: SocketAddr,
+    ) -> Result<(), Error> {
+        let state = self
+            .connections
+            .get(&control)
+            .ok_or("connection not found")?;
+        let local = self
+            .sockets
+            .get::<tcp::Socket>(state.smoltcp_handle)
+            .local_endpoint()
+            .ok_or("connection not found")?;
+        let local = SocketAddr::new(local.addr.into(), ftp::DATA_PORT);
+        let key = ConnectionKey {
+            src: client,
+            dst: local,
+            proto: IpProtocol::Tcp,
+        };
+        let connection = Connection {
+            src: client,
+            dst: Destination {
+                host: state.connection.dst.host.clone(),
+                port: ftp::DATA_PORT,
+            },
+            proto: IpProtocol::Tcp,
+        };
+        let manager = state.manager.clone();
+        let handler = match manager.new_bind(&connection, &state.connection, manager.clone())? {
+            None => {
+                log::debug!(
+                    "The proxy cannot accept FTP data connections for {}",
+                    state.connection
+                );
+                return self.release_ftp_command(control);
+            }
+            Some(handler) => handler,
+        };
+        // A client listening on the same address again has given up on the former connection.
+        self.remove_connection(key)?;
+
+        let socket = match self.new_tcp_socket(&connection) {
+            Some(socket) => socket,
+            None => return Ok(()),
+        };
+        let buffer_size = socket.recv_capacity() + socket.send_capacity();
+        self.add_connection(key, connection, socket, buffer_size, handler, manager)?;
+        let state = self
+            .connections
+            .get_mut(&key)
+            .ok_or("connection not found")?;
+        state.bind = Some(Bind {
+            control,
+            local,
+            announced: false,
+        });
+        self.write_to_server(key)
+    }
+
+    // Forward the data the FTP client has sent on `control` after its pending request for a
+    // data connection, announcing `bound` in the request if the proxy has bound an address.
+    fn forward_ftp_commands(
+        &mut self,
+        control: ConnectionKey,
+        bound: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        let state = match self.connections.get_mut(&control) {
+            None => return Ok(()),
+            Some(state) => state,
+        };
+        let (data, requested) = match (&mut state.ftp, bound) {
+            (Some(ftp), Some(bound)) => ftp.bound(bound),
+            (Some(ftp), None) => ftp.release(),
+            (None, _) => return Ok(()),
+        };
+        let event = IncomingDataEvent {
+            direction: IncomingDirection::FromClient,
+            buffer: &data,
+        };
+        state.handler.push_data(event)?;
+        if let Some(client) = requested {
+            self.bind_ftp_data_connection(control, client)?;
+        }
+        self.write_to_server(control)
+    }
+
+    fn release_ftp_command(&mut self, control: ConnectionKey) -> Result<(), Error> {
+        self.forward_ftp_commands(control, None)
+    }
+
+    // Once the proxy has bound an address for a connection to be accepted, it is announced
+    // through the control connection. Once the peer has connected, the smoltcp socket is
+    // connected to the client.
+    fn accept_bind(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let state = match self.connections.get_mut(&key) {
+            Some(state) if state.bind.is_some() => state,
+            _ => return Ok(()),
+        };
+        if let Some(bind) = state.bind.as_mut().filter(|bind| !bind.announced) {
+            if let Some(bound) = state.handler.get_bind_address() {
+                bind.announced = true;
+                info!("BIND {} via {}", state.connection, bound);
+                let control = bind.control;
+                self.forward_ftp_commands(control, Some(bound))?;
+            }
+        }
+
+        let state = match self.connections.get_mut(&key) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        if state.handler.connection_established() {
+            let bind = state.bind.take().ok_or("connection not found")?;
+            let socket = self.sockets.get_mut::<tcp::Socket>(state.smoltcp_handle);
+            socket.connect(self.iface.context(), key.src, bind.local)?;
+            // Data of the peer is written once the socket is connected.
+            self.write_sockets.insert(state.token);
+            self.expect_smoltcp_send()?;
+        } else if state.close_state & SERVER_WRITE_CLOSED != 0 {
+            // The client end will never be closed as the socket has not been connected.
+            self.remove_connection(key)?;
+        }
+        Ok(())
+    }
+
+    // Update the poll registry depending on the connection's event interests.
+    fn update_mio_socket_interest(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let state = self
+            .connections
+            .get_mut(&key)
+            .ok_or("connection not found")?;
+
+        // Maybe we did not listen for any events before. Therefore, just swallow the error.
+        _ = self.poll.registry().deregister(&mut state.mio_stream);
+
+        // The failed connection to the proxy is not used while waiting for a retry.
+        if state.retry_at.is_some() {
+            return Ok(());
+        }
+
+        // If we do not wait for read or write events, we do not need to register them.
+        if !state.wait_read && !state.wait_write {
+            return Ok(());
+        }
+
+        // This ugliness is due to the way Interest is implemented (as a NonZeroU8 wrapper).
+        let interest;
+        if state.wait_read && !state.wait_write {
+            interest = Interest::READABLE;
+        } else if state.wait_write && !state.wait_read {
+            interest = Interest::WRITABLE;
+        } else {
+            interest = Interest::READABLE | Interest::WRITABLE;
+        }
+
+        self.poll
+            .registry()
+            .register(&mut state.mio_stream, state.token, interest)?;
+        Ok(())
+    }
+
+    // A raw packet was received on the tunnel interface.
+    fn receive_tun(&mut self, frame: &mut [u8]) -> Result<(), Error> {
+        // The MSS of the clients limits the segments which the sockets facing them send.
+        if let Some(mss) = self.options.mss {
+            clamp_mss(frame, mss);
+        }
+        if let Some((key, first_packet, payload_offset, payload_size)) = connection_tuple(frame) {
+            // Traffic to the bypassed ranges is routed around the tunnel, unless set up otherwise.
+            let dst = key.dst.ip();
+            if self
+                .options
+                .bypass
+                .iter()
+                .any(|cidr| cidr.contains_addr(&dst.into()))
+            {
+                log::trace!("Ignoring {key}, which bypasses the tunnel");
+                return Ok(());
+            }
+            if let Some(virt_dns) = &mut self.options.virtdns {
+                virt_dns.touch_ip(&dst);
+            }
+            (|| -> Result<(), Error> {
+                let dns_server = key.dst;
+                let intercepted = self.is_dns_intercepted(key.proto, dns_server);
+                if key.proto == IpProtocol::Tcp && intercepted {
+                    self.receive_dns_stream(&key.into(), first_packet, frame)?;
+                } else if key.proto == IpProtocol::Tcp {
+                    // The destination is only named once, as the connection is set up.
+                    if first_packet {
+                        let connection = self.named_connection(key);
+                        if self.get_connection_manager(&connection).is_none() {
+                            log::trace!("no connect manager");
+                            return Ok(());
+                        }
+                        let connection = match self.resolve_destination(key, connection, frame)? {
+                            None => return Ok(()),
+                            Some(connection) => connection,
+                        };
+                        // Without a socket, smoltcp resets the connection.
+                        if let Some(mut socket) = self.new_tcp_socket(&connection) {
+                            for manager in self.connection_managers.iter_mut() {
+                                if let Some(mut handler) =
+                                    manager.new_connection(&connection, manager.clone())?
+                                {
+                                    if let Some(version) = self.options.proxy_protocol {
+                                        handler = Box::new(ProxyProtocolConnection::new(
+                                            handler,
+                                            &connection,
+                                            version,
+                                        ));
+                                    }
+                                    let manager = manager.clone();
+                                    socket.listen(key.dst)?;
+                                    let buffer_size =
+                                        socket.recv_capacity() + socket.send_capacity();
+                                    self.add_connection(
+                                        key,
+                                        connection,
+                                        socket,
+                                        buffer_size,
+                                        handler,
+                                        manager,
+                                    )?;
+                                    break;
+                                }
+                            }
+                        }
+                    } else if !self.connections.contains_key(&key) {
+                        return Ok(());
+                    }
+
+                    // Inject the packet to advance the smoltcp socket state
+                    self.device.inject_packet(frame);
+
+                    // Having advanced the socket state, we expect the socket to ACK
+                    // Exfiltrate the response packets generated by the socket and inject them
+                    // into the tunnel interface.
+                    self.expect_smoltcp_send()?;
+
+                    // Read from the smoltcp socket and push the data to the connection handler.
+                    self.tunsocket_read_and_forward(key)?;
+
+                    // The connection handler builds up the connection or encapsulates the data.
+                    // Therefore, we now expect it to write data to the server.
+                    self.write_to_server(key)?;
+                } else if key.proto == IpProtocol::Udp
+                    && self.options.local_dns.is_some()
+                    && is_local_dns(dns_server)
+                {
+                    let payload = &frame[payload_offset..payload_offset + payload_size];
+                    self.receive_local_dns(key.src, dns_server, payload)?;
+                } else if key.proto == IpProtocol::Udp && intercepted {
+                    let payload = &frame[payload_offset..payload_offset + payload_size];
+                    if let Some(response) = self.answer_dns_query(&key.into(), payload, false)? {
+                        self.send_dns_response(dns_server, key.src, &response)?;
+                    }
+                } else if key.proto == IpProtocol::Udp {
+                    let payload = payload_offset..payload_offset + payload_size;
+                    self.receive_udp_from_client(key, frame, payload)?;
+                }
+                Ok(())
+            })()
+            .or_else(|error| {
+                if self.schedule_retry(key, &error)? {
+                    return Ok(());
+                }
+                log::error! {"{error}"}
+                Ok::<(), Error>(())
+            })?;
+        }
+        Ok(())
+    }
+
+    // The connection to the destination of `key` as handed to the handler, named after the
+    // domain which the virtual DNS has given its address to.
+    fn named_connection(&mut self, key: ConnectionKey) -> Connection {
+        let connection = Connection::from(key);
+        let virt_dns = match &mut self.options.virtdns {
+            None => return connection,
+            Some(virt_dns) => virt_dns,
+        };
+        match virt_dns.resolve_ip(&key.dst.ip()) {
+            None => connection,
+            Some(name) => connection.to_named(name.clone()),
+        }
+    }
+
+    // The connection with its hostname replaced by an address if the manager has it resolved
+    // locally, or `None` while the hostname is being resolved. The frame is then kept to be
+    // received again once it has been, with only the latest attempt to open a TCP connection kept.
+    fn resolve_destination(
+        &mut self,
+        key: ConnectionKey,
+        mut connection: Connection,
+        frame: &[u8],
+    ) -> Result<Option<Connection>, Error> {
+        let host = match &connection.dst.host {
+            DestinationHost::Hostname(host) => host,
+            DestinationHost::Address(_) => return Ok(Some(connection)),
+        };
+        match self.get_connection_manager(&connection) {
+            Some(manager) if manager.resolves_locally(&connection) => {}
+            _ => return Ok(Some(connection)),
+        }
+        match self.resolver.lookup(host) {
+            Some(Ok(addrs)) => {
+                log::debug!("Resolved {host} to {} for {connection}", addrs[0]);
+                connection.dst.host = DestinationHost::Address(addrs[0]);
+                Ok(Some(connection))
+            }
+            Some(Err(e)) => {
+                Err(format!("Cannot resolve the destination of {connection}: {e}").into())
+            }
+            None => {
+                let frames = self.unresolved.entry(key).or_default();
+                if key.proto == IpProtocol::Tcp {
+                    frames.clear();
+                }
+                if frames.len() < MAX_UDP_DATA_CACHE {
+                    frames.push(frame.to_vec());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // Receive the frames of the connections whose destination was being resolved once more, as a
+    // lookup has finished. Those still waiting for theirs are kept again. The connections whose
+    // hostname the proxy has refused are retried once theirs have been resolved.
+    fn receive_resolved(&mut self) -> Result<(), Error> {
+        for (_, frames) in std::mem::take(&mut self.unresolved) {
+            for mut frame in frames {
+                self.receive_tun(&mut frame)?;
+            }
+        }
+        let refused: Vec<ConnectionKey> = self
+            .connections
+            .iter()
+            .filter(|(_, state)| state.retry_at.is_none() && is_resolving(state))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in refused {
+            self.reconnect(key)?;
+        }
+        Ok(())
+    }
+
+    // Whether DNS messages to `server` are answered here instead of being proxied, provided that
+    // its port is one of the DNS ports. Those to the range of the virtual DNS are answered, or to
+    // any resolver when hijacking, whereas queries over UDP are all passed on to an upstream
+    // resolver. The forwarders may reach the name servers of excluded domains and of rules
+    // through the tunnel, so their connections are never hijacked.
+    fn is_dns_intercepted(&self, proto: IpProtocol, server: SocketAddr) -> bool {
+        let ip = server.ip();
+        let forwarded = (self.dns_forwarder.is_some()
+            && (self.options.nameservers.contains(&ip)
+                || self.options.dns_exclusion_server == Some(ip)))
+            || self.dns_rules.iter().any(
+                |(rule, _)| matches!(rule.target(), DnsTarget::Tcp(server) if server.ip() == ip),
+            );
+        let hijacked = self.options.dns_hijack && !(proto == IpProtocol::Tcp && forwarded);
+        let answering = self.options.virtdns.is_some()
+            || self.dns_forwarder.is_some()
+            || !self.dns_rules.is_empty();
+        let upstream = self.options.dns_upstream.is_some();
+        let ports = self.options.dns_ports.as_deref().unwrap_or(&[DNS_PORT]);
+        ports.contains(&server.port())
+            && ((answering && (tcp_dns::is_virtual(ip) || hijacked))
+                || (proto == IpProtocol::Udp && upstream))
+    }
+
+    // Handle an mDNS or LLMNR query of `client` to the multicast group `group` as configured.
+    fn receive_local_dns(
+        &mut self,
+        client: SocketAddr,
+        group: SocketAddr,
+        query: &[u8],
+    ) -> Result<(), Error> {
+        match &self.options.local_dns {
+            Some(LocalDnsPolicy::Pass(interface)) => {
+                let session = self
+                    .local_dns_sessions
+                    .iter()
+                    .find(|(_, s)| s.client == client);
+                let token = match session {
+                    Some((token, _)) => *token,
+                    None => {
+                        let mut socket = bind_udp(group, interface)?;
+                        let token = self.new_token();
+                        self.poll
+                            .registry()
+                            .register(&mut socket, token, Interest::READABLE)?;
+                        let expiry = self.udp_expiry();
+                        let session = LocalDnsSession {
+                            socket,
+                            client,
+                            expiry,
+                        };
+                        self.local_dns_sessions.insert(token, session);
+                        token
+                    }
+                };
+                let expiry = self.udp_expiry();
+                let next_check = self.next_expiry_check.get_or_insert(expiry);
+                *next_check = expiry.min(*next_check);
+                let session = self.local_dns_sessions.get_mut(&token).unwrap();
+                session.expiry = expiry;
+                session.socket.send_to(query, group)?;
+            }
+            Some(LocalDnsPolicy::Answer) => {
+                let response = match &mut self.options.virtdns {
+                    Some(virtual_dns) => virtual_dns.receive_local_query(query),
+                    None => None,
+                };
+                if let Some(response) = response {
+                    let responder: IpAddr = match client {
+                        SocketAddr::V4(_) => LOCAL_DNS_RESPONDER_V4.into(),
+                        SocketAddr::V6(_) => LOCAL_DNS_RESPONDER_V6.into(),
+                    };
+                    let server = SocketAddr::new(responder, group.port());
+                    self.send_dns_response(server, client, &response)?;
+                }
+            }
+            Some(LocalDnsPolicy::Drop) | None => {
+                log::trace!("Dropping local DNS query of {}", client);
+            }
+        }
+        Ok(())
+    }
+
+    // Relay the responses to the mDNS and LLMNR queries passed on to the local network.
+    fn local_dns_event(&mut self, token: Token) -> Result<(), Error> {
+        let mut buffer = self.device.pool().take_sized(MAX_DNS_MESSAGE);
+        let result = self.relay_local_dns_responses(token, &mut buffer);
+        self.device.pool().give_back(buffer);
+        result
+    }
+
+    fn relay_local_dns_responses(&mut self, token: Token, buffer: &mut [u8]) -> Result<(), Error> {
+        while let Some(session) = self.local_dns_sessions.get(&token) {
+            let client = session.client;
+            match session.socket.recv_from(buffer) {
+                Ok((size, responder)) => {
+                    self.send_dns_response(responder, client, &buffer[..size])?
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    // Answer a DNS query of the client by the virtual DNS or pass it on to the forwarder, which
+    // answers it once the response of the upstream resolver arrives.
+    fn answer_dns_query(
+        &mut self,
+        connection: &Connection,
+        query: &[u8],
+        over_tcp: bool,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let upstream = self.options.dns_upstream.is_some();
+        let answer = |virtual_dns: &mut VirtualDns| match over_tcp {
+            true => virtual_dns.receive_tcp_query(query),
+            false => virtual_dns.receive_query(query),
+        };
+        // The first rule matching the name takes precedence over the DNS handling configured.
+        let parsed = DnsQuery::parse(connection.src, query);
+        let stats = self.options.dns_stats.as_deref();
+        if let (Some(stats), Some(parsed)) = (stats, &parsed) {
+            stats.count_query(parsed.record_type);
+        }
+        let name = parsed.map(|query| query.name);
+        let rules = &self.dns_rules;
+        let rule = name.and_then(|name| rules.iter().find(|(rule, _)| rule.matches(&name)));
+        let response = match (rule, &self.dns_forwarder, &mut self.options.virtdns) {
+            (Some((_, Some(forwarder))), _, _) => {
+                forwarder.send_query(connection, query)?;
+                if let Some(stats) = stats {
+                    stats.count_forwarded();
+                }
+                None
+            }
+            (Some((_, None)), _, Some(virtual_dns)) => answer(virtual_dns),
+            (Some(_), _, None) => None,
+            (None, Some(_), Some(virtual_dns)) if !upstream && !virtual_dns.is_excluded(query) => {
+                answer(virtual_dns)
+            }
+            (None, Some(forwarder), _) => {
+                forwarder.send_query(connection, query)?;
+                if let Some(stats) = stats {
+                    stats.count_forwarded();
+                }
+                None
+            }
+            (None, None, Some(virtual_dns)) => answer(virtual_dns),
+            (None, None, None) => None,
+        };
+        if let Some(handler) = &self.options.on_dns_query {
+            if let Some(mut query) = DnsQuery::parse(connection.src, query) {
+                query.address = self
+                    .options
+                    .virtdns
+                    .as_ref()
+                    .and_then(|dns| dns.lookup(&query.name, query.record_type));
+                handler(&query);
+            }
+        }
+        Ok(response)
+    }
+
+    // DNS clients retry over TCP when a response is truncated. Such connections to the virtual
+    // DNS are terminated here and the queries are answered like those over UDP, each message
+    // being preceded by its length.
+    fn receive_dns_stream(
+        &mut self,
+        connection: &Connection,
+        first_packet: bool,
+        frame: &mut [u8],
+    ) -> Result<(), Error> {
+        if first_packet {
+            let (rx_size, tx_size) = (MAX_DNS_MESSAGE + 2, 2 * (MAX_DNS_MESSAGE + 2));
+            if !self.fits_memory_budget(rx_size + tx_size) {
+                log::warn!("Refusing {connection}, as the memory budget is used up");
+                // Without a socket, smoltcp resets the connection.
+                self.device.inject_packet(frame);
+                return self.expect_smoltcp_send();
+            }
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; rx_size]),
+                tcp::SocketBuffer::new(vec![0; tx_size]),
+            );
+            socket.set_ack_delay(None);
+            socket.listen(SocketAddr::try_from(connection.dst.clone())?)?;
+            let stream = DnsStream {
+                smoltcp_handle: self.sockets.add(socket),
+                buffer_size: rx_size + tx_size,
+                data: Vec::new(),
+            };
+            self.memory_used += stream.buffer_size;
+            self.dns_streams.insert(connection.clone(), stream);
+            log::debug!("DNS over TCP {}", connection);
+        } else if !self.dns_streams.contains_key(connection) {
+            return Ok(());
+        }
+
+        self.device.inject_packet(frame);
+        self.expect_smoltcp_send()?;
+
+        let stream = self
+            .dns_streams
+            .get_mut(connection)
+            .ok_or("DNS stream not found")?;
+        let socket = self.sockets.get_mut::<tcp::Socket>(stream.smoltcp_handle);
+        while socket.can_recv() {
+            socket.recv(|data| {
+                stream.data.extend_from_slice(data);
+                (data.len(), ())
+            })?;
+        }
+        let mut queries = Vec::new();
+        while stream.data.len() >= 2 {
+            let length = u16::from_be_bytes([stream.data[0], stream.data[1]]) as usize;
+            if stream.data.len() < 2 + length {
+                break;
+            }
+            queries.push(stream.data.drain(..2 + length).skip(2).collect::<Vec<u8>>());
+        }
+        for query in queries {
+            if let Some(response) = self.answer_dns_query(connection, &query, true)? {
+                self.send_dns_stream_response(connection, &response)?;
+            }
+        }
+
+        // The connection is closed once the client is done sending queries.
+        let handle = self
+            .dns_streams
+            .get(connection)
+            .ok_or("DNS stream not found")?
+            .smoltcp_handle;
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if socket.state() == State::CloseWait {
+            socket.close();
+            self.expect_smoltcp_send()?;
+        }
+        let socket = self.sockets.get::<tcp::Socket>(handle);
+        if matches!(socket.state(), State::Closed | State::TimeWait) {
+            self.sockets.remove(handle);
+            if let Some(stream) = self.dns_streams.remove(connection) {
+                self.memory_used -= stream.buffer_size;
+            }
+        }
+        Ok(())
+    }
+
+    // Send a DNS response to a client connected over TCP, preceded by its length.
+    fn send_dns_stream_response(
+        &mut self,
+        connection: &Connection,
+        response: &[u8],
+    ) -> Result<(), Error> {
+        let stream = match self.dns_streams.get(connection) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let socket = self.sockets.get_mut::<tcp::Socket>(stream.smoltcp_handle);
+        let mut message = (response.len() as u16).to_be_bytes().to_vec();
+        message.extend(response);
+        if !socket.may_send() || socket.send_queue() + message.len() > socket.send_capacity() {
+            log::debug!("Dropping DNS response to {}", connection.src);
+            return Ok(());
+        }
+        socket.send_slice(&message)?;
+        self.expect_smoltcp_send()
+    }
+
+    // Send a DNS response from `server` to the client on the tunnel side.
+    fn send_dns_response(
+        &mut self,
+        server: SocketAddr,
+        client: SocketAddr,
+        response: &[u8],
+    ) -> Result<(), Error> {
+        let rx_buffer = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY], vec![0; 4096]);
+        // Responses forwarded from resolvers reached over TCP may be larger than usual.
+        let tx_buffer = udp::PacketBuffer::new(
+            vec![udp::PacketMetadata::EMPTY],
+            vec![0; response.len().max(4096)],
+        );
+        let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(server)?;
+        socket
+            .send_slice(response, IpEndpoint::from(client))
+            .expect("failed to send DNS response");
+        let handle = self.sockets.add(socket);
+        self.expect_smoltcp_send()?;
+        self.sockets.remove(handle);
+        Ok(())
+    }
+
+    fn dns_event(&mut self) -> Result<(), Error> {
+        let mut responses = Vec::new();
+        let rules = self
+            .dns_rules
+            .iter()
+            .filter_map(|(_, forwarder)| forwarder.as_ref());
+        for forwarder in self.dns_forwarder.iter().chain(rules) {
+            while let Some(response) = forwarder.receive_response() {
+                responses.push(response);
+            }
+        }
+        for (connection, response) in responses {
+            if let Some(stats) = &self.options.dns_stats {
+                stats.count_response(&response);
+            }
+            if connection.proto == IpProtocol::Tcp {
+                self.send_dns_stream_response(&connection, &response)?;
+                continue;
+            }
+            let server = SocketAddr::try_from(connection.dst)?;
+            self.send_dns_response(server, connection.src, &response)?;
+        }
+        Ok(())
+    }
+
+    fn add_connection<T: AnySocket<'a>>(
+        &mut self,
+        key: ConnectionKey,
+        connection: Connection,
+        socket: T,
+        buffer_size: usize,
+        mut handler: Box<dyn TcpProxy>,
+        manager: Rc<dyn ConnectionManager>,
+    ) -> Result<(), Error> {
+        let server = manager.get_server_for(&connection)?;
+        let client = match self.take_idle_stream(manager.as_ref(), server) {
+            Some((stream, greeted)) => {
+                if greeted {
+                    handler.skip_handshake()?;
+                }
+                ProxyStream::Tcp(stream)
+            }
+            None => ProxyStream::connect(manager.as_ref(), server, self.options.fast_open)
+                .inspect_err(|_| manager.report_health(server, false))?,
+        };
+        client.configure(&self.options)?;
+        let handle = self.sockets.add(socket);
+
+        // The connection to the proxy has to be established in time.
+        let expiry = match self.options.connect_timeout {
+            Some(timeout) if key.proto == IpProtocol::Tcp => {
+                let expiry = std::time::Instant::now() + Duration::from_secs(timeout);
+                let next_check = self.next_expiry_check.get_or_insert(expiry);
+                *next_check = expiry.min(*next_check);
+                Some(expiry)
+            }
+            _ => None,
+        };
+        // Connections are closed once idle for too long, which is checked when the timeout has
+        // passed, and again as long as they have been active since.
+        let now = std::time::Instant::now();
+        if let Some(expiry) = self.idle_timeout(key.proto).map(|timeout| now + timeout) {
+            let next_check = self.next_expiry_check.get_or_insert(expiry);
+            *next_check = expiry.min(*next_check);
+        }
+
+        let token = self.new_token();
+
+        let connection = Rc::new(connection);
+        let mut state = ConnectionState {
+            connection: connection.clone(),
+            smoltcp_handle: handle,
+            mio_stream: client,
+            token,
+            handler,
+            close_state: 0,
+            wait_read: true,
+            wait_write: false,
+            udp_socket: None,
+            udp_token: None,
+            udp_data_cache: VecDeque::default(),
+            queued_datagrams: 0,
+            expiry,
+            last_activity: now,
+            manager,
+            server,
+            reported: false,
+            ftp: (key.proto == IpProtocol::Tcp && key.dst.port() == ftp::CONTROL_PORT)
+                .then(ActiveMode::default),
+            bind: None,
+            attempts: 0,
+            // A connection to a hostname may be retried with its address if the proxy refuses it.
+            client_data: (key.proto == IpProtocol::Tcp
+                && (self.options.connect_retries > 0
+                    || matches!(connection.dst.host, DestinationHost::Hostname(_))))
+            .then(Vec::new),
+            retry_at: None,
+            refused_host: None,
+            buffer_size,
+            queued_to_server: 0,
+            queued_to_client: 0,
+        };
+
+        self.token_to_connection.insert(token, key);
+        self.poll
+            .registry()
+            .register(&mut state.mio_stream, token, Interest::READABLE)?;
+
+        self.connections.insert(key, state);
+        self.memory_used += buffer_size;
+
+        info!("CONNECT {}", connection,);
+        Ok(())
+    }
+
+    // Take an idle connection to `server` which is still open and done with the handshake made
+    // ahead of time, if there is one, along with whether the handler is to skip the handshake.
+    fn take_idle_stream(
+        &mut self,
+        manager: &dyn ConnectionManager,
+        server: SocketAddr,
+    ) -> Option<(TcpStream, bool)> {
+        if manager.get_unix_socket().is_some() || manager.get_bridge().is_some() {
+            return None;
+        }
+        let streams = self.idle_streams.get_mut(&server)?;
+        let now = std::time::Instant::now();
+        streams.retain_mut(|idle| idle.expiry > now && idle.advance());
+        let index = streams.iter().rposition(|idle| idle.handshake.is_empty())?;
+        let idle = streams.remove(index);
+        log::debug!("Reusing connection to {}", server);
+        Some((idle.stream, idle.greeted))
+    }
+
+    // Schedule another attempt to connect through the proxy after the current one has failed
+    // with `error`, unless the connection has been established, the proxy has refused the
+    // request or too many attempts have failed. The delay doubles with every attempt.
+    fn schedule_retry(&mut self, key: ConnectionKey, error: &Error) -> Result<bool, Error> {
+        let state = match self.connections.get_mut(&key) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        if is_resolving(state) {
+            return Ok(true);
+        }
+        // A hostname which the proxy has refused is resolved locally, and the connection retried
+        // at once with the address, which does not count as an attempt.
+        if let DestinationHost::Hostname(host) = &state.connection.dst.host {
+            if state.refused_host.is_none()
+                && state.client_data.is_some()
+                && state.handler.hostname_refused()
+            {
+                log::info!(
+                    "Connection {} through the proxy failed: {}. Retrying with the address of {}",
+                    state.connection,
+                    error,
+                    host
+                );
+                _ = self.poll.registry().deregister(&mut state.mio_stream);
+                _ = state.mio_stream.shutdown(Both);
+                state.refused_host = Some((host.clone(), state.server));
+                state.expiry = None;
+                let retry_at = std::time::Instant::now();
+                state.retry_at = Some(retry_at);
+                self.next_expiry_check = Some(retry_at);
+                return Ok(true);
+            }
+        }
+        if state.client_data.is_none()
+            || state.bind.is_some()
+            || state.retry_at.is_some()
+            || state.handler.connection_established()
+            || state.attempts >= self.options.connect_retries
+            || !is_transport_error(error)
+        {
+            return Ok(false);
+        }
+        _ = self.poll.registry().deregister(&mut state.mio_stream);
+        _ = state.mio_stream.shutdown(Both);
+        if !state.reported {
+            state.reported = true;
+            state.manager.report_health(state.server, false);
+        }
+
+        let delay = self.options.retry_delay.unwrap_or(RETRY_DELAY);
+        let delay = Duration::from_millis(delay.saturating_mul(1 << state.attempts.min(16)));
+        state.attempts += 1;
+        state.expiry = None;
+        let retry_at = std::time::Instant::now() + delay;
+        state.retry_at = Some(retry_at);
+        let next_check = self.next_expiry_check.get_or_insert(retry_at);
+        *next_check = retry_at.min(*next_check);
+        log::warn!(
+            "Connection {} through the proxy failed: {}. Retrying in {:?}",
+            state.connection,
+            error,
+            delay
+        );
+        Ok(true)
+    }
+
+    // Connect through the proxy once more, handing the data the client has sent so far to a new
+    // handler. A hostname which the proxy has refused is resolved first, and the connection kept
+    // waiting while it is.
+    fn reconnect(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let state = self
+            .connections
+            .get_mut(&key)
+            .ok_or("connection not found")?;
+        state.retry_at = None;
+        if is_resolving(state) {
+            let resolution = match &state.connection.dst.host {
+                DestinationHost::Hostname(host) => self.resolver.lookup(host),
+                DestinationHost::Address(_) => None,
+            };
+            match resolution {
+                Some(Ok(addrs)) => {
+                    // The connection handed to the manager before is done with.
+                    state.manager.close_connection(&state.connection);
+                    let mut connection = (*state.connection).clone();
+                    connection.dst.host = DestinationHost::Address(addrs[0]);
+                    state.connection = Rc::new(connection);
+                }
+                Some(Err(e)) => {
+                    let error = format!(
+                        "Cannot resolve the destination of {}: {e}",
+                        state.connection
+                    );
+                    return self.abort_connection(key, error.into());
+                }
+                None => return Ok(()),
+            }
+        }
+        let manager = state.manager.clone();
+        let connection = state.connection.clone();
+        let client_data = state.client_data.clone().unwrap_or_default();
+        manager.close_connection(&connection);
+
+        let attempt = (|| -> Result<(Box<dyn TcpProxy>, SocketAddr, ProxyStream), Error> {
+            let mut handler = manager
+                .new_connection(&connection, manager.clone())?
+                .ok_or("The proxy does not handle the connection anymore")?;
+            if let Some(version) = self.options.proxy_protocol {
+                handler = Box::new(ProxyProtocolConnection::new(handler, &connection, version));
+            }
+            if !client_data.is_empty() {
+                handler.push_data(IncomingDataEvent {
+                    direction: IncomingDirection::FromClient,
+                    buffer: &client_data,
+                })?;
+            }
+            let server = manager.get_server_for(&connection)?;
+            let stream = match self.take_idle_stream(manager.as_ref(), server) {
+                Some((stream, greeted)) => {
+                    if greeted {
+                        handler.skip_handshake()?;
+                    }
+                    ProxyStream::Tcp(stream)
+                }
+                None => ProxyStream::connect(manager.as_ref(), server, self.options.fast_open)
+                    .inspect_err(|_| manager.report_health(server, false))?,
+            };
+            stream.configure(&self.options)?;
+            Ok((handler, server, stream))
+        })();
+        let (handler, server, mio_stream) = match attempt {
+            Ok(attempt) => attempt,
+            Err(error) => return self.retry_or_abort(key, error),
+        };
+
+        let expiry = self.options.connect_timeout.map(|timeout| {
+            let expiry = std::time::Instant::now() + Duration::from_secs(timeout);
+            let next_check = self.next_expiry_check.get_or_insert(expiry);
+            *next_check = expiry.min(*next_check);
+            expiry
+        });
+        let state = self
+            .connections
+            .get_mut(&key)
+            .ok_or("connection not found")?;
+        state.handler = handler;
+        state.server = server;
+        state.mio_stream = mio_stream;
+        state.reported = false;
+        state.expiry = expiry;
+        state.close_state &= !SERVER_WRITE_CLOSED;
+        state.wait_read = true;
+        state.wait_write = false;
+        self.read_sockets.remove(&state.token);
+        log::info!("RETRY {} (attempt {})", connection, state.attempts + 1);
+        self.poll
+            .registry()
+            .register(&mut state.mio_stream, state.token, Interest::READABLE)?;
+        if let Err(error) = self.write_to_server(key) {
+            return self.retry_or_abort(key, error);
+        }
+        Ok(())
+    }
+
+    fn retry_or_abort(&mut self, key: ConnectionKey, error: Error) -> Result<(), Error> {
+        if self.schedule_retry(key, &error)? {
+            return Ok(());
+        }
+        self.abort_connection(key, error)
+    }
+
+    // Give up on a connection, resetting that of the client.
+    fn abort_connection(&mut self, key: ConnectionKey, error: Error) -> Result<(), Error> {
+        log::error!("{error}");
+        if let Some(state) = self.connections.get(&key) {
+            self.sockets
+                .get_mut::<tcp::Socket>(state.smoltcp_handle)
+                .abort();
+            self.expect_smoltcp_send()?;
+        }
+        self.remove_connection(key)
+    }
+
+    // The proxies whose reachability tells whether the tunnel is ready, i.e. those connected to
+    // over TCP.
+    fn probed_servers(&self) -> Vec<SocketAddr> {
+        self.connection_managers
+            .iter()
+            .filter(|manager| {
+                manager.get_unix_socket().is_none()
+                    && manager.get_bridge().is_none()
+                    && manager.get_interface().is_none()
+            })
+            .map(|manager| manager.get_server())
+            .filter(|server| !server.ip().is_unspecified())
+            .collect()
+    }
+
+    // Keep the configured number of connections to each proxy open ahead of time, renewing those
+    // which have been closed or have been idle for too long. They are set up like the others,
+    // and go through the handshake of the proxy as far as it does not depend on the destination.
+    fn fill_warm_pool(&mut self) {
+        let now = std::time::Instant::now();
+        let options = &self.options;
+        if options.warm_pool == 0 || self.next_warm_fill > now {
+            return;
+        }
+        for manager in self.connection_managers.iter() {
+            let server = manager.get_server();
+            if manager.get_unix_socket().is_some()
+                || manager.get_bridge().is_some()
+                || server.ip().is_unspecified()
+            {
+                continue;
+            }
+            // The header of the PROXY protocol comes first, and depends on the client.
+            let handshake = match options.proxy_protocol {
+                None => manager.warm_handshake(),
+                Some(_) => Vec::new(),
+            };
+            let streams = self.idle_streams.entry(server).or_default();
+            streams.retain_mut(|idle| idle.expiry > now && idle.advance());
+            while streams.len() < options.warm_pool {
+                let connected =
+                    ProxyStream::connect_tcp(manager.as_ref(), server, options.fast_open).and_then(
+                        |stream| {
+                            ProxyStream::configure_tcp(&stream, options)?;
+                            Ok(stream)
+                        },
+                    );
+                match connected {
+                    Ok(stream) => {
+                        let expiry = now + Duration::from_secs(WARM_STREAM_TIMEOUT);
+                        let mut idle = IdleStream::new(stream, expiry, handshake.clone());
+                        idle.advance();
+                        streams.push(idle);
+                    }
+                    Err(error) => {
+                        log::debug!("Cannot connect to {} ahead of time: {}", server, error);
+                        break;
+                    }
+                }
             }
+        }
+        self.next_warm_fill = now + Duration::from_secs(WARM_POOL_INTERVAL);
+        let next_check = self.next_expiry_check.get_or_insert(self.next_warm_fill);
+        *next_check = self.next_warm_fill.min(*next_check);
+    }
 
-            // Expect ACKs etc. from smoltcp sockets.
-            self.expect_smoltcp_send()?;
+    // A UDP datagram was received from the client. Datagrams are queued until the proxy has
+    // set up the UDP association.
+    fn receive_udp_from_client(
+        &mut self,
+        key: ConnectionKey,
+        frame: &[u8],
+        payload: Range<usize>,
+    ) -> Result<(), Error> {
+        let payload = &frame[payload];
+        if !self.connections.contains_key(&key) {
+            let connection = self.named_connection(key);
+            let manager = match self.get_connection_manager(&connection) {
+                None => {
+                    log::trace!("no connect manager");
+                    return Ok(());
+                }
+                Some(manager) => manager,
+            };
+            let connection = match self.resolve_destination(key, connection, frame)? {
+                None => return Ok(()),
+                Some(connection) => connection,
+            };
+            if !self.fits_memory_budget(UDP_BUFFER_SIZE) {
+                log::warn!("Dropping a datagram of {connection}, as the memory budget is used up");
+                return Ok(());
+            }
+            let handler = match manager.new_connection(&connection, manager.clone())? {
+                None => return Ok(()),
+                Some(handler) => handler,
+            };
+            let rx_buffer = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY], vec![0; 0]);
+            let tx_buffer = udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; 32],
+                vec![0; UDP_BUFFER_SIZE],
+            );
+            let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+            socket.bind(key.dst)?;
+            self.add_connection(key, connection, socket, UDP_BUFFER_SIZE, handler, manager)?;
         }
 
-        self.check_change_close_state(connection)?;
+        let state = self
+            .connections
+            .get_mut(&key)
+            .ok_or("connection not found")?;
+        state.last_activity = std::time::Instant::now();
+        let budget = self.options.memory_budget.unwrap_or(usize::MAX);
+        let datagram = encapsulate_udp_datagram(&state.connection.dst, payload);
+        if state.udp_data_cache.len() < MAX_UDP_DATA_CACHE
+            && self.memory_used.saturating_add(datagram.len()) <= budget
+        {
+            state.queued_datagrams += datagram.len();
+            self.memory_used += datagram.len();
+            state.udp_data_cache.push_back(datagram);
+        } else {
+            log::trace!("Dropping UDP datagram for {}", state.connection);
+        }
 
-        Ok(())
+        self.write_to_server(key)?;
+        self.send_udp_to_server(key)
     }
 
-    // Update the poll registry depending on the connection's event interests.
-    fn update_mio_socket_interest(&mut self, connection: &Connection) -> Result<(), Error> {
+    // Once the proxy has replied to the UDP ASSOCIATE request, open a socket towards the relay
+    // and flush the datagrams the client has sent in the meantime.
+    fn open_udp_association(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let relay = match self.connections.get(&key) {
+            Some(state) if state.udp_socket.is_none() => state.handler.get_udp_associate(),
+            _ => None,
+        };
+        let relay = match relay {
+            None => return Ok(()),
+            Some(relay) => relay,
+        };
+
+        let mut socket = protect::bind_udp(relay)?;
+        socket.connect(relay)?;
+
+        let token = self.new_token();
+        self.poll
+            .registry()
+            .register(&mut socket, token, Interest::READABLE)?;
+        self.token_to_connection.insert(token, key);
+
         let state = self
             .connections
-            .get_mut(connection)
+            .get_mut(&key)
             .ok_or("connection not found")?;
+        state.udp_socket = Some(socket);
+        state.udp_token = Some(token);
+        info!("UDP ASSOCIATE {} via {}", state.connection, relay);
 
-        // Maybe we did not listen for any events before. Therefore, just swallow the error.
-        _ = self.poll.registry().deregister(&mut state.mio_stream);
+        self.send_udp_to_server(key)
+    }
 
-        // If we do not wait for read or write events, we do not need to register them.
-        if !state.wait_read && !state.wait_write {
-            return Ok(());
+    fn send_udp_to_server(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        if let Some(state) = self.connections.get_mut(&key) {
+            if let Some(socket) = &state.udp_socket {
+                while let Some(datagram) = state.udp_data_cache.pop_front() {
+                    state.queued_datagrams -= datagram.len();
+                    self.memory_used -= datagram.len();
+                    match socket.send(datagram.as_slice()) {
+                        Ok(_) => {}
+                        Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                            // UDP is unreliable anyway, so we can drop the datagram.
+                            log::trace!("Dropping UDP datagram for {}", state.connection);
+                        }
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+            }
         }
+        Ok(())
+    }
 
-        // This ugliness is due to the way Interest is implemented (as a NonZeroU8 wrapper).
-        let interest;
-        if state.wait_read && !state.wait_write {
-            interest = Interest::READABLE;
-        } else if state.wait_write && !state.wait_read {
-            interest = Interest::WRITABLE;
-        } else {
-            interest = Interest::READABLE | Interest::WRITABLE;
-        }
+    fn receive_udp_from_server(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        let mut buffer = self.device.pool().take_sized(0x10000);
+        let result = self.receive_udp_datagrams(key, &mut buffer);
+        self.device.pool().give_back(buffer);
+        result
+    }
 
-        self.poll
-            .registry()
-            .register(&mut state.mio_stream, state.token, interest)?;
+    fn receive_udp_datagrams(
+        &mut self,
+        key: ConnectionKey,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        loop {
+            let state = self
+                .connections
+                .get_mut(&key)
+                .ok_or("connection not found")?;
+            let socket = state
+                .udp_socket
+                .as_ref()
+                .ok_or("UDP association not established")?;
+            let read = match socket.recv(buffer) {
+                Ok(read) => read,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            };
+            state.last_activity = std::time::Instant::now();
+            let payload = match decapsulate_udp_datagram(&buffer[..read]) {
+                Ok((_, payload)) => payload,
+                Err(error) => {
+                    log::debug!(
+                        "Discarding UDP datagram for {}: {}",
+                        state.connection,
+                        error
+                    );
+                    continue;
+                }
+            };
+
+            let socket = self.sockets.get_mut::<udp::Socket>(state.smoltcp_handle);
+            if socket
+                .send_slice(payload, IpEndpoint::from(key.src))
+                .is_err()
+            {
+                log::trace!("Dropping UDP datagram for {}", state.connection);
+            }
+            self.expect_smoltcp_send()?;
+        }
         Ok(())
     }
 
-    // A raw packet was received on the tunnel interface.
-    fn receive_tun(&mut self, frame: &mut [u8]) -> Result<(), Error> {
-        if let Some((connection, first_packet, _payload_offset, _payload_size)) =
-            connection_tuple(frame)
-        {
-            let resolved_conn = match &mut self.options.virtdns {
-                None => connection.clone(),
-                Some(virt_dns) => {
-                    let ip = SocketAddr::try_from(connection.dst.clone())?.ip();
-                    virt_dns.touch_ip(&ip);
-                    match virt_dns.resolve_ip(&ip) {
-                        None => connection.clone(),
-                        Some(name) => connection.to_named(name.clone()),
-                    }
-                }
+    fn udp_expiry(&self) -> std::time::Instant {
+        let timeout = self.options.udp_timeout.unwrap_or(UDP_TIMEOUT);
+        std::time::Instant::now() + Duration::from_secs(timeout)
+    }
+
+    // How long connections over `proto` may be idle before they are closed, if at all.
+    fn idle_timeout(&self, proto: IpProtocol) -> Option<Duration> {
+        let timeout = match proto {
+            IpProtocol::Tcp => self.options.tcp_timeout,
+            _ => Some(self.options.udp_timeout.unwrap_or(UDP_TIMEOUT)),
+        };
+        timeout.map(Duration::from_secs)
+    }
+
+    // Remove the connections and UDP sessions which have been idle for too long as well as the
+    // connections which have not been established in time, and schedule the next check.
+    fn remove_expired_connections(&mut self) -> Result<(), Error> {
+        let now = std::time::Instant::now();
+        match self.next_expiry_check {
+            Some(next_check) if next_check <= now => {}
+            _ => return Ok(()),
+        }
+
+        let (mut expired, mut idle, mut retries) = (Vec::new(), Vec::new(), Vec::new());
+        let tcp_timeout = self.idle_timeout(IpProtocol::Tcp);
+        let udp_timeout = self.idle_timeout(IpProtocol::Udp);
+        self.next_expiry_check = None;
+        for (key, state) in self.connections.iter() {
+            let timeout = match key.proto {
+                IpProtocol::Tcp => tcp_timeout,
+                _ => udp_timeout,
             };
-            let dst = connection.dst;
-            (|| -> Result<(), Error> {
-                if resolved_conn.proto == IpProtocol::Tcp {
-                    let cm = self.get_connection_manager(&resolved_conn);
-                    if cm.is_none() {
-                        log::trace!("no connect manager");
-                        return Ok(());
-                    }
-                    let server = cm.unwrap().get_server();
-                    if first_packet {
-                        for manager in self.connection_managers.iter_mut() {
-                            if let Some(handler) =
-                                manager.new_connection(&resolved_conn, manager.clone())?
-                            {
-                                let mut socket = tcp::Socket::new(
-                                    tcp::SocketBuffer::new(vec![0; 1024 * 128]),
-                                    tcp::SocketBuffer::new(vec![0; 1024 * 128]),
-                                );
-                                socket.set_ack_delay(None);
-                                let dst = SocketAddr::try_from(dst)?;
-                                socket.listen(dst)?;
-                                let handle = self.sockets.add(socket);
-
-                                let client = TcpStream::connect(server)?;
-
-                                let token = self.new_token();
-
-                                let mut state = ConnectionState {
-                                    smoltcp_handle: handle,
-                                    mio_stream: client,
-                                    token,
-                                    handler,
-                                    close_state: 0,
-                                    wait_read: true,
-                                    wait_write: false,
-                                };
-
-                                self.token_to_connection
-                                    .insert(token, resolved_conn.clone());
-                                self.poll.registry().register(
-                                    &mut state.mio_stream,
-                                    token,
-                                    Interest::READABLE,
-                                )?;
-
-                                self.connections.insert(resolved_conn.clone(), state);
-
-                                info!("CONNECT {}", resolved_conn,);
-                                break;
-                            }
-                        }
-                    } else if !self.connections.contains_key(&resolved_conn) {
-                        return Ok(());
+            let idle_expiry = timeout.map(|timeout| state.last_activity + timeout);
+            match state.expiry.or(state.retry_at) {
+                Some(expiry) if expiry <= now && state.retry_at.is_some() => retries.push(*key),
+                Some(expiry) if expiry <= now => expired.push((*key, state.connection.clone())),
+                _ if idle_expiry.is_some_and(|expiry| expiry <= now) => {
+                    idle.push((*key, state.connection.clone()))
+                }
+                expiry => {
+                    if let Some(expiry) = expiry.into_iter().chain(idle_expiry).min() {
+                        let next_check = self.next_expiry_check.get_or_insert(expiry);
+                        *next_check = expiry.min(*next_check);
                     }
+                }
+            }
+        }
 
-                    // Inject the packet to advance the smoltcp socket state
-                    self.device.inject_packet(frame);
+        let mut expired_sessions = Vec::new();
+        for (token, session) in self.local_dns_sessions.iter() {
+            match session.expiry {
+                expiry if expiry <= now => expired_sessions.push(*token),
+                expiry => {
+                    let next_check = self.next_expiry_check.get_or_insert(expiry);
+                    *next_check = expiry.min(*next_check);
+                }
+            }
+        }
+        for token in expired_sessions {
+            if let Some(mut session) = self.local_dns_sessions.remove(&token) {
+                _ = self.poll.registry().deregister(&mut session.socket);
+                self.tokens.release(token);
+            }
+        }
 
-                    // Having advanced the socket state, we expect the socket to ACK
-                    // Exfiltrate the response packets generated by the socket and inject them
-                    // into the tunnel interface.
-                    self.expect_smoltcp_send()?;
+        for key in retries {
+            self.reconnect(key)?;
+        }
 
-                    // Read from the smoltcp socket and push the data to the connection handler.
-                    self.tunsocket_read_and_forward(&resolved_conn)?;
+        for (key, connection) in expired {
+            if key.proto == IpProtocol::Tcp {
+                log::debug!("Connection {} to the proxy timed out", connection);
+                let error = std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Connecting to the proxy timed out",
+                );
+                if self.schedule_retry(key, &error.into())? {
+                    continue;
+                }
+                if let Some(state) = self.connections.get(&key) {
+                    self.sockets
+                        .get_mut::<tcp::Socket>(state.smoltcp_handle)
+                        .abort();
+                    self.expect_smoltcp_send()?;
+                }
+            } else {
+                log::debug!("UDP session {} timed out", connection);
+            }
+            self.remove_connection(key)?;
+        }
 
-                    // The connection handler builds up the connection or encapsulates the data.
-                    // Therefore, we now expect it to write data to the server.
-                    self.write_to_server(&resolved_conn)?;
-                } else if resolved_conn.proto == IpProtocol::Udp && resolved_conn.dst.port == 53 {
-                    if let Some(virtual_dns) = &mut self.options.virtdns {
-                        let payload = &frame[_payload_offset.._payload_offset + _payload_size];
-                        if let Some(response) = virtual_dns.receive_query(payload) {
-                            let rx_buffer = udp::PacketBuffer::new(
-                                vec![udp::PacketMetadata::EMPTY],
-                                vec![0; 4096],
-                            );
-                            let tx_buffer = udp::PacketBuffer::new(
-                                vec![udp::PacketMetadata::EMPTY],
-                                vec![0; 4096],
-                            );
-                            let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
-                            let dst = SocketAddr::try_from(dst)?;
-                            socket.bind(dst)?;
-                            socket
-                                .send_slice(response.as_slice(), resolved_conn.src.into())
-                                .expect("failed to send DNS response");
-                            let handle = self.sockets.add(socket);
-                            self.expect_smoltcp_send()?;
-                            self.sockets.remove(handle);
-                        }
-                    }
-                    // Otherwise, UDP is not yet supported.
+        for (key, connection) in idle {
+            if key.proto == IpProtocol::Tcp {
+                log::debug!("Connection {} has been idle for too long", connection);
+                if let Some(state) = self.connections.get(&key) {
+                    self.sockets
+                        .get_mut::<tcp::Socket>(state.smoltcp_handle)
+                        .abort();
+                    self.expect_smoltcp_send()?;
                 }
-                Ok(())
-            })()
-            .or_else(|error| {
-                log::error! {"{error}"}
-                Ok::<(), Error>(())
-            })?;
+            } else {
+                log::debug!("UDP session {} timed out", connection);
+            }
+            self.remove_connection(key)?;
         }
         Ok(())
     }
 
-    fn write_to_server(&mut self, connection: &Connection) -> Result<(), Error> {
-        if let Some(state) = self.connections.get_mut(connection) {
-            let event = state.handler.peek_data(OutgoingDirection::ToServer);
-            let buffer_size = event.buffer.len();
+    fn write_to_server(&mut self, key: ConnectionKey) -> Result<(), Error> {
+        // The data may be peeked at in turns, as far as it spans more slices than are written at
+        // once, until none is left or the socket takes no more.
+        while let Some(state) = self.connections.get_mut(&key) {
+            if state.retry_at.is_some() {
+                return Ok(());
+            }
+            let slices = state.handler.peek_slices(OutgoingDirection::ToServer);
+            let buffer_size = slices.len();
+            set_queued(
+                &mut self.memory_used,
+                &mut state.queued_to_server,
+                buffer_size,
+            );
             if buffer_size == 0 {
                 state.wait_write = false;
-                self.update_mio_socket_interest(connection)?;
-                self.check_change_close_state(connection)?;
-                return Ok(());
+                self.update_mio_socket_interest(key)?;
+                break;
             }
-            let result = state.mio_stream.write(event.buffer);
+            let result = slices.write_to(&mut state.mio_stream);
             match result {
                 Ok(written) => {
                     state
                         .handler
                         .consume_data(OutgoingDirection::ToServer, written);
-                    state.wait_write = written < buffer_size;
-                    self.update_mio_socket_interest(connection)?;
+                    let queued = buffer_size - written;
+                    set_queued(&mut self.memory_used, &mut state.queued_to_server, queued);
+                    if written < buffer_size {
+                        state.wait_write = true;
+                        self.update_mio_socket_interest(key)?;
+                        break;
+                    }
                 }
-                Err(error) if error.kind() != std::io::ErrorKind::WouldBlock => {
+                // Without the cookie of TCP Fast Open, the data is sent once connected.
+                Err(error)
+                    if error.kind() != std::io::ErrorKind::WouldBlock
+                        && error.raw_os_error() != Some(libc::EINPROGRESS) =>
+                {
                     return Err(error.into());
                 }
                 _ => {
                     // WOULDBLOCK case
                     state.wait_write = true;
-                    self.update_mio_socket_interest(connection)?;
+                    self.update_mio_socket_interest(key)?;
+                    break;
                 }
             }
         }
-        self.check_change_close_state(connection)?;
+        self.check_change_close_state(key)?;
         Ok(())
     }
 
-    fn write_to_client(&mut self, token: Token, connection: &Connection) -> Result<(), Error> {
-        while let Some(state) = self.connections.get_mut(connection) {
+    fn write_to_client(&mut self, token: Token, key: ConnectionKey) -> Result<(), Error> {
+        while let Some(state) = self.connections.get_mut(&key) {
             let socket_handle = state.smoltcp_handle;
             let event = state.handler.peek_data(OutgoingDirection::ToClient);
             let buflen = event.buffer.len();
@@ -645,12 +3076,19 @@ impl<'a> TunToProxy<'a> {
                     state
                         .handler
                         .consume_data(OutgoingDirection::ToClient, consumed);
+                    let queued = buflen - consumed;
+                    set_queued(&mut self.memory_used, &mut state.queued_to_client, queued);
                     self.expect_smoltcp_send()?;
                     if consumed < buflen {
                         self.write_sockets.insert(token);
                         break;
                     } else {
                         self.write_sockets.remove(&token);
+                        if self.read_sockets.remove(&token) {
+                            let state = self.connections.get_mut(&key);
+                            state.ok_or("connection not found")?.wait_read = true;
+                            self.update_mio_socket_interest(key)?;
+                        }
                         if consumed == 0 {
                             break;
                         }
@@ -660,31 +3098,103 @@ impl<'a> TunToProxy<'a> {
                 }
             }
 
-            self.check_change_close_state(connection)?;
+            self.check_change_close_state(key)?;
         }
         Ok(())
     }
 
     fn tun_event(&mut self, event: &Event) -> Result<(), Error> {
         if event.is_readable() {
-            while let Some((rx_token, _)) = self.tun.receive(Instant::now()) {
-                rx_token.consume(|frame| self.receive_tun(frame))?;
+            self.receive_packets()?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self) -> Result<(), Error> {
+        let mut frames = std::mem::take(&mut self.frames);
+        let result = (|| -> Result<(), Error> {
+            loop {
+                let more = self.tun.receive_batch(&mut frames)?;
+                for frame in frames.iter_mut() {
+                    if self.wireguard.is_some() {
+                        if let Some(mss) = self.options.mss {
+                            clamp_mss(frame, mss);
+                        }
+                        self.send_to_wireguard(frame)?;
+                    } else {
+                        self.receive_tun(frame)?;
+                    }
+                }
+                if !more {
+                    return Ok(());
+                }
+            }
+        })();
+        self.frames = frames;
+        result
+    }
+
+    fn send_to_wireguard(&mut self, frame: &[u8]) -> Result<(), Error> {
+        if let Some((tunnel, socket)) = &mut self.wireguard {
+            let mut datagrams = Vec::new();
+            tunnel.encapsulate(frame, &mut datagrams)?;
+            send_datagrams(socket, &datagrams);
+        }
+        Ok(())
+    }
+
+    fn receive_from_wireguard(&mut self) -> Result<(), Error> {
+        let (tunnel, socket) = match &mut self.wireguard {
+            Some(wireguard) => wireguard,
+            None => return Ok(()),
+        };
+        let mut buf = self.device.pool().take_sized(0xffff);
+        let mut packets = Vec::new();
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    self.device.pool().give_back(buf);
+                    return Err(error.into());
+                }
+            };
+            let mut datagrams = Vec::new();
+            if let Err(error) = tunnel.decapsulate(&buf[..len], &mut datagrams, &mut packets) {
+                log::debug!("{error}");
             }
+            send_datagrams(socket, &datagrams);
+        }
+        self.device.pool().give_back(buf);
+        for packet in packets {
+            if let Some(buffer) = self.tun.send(packet) {
+                self.device.pool().give_back(buffer);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_wireguard_timers(&mut self) -> Result<(), Error> {
+        if let Some((tunnel, socket)) = &mut self.wireguard {
+            let mut datagrams = Vec::new();
+            tunnel.update_timers(&mut datagrams)?;
+            send_datagrams(socket, &datagrams);
         }
         Ok(())
     }
 
     fn send_to_smoltcp(&mut self) -> Result<(), Error> {
-        let cloned = self.write_sockets.clone();
-        for token in cloned.iter() {
-            if let Some(connection) = self.token_to_connection.get(token) {
-                let connection = connection.clone();
-                if let Err(error) = self.write_to_client(*token, &connection) {
-                    self.remove_connection(&connection)?;
+        let mut tokens = std::mem::take(&mut self.write_tokens);
+        tokens.extend(self.write_sockets.iter());
+        for token in tokens.drain(..) {
+            if let Some(&key) = self.token_to_connection.get(&token) {
+                if let Err(error) = self.write_to_client(token, key) {
+                    self.remove_connection(key)?;
                     log::error!("Write to client: {}: ", error);
                 }
             }
         }
+        self.write_tokens = tokens;
         Ok(())
     }
 
@@ -698,101 +3208,279 @@ impl<'a> TunToProxy<'a> {
             log::trace!("{e}");
             return Ok(());
         }
-        let connection = conn_ref.unwrap().clone();
+        let key = *conn_ref.unwrap();
 
         (|| -> Result<(), Error> {
-            if event.is_readable() || event.is_read_closed() {
-                {
-                    let state = self.connections.get_mut(&connection).ok_or(e)?;
-
-                    // TODO: Move this reading process to its own function.
-                    let mut vecbuf = Vec::<u8>::new();
-                    let read_result = state.mio_stream.read_to_end(&mut vecbuf);
-                    let read = match read_result {
-                        Ok(read_result) => read_result,
-                        Err(error) => {
-                            if error.kind() != std::io::ErrorKind::WouldBlock {
-                                error!("Read from proxy: {}", error);
-                            }
-                            vecbuf.len()
-                        }
-                    };
-
-                    let data = vecbuf.as_slice();
-                    let data_event = IncomingDataEvent {
-                        direction: IncomingDirection::FromServer,
-                        buffer: &data[0..read],
-                    };
-                    if let Err(error) = state.handler.push_data(data_event) {
-                        state.mio_stream.shutdown(Both)?;
-                        {
-                            let socket = self.sockets.get_mut::<tcp::Socket>(
-                                self.connections.get(&connection).ok_or(e)?.smoltcp_handle,
-                            );
-                            socket.close();
-                        }
-                        self.expect_smoltcp_send()?;
-                        log::error! {"{error}"}
-                        self.remove_connection(&connection.clone())?;
-                        return Ok(());
-                    }
+            let udp_token = self.connections.get(&key).and_then(|s| s.udp_token);
+            if udp_token == Some(event.token()) {
+                return self.receive_udp_from_server(key);
+            }
 
-                    if read == 0 || event.is_read_closed() {
-                        state.wait_read = false;
-                        state.close_state |= SERVER_WRITE_CLOSED;
-                        self.update_mio_socket_interest(&connection)?;
-                        self.check_change_close_state(&connection)?;
-                        self.expect_smoltcp_send()?;
-                    }
+            if key.proto == IpProtocol::Tcp {
+                let state = self.connections.get_mut(&key).ok_or(e)?;
+                if state.expiry.is_some() && state.mio_stream.is_connected() {
+                    state.expiry = None;
                 }
+            }
 
-                // We have read from the proxy server and pushed the data to the connection handler.
-                // Thus, expect data to be processed (e.g. decapsulated) and forwarded to the client.
-                self.write_to_client(event.token(), &connection)?;
-
-                // The connection handler could have produced data that is to be written to the
-                // server.
-                self.write_to_server(&connection)?;
+            // A connection whose client lags behind may still report the proxy hanging up.
+            if (event.is_readable() || event.is_read_closed())
+                && !self.read_sockets.contains(&event.token())
+            {
+                self.read_from_server(event.token(), key)?;
             }
 
             if event.is_writable() {
-                self.write_to_server(&connection)?;
+                self.write_to_server(key)?;
             }
 
             Ok(())
         })()
-        .or_else(|error| {
-            log::error! {"{error}"}
-            self.remove_connection(&connection)?;
-            Ok(())
+        .or_else(|error| self.fail_connection(key, error))
+    }
+
+    // Retry a connection which has failed, or give up on it.
+    fn fail_connection(&mut self, key: ConnectionKey, error: Error) -> Result<(), Error> {
+        if self.schedule_retry(key, &error)? {
+            return Ok(());
+        }
+        log::error! {"{error}"}
+        self.remove_connection(key)?;
+        Ok(())
+    }
+
+    // The data from the proxy which the client has yet to take.
+    fn client_backlog(&mut self, key: ConnectionKey) -> usize {
+        self.connections.get_mut(&key).map_or(0, |state| {
+            let event = state.handler.peek_data(OutgoingDirection::ToClient);
+            event.buffer.len()
         })
     }
 
-    fn udp_event(&mut self, _event: &Event) {}
+    // Read from the proxy in chunks, until there is nothing left to read or the socket towards the
+    // client is full. In the latter case, the proxy is not read from until write_to_client has
+    // drained what is left, so that its window closes as that of the client does.
+    fn read_from_server(&mut self, token: Token, key: ConnectionKey) -> Result<(), Error> {
+        let e = "connection not found";
+        let mut chunk = [0; PROXY_READ_CHUNK];
+        let mut closed = false;
+        loop {
+            let state = self.connections.get_mut(&key).ok_or(e)?;
+            let read = match state.mio_stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(read) => read,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    error!("Read from proxy: {}", error);
+                    closed = true;
+                    break;
+                }
+            };
+
+            if !state.reported {
+                state.reported = true;
+                state.manager.report_health(state.server, true);
+            }
+            state.last_activity = std::time::Instant::now();
+
+            let data_event = IncomingDataEvent {
+                direction: IncomingDirection::FromServer,
+                buffer: &chunk[..read],
+            };
+            if let Err(error) = state.handler.push_data(data_event) {
+                if self.schedule_retry(key, &error)? {
+                    return Ok(());
+                }
+                let state = self.connections.get_mut(&key).ok_or(e)?;
+                if !state.handler.is_reusable() {
+                    state.mio_stream.shutdown(Both)?;
+                }
+                if key.proto == IpProtocol::Tcp {
+                    let socket = self.sockets.get_mut::<tcp::Socket>(
+                        self.connections.get(&key).ok_or(e)?.smoltcp_handle,
+                    );
+                    socket.close();
+                }
+                self.expect_smoltcp_send()?;
+                log::error! {"{error}"}
+                self.remove_connection(key)?;
+                return Ok(());
+            }
+            if state.handler.connection_established() {
+                if let Some((name, server)) = state.refused_host.take() {
+                    if server == state.server {
+                        state
+                            .manager
+                            .report_refused_hostname(&state.connection, &name);
+                    }
+                }
+            }
+
+            if key.proto == IpProtocol::Tcp {
+                self.write_to_client(token, key)?;
+                if !self.connections.contains_key(&key) {
+                    return Ok(());
+                }
+                if self.client_backlog(key) > 0 {
+                    self.read_sockets.insert(token);
+                    self.write_sockets.insert(token);
+                    let state = self.connections.get_mut(&key).ok_or(e)?;
+                    state.wait_read = false;
+                    self.update_mio_socket_interest(key)?;
+                    break;
+                }
+            }
+        }
+
+        if closed {
+            let state = self.connections.get(&key).ok_or(e)?;
+            let error = std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The proxy closed the connection",
+            );
+            if !state.handler.connection_established() && self.schedule_retry(key, &error.into())? {
+                return Ok(());
+            }
+            let state = self.connections.get_mut(&key).ok_or(e)?;
+            state.wait_read = false;
+            state.close_state |= SERVER_WRITE_CLOSED;
+            self.update_mio_socket_interest(key)?;
+            self.check_change_close_state(key)?;
+            self.expect_smoltcp_send()?;
+        }
+
+        // The proxy server may have acknowledged a UDP association.
+        self.open_udp_association(key)?;
+
+        // The proxy server may have bound an address or accepted a connection.
+        if key.proto == IpProtocol::Tcp {
+            self.accept_bind(key)?;
+        }
+
+        // We have read from the proxy server and pushed the data to the connection handler.
+        // Thus, expect data to be processed (e.g. decapsulated) and forwarded to the client.
+        if key.proto == IpProtocol::Tcp {
+            self.write_to_client(token, key)?;
+        }
+
+        // The connection handler could have produced data that is to be written to the
+        // server.
+        self.write_to_server(key)?;
+        Ok(())
+    }
+
+    fn udp_event(&mut self, event: &Event) -> Result<(), Error> {
+        if event.is_readable() {
+            self.receive_from_wireguard()?;
+        }
+        Ok(())
+    }
 
     pub fn run(&mut self) -> Result<(), Error> {
         let mut events = Events::with_capacity(1024);
+        self.fill_warm_pool();
+        if sd_notify::is_supervised() {
+            sd_notify::notify_ready_when_reachable(self.probed_servers());
+        }
+        let watchdog = sd_notify::watchdog_interval();
+        let mut next_watchdog = watchdog.map(|_| std::time::Instant::now());
         loop {
-            match self.poll.poll(&mut events, None) {
+            if let (Some(interval), Some(next)) = (watchdog, next_watchdog.as_mut()) {
+                if *next <= std::time::Instant::now() {
+                    sd_notify::notify("WATCHDOG=1");
+                    *next = std::time::Instant::now() + interval;
+                }
+            }
+            let next_check = match &self.wireguard {
+                Some((tunnel, _)) => {
+                    let next_timer = tunnel.next_timer();
+                    Some(
+                        self.next_expiry_check
+                            .map_or(next_timer, |t| t.min(next_timer)),
+                    )
+                }
+                None => self.next_expiry_check,
+            };
+            let next_check = match (next_check, next_watchdog) {
+                (Some(next_check), Some(next)) => Some(next_check.min(next)),
+                (next_check, next) => next_check.or(next),
+            };
+            // The timers of the stack, e.g. of its retransmissions and delayed ACKs, go off without
+            // waiting for unrelated events.
+            let next_smoltcp_poll = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map(|delay| std::time::Instant::now() + std::time::Duration::from(delay));
+            let next_check = match (next_check, next_smoltcp_poll) {
+                (Some(next_check), Some(next)) => Some(next_check.min(next)),
+                (next_check, next) => next_check.or(next),
+            };
+            let next_save = self
+                .options
+                .virtdns
+                .as_ref()
+                .and_then(|dns| dns.next_save());
+            let next_check = match (next_check, next_save) {
+                (Some(next_check), Some(next)) => Some(next_check.min(next)),
+                (next_check, next) => next_check.or(next),
+            };
+            let timeout = next_check
+                .map(|next_check| next_check.saturating_duration_since(std::time::Instant::now()));
+            match self.poll.poll(&mut events, timeout) {
                 Ok(()) => {
                     for event in events.iter() {
                         match event.token() {
                             EXIT_TOKEN => {
+                                if sd_notify::is_supervised() {
+                                    sd_notify::notify("STOPPING=1");
+                                }
+                                self.close_connections()?;
+                                self.tun.flush();
+                                if let Some(virtual_dns) = &mut self.options.virtdns {
+                                    virtual_dns.save_state();
+                                }
                                 log::info!("exiting...");
                                 return Ok(());
                             }
                             TUN_TOKEN => self.tun_event(event)?,
-                            UDP_TOKEN => self.udp_event(event),
+                            UDP_TOKEN => self.udp_event(event)?,
+                            WAKER_TOKEN => {
+                                self.dns_event()?;
+                                self.receive_packets()?;
+                                self.receive_resolved()?;
+                            }
+                            token if self.local_dns_sessions.contains_key(&token) => {
+                                self.local_dns_event(token)?
+                            }
                             _ => self.mio_socket_event(event)?,
                         }
                     }
+                    if next_smoltcp_poll.is_some_and(|next| next <= std::time::Instant::now()) {
+                        self.expect_smoltcp_send()?;
+                    }
                     self.send_to_smoltcp()?;
+                    if let Some(virtual_dns) = &mut self.options.virtdns {
+                        let now = std::time::Instant::now();
+                        if virtual_dns.next_save().is_some_and(|next| next <= now) {
+                            virtual_dns.save_state();
+                        }
+                    }
+                    self.remove_expired_connections()?;
+                    self.fill_warm_pool();
+                    self.update_wireguard_timers()?;
+                    self.tun.flush();
+                    self.tokens.recycle();
                 }
                 Err(e) => {
                     if e.kind() != std::io::ErrorKind::Interrupted {
                         return Err(e.into());
                     } else {
-                        log::warn!("Poll interrupted: {e}")
+                        // As by a signal, or by io_uring completing its requests.
+                        log::debug!("Poll interrupted: {e}")
                     }
                 }
             }
@@ -803,4 +3491,52 @@ impl<'a> TunToProxy<'a> {
         self.exit_sender.write_all(&[1])?;
         Ok(())
     }
+
+    pub fn shutdown_handle(&self) -> Result<ShutdownHandle, Error> {
+        let fd = nix::fcntl::fcntl(
+            self.exit_sender.as_raw_fd(),
+            nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(0),
+        )?;
+        Ok(ShutdownHandle(unsafe { std::fs::File::from_raw_fd(fd) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_tokens_of_new_generation() {
+        let mut tokens = Tokens::new();
+        let first = tokens.take();
+        assert_eq!(first, Token(usize::from(WAKER_TOKEN) + 1));
+        tokens.release(first);
+        assert_ne!(tokens.take(), first);
+        tokens.recycle();
+        let reused = tokens.take();
+        assert_ne!(reused, first);
+        assert_eq!(reused.0 & TOKEN_INDEX_MASK, first.0);
+    }
+
+    #[test]
+    fn wraps_generations_around() {
+        let mut tokens = Tokens::new();
+        let first = tokens.take();
+        tokens.release(Token(!TOKEN_INDEX_MASK | first.0));
+        tokens.recycle();
+        assert_eq!(tokens.take(), first);
+    }
+
+    #[test]
+    fn retries_transport_errors_only() {
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(is_transport_error(&refused.into()));
+        assert!(is_transport_error(&nix::errno::Errno::ECONNRESET.into()));
+        assert!(is_transport_error(&nix::errno::Errno::EHOSTUNREACH.into()));
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        assert!(is_transport_error(&timeout.into()));
+        assert!(!is_transport_error(&"SOCKS authentication failed.".into()));
+        let invalid = std::io::Error::from(std::io::ErrorKind::InvalidData);
+        assert!(!is_transport_error(&invalid.into()));
+    }
 }
@@ -0,0 +1,206 @@
+use crate::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use {
+    crate::netlink::Netlink,
+    crate::privileges::{find_group, find_user},
+    std::ffi::CString,
+    std::fs::{File, OpenOptions},
+    std::os::unix::fs::OpenOptionsExt,
+    std::os::unix::io::{AsRawFd, IntoRawFd, RawFd},
+};
+
+// _IOW('T', 202, int) and its neighbours, with which the device behind /dev/net/tun is set up.
+#[cfg(target_os = "linux")]
+pub(crate) const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+#[cfg(target_os = "linux")]
+const TUNSETPERSIST: libc::c_ulong = 0x4004_54cb;
+#[cfg(target_os = "linux")]
+const TUNSETOWNER: libc::c_ulong = 0x4004_54cc;
+#[cfg(target_os = "linux")]
+const TUNSETGROUP: libc::c_ulong = 0x4004_54ce;
+#[cfg(target_os = "linux")]
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+#[cfg(target_os = "linux")]
+const TUNGETIFF: libc::c_ulong = 0x8004_54d2;
+
+// The offloads of linux/if_tun.h taken on: the checksums, and the segmentation of TCP.
+#[cfg(target_os = "linux")]
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+#[cfg(target_os = "linux")]
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+#[cfg(target_os = "linux")]
+const TUN_F_TSO6: libc::c_ulong = 0x04;
+
+// struct ifreq, of which TUNSETIFF only reads the name and the flags.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct InterfaceRequest {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    padding: [u8; 22],
+}
+
+/// Whether the packets of the tun device are preceded by a virtio-net header, as set up with
+/// [`TunConfig::with_offload`].
+#[cfg(target_os = "linux")]
+pub(crate) fn has_vnet_header(fd: RawFd) -> bool {
+    let mut request = InterfaceRequest {
+        name: [0; libc::IFNAMSIZ],
+        flags: 0,
+        padding: [0; 22],
+    };
+    // Descriptors other than those of tun devices have no flags to tell.
+    let result = unsafe { libc::ioctl(fd, TUNGETIFF as _, &mut request) };
+    result == 0 && request.flags & libc::IFF_VNET_HDR as libc::c_short != 0
+}
+
+/// An address of the tun interface in CIDR notation, e.g. `10.0.0.1/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TunAddress {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl FromStr for TunAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || Error::from(format!("`{s}` is not an address in CIDR notation"));
+        let (addr, len) = s.split_once('/').ok_or_else(e)?;
+        let addr = IpAddr::from_str(addr).map_err(|_| e())?;
+        let prefix_len = u8::from_str(len).map_err(|_| e())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(e());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// The tun interface tun2proxy creates for itself, instead of relying on one created beforehand.
+/// Unless persistent, the interface is gone once tun2proxy closes it on exit.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug)]
+pub struct TunConfig {
+    name: String,
+    owner: Option<String>,
+    group: Option<String>,
+    persistent: bool,
+    // Whether TCP segments of up to 64 KiB are exchanged with the kernel, which segments and
+    // coalesces them in place of tun2proxy, along with a virtio-net header ahead of each packet.
+    offload: bool,
+    mtu: Option<u32>,
+    addresses: Vec<TunAddress>,
+}
+
+#[cfg(target_os = "linux")]
+impl TunConfig {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            owner: None,
+            group: None,
+            persistent: false,
+            offload: false,
+            mtu: None,
+            addresses: Vec::new(),
+        }
+    }
+
+    pub fn with_owner(mut self, user: &str) -> Self {
+        self.owner = Some(user.to_string());
+        self
+    }
+
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    pub fn with_offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    pub fn with_address(mut self, address: TunAddress) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    fn ioctl(file: &File, request: libc::c_ulong, value: libc::c_ulong) -> Result<(), Error> {
+        if unsafe { libc::ioctl(file.as_raw_fd(), request as _, value) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Create the interface, or attach to it if it persists from an earlier run, bring it up with
+    /// its MTU and addresses and return its file descriptor.
+    pub fn create(&self) -> Result<RawFd, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        let name = CString::new(self.name.as_str()).map_err(|_| "Invalid interface name")?;
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        if self.offload {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        let mut request = InterfaceRequest {
+            name: [0; libc::IFNAMSIZ],
+            flags: flags as libc::c_short,
+            padding: [0; 22],
+        };
+        let name = name.as_bytes();
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(format!("The interface name `{}` is too long", self.name).into());
+        }
+        request.name[..name.len()].copy_from_slice(name);
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut request) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        if let Some(owner) = &self.owner {
+            let (uid, _) = find_user(owner)?;
+            Self::ioctl(&file, TUNSETOWNER, uid.as_raw().into())?;
+        }
+        if let Some(group) = &self.group {
+            let gid = find_group(group)?;
+            Self::ioctl(&file, TUNSETGROUP, gid.as_raw().into())?;
+        }
+        Self::ioctl(&file, TUNSETPERSIST, self.persistent.into())?;
+        if self.offload {
+            Self::ioctl(&file, TUNSETOFFLOAD, TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6)?;
+        }
+
+        let index = unsafe { libc::if_nametoindex(request.name.as_ptr() as *const libc::c_char) };
+        if index == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut netlink = Netlink::new()?;
+        netlink.set_link_up(index, self.mtu)?;
+        for address in &self.addresses {
+            // A persistent interface keeps the addresses it was given before.
+            match netlink.add_address(index, address.addr, address.prefix_len) {
+                Err(Error::OSError(nix::errno::Errno::EEXIST)) => {}
+                result => result?,
+            }
+        }
+        log::info!("Created the tun interface {}", self.name);
+        Ok(file.into_raw_fd())
+    }
+}
@@ -0,0 +1,536 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+use smoltcp::phy::{Device, DeviceCapabilities, TunTapInterface};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+// The opcodes, offsets and registrations of linux/io_uring.h used here.
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_SETUP_COOP_TASKRUN: u32 = 1 << 8;
+const IORING_REGISTER_EVENTFD: libc::c_uint = 4;
+
+const RING_ENTRIES: u32 = 256;
+// The reads kept in flight on the tun device, and the writes at most, so that the completions
+// never overflow their ring, which has twice as many entries.
+const READS: usize = 64;
+const MAX_WRITES: usize = 128;
+// The user data of the writes, which tells them from the reads, identified by their buffer.
+const WRITE_FLAG: u64 = 1 << 63;
+// The user data of the cancellations of the reads.
+const CANCEL_FLAG: u64 = 1 << 62;
+// How long dropping the device waits for the reads and writes in flight to complete.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// struct io_sqring_offsets
+#[repr(C)]
+#[derive(Default)]
+struct SubmissionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+// struct io_cqring_offsets
+#[repr(C)]
+#[derive(Default)]
+struct CompletionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+// struct io_uring_params
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SubmissionOffsets,
+    cq_off: CompletionOffsets,
+}
+
+// struct io_uring_sqe, of which only the fields of reads and writes are named.
+#[repr(C)]
+#[derive(Default)]
+struct Submission {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    padding: [u64; 3],
+}
+
+// struct io_uring_cqe
+#[repr(C)]
+struct Completion {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// A region shared with the kernel, unmapped once the ring is dropped.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    // The field at `offset` into the region.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// An io_uring instance, through which a batch of reads and writes is submitted with a single
+/// syscall, and whose completions are taken without any.
+pub(crate) struct Ring {
+    fd: RawFd,
+    // The completion ring shares the mapping of the submission ring on kernels since 5.4.
+    sq_ring: Mapping,
+    cq_ring: Option<Mapping>,
+    sqes: Mapping,
+    sq_offsets: SubmissionOffsets,
+    cq_offsets: CompletionOffsets,
+    sq_entries: u32,
+    // The submissions queued which the kernel has not consumed yet.
+    unsubmitted: u32,
+}
+
+impl Ring {
+    pub(crate) fn new() -> io::Result<Self> {
+        // Without interrupting the event loop to complete the requests, where the kernel supports
+        // it, since 5.19.
+        let mut params = Params {
+            flags: IORING_SETUP_COOP_TASKRUN,
+            ..Params::default()
+        };
+        let mut fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, RING_ENTRIES, &mut params) };
+        if fd < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+            params = Params::default();
+            fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, RING_ENTRIES, &mut params) };
+        }
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        // The ring owns the descriptor from here on, so that it is closed on errors.
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<Completion>();
+        let mappings = || -> io::Result<_> {
+            let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+            let sq_ring = match single {
+                true => Mapping::new(fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?,
+                false => Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+            };
+            let cq_ring = match single {
+                true => None,
+                false => Some(Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?),
+            };
+            let sqes_len = params.sq_entries as usize * std::mem::size_of::<Submission>();
+            let sqes = Mapping::new(fd, sqes_len, IORING_OFF_SQES)?;
+            Ok((sq_ring, cq_ring, sqes))
+        };
+        let (sq_ring, cq_ring, sqes) = match mappings() {
+            Ok(mappings) => mappings,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            fd,
+            sq_ring,
+            cq_ring,
+            sqes,
+            sq_entries: params.sq_entries,
+            sq_offsets: params.sq_off,
+            cq_offsets: params.cq_off,
+            unsubmitted: 0,
+        })
+    }
+
+    fn cq_ring(&self) -> &Mapping {
+        self.cq_ring.as_ref().unwrap_or(&self.sq_ring)
+    }
+
+    // Signal the completions through `event_fd`, e.g. to wake a poll.
+    fn register_eventfd(&self, event_fd: RawFd) -> io::Result<()> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd,
+                IORING_REGISTER_EVENTFD,
+                &event_fd as *const RawFd,
+                1,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Queue a submission, unless the ring is full.
+    fn push(&mut self, submission: Submission) -> bool {
+        let head = unsafe { &*self.sq_ring.at::<AtomicU32>(self.sq_offsets.head) };
+        let tail = unsafe { &*self.sq_ring.at::<AtomicU32>(self.sq_offsets.tail) };
+        let mask = unsafe { *self.sq_ring.at::<u32>(self.sq_offsets.ring_mask) };
+        // Only the kernel moves the head, and only this side the tail.
+        let current = tail.load(Ordering::Relaxed);
+        if current.wrapping_sub(head.load(Ordering::Acquire)) == self.sq_entries {
+            return false;
+        }
+        let index = current & mask;
+        unsafe {
+            let sqes = self.sqes.ptr as *mut Submission;
+            sqes.add(index as usize).write(submission);
+            let array = self.sq_ring.at::<u32>(self.sq_offsets.array);
+            array.add(index as usize).write(index);
+        }
+        tail.store(current.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        true
+    }
+
+    /// Hand the queued submissions to the kernel.
+    pub(crate) fn submit(&mut self) -> io::Result<()> {
+        while self.unsubmitted > 0 {
+            let submitted = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    self.unsubmitted,
+                    0,
+                    0,
+                    std::ptr::null::<libc::c_void>(),
+                    0,
+                )
+            };
+            if submitted < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // The kernel is short of resources, so the submissions wait for the next call.
+                    Some(libc::EAGAIN) | Some(libc::EBUSY) => return Ok(()),
+                    _ => return Err(e),
+                }
+            }
+            self.unsubmitted -= submitted as u32;
+        }
+        Ok(())
+    }
+
+    // Take the next completion, if any.
+    fn pop(&mut self) -> Option<Completion> {
+        let ring = self.cq_ring();
+        let head = unsafe { &*ring.at::<AtomicU32>(self.cq_offsets.head) };
+        let tail = unsafe { &*ring.at::<AtomicU32>(self.cq_offsets.tail) };
+        let mask = unsafe { *ring.at::<u32>(self.cq_offsets.ring_mask) };
+        let current = head.load(Ordering::Relaxed);
+        if current == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let completions = ring.at::<Completion>(self.cq_offsets.cqes);
+        let completion = unsafe { completions.add((current & mask) as usize).read() };
+        head.store(current.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A tun device whose packets are read and written through io_uring: reads are kept in flight,
+/// and the writes of a wakeup of the event loop are submitted at once through
+/// [`flush`](Self::flush). The completions are signalled through the descriptor of an eventfd,
+/// which is polled in place of that of the device.
+pub(crate) struct UringTun {
+    ring: Ring,
+    device: TunTapInterface,
+    event_fd: RawFd,
+    // The buffers of the reads, which are identified by their index, and whether they are in
+    // flight.
+    reads: Vec<Vec<u8>>,
+    reading: Vec<bool>,
+    // The packets being written, by the user data of their writes.
+    writes: HashMap<u64, Vec<u8>>,
+    next_write: u64,
+    received: VecDeque<Vec<u8>>,
+}
+
+impl UringTun {
+    pub(crate) fn new(device: TunTapInterface, ring: Ring) -> io::Result<Self> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mtu = device.capabilities().max_transmission_unit;
+        let mut tun = Self {
+            ring,
+            device,
+            event_fd,
+            reads: vec![vec![0; mtu]; READS],
+            reading: vec![false; READS],
+            writes: HashMap::new(),
+            next_write: 0,
+            received: VecDeque::new(),
+        };
+        tun.ring.register_eventfd(event_fd)?;
+        // Reads of a non-blocking descriptor would fail at once rather than wait for a packet.
+        let fd = tun.device.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for index in 0..READS {
+            tun.read(index);
+        }
+        tun.ring.submit()?;
+        log::info!("Reading and writing the packets of the tun device through io_uring");
+        Ok(tun)
+    }
+
+    pub(crate) fn capabilities(&self) -> DeviceCapabilities {
+        self.device.capabilities()
+    }
+
+    fn read(&mut self, index: usize) {
+        let buffer = &mut self.reads[index];
+        let submission = Submission {
+            opcode: IORING_OP_READ,
+            fd: self.device.as_raw_fd(),
+            // The current position, which a tun device does not have.
+            off: u64::MAX,
+            addr: buffer.as_mut_ptr() as u64,
+            len: buffer.len() as u32,
+            user_data: index as u64,
+            ..Submission::default()
+        };
+        // There is room for every read, as the writes are limited.
+        if self.ring.push(submission) {
+            self.reading[index] = true;
+        } else {
+            log::error!("The submission ring of io_uring is full");
+        }
+    }
+
+    // Take the completions, keeping the packets read, and read again into their buffers.
+    fn reap(&mut self) {
+        while let Some(completion) = self.ring.pop() {
+            if completion.user_data & WRITE_FLAG != 0 {
+                self.writes.remove(&completion.user_data);
+                if completion.res < 0 {
+                    let e = io::Error::from_raw_os_error(-completion.res);
+                    log::debug!("Write to the tun device: {e}");
+                }
+                continue;
+            }
+            let index = completion.user_data as usize;
+            self.reading[index] = false;
+            match completion.res {
+                len if len > 0 => {
+                    let packet = self.reads[index][..len as usize].to_vec();
+                    self.received.push_back(packet);
+                }
+                0 => {}
+                res if -res == libc::EAGAIN || -res == libc::EINTR => {}
+                res => {
+                    // The device is gone, so that reading again would only fail again.
+                    let e = io::Error::from_raw_os_error(-res);
+                    log::error!("Read from the tun device: {e}");
+                    continue;
+                }
+            }
+            self.read(index);
+        }
+        self.flush();
+    }
+
+    // Reset the counter of the eventfd, so that it is only readable again on new completions.
+    fn clear_event(&self) {
+        let mut counter = [0u8; 8];
+        let counter = counter.as_mut_ptr() as *mut libc::c_void;
+        unsafe { libc::read(self.event_fd, counter, 8) };
+    }
+
+    pub(crate) fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.received.is_empty() {
+            self.clear_event();
+            self.reap();
+        }
+        self.received.pop_front()
+    }
+
+    // Cancel the reads in flight, and wait for them and the writes to complete, returning whether
+    // they all have, so that the kernel no longer uses their buffers.
+    fn cancel(&mut self) -> bool {
+        for index in 0..READS {
+            let submission = Submission {
+                opcode: IORING_OP_ASYNC_CANCEL,
+                fd: -1,
+                addr: index as u64,
+                user_data: CANCEL_FLAG,
+                ..Submission::default()
+            };
+            if self.reading[index] && !self.ring.push(submission) {
+                return false;
+            }
+        }
+        self.flush();
+        let deadline = Instant::now() + CANCEL_TIMEOUT;
+        loop {
+            self.clear_event();
+            while let Some(completion) = self.ring.pop() {
+                match completion.user_data {
+                    data if data & CANCEL_FLAG != 0 => {}
+                    data if data & WRITE_FLAG != 0 => {
+                        self.writes.remove(&data);
+                    }
+                    index => self.reading[index as usize] = false,
+                }
+            }
+            if !self.reading.contains(&true) && self.writes.is_empty() {
+                return true;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return false;
+            }
+            let mut poll_fd = libc::pollfd {
+                fd: self.event_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().max(1) as libc::c_int;
+            unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+        }
+    }
+
+    pub(crate) fn send(&mut self, buffer: Vec<u8>) {
+        if self.writes.len() >= MAX_WRITES {
+            // Most writes are done as soon as they are submitted.
+            self.flush();
+            self.reap();
+            // The packets read in the meantime are taken on another wakeup.
+            if !self.received.is_empty() {
+                let counter = 1u64.to_ne_bytes();
+                let counter = counter.as_ptr() as *const libc::c_void;
+                unsafe { libc::write(self.event_fd, counter, 8) };
+            }
+        }
+        // Like the tun device itself, packets are dropped under pressure.
+        if self.writes.len() >= MAX_WRITES {
+            log::debug!("Dropping a packet, as the tun device is busy");
+            return;
+        }
+        let user_data = WRITE_FLAG | self.next_write;
+        self.next_write += 1;
+        let submission = Submission {
+            opcode: IORING_OP_WRITE,
+            fd: self.device.as_raw_fd(),
+            off: u64::MAX,
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            user_data,
+            ..Submission::default()
+        };
+        if !self.ring.push(submission) {
+            log::debug!("Dropping a packet, as the submission ring of io_uring is full");
+            return;
+        }
+        // The buffer stays where it is on the heap while the kernel writes from it.
+        self.writes.insert(user_data, buffer);
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Err(e) = self.ring.submit() {
+            log::error!("Submit to io_uring: {e}");
+        }
+    }
+}
+
+impl AsRawFd for UringTun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.event_fd
+    }
+}
+
+impl Drop for UringTun {
+    fn drop(&mut self) {
+        // The buffers are freed only once the kernel is done with them, which it may not be while
+        // tearing down the ring. Those of reads and writes which do not complete in time are
+        // leaked instead.
+        if !self.cancel() {
+            log::warn!("The reads and writes of io_uring in flight could not be cancelled");
+            for (buffer, &reading) in self.reads.iter_mut().zip(&self.reading) {
+                if reading {
+                    std::mem::forget(std::mem::take(buffer));
+                }
+            }
+            std::mem::forget(std::mem::take(&mut self.writes));
+        }
+        unsafe { libc::close(self.event_fd) };
+    }
+}
@@ -1,66 +1,91 @@
+use crate::buffers::BufferPool;
 use smoltcp::phy;
 use smoltcp::phy::{Device, DeviceCapabilities};
 use smoltcp::time::Instant;
+use std::collections::VecDeque;
 
 #[derive(Default)]
 pub struct VirtualTunDevice {
     capabilities: DeviceCapabilities,
-    inbuf: Vec<Vec<u8>>,
-    outbuf: Vec<Vec<u8>>,
+    // The packets leave in the order they came in, lest a FIN overtake the data before it.
+    inbuf: VecDeque<Vec<u8>>,
+    outbuf: VecDeque<Vec<u8>>,
+    // The buffers of the packets, which are given back once the stack or the tunnel is done with
+    // them.
+    pool: BufferPool,
 }
 
 impl VirtualTunDevice {
     pub fn inject_packet(&mut self, buffer: &[u8]) {
-        self.inbuf.push(buffer.to_vec());
+        let mut packet = self.pool.take();
+        packet.extend_from_slice(buffer);
+        self.inbuf.push_back(packet);
     }
 
     pub fn exfiltrate_packet(&mut self) -> Option<Vec<u8>> {
-        self.outbuf.pop()
+        self.outbuf.pop_front()
+    }
+
+    pub(crate) fn pool(&self) -> &BufferPool {
+        &self.pool
     }
 }
 
-pub struct VirtRxToken {
+pub struct VirtRxToken<'a> {
     buffer: Vec<u8>,
+    pool: &'a BufferPool,
 }
 
-impl phy::RxToken for VirtRxToken {
+impl<'a> phy::RxToken for VirtRxToken<'a> {
     fn consume<R, F>(mut self, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        f(&mut self.buffer[..])
+        let result = f(&mut self.buffer[..]);
+        self.pool.give_back(self.buffer);
+        result
     }
 }
 
-pub struct VirtTxToken<'a>(&'a mut VirtualTunDevice);
+pub struct VirtTxToken<'a> {
+    outbuf: &'a mut VecDeque<Vec<u8>>,
+    pool: &'a BufferPool,
+}
 
 impl<'a> phy::TxToken for VirtTxToken<'a> {
     fn consume<R, F>(self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0; len];
+        let mut buffer = self.pool.take_sized(len);
         let result = f(&mut buffer);
-        self.0.outbuf.push(buffer);
+        self.outbuf.push_back(buffer);
         result
     }
 }
 
 impl Device for VirtualTunDevice {
-    type RxToken<'a> = VirtRxToken;
+    type RxToken<'a> = VirtRxToken<'a>;
     type TxToken<'a> = VirtTxToken<'a>;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        if let Some(buffer) = self.inbuf.pop() {
-            let rx = Self::RxToken { buffer };
-            let tx = VirtTxToken(self);
+        if let Some(buffer) = self.inbuf.pop_front() {
+            let pool = &self.pool;
+            let rx = Self::RxToken { buffer, pool };
+            let tx = VirtTxToken {
+                outbuf: &mut self.outbuf,
+                pool,
+            };
             return Some((rx, tx));
         }
         None
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        return Some(VirtTxToken(self));
+        Some(VirtTxToken {
+            outbuf: &mut self.outbuf,
+            pool: &self.pool,
+        })
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
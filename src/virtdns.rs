@@ -1,19 +1,34 @@
+use crate::error::Error;
+use crate::resolve::skip_name;
 use hashlink::linked_hash_map::RawEntryMut;
 use hashlink::LruCache;
 use smoltcp::wire::Ipv4Cidr;
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-const DNS_TTL: u8 = 30; // TTL in DNS replies in seconds
-const MAPPING_TIMEOUT: u64 = 60; // Mapping timeout in seconds
+const DNS_TTL: u32 = 30; // Default TTL in DNS replies in seconds
+const MAPPING_TIMEOUT: u64 = 60; // Default mapping timeout in seconds
+const MAX_UDP_SIZE: usize = 512; // Size limit of responses to clients without EDNS
+const EDNS_UDP_SIZE: u16 = 1232; // UDP payload size advertised through EDNS
+const EDNS_OPT_SIZE: usize = 11; // Size of an OPT record without options
+const STATE_SAVE_DELAY: Duration = Duration::from_secs(5); // Delay of writing changed mappings
 
 #[derive(Eq, PartialEq, Debug)]
 #[allow(dead_code, clippy::upper_case_acronyms)]
 enum DnsRecordType {
     A = 1,
+    PTR = 12,
     AAAA = 28,
 }
 
@@ -23,30 +38,510 @@ enum DnsClass {
     IN = 1,
 }
 
+const DNS_TYPE_OPT: u16 = 41;
+
+// The EDNS parameters of a query, taken from its OPT record as defined by RFC 6891.
+struct Edns {
+    udp_size: u16,
+    version: u8,
+    dnssec_ok: bool,
+}
+
+impl Edns {
+    // Look for the OPT record among the records following the question which ends at `offset`.
+    fn parse(data: &[u8], mut offset: usize) -> Option<Self> {
+        let count = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]) as usize;
+        let additional = count(6) + count(8);
+        for i in 0..additional + count(10) {
+            let name_end = skip_name(data, offset)?;
+            let header = data.get(name_end..name_end + 10)?;
+            let record_type = u16::from_be_bytes([header[0], header[1]]);
+            // The OPT record is owned by the root domain.
+            if i >= additional && record_type == DNS_TYPE_OPT && name_end == offset + 1 {
+                return Some(Self {
+                    udp_size: u16::from_be_bytes([header[2], header[3]]),
+                    version: header[5],
+                    dnssec_ok: header[6] & 0x80 != 0,
+                });
+            }
+            offset = name_end + 10 + u16::from_be_bytes([header[8], header[9]]) as usize;
+        }
+        None
+    }
+}
+
+// Whether the lower case `name` is `domain` or, if given as e.g. `*.corp.example`, one of its
+// subdomains.
+pub(crate) fn matches_domain(domain: &str, name: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(parent) => name
+            .strip_suffix(parent)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => name == domain,
+    }
+}
+
 struct NameCacheEntry {
     name: String,
     expiry: Instant,
 }
 
-pub struct VirtualDns {
-    lru_cache: LruCache<IpAddr, NameCacheEntry>,
+/// Unique local IPv6 prefix from which virtual addresses are handed out for AAAA queries, given
+/// as e.g. `fd00:198:18::/64`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ipv6Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl FromStr for Ipv6Prefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || Error::from(format!("`{s}` is not a unique local IPv6 prefix"));
+        let (addr, len) = s.split_once('/').ok_or_else(e)?;
+        let addr = Ipv6Addr::from_str(addr).map_err(|_| e())?;
+        let len = u8::from_str(len).map_err(|_| e())?;
+        // The prefix has to lie within fc00::/7 and leave room for addresses.
+        if addr.segments()[0] & 0xfe00 != 0xfc00 || !(7..=112).contains(&len) {
+            return Err(e());
+        }
+        let mask = u128::MAX << (128 - len);
+        let addr = Ipv6Addr::from(u128::from(addr) & mask);
+        Ok(Self { addr, len })
+    }
+}
+
+impl Ipv6Prefix {
+    /// The address within the prefix which hashed mappings give `name`, unless another name with
+    /// the same hash got it first.
+    pub fn hashed_address(&self, name: &str) -> Ipv6Addr {
+        let last_addr = u128::from(self.addr) | (u128::MAX >> self.len);
+        Ipv6Addr::from(hashed_offset(u128::from(self.addr), last_addr, name))
+    }
+}
+
+/// The address within 198.18.0.0/15 which hashed mappings give `name`, unless another name with
+/// the same hash got it first.
+pub fn hashed_ipv4_address(name: &str) -> Ipv4Addr {
+    let network = u32::from(Ipv4Addr::new(198, 18, 0, 0));
+    let broadcast = u32::from(Ipv4Addr::new(198, 19, 255, 255));
+    let addr = hashed_offset(network.into(), broadcast.into(), name);
+    Ipv4Addr::from(addr as u32)
+}
+
+// The address between `network` and `broadcast` (which is never handed out) given by the FNV-1a
+// hash of `name`, which stays the same across runs, builds and platforms.
+fn hashed_offset(network: u128, broadcast: u128, name: &str) -> u128 {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    network + u128::from(hash) % (broadcast - network)
+}
+
+/// What happens once the virtual DNS holds as many mappings as allowed and a new name is queried.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DnsEviction {
+    /// Evict the least recently used mapping, even if it has not expired yet.
+    #[default]
+    Lru,
+    /// Keep the mappings and fail the query.
+    None,
+}
+
+/// A static record of the virtual DNS, given as `name=IP`, e.g. `git.corp.example=10.0.0.5`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsRecord {
+    name: String,
+    addr: IpAddr,
+}
+
+impl FromStr for DnsRecord {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || Error::from(format!("`{s}` is not a DNS record of the form name=IP"));
+        let (name, addr) = s.split_once('=').ok_or_else(e)?;
+        let addr = IpAddr::from_str(addr).map_err(|_| e())?;
+        if name.is_empty() {
+            return Err(e());
+        }
+        Ok(Self {
+            name: name.into(),
+            addr,
+        })
+    }
+}
+
+impl DnsRecord {
+    /// The records of a hosts file, in which each line holds an IP address followed by its
+    /// names, as in `/etc/hosts`.
+    pub fn read_hosts(path: &Path) -> Result<Vec<Self>, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::from(format!("{}: {e}", path.display())))?;
+        let mut records = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let addr = IpAddr::from_str(addr).map_err(|_| {
+                let e = format!(
+                    "{}:{}: `{addr}` is not an IP address",
+                    path.display(),
+                    number + 1
+                );
+                Error::from(e)
+            })?;
+            for name in fields {
+                records.push(Self {
+                    name: name.into(),
+                    addr,
+                });
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// The address family to which the answers of the virtual DNS are limited. Queries for the other
+/// family are answered without records, so that applications fall back to the one given.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DnsFilter {
+    /// Answer A queries only.
+    Ipv4Only,
+    /// Answer AAAA queries only, which requires an IPv6 prefix.
+    Ipv6Only,
+}
+
+/// How the virtual DNS treats queries of DNSSEC-aware clients, which set the DO bit. Virtual
+/// answers cannot be signed, so that strict validators would reject them otherwise.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DnssecMode {
+    /// Answer them, but clear the DO bit of the response, so that the client sees a resolver
+    /// without DNSSEC support and downgrades.
+    Strip,
+    /// Forward them untouched to a real name server, like queries for excluded domains.
+    Forward,
+}
+
+/// What happens to multicast DNS and LLMNR queries, which are meant for the local network rather
+/// than for a proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalDnsPolicy {
+    /// Drop them silently.
+    Drop,
+    /// Send them out of the given interface and relay the unicast responses back.
+    Pass(String),
+    /// Answer them through the virtual DNS.
+    Answer,
+}
+
+/// A DNS query intercepted from the tunnel, as passed to the handler given through
+/// [`Options::with_dns_query_handler`](crate::Options::with_dns_query_handler).
+#[derive(Clone, Debug)]
+pub struct DnsQuery {
+    pub client: SocketAddr,
+    pub name: String,
+    /// The record type, e.g. 1 for A or 28 for AAAA
+    pub record_type: u16,
+    /// The virtual address the name is mapped to, unless the query is left to a real resolver
+    pub address: Option<IpAddr>,
+}
+
+impl DnsQuery {
+    pub(crate) fn parse(client: SocketAddr, data: &[u8]) -> Option<Self> {
+        if data.len() < 17 {
+            return None;
+        }
+        let (name, offset) = VirtualDns::parse_qname(data, 12)?;
+        let record_type = data.get(offset..offset + 2)?;
+        Some(Self {
+            client,
+            name,
+            record_type: u16::from_be_bytes([record_type[0], record_type[1]]),
+            address: None,
+        })
+    }
+}
+
+impl fmt::Display for DnsQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "client={} name={} type=", self.client, self.name)?;
+        match self.record_type {
+            1 => write!(f, "A")?,
+            28 => write!(f, "AAAA")?,
+            record_type => write!(f, "TYPE{record_type}")?,
+        }
+        match self.address {
+            Some(address) => write!(f, " address={address}"),
+            None => write!(f, " address=-"),
+        }
+    }
+}
+
+/// Counters of the DNS handling, shared with the embedder through
+/// [`Options::with_dns_stats`](crate::Options::with_dns_stats) and readable at any time, e.g.
+/// from another thread.
+#[derive(Default, Debug)]
+pub struct DnsStats {
+    queries_a: AtomicU64,
+    queries_aaaa: AtomicU64,
+    queries_other: AtomicU64,
+    forwarded: AtomicU64,
+    responses: AtomicU64,
+    failures: AtomicU64,
+    allocations: AtomicU64,
+    cache_hits: AtomicU64,
+    mappings: AtomicUsize,
+}
+
+impl DnsStats {
+    /// A queries captured from the tunnel.
+    pub fn queries_a(&self) -> u64 {
+        self.queries_a.load(Ordering::Relaxed)
+    }
+
+    /// AAAA queries captured from the tunnel.
+    pub fn queries_aaaa(&self) -> u64 {
+        self.queries_aaaa.load(Ordering::Relaxed)
+    }
+
+    /// Queries of any other type captured from the tunnel.
+    pub fn queries_other(&self) -> u64 {
+        self.queries_other.load(Ordering::Relaxed)
+    }
+
+    /// Queries passed on to an upstream resolver.
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Responses received from the upstream resolvers.
+    pub fn responses(&self) -> u64 {
+        self.responses.load(Ordering::Relaxed)
+    }
+
+    /// Responses of the upstream resolvers with the SERVFAIL or REFUSED code.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Names newly mapped to a virtual address.
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// Queries answered with the virtual address a name is mapped to already.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// The share of queries for virtual addresses answered from the existing mappings.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let hits = self.cache_hits() as f64;
+        match hits + self.allocations() as f64 {
+            total if total > 0.0 => hits / total,
+            _ => 0.0,
+        }
+    }
+
+    /// The number of mappings currently held by the virtual DNS.
+    pub fn mappings(&self) -> usize {
+        self.mappings.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_query(&self, record_type: u16) {
+        let counter = match record_type {
+            1 => &self.queries_a,
+            28 => &self.queries_aaaa,
+            _ => &self.queries_other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_forwarded(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_response(&self, response: &[u8]) {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        // SERVFAIL or REFUSED
+        if response.len() >= 4 && matches!(response[3] & 0x0f, 2 | 5) {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Display for DnsStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "queries_a={} queries_aaaa={} queries_other={} forwarded={} responses={} failures={} ",
+            self.queries_a(),
+            self.queries_aaaa(),
+            self.queries_other(),
+            self.forwarded(),
+            self.responses(),
+            self.failures()
+        )?;
+        write!(
+            f,
+            "allocations={} cache_hits={} hit_rate={:.0}% mappings={}",
+            self.allocations(),
+            self.cache_hits(),
+            100.0 * self.cache_hit_rate(),
+            self.mappings()
+        )
+    }
+}
+
+/// A file keeping the mappings of the virtual DNS across restarts, so that clients which still
+/// hold virtual addresses from before can keep connecting to them. The file is read up front, as
+/// privileges may be dropped by the time the mappings are written. It is replaced by a temporary
+/// file written next to it, so that its directory has to stay writable.
+pub struct VirtualDnsState {
+    path: PathBuf,
+    content: String,
+}
+
+impl VirtualDnsState {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let e = |error: String| Error::from(format!("{}: {error}", path.display()));
+        if path.file_name().is_none() {
+            return Err(e("not a file".to_string()));
+        }
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(e(error.to_string())),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            content,
+        })
+    }
+
+    /// The directory of the file, in which the temporary file is created.
+    pub fn directory(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    // Replace the file, which is never left partly written, even if the system crashes.
+    fn write(&self, content: &str) -> std::io::Result<()> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temporary = self.path.with_file_name(name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+        // The rename only persists once the directory has been synced as well.
+        File::open(self.directory())?.sync_all()
+    }
+}
+
+// A range of virtual addresses, which are handed out in turn.
+struct AddressPool {
     name_to_ip: HashMap<String, IpAddr>,
     network_addr: IpAddr,
     broadcast_addr: IpAddr,
     next_addr: IpAddr,
 }
 
+impl AddressPool {
+    fn new(network_addr: IpAddr, broadcast_addr: IpAddr) -> Self {
+        Self {
+            name_to_ip: Default::default(),
+            network_addr,
+            broadcast_addr,
+            next_addr: network_addr,
+        }
+    }
+
+    fn advance(&mut self) -> Option<()> {
+        self.next_addr = VirtualDns::increment_ip(self.next_addr)?;
+        if self.next_addr == self.broadcast_addr {
+            // Wrap around.
+            self.next_addr = self.network_addr;
+        }
+        Some(())
+    }
+
+    // Start looking for a vacant address at the one `name` hashes to.
+    fn seek_hashed(&mut self, name: &str) {
+        self.next_addr = match (self.network_addr, self.broadcast_addr) {
+            (IpAddr::V4(network), IpAddr::V4(broadcast)) => {
+                let addr =
+                    hashed_offset(u32::from(network).into(), u32::from(broadcast).into(), name);
+                IpAddr::V4(Ipv4Addr::from(addr as u32))
+            }
+            (IpAddr::V6(network), IpAddr::V6(broadcast)) => IpAddr::V6(Ipv6Addr::from(
+                hashed_offset(network.into(), broadcast.into(), name),
+            )),
+            _ => unreachable!(),
+        };
+    }
+}
+
+type EvictionHandler = Rc<dyn Fn(IpAddr, &str)>;
+
+pub struct VirtualDns {
+    lru_cache: LruCache<IpAddr, NameCacheEntry>,
+    ipv4: AddressPool,
+    ipv6: Option<AddressPool>,
+    ttl: u32,
+    mapping_timeout: u64,
+    excluded: Vec<String>,
+    state: Option<VirtualDnsState>,
+    // When the mappings are due to be written to the state file, once they have changed.
+    save_at: Option<Instant>,
+    filter: Option<DnsFilter>,
+    dnssec: Option<DnssecMode>,
+    records: HashMap<String, Vec<IpAddr>>,
+    max_mappings: Option<usize>,
+    eviction: DnsEviction,
+    on_evict: Option<EvictionHandler>,
+    hashed: bool,
+    stats: Option<Arc<DnsStats>>,
+}
+
 impl Default for VirtualDns {
     fn default() -> Self {
         let start_addr = Ipv4Addr::from_str("198.18.0.0").unwrap();
         let cidr = Ipv4Cidr::new(start_addr.into(), 15);
 
         Self {
-            next_addr: start_addr.into(),
-            name_to_ip: Default::default(),
-            network_addr: IpAddr::try_from(cidr.network().address().into_address()).unwrap(),
-            broadcast_addr: IpAddr::try_from(cidr.broadcast().unwrap().into_address()).unwrap(),
+            ipv4: AddressPool::new(
+                IpAddr::try_from(cidr.network().address().into_address()).unwrap(),
+                IpAddr::try_from(cidr.broadcast().unwrap().into_address()).unwrap(),
+            ),
+            ipv6: None,
             lru_cache: LruCache::new_unbounded(),
+            ttl: DNS_TTL,
+            mapping_timeout: MAPPING_TIMEOUT,
+            excluded: Vec::new(),
+            state: None,
+            save_at: None,
+            filter: None,
+            dnssec: None,
+            records: HashMap::new(),
+            max_mappings: None,
+            eviction: DnsEviction::default(),
+            on_evict: None,
+            hashed: false,
+            stats: None,
         }
     }
 }
@@ -56,7 +551,257 @@ impl VirtualDns {
         Default::default()
     }
 
+    /// Answer AAAA queries with addresses from `prefix` instead of leaving them unanswered.
+    pub fn set_ipv6_prefix(&mut self, prefix: Ipv6Prefix) {
+        let last_addr = u128::from(prefix.addr) | (u128::MAX >> prefix.len);
+        self.ipv6 = Some(AddressPool::new(
+            IpAddr::V6(prefix.addr),
+            IpAddr::V6(Ipv6Addr::from(last_addr)),
+        ));
+    }
+
+    /// Answer queries for the name of `record` with its address, besides those of other records
+    /// for the same name.
+    pub fn add_record(&mut self, record: DnsRecord) {
+        let name = record.name.trim_end_matches('.').to_ascii_lowercase();
+        let addrs = self.records.entry(name).or_default();
+        if !addrs.contains(&record.addr) {
+            addrs.push(record.addr);
+        }
+    }
+
+    /// Hold at most `max` mappings, making room for new ones as given by `eviction`.
+    pub fn set_max_mappings(&mut self, max: usize, eviction: DnsEviction) {
+        self.max_mappings = Some(max);
+        self.eviction = eviction;
+    }
+
+    /// Call `handler` with the address and name of each mapping which is evicted before it has
+    /// expired, as clients may still use the address.
+    pub fn set_eviction_handler(&mut self, handler: EvictionHandler) {
+        self.on_evict = Some(handler);
+    }
+
+    pub fn set_filter(&mut self, filter: DnsFilter) {
+        self.filter = Some(filter);
+    }
+
+    pub fn set_dnssec(&mut self, mode: DnssecMode) {
+        self.dnssec = Some(mode);
+    }
+
+    /// Map names to addresses derived from their hash rather than handing addresses out in turn,
+    /// so that a name gets the same address across runs and instances.
+    pub fn set_hashed(&mut self) {
+        self.hashed = true;
+    }
+
+    pub fn set_stats(&mut self, stats: Arc<DnsStats>) {
+        stats
+            .mappings
+            .store(self.lru_cache.len(), Ordering::Relaxed);
+        self.stats = Some(stats);
+    }
+
+    // Keep the number of mappings of the statistics up to date.
+    fn count_mappings(&self) {
+        if let Some(stats) = &self.stats {
+            stats
+                .mappings
+                .store(self.lru_cache.len(), Ordering::Relaxed);
+        }
+    }
+
+    // Whether the address family of `addr` is not filtered out.
+    fn is_answered_with(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(_) => self.filter != Some(DnsFilter::Ipv6Only),
+            IpAddr::V6(_) => self.filter != Some(DnsFilter::Ipv4Only),
+        }
+    }
+
+    // Whether the queries of `record_type` are answered with an address.
+    fn is_answered(&self, record_type: u16) -> bool {
+        if record_type == DnsRecordType::A as u16 {
+            self.filter != Some(DnsFilter::Ipv6Only)
+        } else if record_type == DnsRecordType::AAAA as u16 {
+            self.ipv6.is_some() && self.filter != Some(DnsFilter::Ipv4Only)
+        } else {
+            false
+        }
+    }
+
+    /// Advertise `ttl` seconds as the TTL of answers.
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+
+    /// Keep mappings which are neither queried nor used by connections for `timeout` seconds.
+    /// Mappings are kept at least for the advertised TTL, during which clients may still connect
+    /// to the address without querying it again.
+    pub fn set_mapping_timeout(&mut self, timeout: u64) {
+        self.mapping_timeout = timeout;
+    }
+
+    /// The address `name` is currently mapped to for queries of `record_type`.
+    pub fn lookup(&self, name: &str, record_type: u16) -> Option<IpAddr> {
+        if let Some(records) = self.records.get(&name.to_ascii_lowercase()) {
+            let ipv6 = record_type == DnsRecordType::AAAA as u16;
+            return records.iter().copied().find(|addr| addr.is_ipv6() == ipv6);
+        }
+        let pool = match record_type {
+            1 => &self.ipv4,
+            28 => self.ipv6.as_ref()?,
+            _ => return None,
+        };
+        pool.name_to_ip.get(name).copied()
+    }
+
+    /// Leave queries for `domain`, e.g. `corp.example`, or for its subdomains if given as e.g.
+    /// `*.corp.example`, to a real name server instead of answering them.
+    pub fn exclude(&mut self, domain: &str) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.excluded.push(domain);
+    }
+
+    /// Whether some queries are left to a real name server.
+    pub fn has_exclusions(&self) -> bool {
+        !self.excluded.is_empty() || self.dnssec == Some(DnssecMode::Forward)
+    }
+
+    /// Whether the query is for an excluded domain or, if DNSSEC queries are forwarded, has the
+    /// DO bit set. Queries for the address family filtered out and for names with static records
+    /// are answered all the same.
+    pub fn is_excluded(&self, data: &[u8]) -> bool {
+        if data.len() < 17 || !self.has_exclusions() {
+            return false;
+        }
+        let (name, offset) = match VirtualDns::parse_qname(data, 12) {
+            Some((name, offset)) => (name.to_ascii_lowercase(), offset),
+            None => return false,
+        };
+        // Static records take precedence.
+        if self.records.contains_key(&name) {
+            return false;
+        }
+        let filtered = matches!(
+            (data.get(offset..offset + 2), self.filter),
+            (Some([0, 1]), Some(DnsFilter::Ipv6Only)) | (Some([0, 28]), Some(DnsFilter::Ipv4Only))
+        );
+        if filtered {
+            return false;
+        }
+        let dnssec_ok = || Edns::parse(data, offset + 4).is_some_and(|edns| edns.dnssec_ok);
+        if self.dnssec == Some(DnssecMode::Forward) && dnssec_ok() {
+            return true;
+        }
+        self.excluded
+            .iter()
+            .any(|domain| matches_domain(domain, &name))
+    }
+
+    /// Restore the mappings of `state` and keep it up to date from now on. Restored mappings
+    /// outside the configured address ranges are dropped.
+    pub fn set_state(&mut self, mut state: VirtualDnsState) {
+        let content = std::mem::take(&mut state.content);
+        // Mappings are given the full lifetime again, as they may be in use.
+        let expiry = self.expiry();
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let (ip, name) = match (fields.next(), fields.next()) {
+                (Some(ip), Some(name)) => (ip, name),
+                _ => continue,
+            };
+            let ip = match IpAddr::from_str(ip) {
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            let pool = match (ip, &mut self.ipv6) {
+                (IpAddr::V6(_), Some(pool)) => pool,
+                (IpAddr::V6(_), None) => continue,
+                (IpAddr::V4(_), _) => &mut self.ipv4,
+            };
+            if ip < pool.network_addr || ip >= pool.broadcast_addr {
+                continue;
+            }
+            let name = name.to_string();
+            if let Some(previous) = self.lru_cache.insert(
+                ip,
+                NameCacheEntry {
+                    name: name.clone(),
+                    expiry,
+                },
+            ) {
+                pool.name_to_ip.remove(&previous.name);
+            }
+            pool.name_to_ip.insert(name, ip);
+        }
+        if !self.lru_cache.is_empty() {
+            log::info!("Restored {} virtual DNS mappings", self.lru_cache.len());
+        }
+        self.state = Some(state);
+    }
+
+    // Have the mappings written to the state file a while after they first change, so that a
+    // burst of changes is written at once.
+    fn state_changed(&mut self) {
+        if self.state.is_some() && self.save_at.is_none() {
+            self.save_at = Some(Instant::now() + STATE_SAVE_DELAY);
+        }
+    }
+
+    /// When the mappings are due to be written through `save_state`, if they have changed.
+    pub fn next_save(&self) -> Option<Instant> {
+        self.save_at
+    }
+
+    /// Write the mappings to the state file if they have changed since the last time.
+    pub fn save_state(&mut self) {
+        let state = match &self.state {
+            Some(state) if self.save_at.is_some() => state,
+            _ => return,
+        };
+        self.save_at = None;
+        let mut content = String::new();
+        for (ip, entry) in self.lru_cache.iter() {
+            content.push_str(&format!("{ip} {}\n", entry.name));
+        }
+        if let Err(error) = state.write(&content) {
+            log::warn!("Virtual DNS mappings could not be saved: {error}");
+        }
+    }
+
+    fn expiry(&self) -> Instant {
+        Instant::now() + Duration::from_secs(self.mapping_timeout.max(self.ttl.into()))
+    }
+
     pub fn receive_query(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.answer_query(data, false)
+    }
+
+    /// Answer a multicast DNS or LLMNR query like any other. Such queries do not ask for recursion
+    /// and, in the case of mDNS, may ask for a unicast response through the top bit of the class.
+    pub fn receive_local_query(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 17 {
+            return None;
+        }
+        let mut query = data.to_vec();
+        let (_, offset) = VirtualDns::parse_qname(&query, 12)?;
+        *query.get_mut(offset + 2)? &= 0x7f; // Unicast response requested
+        query[2] |= 0x01; // Recursion desired
+        let mut response = self.receive_query(&query)?;
+        response[2] &= !0x01; // Recursion not desired
+        response[2] |= 0x04; // Answer is authoritative
+        response[3] &= !0x80; // Recursion not available
+        Some(response)
+    }
+
+    /// Queries received over TCP are answered in full, as the client does not have to retry.
+    pub fn receive_tcp_query(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.answer_query(data, true)
+    }
+
+    fn answer_query(&mut self, data: &[u8], over_tcp: bool) -> Option<Vec<u8>> {
         if data.len() < 17 {
             return None;
         }
@@ -79,30 +824,55 @@ impl VirtualDns {
         let qtype = (data[offset] as u16) << 8 | data[offset + 1] as u16;
         let qclass = (data[offset + 2] as u16) << 8 | data[offset + 3] as u16;
 
-        if qtype != DnsRecordType::A as u16 && qtype != DnsRecordType::AAAA as u16
+        if qtype != DnsRecordType::A as u16
+            && qtype != DnsRecordType::AAAA as u16
+            && qtype != DnsRecordType::PTR as u16
             || qclass != DnsClass::IN as u16
         {
             return None;
         }
 
-        if qtype == DnsRecordType::A as u16 {
-            log::info!("DNS query: {}", qname);
+        // Reverse queries for virtual addresses are answered with the names mapped to them, while
+        // those for other addresses are left to time out as before.
+        let reverse = qtype == DnsRecordType::PTR as u16;
+        let mapped_name = match VirtualDns::parse_reverse_name(&qname) {
+            Some(ip) if reverse && self.is_virtual(&ip) => {
+                self.lru_cache.peek(&ip).map(|entry| entry.name.clone())
+            }
+            _ if reverse => return None,
+            _ => None,
+        };
+
+        // Queries of EDNS versions other than 0 are rejected with BADVERS.
+        let edns = Edns::parse(data, offset + 4);
+        let bad_version = edns.as_ref().is_some_and(|edns| edns.version > 0);
+
+        // Names with static records are answered with their addresses of the family queried, if
+        // any, instead of virtual addresses.
+        let records = match self.records.get(&qname.to_ascii_lowercase()) {
+            Some(records) if !bad_version && !reverse => Some(
+                records
+                    .iter()
+                    .copied()
+                    .filter(|addr| addr.is_ipv6() == (qtype == DnsRecordType::AAAA as u16))
+                    .filter(|addr| self.is_answered_with(addr))
+                    .collect::<Vec<_>>(),
+            ),
+            _ => None,
+        };
+
+        // AAAA queries are only answered with virtual IPv6 addresses if a prefix is configured,
+        // and queries of the family filtered out are not answered at all.
+        let answered = records.is_none() && !bad_version && self.is_answered(qtype);
+        if answered {
+            log::debug!("DNS query: {}", qname);
         }
 
         let mut response = Vec::<u8>::new();
         response.extend(&data[0..offset + 4]);
         response[2] |= 0x80; // Message is a response
         response[3] |= 0x80; // Recursion available
-
-        // Record count of the answer section:
-        // We only send an answer record for A queries, assuming that IPv4 is supported everywhere.
-        // This way, we do not have to handle two IP spaces for the virtual DNS feature.
-        response[6] = 0;
-        response[7] = if qtype == DnsRecordType::A as u16 {
-            1
-        } else {
-            0
-        };
+        response[3] &= !0x20; // Data is not authenticated, whatever the client asks for
 
         // Zero count of other sections:
         // authority section
@@ -112,29 +882,87 @@ impl VirtualDns {
         // additional section
         response[10] = 0;
         response[11] = 0;
-        if qtype == DnsRecordType::A as u16 {
-            if let Some(ip) = self.allocate_ip(qname) {
-                response.extend(&[
-                    0xc0, 0x0c, // Question name pointer
-                    0, 1, // Record type: A
-                    0, 1, // Class: IN
-                    0, 0, 0, DNS_TTL, // TTL
-                    0, 4, // Data length: 4 bytes
-                ]);
-                match ip as IpAddr {
-                    IpAddr::V4(ip) => response.extend(ip.octets().as_ref()),
-                    IpAddr::V6(ip) => response.extend(ip.octets().as_ref()),
-                };
-            } else {
-                log::error!("Virtual IP space for DNS exhausted");
-                response[7] = 0; // No answers
 
-                // Set rcode to SERVFAIL
-                response[3] &= 0xf0;
-                response[3] |= 2;
+        // Unless virtual IPv6 addresses are configured, we only send an answer record for A
+        // queries, assuming that IPv4 is supported everywhere.
+        let address_data = |ip: &IpAddr| match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let answers = match records {
+            Some(records) => {
+                response[2] |= 0x04; // Answer is authoritative
+                records.iter().map(address_data).collect()
             }
-        } else {
+            None if answered => {
+                match self.allocate_ip(qname, qtype == DnsRecordType::AAAA as u16) {
+                    Some(ip) => vec![address_data(&ip)],
+                    None => {
+                        log::error!("Virtual IP space for DNS exhausted");
+
+                        // Set rcode to SERVFAIL
+                        response[3] &= 0xf0;
+                        response[3] |= 2;
+                        Vec::new()
+                    }
+                }
+            }
+            None if reverse && !bad_version => {
+                response[2] |= 0x04; // Answer is authoritative
+                match mapped_name {
+                    Some(name) => vec![VirtualDns::encode_name(&name)],
+                    None => {
+                        // Set rcode to NXDOMAIN
+                        response[3] &= 0xf0;
+                        response[3] |= 3;
+                        Vec::new()
+                    }
+                }
+            }
+            None => Vec::new(),
+        };
+
+        // Record count of the answer section
+        let count = answers.len() as u16;
+        response[6..8].copy_from_slice(&count.to_be_bytes());
+        for answer in answers {
+            response.extend(&[
+                0xc0,
+                0x0c, // Question name pointer
+                data[offset],
+                data[offset + 1], // Record type: A, AAAA or PTR
+                0,
+                1, // Class: IN
+            ]);
+            response.extend(self.ttl.to_be_bytes()); // TTL
+            response.extend((answer.len() as u16).to_be_bytes()); // Data length
+            response.extend(answer);
+        }
+
+        // A response the client cannot receive is truncated to the question, so that the client
+        // may retry over TCP.
+        let (max_size, opt_size) = match &edns {
+            Some(edns) => (MAX_UDP_SIZE.max(edns.udp_size.into()), EDNS_OPT_SIZE),
+            None => (MAX_UDP_SIZE, 0),
+        };
+        if !over_tcp && response.len() + opt_size > max_size {
+            response.truncate(offset + 4);
+            response[6] = 0;
             response[7] = 0; // No answers
+            response[2] |= 0x02; // Message is truncated
+        }
+
+        if let Some(edns) = edns {
+            response[11] = 1; // OPT record in the additional section
+            response.push(0); // Root domain
+            response.extend(DNS_TYPE_OPT.to_be_bytes()); // Record type: OPT
+            response.extend(EDNS_UDP_SIZE.to_be_bytes()); // UDP payload size
+            let extended_rcode = if bad_version { 1 } else { 0 }; // BADVERS
+                                                                  // DNSSEC OK bit as in the query, unless the client is to downgrade
+            let dnssec_ok = edns.dnssec_ok && self.dnssec != Some(DnssecMode::Strip);
+            let flags = if dnssec_ok { 0x80 } else { 0 };
+            response.extend(&[extended_rcode, 0, flags, 0]); // Upper rcode bits, version 0, flags
+            response.extend(&[0, 0]); // No options
         }
         Some(response)
     }
@@ -171,10 +999,11 @@ impl VirtualDns {
     // which connects the tun interface to the client, so existing IP address to name
     // mappings to not expire as long as the connection is active.
     pub fn touch_ip(&mut self, addr: &IpAddr) -> bool {
+        let expiry = self.expiry();
         match self.lru_cache.get_mut(addr) {
             None => false,
             Some(entry) => {
-                entry.expiry = Instant::now() + Duration::from_secs(MAPPING_TIMEOUT);
+                entry.expiry = expiry;
                 true
             }
         }
@@ -187,7 +1016,18 @@ impl VirtualDns {
         }
     }
 
-    fn allocate_ip(&mut self, name: String) -> Option<IpAddr> {
+    fn remove_mapping(&mut self, ip: &IpAddr) {
+        if let Some(entry) = self.lru_cache.remove(ip) {
+            match (ip, &mut self.ipv6) {
+                (IpAddr::V6(_), Some(pool)) => pool.name_to_ip.remove(&entry.name),
+                _ => self.ipv4.name_to_ip.remove(&entry.name),
+            };
+            self.count_mappings();
+            self.state_changed();
+        }
+    }
+
+    fn allocate_ip(&mut self, name: String, ipv6: bool) -> Option<IpAddr> {
         let now = Instant::now();
 
         loop {
@@ -197,47 +1037,129 @@ impl VirtualDns {
             }
             let (ip, entry) = p.unwrap();
             if now > entry.expiry {
-                let name = entry.name.clone();
-                self.lru_cache.remove(&ip.clone());
-                self.name_to_ip.remove(&name);
+                let ip = *ip;
+                self.remove_mapping(&ip);
                 continue;
             }
             break;
         }
 
-        if let Some(ip) = self.name_to_ip.get(&name) {
-            let result = Some(*ip);
-            self.touch_ip(&ip.clone());
-            return result;
+        let pool = match ipv6 {
+            true => self.ipv6.as_ref()?,
+            false => &self.ipv4,
+        };
+        if let Some(&ip) = pool.name_to_ip.get(&name) {
+            self.touch_ip(&ip);
+            if let Some(stats) = &self.stats {
+                stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            return Some(ip);
         }
 
-        let started_at = self.next_addr;
+        // Make room for the new mapping once the table is full, starting with the mapping which
+        // has been used least recently.
+        while self
+            .max_mappings
+            .is_some_and(|max| self.lru_cache.len() >= max)
+        {
+            let (ip, evicted) = match self.lru_cache.iter().next() {
+                Some((ip, entry)) if self.eviction == DnsEviction::Lru => (*ip, entry.name.clone()),
+                _ => return None,
+            };
+            self.remove_mapping(&ip);
+            log::warn!("Mapping of {evicted} to {ip} evicted before it expired");
+            if let Some(handler) = &self.on_evict {
+                handler(ip, &evicted);
+            }
+        }
+
+        let expiry = self.expiry();
+        let pool = match ipv6 {
+            true => self.ipv6.as_mut()?,
+            false => &mut self.ipv4,
+        };
+
+        if self.hashed {
+            pool.seek_hashed(&name);
+        }
+        let started_at = pool.next_addr;
 
         loop {
             if let RawEntryMut::Vacant(vacant) =
-                self.lru_cache.raw_entry_mut().from_key(&self.next_addr)
+                self.lru_cache.raw_entry_mut().from_key(&pool.next_addr)
             {
-                let expiry = Instant::now() + Duration::from_secs(MAPPING_TIMEOUT);
                 vacant.insert(
-                    self.next_addr,
+                    pool.next_addr,
                     NameCacheEntry {
                         name: name.clone(),
                         expiry,
                     },
                 );
                 // e.insert(name.clone());
-                self.name_to_ip.insert(name, self.next_addr);
-                return Some(self.next_addr);
+                let ip = pool.next_addr;
+                pool.name_to_ip.insert(name, ip);
+                // Addresses are handed out in turn, so that freed ones are reused as late as
+                // possible.
+                pool.advance()?;
+                self.state_changed();
+                if let Some(stats) = &self.stats {
+                    stats.allocations.fetch_add(1, Ordering::Relaxed);
+                }
+                self.count_mappings();
+                return Some(ip);
+            }
+            pool.advance()?;
+            if pool.next_addr == started_at {
+                return None;
+            }
+        }
+    }
+
+    // Whether `ip` lies within the ranges of virtual addresses.
+    fn is_virtual(&self, ip: &IpAddr) -> bool {
+        let contains = |pool: &AddressPool| pool.network_addr <= *ip && *ip <= pool.broadcast_addr;
+        contains(&self.ipv4) || self.ipv6.as_ref().is_some_and(contains)
+    }
+
+    /// The address of a reverse name, e.g. `4.3.2.1.in-addr.arpa` for `1.2.3.4`, or the name of
+    /// an IPv6 address in nibbles under `ip6.arpa`.
+    fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+        let name = name.to_ascii_lowercase();
+        if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+            let labels: Vec<&str> = labels.split('.').collect();
+            if labels.len() != 4 {
+                return None;
             }
-            self.next_addr = Self::increment_ip(self.next_addr)?;
-            if self.next_addr == self.broadcast_addr {
-                // Wrap around.
-                self.next_addr = self.network_addr;
+            let mut octets = [0; 4];
+            for (octet, label) in octets.iter_mut().zip(labels.iter().rev()) {
+                *octet = u8::from_str(label).ok()?;
             }
-            if self.next_addr == started_at {
+            return Some(IpAddr::from(octets));
+        }
+        let labels: Vec<&str> = name.strip_suffix(".ip6.arpa")?.split('.').collect();
+        if labels.len() != 32 {
+            return None;
+        }
+        let mut addr = 0u128;
+        for label in labels.iter().rev() {
+            if label.len() != 1 {
                 return None;
             }
+            addr = addr << 4 | u128::from_str_radix(label, 16).ok()?;
         }
+        Some(IpAddr::V6(Ipv6Addr::from(addr)))
+    }
+
+    /// Encode `name` as a sequence of labels, taking each character for a byte as the name was
+    /// parsed by `parse_qname`.
+    fn encode_name(name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        for label in name.split('.') {
+            data.push(label.chars().count() as u8);
+            data.extend(label.chars().map(|c| c as u8));
+        }
+        data.push(0);
+        data
     }
 
     /// Parse a non-root DNS qname at a specific offset and return the name along with its size.
@@ -279,3 +1201,127 @@ impl VirtualDns {
         Some((qname, offset))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: u8 = 0x20;
+    const CD: u8 = 0x10;
+
+    // A query with recursion desired, the given flags of the fourth header byte and, if a UDP
+    // payload size is given, an OPT record with or without the DO bit.
+    fn query(name: &str, qtype: DnsRecordType, flags: u8, edns: Option<(u16, bool)>) -> Vec<u8> {
+        let mut query = vec![0x4a, 0x21, 0x01, flags, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend(VirtualDns::encode_name(name));
+        query.extend((qtype as u16).to_be_bytes());
+        query.extend((DnsClass::IN as u16).to_be_bytes());
+        if let Some((udp_size, dnssec_ok)) = edns {
+            query[11] = 1;
+            query.push(0);
+            query.extend(DNS_TYPE_OPT.to_be_bytes());
+            query.extend(udp_size.to_be_bytes());
+            let flags = if dnssec_ok { 0x80 } else { 0 };
+            query.extend(&[0, 0, flags, 0, 0, 0]);
+        }
+        query
+    }
+
+    // As systemd-resolved asks in its default allow-downgrade mode.
+    fn resolved_query(name: &str) -> Vec<u8> {
+        query(name, DnsRecordType::A, 0, Some((1200, true)))
+    }
+
+    // As unbound asks when forwarding, validating answers itself.
+    fn unbound_query(name: &str) -> Vec<u8> {
+        query(name, DnsRecordType::A, CD, Some((1232, true)))
+    }
+
+    // As `dig +dnssec` asks.
+    fn dig_query(name: &str) -> Vec<u8> {
+        query(name, DnsRecordType::A, AD, Some((1232, true)))
+    }
+
+    fn dnssec_ok(response: &[u8]) -> bool {
+        assert_eq!(response[10..12], [0, 1]);
+        response[response.len() - 4] & 0x80 != 0
+    }
+
+    fn answer(response: &[u8]) -> Ipv4Addr {
+        assert_eq!(response[3] & 0x0f, 0);
+        assert_eq!(response[6..8], [0, 1]);
+        let end = response.len() - EDNS_OPT_SIZE;
+        let octets: [u8; 4] = response[end - 4..end].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    #[test]
+    fn strips_dnssec_of_validating_resolvers() {
+        let mut dns = VirtualDns::new();
+        dns.set_dnssec(DnssecMode::Strip);
+        for query in [
+            resolved_query("example.com"),
+            unbound_query("example.com"),
+            dig_query("example.com"),
+        ] {
+            assert!(!dns.is_excluded(&query));
+            let response = dns.receive_query(&query).unwrap();
+            assert_eq!(response[..2], query[..2]);
+            assert_eq!(response[3] & AD, 0);
+            assert!(!dnssec_ok(&response));
+            let ip = dns.lookup("example.com", DnsRecordType::A as u16);
+            assert_eq!(Some(IpAddr::V4(answer(&response))), ip);
+        }
+    }
+
+    #[test]
+    fn forwards_dnssec_of_validating_resolvers() {
+        let mut dns = VirtualDns::new();
+        dns.set_dnssec(DnssecMode::Forward);
+        assert!(dns.has_exclusions());
+        assert!(dns.is_excluded(&resolved_query("example.com")));
+        assert!(dns.is_excluded(&unbound_query("example.com")));
+        assert!(dns.is_excluded(&dig_query("example.com")));
+
+        // Queries without the DO bit are answered as usual.
+        let plain = query("example.com", DnsRecordType::A, 0, Some((1232, false)));
+        assert!(!dns.is_excluded(&plain));
+        assert!(!dns.is_excluded(&query("example.com", DnsRecordType::A, 0, None)));
+        let response = dns.receive_query(&plain).unwrap();
+        assert!(!dnssec_ok(&response));
+        answer(&response);
+    }
+
+    #[test]
+    fn answers_static_records_of_validating_resolvers() {
+        let mut dns = VirtualDns::new();
+        dns.set_dnssec(DnssecMode::Forward);
+        dns.add_record("git.corp.example=10.0.0.5".parse().unwrap());
+        let query = resolved_query("git.corp.example");
+        assert!(!dns.is_excluded(&query));
+        let response = dns.receive_query(&query).unwrap();
+        assert_eq!(response[3] & AD, 0);
+        assert_eq!(answer(&response), Ipv4Addr::new(10, 0, 0, 5));
+    }
+
+    #[test]
+    fn never_claims_authenticated_data() {
+        let mut dns = VirtualDns::new();
+        let response = dns.receive_query(&dig_query("example.com")).unwrap();
+        assert_eq!(response[3] & AD, 0);
+        // Without a DNSSEC mode, the DO bit is echoed as before.
+        assert!(dnssec_ok(&response));
+        answer(&response);
+    }
+
+    #[test]
+    fn rejects_other_edns_versions() {
+        let mut dns = VirtualDns::new();
+        let mut query = resolved_query("example.com");
+        let version = query.len() - 5;
+        query[version] = 1;
+        let response = dns.receive_query(&query).unwrap();
+        assert_eq!(response[6..8], [0, 0]);
+        assert_eq!(response[response.len() - 6], 1); // BADVERS
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::Error;
+use crate::tls::{TlsConfig, TlsConnection};
+use crate::tun2proxy::{
+    Connection, ConnectionManager, Direction, IncomingDataEvent, IncomingDirection,
+    OutgoingDataEvent, OutgoingDirection, OutgoingSlices, TcpProxy,
+};
+use crate::vmess::{extend_destination, parse_uuid};
+use crate::Credentials;
+use smoltcp::wire::IpProtocol;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+const VERSION: u8 = 0;
+const COMMAND_TCP: u8 = 1;
+
+#[derive(Eq, PartialEq, Debug)]
+enum VlessState {
+    ExpectResponseHeader,
+    Established,
+}
+
+pub(crate) struct VlessConnection {
+    state: VlessState,
+    server_inbuf: VecDeque<u8>,
+    client_outbuf: VecDeque<u8>,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl VlessConnection {
+    fn new(connection: &Connection, id: &[u8; 16]) -> Self {
+        let mut header = vec![VERSION];
+        header.extend(id);
+        header.extend([0, COMMAND_TCP]); // No addons
+        extend_destination(&mut header, &connection.dst);
+        Self {
+            state: VlessState::ExpectResponseHeader,
+            server_inbuf: VecDeque::default(),
+            client_outbuf: VecDeque::default(),
+            server_outbuf: header.into(),
+        }
+    }
+
+    fn receive_response_header(&mut self) -> Result<(), Error> {
+        if self.server_inbuf.len() < 2 {
+            return Ok(());
+        }
+        if self.server_inbuf[0] != VERSION {
+            return Err("VLESS server replied with an unexpected version.".into());
+        }
+        let header_size = 2 + self.server_inbuf[1] as usize;
+        if self.server_inbuf.len() < header_size {
+            return Ok(());
+        }
+        self.server_inbuf.drain(0..header_size);
+        self.client_outbuf.extend(self.server_inbuf.drain(..));
+        self.state = VlessState::Established;
+        Ok(())
+    }
+}
+
+impl TcpProxy for VlessConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => match self.state {
+                VlessState::ExpectResponseHeader => {
+                    self.server_inbuf.extend(event.buffer.iter());
+                    self.receive_response_header()
+                }
+                VlessState::Established => {
+                    self.client_outbuf.extend(event.buffer.iter());
+                    Ok(())
+                }
+            },
+            IncomingDirection::FromClient => {
+                self.server_outbuf.extend(event.buffer.iter());
+                Ok(())
+            }
+        }
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        buffer.drain(0..size);
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        OutgoingDataEvent {
+            direction: dir,
+            buffer: buffer.make_contiguous(),
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &self.server_outbuf
+        } else {
+            &self.client_outbuf
+        };
+        buffer.into()
+    }
+
+    fn connection_established(&self) -> bool {
+        self.state == VlessState::Established
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Incoming(incoming) => match incoming {
+                IncomingDirection::FromServer => !self.server_inbuf.is_empty(),
+                IncomingDirection::FromClient => false,
+            },
+            Direction::Outgoing(outgoing) => match outgoing {
+                OutgoingDirection::ToServer => !self.server_outbuf.is_empty(),
+                OutgoingDirection::ToClient => !self.client_outbuf.is_empty(),
+            },
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+pub(crate) struct VlessManager {
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+    id: [u8; 16],
+    tls: Option<TlsConfig>,
+}
+
+impl ConnectionManager for VlessManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        connection.proto == IpProtocol::Tcp
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if connection.proto != IpProtocol::Tcp {
+            return Ok(None);
+        }
+        let handler = Box::new(VlessConnection::new(connection, &self.id));
+        match &self.tls {
+            None => Ok(Some(handler)),
+            Some(tls) => Ok(Some(Box::new(TlsConnection::new(handler, tls)?))),
+        }
+    }
+
+    fn close_connection(&self, _: &Connection) {}
+
+    fn get_server(&self) -> SocketAddr {
+        self.server
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        &self.credentials
+    }
+}
+
+impl VlessManager {
+    pub fn new(
+        server: SocketAddr,
+        credentials: Option<Credentials>,
+        tls: Option<TlsConfig>,
+    ) -> Result<Rc<Self>, Error> {
+        let username = credentials
+            .as_ref()
+            .map(|credentials| credentials.username.clone())
+            .ok_or("VLESS requires a user ID")?;
+        let id = parse_uuid(std::str::from_utf8(&username)?)?;
+        Ok(Rc::new(Self {
+            server,
+            credentials,
+            id,
+            tls,
+        }))
+    }
+}
@@ -0,0 +1,671 @@
+use crate::error::Error;
+use crate::tun2proxy::{
+    Connection, ConnectionManager, Destination, DestinationHost, Direction, IncomingDataEvent,
+    IncomingDirection, OutgoingDataEvent, OutgoingDirection, OutgoingSlices, TcpProxy,
+};
+use crate::Credentials;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use smoltcp::wire::IpProtocol;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const VERSION: u8 = 1;
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const COMMAND_TCP: u8 = 1;
+const MAX_CHUNK_SIZE: usize = 0x3fff;
+const TAG_SIZE: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum VmessSecurity {
+    Aes128Gcm = 3,
+    Chacha20Poly1305 = 4,
+    None = 5,
+}
+
+impl std::str::FromStr for VmessSecurity {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-128-gcm" => Ok(VmessSecurity::Aes128Gcm),
+            "chacha20-poly1305" => Ok(VmessSecurity::Chacha20Poly1305),
+            "none" => Ok(VmessSecurity::None),
+            _ => Err(format!("`{s}` is an invalid VMess security type").into()),
+        }
+    }
+}
+
+/// Parse a user ID in the usual 8-4-4-4-12 hexadecimal UUID notation.
+pub(crate) fn parse_uuid(s: &str) -> Result<[u8; 16], Error> {
+    let e = format!("`{s}` is not a valid UUID");
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+    if hex.len() != 32 || s.len() != 36 {
+        return Err(e.into());
+    }
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        let digits = std::str::from_utf8(&hex[2 * i..2 * i + 2]).map_err(|_| Error::from(&e))?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| Error::from(&e))?;
+    }
+    Ok(uuid)
+}
+
+/// Append the port and address of a destination in the encoding shared by VMess and VLESS.
+pub(crate) fn extend_destination(buffer: &mut Vec<u8>, dst: &Destination) {
+    buffer.extend(dst.port.to_be_bytes());
+    match &dst.host {
+        DestinationHost::Address(IpAddr::V4(ip)) => {
+            buffer.push(1);
+            buffer.extend(ip.octets());
+        }
+        DestinationHost::Hostname(host) => {
+            buffer.extend([2, host.len() as u8]);
+            buffer.extend(host.as_bytes());
+        }
+        DestinationHost::Address(IpAddr::V6(ip)) => {
+            buffer.push(3);
+            buffer.extend(ip.octets());
+        }
+    }
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    Ok(bytes)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn hmac(hash: &dyn Fn(&[u8]) -> [u8; 32], key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut padded_key = [0u8; 64];
+    if key.len() > padded_key.len() {
+        padded_key[..32].copy_from_slice(&hash(key));
+    } else {
+        padded_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = padded_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend(message);
+    let mut outer: Vec<u8> = padded_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(hash(&inner));
+    hash(&outer)
+}
+
+/// The VMess key derivation function. Every path element adds a layer of HMAC which uses the
+/// HMAC of the previous layer as its hash function.
+fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    fn nested_hash(keys: &[&[u8]], message: &[u8]) -> [u8; 32] {
+        match keys.split_last() {
+            None => sha256(message),
+            Some((key, parents)) => hmac(&|m| nested_hash(parents, m), key, message),
+        }
+    }
+    let mut keys: Vec<&[u8]> = vec![b"VMess AEAD KDF"];
+    keys.extend(path);
+    nested_hash(&keys, key)
+}
+
+fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    result.copy_from_slice(&kdf(key, path)[..16]);
+    result
+}
+
+fn kdf12(key: &[u8], path: &[&[u8]]) -> [u8; 12] {
+    let mut result = [0u8; 12];
+    result.copy_from_slice(&kdf(key, path)[..12]);
+    result
+}
+
+fn fnv1a32(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn aes_128_gcm_seal(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes128Gcm::new_from_slice(key).map_err(|_| Error::from("invalid key length"))?;
+    cipher
+        .encrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| "VMess encryption failed".into())
+}
+
+fn aes_128_gcm_open(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes128Gcm::new_from_slice(key).map_err(|_| Error::from("invalid key length"))?;
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| "VMess decryption failed".into())
+}
+
+/// The key of a user ID by which requests are authenticated and their headers encrypted.
+fn cmd_key(id: &[u8; 16]) -> [u8; 16] {
+    let mut cmd_key_source = id.to_vec();
+    cmd_key_source.extend(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+    Md5::digest(&cmd_key_source).into()
+}
+
+/// The encrypted ID which tells the server the user of a request made at `timestamp`.
+fn create_auth_id(cmd_key: &[u8; 16], timestamp: u64, random: [u8; 4]) -> [u8; 16] {
+    let mut auth_id = [0u8; 16];
+    auth_id[..8].copy_from_slice(&timestamp.to_be_bytes());
+    auth_id[8..12].copy_from_slice(&random);
+    let checksum = crc32fast::hash(&auth_id[..12]);
+    auth_id[12..].copy_from_slice(&checksum.to_be_bytes());
+
+    let key = kdf16(cmd_key, &[b"AES Auth ID Encryption"]);
+    let cipher = Aes128::new(GenericArray::from_slice(&key));
+    let mut block = GenericArray::clone_from_slice(&auth_id);
+    cipher.encrypt_block(&mut block);
+    auth_id.copy_from_slice(&block);
+    auth_id
+}
+
+/// The request header as sent in AEAD mode, following its authentication ID.
+fn seal_header(
+    cmd_key: &[u8; 16],
+    auth_id: &[u8; 16],
+    nonce: &[u8; 8],
+    header: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let path: [&[u8]; 3] = [b"VMess Header AEAD Key_Length", auth_id, nonce];
+    let key = kdf16(cmd_key, &path);
+    let path: [&[u8]; 3] = [b"VMess Header AEAD Nonce_Length", auth_id, nonce];
+    let iv = kdf12(cmd_key, &path);
+    let length = (header.len() as u16).to_be_bytes();
+    let encrypted_length = aes_128_gcm_seal(&key, &iv, &length, auth_id)?;
+
+    let path: [&[u8]; 3] = [b"VMess Header AEAD Key", auth_id, nonce];
+    let key = kdf16(cmd_key, &path);
+    let path: [&[u8]; 3] = [b"VMess Header AEAD Nonce", auth_id, nonce];
+    let iv = kdf12(cmd_key, &path);
+    let encrypted_header = aes_128_gcm_seal(&key, &iv, header, auth_id)?;
+
+    let mut sealed = auth_id.to_vec();
+    sealed.extend(encrypted_length);
+    sealed.extend(nonce);
+    sealed.extend(encrypted_header);
+    Ok(sealed)
+}
+
+enum BodyCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+    None,
+}
+
+/// Encrypts or decrypts the chunks of one direction of the body stream.
+struct ChunkCipher {
+    cipher: BodyCipher,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkCipher {
+    fn new(security: VmessSecurity, key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        let cipher = match security {
+            VmessSecurity::Aes128Gcm => {
+                BodyCipher::Aes128Gcm(Box::new(Aes128Gcm::new(GenericArray::from_slice(key))))
+            }
+            VmessSecurity::Chacha20Poly1305 => {
+                let mut chacha_key = [0u8; 32];
+                chacha_key[..16].copy_from_slice(&Md5::digest(key));
+                let second_half = Md5::digest(&chacha_key[..16]);
+                chacha_key[16..].copy_from_slice(&second_half);
+                BodyCipher::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new(
+                    GenericArray::from_slice(&chacha_key),
+                )))
+            }
+            VmessSecurity::None => BodyCipher::None,
+        };
+        Self {
+            cipher,
+            iv: *iv,
+            count: 0,
+        }
+    }
+
+    fn overhead(&self) -> usize {
+        match self.cipher {
+            BodyCipher::None => 0,
+            _ => TAG_SIZE,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&self.iv[..12]);
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        match &self.cipher {
+            BodyCipher::Aes128Gcm(cipher) => cipher.encrypt(nonce, data),
+            BodyCipher::Chacha20Poly1305(cipher) => cipher.encrypt(nonce, data),
+            BodyCipher::None => Ok(data.to_vec()),
+        }
+        .map_err(|_| "VMess encryption failed".into())
+    }
+
+    fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        match &self.cipher {
+            BodyCipher::Aes128Gcm(cipher) => cipher.decrypt(nonce, data),
+            BodyCipher::Chacha20Poly1305(cipher) => cipher.decrypt(nonce, data),
+            BodyCipher::None => Ok(data.to_vec()),
+        }
+        .map_err(|_| "VMess decryption failed".into())
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum VmessState {
+    ExpectResponseHeader,
+    Established,
+}
+
+pub(crate) struct VmessConnection {
+    state: VmessState,
+    response_key: [u8; 16],
+    response_iv: [u8; 16],
+    response_auth: u8,
+    request_cipher: ChunkCipher,
+    response_cipher: ChunkCipher,
+    server_inbuf: VecDeque<u8>,
+    client_outbuf: VecDeque<u8>,
+    server_outbuf: VecDeque<u8>,
+}
+
+impl VmessConnection {
+    fn new(connection: &Connection, id: &[u8; 16], security: VmessSecurity) -> Result<Self, Error> {
+        let request_key: [u8; 16] = random_bytes()?;
+        let request_iv: [u8; 16] = random_bytes()?;
+        let [response_auth, padding_len]: [u8; 2] = random_bytes()?;
+        let padding_len = padding_len % 16;
+
+        let mut response_key = [0u8; 16];
+        response_key.copy_from_slice(&sha256(&request_key)[..16]);
+        let mut response_iv = [0u8; 16];
+        response_iv.copy_from_slice(&sha256(&request_iv)[..16]);
+
+        let mut header = vec![VERSION];
+        header.extend(request_iv);
+        header.extend(request_key);
+        header.extend([
+            response_auth,
+            OPTION_CHUNK_STREAM,
+            padding_len << 4 | security as u8,
+            0, // Reserved
+            COMMAND_TCP,
+        ]);
+        extend_destination(&mut header, &connection.dst);
+        let padding: [u8; 16] = random_bytes()?;
+        header.extend(&padding[..padding_len as usize]);
+        header.extend(fnv1a32(&header).to_be_bytes());
+
+        let mut result = Self {
+            state: VmessState::ExpectResponseHeader,
+            response_key,
+            response_iv,
+            response_auth,
+            request_cipher: ChunkCipher::new(security, &request_key, &request_iv),
+            response_cipher: ChunkCipher::new(security, &response_key, &response_iv),
+            server_inbuf: VecDeque::default(),
+            client_outbuf: VecDeque::default(),
+            server_outbuf: VecDeque::default(),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::from("system time before UNIX epoch"))?
+            .as_secs();
+        let cmd_key = cmd_key(id);
+        let auth_id = create_auth_id(&cmd_key, timestamp, random_bytes()?);
+        let sealed = seal_header(&cmd_key, &auth_id, &random_bytes()?, &header)?;
+        result.server_outbuf.extend(sealed);
+        Ok(result)
+    }
+
+    fn receive_response_header(&mut self) -> Result<(), Error> {
+        let length_size = 2 + TAG_SIZE;
+        if self.server_inbuf.len() < length_size {
+            return Ok(());
+        }
+        let data = self.server_inbuf.make_contiguous();
+
+        let key = kdf16(&self.response_key, &[b"AEAD Resp Header Len Key"]);
+        let iv = kdf12(&self.response_iv, &[b"AEAD Resp Header Len IV"]);
+        let length = aes_128_gcm_open(&key, &iv, &data[..length_size], &[])?;
+        let header_size = ((length[0] as usize) << 8 | length[1] as usize) + TAG_SIZE;
+        if data.len() < length_size + header_size {
+            return Ok(());
+        }
+
+        let key = kdf16(&self.response_key, &[b"AEAD Resp Header Key"]);
+        let iv = kdf12(&self.response_iv, &[b"AEAD Resp Header IV"]);
+        let encrypted_header = &data[length_size..length_size + header_size];
+        let header = aes_128_gcm_open(&key, &iv, encrypted_header, &[])?;
+        if header.len() < 4 || header[0] != self.response_auth {
+            return Err("VMess server replied with an unexpected response header.".into());
+        }
+
+        self.server_inbuf.drain(0..length_size + header_size);
+        self.state = VmessState::Established;
+        self.receive_chunks()
+    }
+
+    fn receive_chunks(&mut self) -> Result<(), Error> {
+        while self.server_inbuf.len() >= 2 {
+            let size = (self.server_inbuf[0] as usize) << 8 | self.server_inbuf[1] as usize;
+            if self.server_inbuf.len() < 2 + size {
+                break;
+            }
+            let chunk: Vec<u8> = self.server_inbuf.drain(0..2 + size).skip(2).collect();
+            // An empty chunk marks the end of the stream.
+            if size > self.response_cipher.overhead() {
+                let data = self.response_cipher.open(&chunk)?;
+                self.client_outbuf.extend(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_chunks(&mut self, data: &[u8]) -> Result<(), Error> {
+        for chunk in data.chunks(MAX_CHUNK_SIZE) {
+            let sealed = self.request_cipher.seal(chunk)?;
+            self.server_outbuf
+                .extend((sealed.len() as u16).to_be_bytes());
+            self.server_outbuf.extend(sealed);
+        }
+        Ok(())
+    }
+}
+
+impl TcpProxy for VmessConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => {
+                self.server_inbuf.extend(event.buffer.iter());
+                match self.state {
+                    VmessState::ExpectResponseHeader => self.receive_response_header(),
+                    VmessState::Established => self.receive_chunks(),
+                }
+            }
+            IncomingDirection::FromClient => self.send_chunks(event.buffer),
+        }
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        buffer.drain(0..size);
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &mut self.server_outbuf
+        } else {
+            &mut self.client_outbuf
+        };
+        OutgoingDataEvent {
+            direction: dir,
+            buffer: buffer.make_contiguous(),
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        let buffer = if dir == OutgoingDirection::ToServer {
+            &self.server_outbuf
+        } else {
+            &self.client_outbuf
+        };
+        buffer.into()
+    }
+
+    fn connection_established(&self) -> bool {
+        self.state == VmessState::Established
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Incoming(incoming) => match incoming {
+                IncomingDirection::FromServer => !self.server_inbuf.is_empty(),
+                IncomingDirection::FromClient => false,
+            },
+            Direction::Outgoing(outgoing) => match outgoing {
+                OutgoingDirection::ToServer => !self.server_outbuf.is_empty(),
+                OutgoingDirection::ToClient => !self.client_outbuf.is_empty(),
+            },
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+pub(crate) struct VmessManager {
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+    id: [u8; 16],
+    security: VmessSecurity,
+}
+
+impl ConnectionManager for VmessManager {
+    fn handles_connection(&self, connection: &Connection) -> bool {
+        connection.proto == IpProtocol::Tcp
+    }
+
+    fn new_connection(
+        &self,
+        connection: &Connection,
+        _: Rc<dyn ConnectionManager>,
+    ) -> Result<Option<Box<dyn TcpProxy>>, Error> {
+        if connection.proto != IpProtocol::Tcp {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(VmessConnection::new(
+            connection,
+            &self.id,
+            self.security,
+        )?)))
+    }
+
+    fn close_connection(&self, _: &Connection) {}
+
+    fn get_server(&self) -> SocketAddr {
+        self.server
+    }
+
+    fn get_credentials(&self) -> &Option<Credentials> {
+        &self.credentials
+    }
+}
+
+impl VmessManager {
+    pub fn new(
+        server: SocketAddr,
+        credentials: Option<Credentials>,
+        security: VmessSecurity,
+    ) -> Result<Rc<Self>, Error> {
+        let username = credentials
+            .as_ref()
+            .map(|credentials| credentials.username.clone())
+            .ok_or("VMess requires a user ID")?;
+        let id = parse_uuid(std::str::from_utf8(&username)?)?;
+        Ok(Rc::new(Self {
+            server,
+            credentials,
+            id,
+            security,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn range<const N: usize>(start: u8) -> [u8; N] {
+        std::array::from_fn(|i| start + i as u8)
+    }
+
+    // A connection whose request was sent with the key 20..2f and the IV 10..1f.
+    fn connection(security: VmessSecurity) -> VmessConnection {
+        let request_key: [u8; 16] = range(0x20);
+        let request_iv: [u8; 16] = range(0x10);
+        let mut response_key = [0u8; 16];
+        response_key.copy_from_slice(&sha256(&request_key)[..16]);
+        let mut response_iv = [0u8; 16];
+        response_iv.copy_from_slice(&sha256(&request_iv)[..16]);
+        VmessConnection {
+            state: VmessState::ExpectResponseHeader,
+            response_key,
+            response_iv,
+            response_auth: 0x5a,
+            request_cipher: ChunkCipher::new(security, &request_key, &request_iv),
+            response_cipher: ChunkCipher::new(security, &response_key, &response_iv),
+            server_inbuf: VecDeque::default(),
+            client_outbuf: VecDeque::default(),
+            server_outbuf: VecDeque::default(),
+        }
+    }
+
+    fn receive(connection: &mut VmessConnection, data: &[u8]) -> Result<(), Error> {
+        connection.push_data(IncomingDataEvent {
+            direction: IncomingDirection::FromServer,
+            buffer: data,
+        })
+    }
+
+    // The example response header with the authentication byte 5a, followed by the chunks
+    // `HTTP/1.1 200 OK\r\n` and `\r\n` as encrypted with AES-128-GCM or ChaCha20-Poly1305.
+    const RESPONSE_HEADER: &str = "bdbf42fa9d3621ee2883fcdb50faf39e51498040906ad3f95ba36908f282c6\
+        bff05c15fc06af0021";
+    const AES_RESPONSE_CHUNKS: &str = "10b2f32276d1554c5c3f79f2ded471944c4e0df912aee07e973733\
+        8ddaa714af990012c8550dc10671565c4a5a932e2967bca1f8bf";
+    const CHACHA_RESPONSE_CHUNKS: &str = "4984c67f087354e0298db7a4ba87d349466c3533b759044e1863\
+        5f31aabf35a18c001207ca72a3e9e067856a0299c53c5ce6f827a0";
+
+    #[test]
+    fn kdf_matches_v2ray() {
+        // The value test of v2ray's proxy/vmess/aead package.
+        let path: [&[u8]; 3] = [
+            b"Demo Path for KDF Value Test",
+            b"Demo Path for KDF Value Test2",
+            b"Demo Path for KDF Value Test3",
+        ];
+        let key = kdf16(b"Demo Key for KDF Value Test", &path);
+        assert_eq!(key.to_vec(), from_hex("53e9d7e1bd7bd25022b71ead07d8a596"));
+    }
+
+    #[test]
+    fn hmac_matches_rfc4231() {
+        // Test cases 1 and 6 of RFC 4231.
+        let mac = hmac(&sha256, &[0x0b; 20], b"Hi There");
+        let expected = "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7";
+        assert_eq!(mac.to_vec(), from_hex(expected));
+        let message = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        let mac = hmac(&sha256, &[0xaa; 131], message);
+        let expected = "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54";
+        assert_eq!(mac.to_vec(), from_hex(expected));
+    }
+
+    #[test]
+    fn seals_request_header() {
+        let id = parse_uuid("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap();
+        let cmd_key = cmd_key(&id);
+        assert_eq!(
+            cmd_key.to_vec(),
+            from_hex("b50d916ac0cec067981af8e5f38a758f")
+        );
+
+        let auth_id = create_auth_id(&cmd_key, 1700000000, [1, 2, 3, 4]);
+        assert_eq!(
+            auth_id.to_vec(),
+            from_hex("4774fe5cc901ea4f81f2159909767a36")
+        );
+
+        // A request for example.com:443 with AES-128-GCM and 0 bytes of padding.
+        let mut header = vec![VERSION];
+        header.extend(range::<16>(0x10));
+        header.extend(range::<16>(0x20));
+        header.extend([0x5a, OPTION_CHUNK_STREAM, VmessSecurity::Aes128Gcm as u8, 0]);
+        header.push(COMMAND_TCP);
+        let dst = Destination {
+            host: DestinationHost::Hostname("example.com".into()),
+            port: 443,
+        };
+        extend_destination(&mut header, &dst);
+        assert_eq!(fnv1a32(&header), 0xf36802cb);
+        header.extend(fnv1a32(&header).to_be_bytes());
+
+        let sealed = seal_header(&cmd_key, &auth_id, &range(0), &header).unwrap();
+        let expected = "4774fe5cc901ea4f81f2159909767a36ca396a82da31c11d8f5edbb63d758f08357c\
+            00010203040506078e3573dd1b58552f2be5798b573e8f5286f9823ece3f31fea35316ccbfd31dcae4\
+            741edbc15bbb91160b871f3ea8abec0f423474fe6e7fc5753c0df46679db5983e53455a6e01eb133";
+        assert_eq!(sealed, from_hex(expected));
+    }
+
+    #[test]
+    fn opens_aes_128_gcm_response() {
+        let mut connection = connection(VmessSecurity::Aes128Gcm);
+        let response = from_hex(&(RESPONSE_HEADER.to_owned() + AES_RESPONSE_CHUNKS));
+        // The response arrives in pieces.
+        let (header, chunks) = response.split_at(20);
+        receive(&mut connection, header).unwrap();
+        assert!(!connection.connection_established());
+        receive(&mut connection, chunks).unwrap();
+        assert!(connection.connection_established());
+        let data: Vec<u8> = connection.client_outbuf.drain(..).collect();
+        assert_eq!(data, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn opens_chacha20_poly1305_response() {
+        let mut connection = connection(VmessSecurity::Chacha20Poly1305);
+        let response = from_hex(&(RESPONSE_HEADER.to_owned() + CHACHA_RESPONSE_CHUNKS));
+        receive(&mut connection, &response).unwrap();
+        let data: Vec<u8> = connection.client_outbuf.drain(..).collect();
+        assert_eq!(data, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn rejects_unexpected_response_auth() {
+        let mut connection = connection(VmessSecurity::Aes128Gcm);
+        connection.response_auth = 0x5b;
+        assert!(receive(&mut connection, &from_hex(RESPONSE_HEADER)).is_err());
+    }
+
+    #[test]
+    fn seals_request_chunks() {
+        let mut connection = connection(VmessSecurity::Aes128Gcm);
+        connection.send_chunks(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let data: Vec<u8> = connection.server_outbuf.drain(..).collect();
+        let expected = "0022098c9a966092bb508f7a91c00a0d82601c6bfdedcabed51404e1851375fcb951b215";
+        assert_eq!(data, from_hex(expected));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::error::Error;
+use crate::tls::TlsOptions;
+use crate::tun2proxy::{
+    Direction, IncomingDataEvent, IncomingDirection, OutgoingDataEvent, OutgoingDirection,
+    OutgoingSlices, TcpProxy,
+};
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_PAYLOAD: usize = 0x4000;
+const MAX_RESPONSE: usize = 0x4000;
+const MAX_FRAME: usize = 0x100_0000;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Clone, Debug)]
+pub struct WebSocketOptions {
+    /// Path requested in the upgrade request
+    pub path: String,
+    /// Value of the Host header of the upgrade request
+    pub host: String,
+    /// TLS options if the WebSocket connection is secured, i.e. `wss`
+    pub tls: Option<TlsOptions>,
+}
+
+fn encode_frame(opcode: u8, payload: &[u8], outbuf: &mut VecDeque<u8>) -> Result<(), Error> {
+    let mut mask = [0u8; 4];
+    getrandom::getrandom(&mut mask).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    outbuf.push_back(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => outbuf.push_back(0x80 | len as u8),
+        len if len <= 0xffff => {
+            outbuf.push_back(0x80 | 126);
+            outbuf.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            outbuf.push_back(0x80 | 127);
+            outbuf.extend((len as u64).to_be_bytes());
+        }
+    }
+    outbuf.extend(mask);
+    outbuf.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    Ok(())
+}
+
+/// Wraps the connection to the proxy server in a WebSocket connection. After the upgrade, the
+/// stream of the wrapped handler is carried in binary messages.
+pub(crate) struct WebSocketConnection {
+    inner: Box<dyn TcpProxy>,
+    accept: Option<String>,
+    server_inbuf: Vec<u8>,
+    server_outbuf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl WebSocketConnection {
+    pub fn new(inner: Box<dyn TcpProxy>, options: &WebSocketOptions) -> Result<Self, Error> {
+        let mut key = [0u8; 16];
+        getrandom::getrandom(&mut key).map_err(|_| Error::from("failed to obtain random bytes"))?;
+        let key = base64::engine::general_purpose::STANDARD.encode(key);
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(ACCEPT_GUID);
+        let accept = base64::engine::general_purpose::STANDARD.encode(hasher.finalize());
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            options.path, options.host
+        );
+        Ok(Self {
+            inner,
+            accept: Some(accept),
+            server_inbuf: Vec::new(),
+            server_outbuf: request.into_bytes().into(),
+            closed: false,
+        })
+    }
+
+    fn established(&self) -> bool {
+        self.accept.is_none()
+    }
+
+    // Frame the data the wrapped handler wants to send once the upgrade is complete.
+    fn pump(&mut self) -> Result<(), Error> {
+        if !self.established() || self.closed {
+            return Ok(());
+        }
+        loop {
+            let event = self.inner.peek_data(OutgoingDirection::ToServer);
+            if event.buffer.is_empty() {
+                break;
+            }
+            let len = event.buffer.len().min(MAX_PAYLOAD);
+            encode_frame(OPCODE_BINARY, &event.buffer[..len], &mut self.server_outbuf)?;
+            self.inner.consume_data(OutgoingDirection::ToServer, len);
+        }
+        Ok(())
+    }
+
+    fn receive_response(&mut self) -> Result<(), Error> {
+        let end = match self
+            .server_inbuf
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            Some(position) => position + 4,
+            None if self.server_inbuf.len() > MAX_RESPONSE => {
+                return Err("WebSocket upgrade response is too long".into());
+            }
+            None => return Ok(()),
+        };
+        let response = String::from_utf8_lossy(&self.server_inbuf[..end]).into_owned();
+        self.server_inbuf.drain(..end);
+
+        let mut lines = response.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap_or_default();
+        if status != "101" {
+            return Err(format!("WebSocket upgrade failed with status `{status}`").into());
+        }
+        let accept = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("sec-websocket-accept")
+                .then(|| value.trim())
+        });
+        if accept != self.accept.as_deref() {
+            return Err("WebSocket upgrade response has an invalid accept key".into());
+        }
+        self.accept = None;
+        Ok(())
+    }
+
+    fn receive_frames(&mut self) -> Result<(), Error> {
+        let mut offset = 0;
+        let mut payload = Vec::new();
+        while !self.closed {
+            let data = &self.server_inbuf[offset..];
+            if data.len() < 2 {
+                break;
+            }
+            if data[1] & 0x80 != 0 {
+                return Err("WebSocket server sent a masked frame".into());
+            }
+            let opcode = data[0] & 0x0f;
+            let (len, header) = match data[1] & 0x7f {
+                126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as u64, 4),
+                127 if data.len() >= 10 => (
+                    data[2..10]
+                        .iter()
+                        .fold(0u64, |len, byte| len << 8 | *byte as u64),
+                    10,
+                ),
+                126 | 127 => break,
+                len => (len as u64, 2),
+            };
+            let len = match usize::try_from(len) {
+                Ok(len) if len <= MAX_FRAME => len,
+                _ => return Err("WebSocket frame is too long".into()),
+            };
+            if data.len() < header + len {
+                break;
+            }
+            let frame = &data[header..header + len];
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => payload.extend(frame),
+                OPCODE_PING => encode_frame(OPCODE_PONG, frame, &mut self.server_outbuf)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // Echo the status code. Data still in flight is delivered before the server
+                    // closes the underlying connection.
+                    let status = &frame[..frame.len().min(2)];
+                    encode_frame(OPCODE_CLOSE, status, &mut self.server_outbuf)?;
+                    self.closed = true;
+                }
+                _ => return Err(format!("WebSocket frame has unknown opcode {opcode}").into()),
+            }
+            offset += header + len;
+        }
+        self.server_inbuf.drain(..offset);
+
+        if !payload.is_empty() {
+            self.inner.push_data(IncomingDataEvent {
+                direction: IncomingDirection::FromServer,
+                buffer: &payload,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl TcpProxy for WebSocketConnection {
+    fn push_data(&mut self, event: IncomingDataEvent<'_>) -> Result<(), Error> {
+        match event.direction {
+            IncomingDirection::FromServer => {
+                if !self.closed {
+                    self.server_inbuf.extend(event.buffer);
+                }
+                if !self.established() {
+                    self.receive_response()?;
+                }
+                if self.established() {
+                    self.receive_frames()?;
+                }
+            }
+            IncomingDirection::FromClient => self.inner.push_data(event)?,
+        }
+        self.pump()
+    }
+
+    fn consume_data(&mut self, dir: OutgoingDirection, size: usize) {
+        if dir == OutgoingDirection::ToServer {
+            self.server_outbuf.drain(0..size);
+        } else {
+            self.inner.consume_data(dir, size);
+        }
+    }
+
+    fn peek_data(&mut self, dir: OutgoingDirection) -> OutgoingDataEvent {
+        if dir == OutgoingDirection::ToServer {
+            OutgoingDataEvent {
+                direction: dir,
+                buffer: self.server_outbuf.make_contiguous(),
+            }
+        } else {
+            self.inner.peek_data(dir)
+        }
+    }
+
+    fn peek_slices(&mut self, dir: OutgoingDirection) -> OutgoingSlices<'_> {
+        if dir == OutgoingDirection::ToServer {
+            (&self.server_outbuf).into()
+        } else {
+            self.inner.peek_slices(dir)
+        }
+    }
+
+    fn connection_established(&self) -> bool {
+        self.established() && self.inner.connection_established()
+    }
+
+    fn have_data(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::Outgoing(OutgoingDirection::ToServer) => !self.server_outbuf.is_empty(),
+            _ => self.inner.have_data(dir),
+        }
+    }
+
+    fn get_udp_associate(&self) -> Option<SocketAddr> {
+        self.inner.get_udp_associate()
+    }
+
+    fn get_bind_address(&self) -> Option<SocketAddr> {
+        self.inner.get_bind_address()
+    }
+
+    fn hostname_refused(&self) -> bool {
+        self.inner.hostname_refused()
+    }
+}
@@ -0,0 +1,640 @@
+use crate::error::Error;
+use blake2::digest::consts::U16;
+use blake2::digest::{FixedOutput, Mac, Update};
+use blake2::{Blake2s256, Blake2sMac, Digest};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use hmac::SimpleHmac;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+const LABEL_COOKIE: &[u8] = b"cookie--";
+
+const MESSAGE_INITIATION: u8 = 1;
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_COOKIE_REPLY: u8 = 3;
+const MESSAGE_TRANSPORT: u8 = 4;
+
+const INITIATION_SIZE: usize = 148;
+const RESPONSE_SIZE: usize = 92;
+const COOKIE_REPLY_SIZE: usize = 64;
+const TRANSPORT_HEADER_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const COOKIE_LIFETIME: Duration = Duration::from_secs(120);
+
+const MAX_QUEUED_PACKETS: usize = 64;
+const REPLAY_WINDOW_SIZE: u64 = 2048;
+
+#[derive(Clone, Debug)]
+pub struct WireGuardOptions {
+    /// Private key of this peer
+    pub private_key: [u8; 32],
+    /// Public key of the remote peer
+    pub public_key: [u8; 32],
+    /// Optional pre-shared key mixed into the handshake
+    pub preshared_key: Option<[u8; 32]>,
+    /// Interval of persistent keepalive messages in seconds
+    pub keepalive: Option<u16>,
+}
+
+/// Decode a key in the base64 format used by the wg tool.
+pub(crate) fn parse_key(s: &str) -> Result<[u8; 32], Error> {
+    use base64::Engine;
+    let e = format!("`{s}` is not a valid WireGuard key");
+    // Query strings turn an unescaped '+' into a space.
+    let s = s.replace(' ', "+");
+    base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::from(&e))
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    hasher.finalize().into()
+}
+
+fn mac(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(key).expect("valid key length");
+    Update::update(&mut mac, data);
+    mac.finalize_fixed().into()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hmac =
+        <SimpleHmac<Blake2s256> as KeyInit>::new_from_slice(key).expect("any key length");
+    Mac::update(&mut hmac, data);
+    hmac.finalize().into_bytes().into()
+}
+
+/// The HKDF of the Noise protocol, returning `N` derived keys.
+fn kdf<const N: usize>(key: &[u8], input: &[u8]) -> [[u8; 32]; N] {
+    let secret = hmac(key, input);
+    let mut result = [[0u8; 32]; N];
+    let mut previous: Vec<u8> = Vec::new();
+    for (i, output) in result.iter_mut().enumerate() {
+        previous.push(i as u8 + 1);
+        *output = hmac(&secret, &previous);
+        previous = output.to_vec();
+    }
+    result
+}
+
+fn aead_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn seal(key: &[u8; 32], counter: u64, msg: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&aead_nonce(counter).into(), Payload { msg, aad })
+        .expect("encryption does not fail")
+}
+
+fn open(key: &[u8; 32], counter: u64, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&aead_nonce(counter).into(), Payload { msg, aad })
+        .map_err(|_| "WireGuard decryption failed".into())
+}
+
+fn dh(private: &[u8; 32], public: &[u8; 32]) -> [u8; 32] {
+    x25519_dalek::x25519(*private, *public)
+}
+
+fn public_key(private: &[u8; 32]) -> [u8; 32] {
+    x25519_dalek::x25519(*private, x25519_dalek::X25519_BASEPOINT_BYTES)
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| Error::from("failed to obtain random bytes"))?;
+    Ok(bytes)
+}
+
+fn tai64n(now: SystemTime) -> [u8; 12] {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut timestamp = [0u8; 12];
+    timestamp[..8].copy_from_slice(&(0x400000000000000a + now.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+/// Sliding window of received counters, rejecting replayed transport messages.
+#[derive(Default)]
+struct ReplayWindow {
+    greatest: Option<u64>,
+    bitmap: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn bit(&mut self, counter: u64) -> (&mut u64, u64) {
+        let index = (counter % REPLAY_WINDOW_SIZE) as usize;
+        (&mut self.bitmap[index / 64], 1 << (index % 64))
+    }
+
+    fn check_and_update(&mut self, counter: u64) -> bool {
+        match self.greatest {
+            Some(greatest) if counter <= greatest => {
+                if greatest - counter >= REPLAY_WINDOW_SIZE {
+                    return false;
+                }
+                let (word, bit) = self.bit(counter);
+                let seen = *word & bit != 0;
+                *word |= bit;
+                !seen
+            }
+            greatest => {
+                let first = greatest.map_or(0, |greatest| greatest + 1);
+                if counter - first >= REPLAY_WINDOW_SIZE {
+                    self.bitmap = Default::default();
+                } else {
+                    for skipped in first..counter {
+                        let (word, bit) = self.bit(skipped);
+                        *word &= !bit;
+                    }
+                }
+                let (word, bit) = self.bit(counter);
+                *word |= bit;
+                self.greatest = Some(counter);
+                true
+            }
+        }
+    }
+}
+
+struct Session {
+    local_index: u32,
+    remote_index: u32,
+    send_key: [u8; 32],
+    receive_key: [u8; 32],
+    send_counter: u64,
+    replay: ReplayWindow,
+    established: Instant,
+}
+
+struct Handshake {
+    local_index: u32,
+    ephemeral: [u8; 32],
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    mac1: [u8; 16],
+    sent: Instant,
+}
+
+/// A sans-IO WireGuard peer which encapsulates IP packets into UDP datagrams sent to a single
+/// remote peer, and decapsulates the datagrams received from it.
+pub(crate) struct WireGuardTunnel {
+    private_key: [u8; 32],
+    public_key: [u8; 32],
+    remote_public_key: [u8; 32],
+    preshared_key: [u8; 32],
+    keepalive: Option<Duration>,
+    static_secret: [u8; 32],
+    handshake: Option<Handshake>,
+    cookie: Option<([u8; 16], Instant)>,
+    current: Option<Session>,
+    previous: Option<Session>,
+    queue: VecDeque<Vec<u8>>,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl WireGuardTunnel {
+    pub fn new(options: &WireGuardOptions) -> Self {
+        let now = Instant::now();
+        Self {
+            private_key: options.private_key,
+            public_key: public_key(&options.private_key),
+            remote_public_key: options.public_key,
+            preshared_key: options.preshared_key.unwrap_or_default(),
+            keepalive: options
+                .keepalive
+                .filter(|keepalive| *keepalive > 0)
+                .map(|keepalive| Duration::from_secs(keepalive.into())),
+            static_secret: dh(&options.private_key, &options.public_key),
+            handshake: None,
+            cookie: None,
+            current: None,
+            previous: None,
+            queue: VecDeque::new(),
+            last_sent: now,
+            last_received: now,
+        }
+    }
+
+    fn create_initiation(&mut self, now: Instant) -> Result<Vec<u8>, Error> {
+        let local_index = u32::from_le_bytes(random_bytes()?);
+        let timestamp = tai64n(SystemTime::now());
+        Ok(self.initiation(local_index, random_bytes()?, &timestamp, now))
+    }
+
+    fn initiation(
+        &mut self,
+        local_index: u32,
+        ephemeral: [u8; 32],
+        timestamp: &[u8; 12],
+        now: Instant,
+    ) -> Vec<u8> {
+        let ephemeral_public = public_key(&ephemeral);
+
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let h = hash(&[&chaining_key, IDENTIFIER]);
+        let h = hash(&[&h, &self.remote_public_key]);
+        let [chaining_key] = kdf(&chaining_key, &ephemeral_public);
+        let h = hash(&[&h, &ephemeral_public]);
+        let [chaining_key, key] = kdf(&chaining_key, &dh(&ephemeral, &self.remote_public_key));
+        let encrypted_static = seal(&key, 0, &self.public_key, &h);
+        let h = hash(&[&h, &encrypted_static]);
+        let [chaining_key, key] = kdf(&chaining_key, &self.static_secret);
+        let encrypted_timestamp = seal(&key, 0, timestamp, &h);
+        let h = hash(&[&h, &encrypted_timestamp]);
+
+        let mut message = vec![MESSAGE_INITIATION, 0, 0, 0];
+        message.extend(local_index.to_le_bytes());
+        message.extend(ephemeral_public);
+        message.extend(encrypted_static);
+        message.extend(encrypted_timestamp);
+        let mac1 = mac(&hash(&[LABEL_MAC1, &self.remote_public_key]), &message);
+        message.extend(mac1);
+        match self.cookie {
+            Some((cookie, received)) if now.duration_since(received) < COOKIE_LIFETIME => {
+                message.extend(mac(&cookie, &message));
+            }
+            _ => message.extend([0u8; 16]),
+        }
+
+        self.handshake = Some(Handshake {
+            local_index,
+            ephemeral,
+            chaining_key,
+            hash: h,
+            mac1,
+            sent: now,
+        });
+        self.last_sent = now;
+        message
+    }
+
+    fn receive_response(&mut self, message: &[u8], now: Instant) -> Result<(), Error> {
+        let receiver = u32::from_le_bytes(message[8..12].try_into().unwrap());
+        let handshake = match &self.handshake {
+            Some(handshake) if handshake.local_index == receiver => handshake,
+            _ => return Err("WireGuard response does not match a handshake".into()),
+        };
+        let remote_index = u32::from_le_bytes(message[4..8].try_into().unwrap());
+        let ephemeral_public: [u8; 32] = message[12..44].try_into().unwrap();
+        let encrypted_nothing = &message[44..60];
+
+        let [chaining_key] = kdf(&handshake.chaining_key, &ephemeral_public);
+        let h = hash(&[&handshake.hash, &ephemeral_public]);
+        let [chaining_key] = kdf(&chaining_key, &dh(&handshake.ephemeral, &ephemeral_public));
+        let [chaining_key] = kdf(&chaining_key, &dh(&self.private_key, &ephemeral_public));
+        let [chaining_key, tau, key] = kdf(&chaining_key, &self.preshared_key);
+        let h = hash(&[&h, &tau]);
+        open(&key, 0, encrypted_nothing, &h)?;
+        let [send_key, receive_key] = kdf(&chaining_key, &[]);
+
+        let session = Session {
+            local_index: handshake.local_index,
+            remote_index,
+            send_key,
+            receive_key,
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+            established: now,
+        };
+        self.handshake = None;
+        self.previous = self.current.replace(session);
+        log::info!("WireGuard handshake completed");
+        Ok(())
+    }
+
+    fn receive_cookie_reply(&mut self, message: &[u8], now: Instant) -> Result<(), Error> {
+        let receiver = u32::from_le_bytes(message[4..8].try_into().unwrap());
+        let handshake = match &self.handshake {
+            Some(handshake) if handshake.local_index == receiver => handshake,
+            _ => return Ok(()),
+        };
+        let key = hash(&[LABEL_COOKIE, &self.remote_public_key]);
+        let cookie = XChaCha20Poly1305::new((&key).into())
+            .decrypt(
+                message[8..32].into(),
+                Payload {
+                    msg: &message[32..64],
+                    aad: &handshake.mac1,
+                },
+            )
+            .map_err(|_| Error::from("WireGuard cookie decryption failed"))?;
+        self.cookie = Some((cookie.as_slice().try_into().unwrap(), now));
+        Ok(())
+    }
+
+    fn seal_transport(&mut self, packet: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let session = self.current.as_mut()?;
+        if session.send_counter >= REJECT_AFTER_MESSAGES
+            || now.duration_since(session.established) >= REJECT_AFTER_TIME
+        {
+            return None;
+        }
+        let mut padded = packet.to_vec();
+        padded.resize(packet.len().div_ceil(16) * 16, 0);
+
+        let mut message = vec![MESSAGE_TRANSPORT, 0, 0, 0];
+        message.extend(session.remote_index.to_le_bytes());
+        message.extend(session.send_counter.to_le_bytes());
+        message.extend(seal(&session.send_key, session.send_counter, &padded, &[]));
+        session.send_counter += 1;
+        self.last_sent = now;
+        Some(message)
+    }
+
+    fn needs_handshake(&self, now: Instant) -> bool {
+        if let Some(handshake) = &self.handshake {
+            return now.duration_since(handshake.sent) >= REKEY_TIMEOUT;
+        }
+        match &self.current {
+            None => true,
+            Some(session) => {
+                session.send_counter >= REKEY_AFTER_MESSAGES
+                    || now.duration_since(session.established) >= REKEY_AFTER_TIME
+            }
+        }
+    }
+
+    /// Encapsulate a packet read from the tunnel interface. Packets are queued until a session
+    /// with the remote peer has been established.
+    pub fn encapsulate(
+        &mut self,
+        packet: &[u8],
+        datagrams: &mut Vec<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        if self.needs_handshake(now) {
+            datagrams.push(self.create_initiation(now)?);
+        }
+        match self.seal_transport(packet, now) {
+            Some(message) => datagrams.push(message),
+            None => {
+                if self.queue.len() >= MAX_QUEUED_PACKETS {
+                    self.queue.pop_front();
+                }
+                self.queue.push_back(packet.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    /// Decapsulate a datagram received from the remote peer. Handshake messages may produce
+    /// datagrams to send back, while transport messages produce packets for the tunnel interface.
+    pub fn decapsulate(
+        &mut self,
+        datagram: &[u8],
+        datagrams: &mut Vec<Vec<u8>>,
+        packets: &mut Vec<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        match (datagram.first(), datagram.len()) {
+            (Some(&MESSAGE_RESPONSE), RESPONSE_SIZE) => {
+                self.receive_response(datagram, now)?;
+                // Confirm the session to the responder, which waits for the first transport
+                // message before using it.
+                let queued: Vec<Vec<u8>> = self.queue.drain(..).collect();
+                for packet in queued.iter() {
+                    datagrams.extend(self.seal_transport(packet, now));
+                }
+                if queued.is_empty() {
+                    datagrams.extend(self.seal_transport(&[], now));
+                }
+            }
+            (Some(&MESSAGE_COOKIE_REPLY), COOKIE_REPLY_SIZE) => {
+                self.receive_cookie_reply(datagram, now)?;
+            }
+            (Some(&MESSAGE_TRANSPORT), len) if len >= TRANSPORT_HEADER_SIZE + TAG_SIZE => {
+                let receiver = u32::from_le_bytes(datagram[4..8].try_into().unwrap());
+                let counter = u64::from_le_bytes(datagram[8..16].try_into().unwrap());
+                let session = self
+                    .current
+                    .iter_mut()
+                    .chain(self.previous.iter_mut())
+                    .find(|session| session.local_index == receiver)
+                    .ok_or("WireGuard transport message for an unknown session")?;
+                if now.duration_since(session.established) >= REJECT_AFTER_TIME {
+                    return Err("WireGuard transport message for an expired session".into());
+                }
+                let packet = open(
+                    &session.receive_key,
+                    counter,
+                    &datagram[TRANSPORT_HEADER_SIZE..],
+                    &[],
+                )?;
+                if !session.replay.check_and_update(counter) {
+                    return Err("WireGuard transport message was replayed".into());
+                }
+                // Keepalive messages are empty.
+                if let Some(length) = packet_length(&packet) {
+                    packets.push(packet[..length.min(packet.len())].to_vec());
+                    self.last_received = now;
+                }
+            }
+            (Some(&MESSAGE_INITIATION), INITIATION_SIZE) => {
+                log::debug!("Ignoring WireGuard handshake initiated by the remote peer");
+            }
+            _ => return Err("WireGuard received an invalid message".into()),
+        }
+        Ok(())
+    }
+
+    /// Handle handshake retransmissions and keepalive messages.
+    pub fn update_timers(&mut self, datagrams: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        let now = Instant::now();
+        if !self.queue.is_empty() && self.needs_handshake(now) {
+            datagrams.push(self.create_initiation(now)?);
+        }
+        let since_sent = now.duration_since(self.last_sent);
+        let passive_keepalive =
+            self.last_received > self.last_sent && since_sent >= KEEPALIVE_TIMEOUT;
+        let persistent_keepalive =
+            matches!(self.keepalive, Some(keepalive) if since_sent >= keepalive);
+        if passive_keepalive || persistent_keepalive {
+            if self.needs_handshake(now) {
+                datagrams.push(self.create_initiation(now)?);
+            } else {
+                datagrams.extend(self.seal_transport(&[], now));
+            }
+        }
+        Ok(())
+    }
+
+    /// The point in time at which `update_timers` has to be called next.
+    pub fn next_timer(&self) -> Instant {
+        let mut next = self.last_sent + KEEPALIVE_TIMEOUT;
+        if let Some(keepalive) = self.keepalive {
+            next = next.min(self.last_sent + keepalive);
+        }
+        if let Some(handshake) = &self.handshake {
+            next = next.min(handshake.sent + REKEY_TIMEOUT);
+        }
+        next
+    }
+}
+
+// The length of the IP packet, as opposed to the padded plaintext of a transport message.
+fn packet_length(packet: &[u8]) -> Option<usize> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => Some(u16::from_be_bytes([packet[2], packet[3]]) as usize),
+        6 if packet.len() >= 40 => Some(40 + u16::from_be_bytes([packet[4], packet[5]]) as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages exchanged by the initiator with the private key 10..10 and the responder with the
+    // private key 20..20 using the pre-shared key 30..30, as computed by an independent
+    // implementation. The initiator uses the index 11223344 and the ephemeral key 40..5f, the
+    // responder uses the index 55667788 and the ephemeral key 60..7f.
+    const INITIATION: &str = "010000004433221179a631eede1bf9c98f12032cdeadd0e7a079398fc786b88cc846\
+        ec89af85a51ab538728a1ce3569176de7636e74162bdd5280da06fdf42d0b85ebb6366b6c55730d4cd43da0b18\
+        126b835128f6a1d6bee5075aeb45f866c79bc087c4c30ec71d2e55f9972f00ac39b2b6de5be0dbd41b15e69c6d\
+        b6eff68c3ee705ac00000000000000000000000000000000";
+    const RESPONSE: &str = "020000008877665544332211675dd574ed7789310b3d2e7681f3790b466c773b1521fe\
+        cf36577958371ea52fc8df36a88e82b7c919ddfb5bbd5a64dc89c3965db520beec333e5e202efdedeb00000000\
+        000000000000000000000000";
+    const KEEPALIVE: &str = "04000000887766550000000000000000a47a67bd02886382aa639bcc3fb24861";
+    const FROM_RESPONDER: &str = "0400000044332211000000000000000021fd2f9d82b1770dce1245091c6ec9fa\
+        094748f989ab29c1d6895739d4637c153cdf830921bc15a62ec99bd743132a9f2d30766ee73ef785bd2e953cd1\
+        ce77fd";
+    const TO_RESPONDER: &str = "040000008877665501000000000000001e5374104dabb27d4327a6d1aab3e7be60\
+        6ac923597f81077f1a85b521f76d28b15082572dc8477cc0c44a17d6ad2245b7f2a1552d6d4168e0a7bd8e2ea2\
+        5363";
+    const COOKIE_REPLY: &str = "0300000044332211c0c1c2c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d724\
+        f826d0a611e4e6746aaecf400cf6ca7f74bf6c890eba357c718e0a7ac9581a";
+
+    // A UDP datagram of 33 bytes, sent back and forth once the handshake is completed.
+    const PACKET: &str = "450000210000000040110000c0a80001c0a8000230390035000d000068656c6c6f";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn range<const N: usize>(start: u8) -> [u8; N] {
+        std::array::from_fn(|i| start + i as u8)
+    }
+
+    fn tunnel() -> WireGuardTunnel {
+        WireGuardTunnel::new(&WireGuardOptions {
+            private_key: [0x10; 32],
+            public_key: public_key(&[0x20; 32]),
+            preshared_key: Some([0x30; 32]),
+            keepalive: None,
+        })
+    }
+
+    fn initiate(tunnel: &mut WireGuardTunnel) -> Vec<u8> {
+        let timestamp = tai64n(UNIX_EPOCH + Duration::new(1700000000, 10));
+        tunnel.initiation(0x11223344, range(0x40), &timestamp, Instant::now())
+    }
+
+    #[test]
+    fn derives_initial_state() {
+        // The initial chaining key and hash of wireguard-go.
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let expected = "60e26daef327efc02ec335e2a025d2d016eb4206f87277f52d38d1988b78cd36";
+        assert_eq!(chaining_key.to_vec(), from_hex(expected));
+        let h = hash(&[&chaining_key, IDENTIFIER]);
+        let expected = "2211b361081ac566691243db458ad5322d9c6c662293e8b70ee19c65ba079ef3";
+        assert_eq!(h.to_vec(), from_hex(expected));
+    }
+
+    #[test]
+    fn encodes_tai64n() {
+        let timestamp = tai64n(UNIX_EPOCH + Duration::new(1700000000, 10));
+        assert_eq!(timestamp.to_vec(), from_hex("400000006553f10a0000000a"));
+    }
+
+    #[test]
+    fn creates_initiation() {
+        let mut tunnel = tunnel();
+        assert_eq!(initiate(&mut tunnel), from_hex(INITIATION));
+    }
+
+    #[test]
+    fn completes_handshake() {
+        let mut tunnel = tunnel();
+        initiate(&mut tunnel);
+        let (mut datagrams, mut packets) = (Vec::new(), Vec::new());
+        tunnel
+            .decapsulate(&from_hex(RESPONSE), &mut datagrams, &mut packets)
+            .unwrap();
+        // The session is confirmed with a keepalive message.
+        assert_eq!(datagrams, [from_hex(KEEPALIVE)]);
+        assert!(packets.is_empty());
+
+        datagrams.clear();
+        tunnel
+            .decapsulate(&from_hex(FROM_RESPONDER), &mut datagrams, &mut packets)
+            .unwrap();
+        assert!(datagrams.is_empty());
+        assert_eq!(packets, [from_hex(PACKET)]);
+
+        tunnel
+            .encapsulate(&from_hex(PACKET), &mut datagrams)
+            .unwrap();
+        assert_eq!(datagrams, [from_hex(TO_RESPONDER)]);
+
+        // Transport messages are accepted only once.
+        let replayed = tunnel.decapsulate(&from_hex(FROM_RESPONDER), &mut datagrams, &mut packets);
+        assert!(replayed.is_err());
+    }
+
+    #[test]
+    fn rejects_forged_response() {
+        let mut tunnel = tunnel();
+        initiate(&mut tunnel);
+        let mut response = from_hex(RESPONSE);
+        response[50] ^= 1;
+        let (mut datagrams, mut packets) = (Vec::new(), Vec::new());
+        assert!(tunnel
+            .decapsulate(&response, &mut datagrams, &mut packets)
+            .is_err());
+        assert!(tunnel.current.is_none());
+    }
+
+    #[test]
+    fn answers_cookie_reply() {
+        let mut tunnel = tunnel();
+        initiate(&mut tunnel);
+        let (mut datagrams, mut packets) = (Vec::new(), Vec::new());
+        tunnel
+            .decapsulate(&from_hex(COOKIE_REPLY), &mut datagrams, &mut packets)
+            .unwrap();
+        // The initiation is sent again, with the cookie as key of its second MAC.
+        let initiation = initiate(&mut tunnel);
+        assert_eq!(initiation[..132], from_hex(INITIATION)[..132]);
+        assert_eq!(
+            initiation[132..],
+            from_hex("c90192eae6cdcff32bf18168e1beb258")
+        );
+    }
+}
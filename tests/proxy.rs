@@ -54,6 +54,18 @@ mod tests {
         reqwest::blocking::get("https://example.org").expect("failed to issue HTTPs request");
     }
 
+    fn request_example_http() {
+        reqwest::blocking::get("http://example.org").expect("failed to issue HTTP request");
+    }
+
+    fn request_example_http_after_fallback() {
+        // The proxy refuses the hostname of the first request, which is retried at once with the
+        // address resolved locally. Its acceptance tells that the proxy resolves no hostnames, so
+        // the second request is made with the address right away.
+        request_example_http();
+        request_example_http();
+    }
+
     fn run_test<F, T>(filter: F, test_function: T)
     where
         F: Fn(&Test) -> bool,
@@ -65,34 +77,7 @@ mod tests {
                     if !filter(&test) {
                         continue;
                     }
-
-                    let bypass_ip = match env::var("BYPASS_IP") {
-                        Err(_) => test.proxy.addr.ip(),
-                        Ok(ip_str) => IpAddr::from_str(ip_str.as_str()).unwrap(),
-                    };
-
-                    let mut setup =
-                        Setup::new(TUN_TEST_DEVICE, &bypass_ip, get_default_cidrs(), false);
-                    setup.configure().unwrap();
-
-                    match fork::fork() {
-                        Ok(Fork::Parent(child)) => {
-                            test_function();
-                            signal::kill(Pid::from_raw(child), signal::SIGINT)
-                                .expect("failed to kill child");
-                            setup.restore().unwrap();
-                        }
-                        Ok(Fork::Child) => {
-                            prctl::set_death_signal(signal::SIGINT as isize).unwrap();
-                            let _ = main_entry(
-                                &NetworkInterface::Named(TUN_TEST_DEVICE.into()),
-                                &test.proxy,
-                                Options::new().with_virtual_dns(),
-                            );
-                            std::process::exit(0);
-                        }
-                        Err(_) => panic!(),
-                    }
+                    run_single_test(&test, &test_function);
                 }
                 Err(_) => {
                     continue;
@@ -101,6 +86,37 @@ mod tests {
         }
     }
 
+    fn run_single_test<T>(test: &Test, test_function: T)
+    where
+        T: Fn(),
+    {
+        let bypass_ip = match env::var("BYPASS_IP") {
+            Err(_) => test.proxy.addr.ip(),
+            Ok(ip_str) => IpAddr::from_str(ip_str.as_str()).unwrap(),
+        };
+
+        let mut setup = Setup::new(TUN_TEST_DEVICE, &bypass_ip, get_default_cidrs(), false);
+        setup.configure().unwrap();
+
+        match fork::fork() {
+            Ok(Fork::Parent(child)) => {
+                test_function();
+                signal::kill(Pid::from_raw(child), signal::SIGINT).expect("failed to kill child");
+                setup.restore().unwrap();
+            }
+            Ok(Fork::Child) => {
+                prctl::set_death_signal(signal::SIGINT as isize).unwrap();
+                let _ = main_entry(
+                    &NetworkInterface::Named(TUN_TEST_DEVICE.into()),
+                    &test.proxy,
+                    Options::new().with_virtual_dns(),
+                );
+                std::process::exit(0);
+            }
+            Err(_) => panic!(),
+        }
+    }
+
     fn require_var(var: &str) {
         env::var(var).unwrap_or_else(|_| panic!("{} environment variable required", var));
     }
@@ -145,6 +161,24 @@ mod tests {
         )
     }
 
+    #[serial]
+    #[test_log::test]
+    fn test_socks4_hostname() {
+        require_var("SOCKS4_SERVER");
+        run_test(
+            |test| test.proxy.proxy_type == ProxyType::Socks4,
+            request_example_http,
+        )
+    }
+
+    #[serial]
+    #[test_log::test]
+    fn test_socks4_hostname_fallback() {
+        require_var("SOCKS4_LEGACY_SERVER");
+        let test = test_from_env("SOCKS4_LEGACY_SERVER").unwrap();
+        run_single_test(&test, request_example_http_after_fallback)
+    }
+
     #[serial]
     #[test_log::test]
     fn test_socks5_dns() {